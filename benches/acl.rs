@@ -0,0 +1,67 @@
+//! Measures the cost of `Acl::is_bypass` on a large ACL, uncached and cached, and of
+//! evicting cached decisions on a small ACL with more hosts than the cache holds.
+//!
+//! Run with `cargo bench --bench acl`.
+
+use std::{
+    hint::black_box,
+    net::{IpAddr, Ipv4Addr},
+    time::{Duration, Instant},
+};
+
+use ss_rs::acl::Acl;
+
+const RULES: usize = 10000;
+const HOSTS: usize = 100;
+const ROUNDS: usize = 100;
+const CHURN_HOSTS: usize = 100000;
+
+fn main() {
+    let mut data = String::from("[proxy_all]\n\n[bypass_list]\n");
+    for i in 0..RULES {
+        data.push_str(&format!("(^|\\.)site{}\\.example\\.com$\n", i));
+    }
+
+    let acl = Acl::from_str(&data);
+    let ip = IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34));
+    let hosts: Vec<String> = (0..HOSTS).map(|i| format!("www.host{}.net", i)).collect();
+
+    // The first round runs the rules, the others hit the cache.
+    let mut uncached = Duration::ZERO;
+    let mut cached = Duration::ZERO;
+
+    for round in 0..ROUNDS {
+        for host in &hosts {
+            let start = Instant::now();
+            black_box(acl.is_bypass(black_box(ip), black_box(Some(host))));
+            let elapsed = start.elapsed();
+
+            match round {
+                0 => uncached += elapsed,
+                _ => cached += elapsed,
+            }
+        }
+    }
+
+    let uncached = uncached / HOSTS as u32;
+    let cached = cached / (HOSTS * (ROUNDS - 1)) as u32;
+
+    println!("is_bypass with {} regex rules:", RULES);
+    println!("  uncached: {:?} per lookup", uncached);
+    println!("  cached:   {:?} per lookup", cached);
+
+    // Every lookup misses, so once the cache is full each one evicts a decision.
+    let acl = Acl::from_str("[proxy_all]\n\n[bypass_list]\n||example.com\n");
+    let hosts: Vec<String> = (0..CHURN_HOSTS)
+        .map(|i| format!("www.host{}.net", i))
+        .collect();
+
+    let start = Instant::now();
+    for host in &hosts {
+        black_box(acl.is_bypass(black_box(ip), black_box(Some(host))));
+    }
+    let churn = start.elapsed() / CHURN_HOSTS as u32;
+
+    println!("is_bypass with {} distinct hosts:", CHURN_HOSTS);
+    println!("  evicting: {:?} per lookup", churn);
+}
@@ -12,6 +12,9 @@ mod constants {
 }
 
 /// Represents a CIDR network.
+///
+/// An IPv6 network may carry a zone identifier (e.g. `fe80::1%eth0/64`).
+/// The zone is accepted but ignored, since networks are matched on address bits only.
 pub struct Cidr {
     /// The network.
     pub addr: IpAddr,
@@ -29,14 +32,20 @@ impl FromStr for Cidr {
             None => return Err(Error::NoSlash),
         };
 
-        let is_ipv4 = Ipv4Addr::from_str(addr);
-        let is_ipv6 = Ipv6Addr::from_str(addr);
+        // Strips the zone identifier, which is only valid for an IPv6 address.
+        let (ip, zoned) = match addr.split_once('%') {
+            Some((ip, zone)) if !zone.is_empty() => (ip, true),
+            _ => (addr, false),
+        };
+
+        let is_ipv4 = Ipv4Addr::from_str(ip).ok().filter(|_| !zoned);
+        let is_ipv6 = Ipv6Addr::from_str(ip).ok();
 
-        if is_ipv4.is_err() && is_ipv6.is_err() {
+        if is_ipv4.is_none() && is_ipv6.is_none() {
             return Err(Error::NotAddr(addr.to_owned()));
         }
 
-        if let Ok(v4) = is_ipv4 {
+        if let Some(v4) = is_ipv4 {
             let mask = match mask.parse::<u8>() {
                 Ok(res) => res,
                 Err(_) => return Err(Error::NotMask(mask.to_string())),
@@ -131,6 +140,16 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_zone() {
+        let cidr: Cidr = "fe80::1%eth0/64".parse().unwrap();
+        assert_eq!(cidr.addr, "fe80::1".parse::<IpAddr>().unwrap());
+        assert_eq!(cidr.mask, 64);
+
+        assert!("fe80::1%/64".parse::<Cidr>().is_err());
+        assert!("127.0.0.1%eth0/8".parse::<Cidr>().is_err());
+    }
+
     #[test]
     fn test_error() {
         assert!("127.0.0.1".parse::<Cidr>().is_err());
@@ -0,0 +1,193 @@
+//! A set of domain suffixes.
+
+use std::collections::HashMap;
+
+/// A set of domain suffixes, stored as a trie of labels from the top-level domain down.
+///
+/// A domain matches itself and all of its subdomains, case-insensitively.
+/// A wildcard domain, like `*.example.com`, only matches the subdomains.
+pub struct DomainSet {
+    root: DomainNode,
+    len: usize,
+}
+
+#[derive(Default)]
+struct DomainNode {
+    children: HashMap<Box<str>, DomainNode>,
+    is_complete: bool,
+    is_wildcard: bool,
+}
+
+impl DomainSet {
+    /// Creates a empty domain set.
+    pub fn new() -> Self {
+        DomainSet {
+            root: DomainNode::default(),
+            len: 0,
+        }
+    }
+
+    /// Inserts a domain, or a wildcard domain, into the set.
+    pub fn insert(&mut self, domain: &str) {
+        let domain = normalize(domain);
+        let (domain, wildcard) = match domain.strip_prefix("*.") {
+            Some(domain) => (domain, true),
+            None => (domain.as_str(), false),
+        };
+        let mut cur = &mut self.root;
+
+        for label in domain.rsplit('.') {
+            cur = cur.children.entry(label.into()).or_default();
+        }
+
+        let flag = match wildcard {
+            true => &mut cur.is_wildcard,
+            false => &mut cur.is_complete,
+        };
+        if !*flag {
+            *flag = true;
+            self.len += 1;
+        }
+    }
+
+    /// Returns true if the host is a domain in the set or a subdomain of one.
+    pub fn contains(&self, host: &str) -> bool {
+        let host = normalize(host);
+        let mut labels = host.rsplit('.').peekable();
+        let mut cur = &self.root;
+
+        while let Some(label) = labels.next() {
+            cur = match cur.children.get(label) {
+                Some(node) => node,
+                None => return false,
+            };
+
+            if cur.is_complete || (cur.is_wildcard && labels.peek().is_some()) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Returns the number of domains in the set.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the set has no domains.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for DomainSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Lowercases the domain and trims the dot of a fully qualified domain name.
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Parses a domain rule: `||example.com`, or a bare `example.com`, either of them may be
+/// a wildcard like `*.example.com`.
+///
+/// Bare rules must have a dot and a letter, and only letters, digits, `-`, `_` and dots,
+/// anything else is left to be parsed as a regex.
+pub fn parse_domain(rule: &str) -> Option<&str> {
+    let (domain, explicit) = match rule.strip_prefix("||") {
+        Some(domain) => (domain, true),
+        None => (rule, false),
+    };
+    let (name, wildcard) = match domain.strip_prefix("*.") {
+        Some(name) => (name, true),
+        None => (domain, false),
+    };
+
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        && (explicit
+            || wildcard
+            || (name.contains('.') && name.chars().any(|c| c.is_ascii_alphabetic())));
+
+    match valid {
+        true => Some(domain),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_domain_set() {
+        let mut domain_set = DomainSet::new();
+        domain_set.insert("google.com");
+        domain_set.insert("co.uk");
+        domain_set.insert("Example.ORG.");
+        domain_set.insert("google.com");
+        assert_eq!(domain_set.len(), 3);
+
+        assert!(domain_set.contains("google.com"));
+        assert!(domain_set.contains("www.google.com"));
+        assert!(domain_set.contains("a.b.google.com"));
+        assert!(domain_set.contains("GOOGLE.com."));
+        assert!(domain_set.contains("bbc.co.uk"));
+        assert!(domain_set.contains("example.org"));
+
+        assert!(!domain_set.contains("com"));
+        assert!(!domain_set.contains("notgoogle.com"));
+        assert!(!domain_set.contains("google.com.hk"));
+        assert!(!domain_set.contains("google.co"));
+        assert!(!domain_set.contains(""));
+    }
+
+    #[test]
+    fn test_wildcard() {
+        let mut domain_set = DomainSet::new();
+        domain_set.insert("*.example.com");
+        domain_set.insert("*.cn");
+        domain_set.insert("*.cn");
+        assert_eq!(domain_set.len(), 2);
+
+        assert!(domain_set.contains("www.example.com"));
+        assert!(domain_set.contains("a.b.example.com"));
+        assert!(domain_set.contains("gov.cn"));
+        assert!(!domain_set.contains("example.com"));
+        assert!(!domain_set.contains("cn"));
+
+        // Both the domain and its subdomains
+        domain_set.insert("example.com");
+        assert_eq!(domain_set.len(), 3);
+        assert!(domain_set.contains("example.com"));
+    }
+
+    #[test]
+    fn test_parse_domain() {
+        assert_eq!(parse_domain("||example.com"), Some("example.com"));
+        assert_eq!(parse_domain("||localhost"), Some("localhost"));
+        assert_eq!(parse_domain("example.com"), Some("example.com"));
+        assert_eq!(
+            parse_domain("my_host-1.example.com"),
+            Some("my_host-1.example.com")
+        );
+        assert_eq!(parse_domain("*.example.com"), Some("*.example.com"));
+        assert_eq!(parse_domain("||*.cn"), Some("*.cn"));
+
+        assert_eq!(parse_domain(r"(^|\.)example\.com$"), None);
+        assert_eq!(parse_domain("localhost"), None);
+        assert_eq!(parse_domain("1.2.3"), None);
+        assert_eq!(parse_domain("||"), None);
+        assert_eq!(parse_domain("||.example.com"), None);
+        assert_eq!(parse_domain("||example.*"), None);
+        assert_eq!(parse_domain("*.*.example.com"), None);
+        assert_eq!(parse_domain("*example.com"), None);
+    }
+}
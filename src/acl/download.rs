@@ -0,0 +1,386 @@
+//! Downloading ACL files over HTTP.
+//!
+//! A minimal HTTP/1.1 client, enough to fetch a rule file: `GET` only, following redirects.
+//! `https` URLs need the `tls` feature.
+
+use std::{
+    io::{self, ErrorKind},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+mod constants {
+    use std::time::Duration;
+
+    pub const TIMEOUT: Duration = Duration::from_secs(30);
+    pub const MAX_REDIRECTS: usize = 5;
+    pub const MAX_RESPONSE_SIZE: usize = 32 * 1024 * 1024;
+}
+
+/// The status, the `Location` header and the body of a response.
+type Response = (u16, Option<String>, Vec<u8>);
+
+/// An `http` or `https` URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpUrl {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl FromStr for HttpUrl {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || io::Error::new(ErrorKind::InvalidInput, format!("invalid URL {}", s));
+
+        let (tls, rest) = match s.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => (false, rest),
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("https") => (true, rest),
+            _ => return Err(invalid()),
+        };
+
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('/') => (&rest[..i], rest[i..].to_owned()),
+            Some(i) => (&rest[..i], format!("/{}", &rest[i..])),
+            None => (rest, "/".to_owned()),
+        };
+        let path = path.split('#').next().unwrap_or_default().to_owned();
+
+        let default_port = if tls { 443 } else { 80 };
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(rest) => {
+                let (host, port) = rest.split_once(']').ok_or_else(invalid)?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid())?,
+            None => default_port,
+        };
+
+        if host.is_empty() || host.contains('@') {
+            return Err(invalid());
+        }
+
+        Ok(HttpUrl {
+            tls,
+            host: host.to_owned(),
+            port,
+            path,
+        })
+    }
+}
+
+impl HttpUrl {
+    /// Resolves a `Location` header against this URL.
+    fn join(&self, location: &str) -> io::Result<HttpUrl> {
+        if location.contains("://") {
+            return location.parse();
+        }
+
+        let path = match location.starts_with('/') {
+            true => location.to_owned(),
+            false => {
+                let dir = &self.path[..self.path.rfind('/').map_or(0, |i| i + 1)];
+                format!("{}{}", dir, location)
+            }
+        };
+
+        Ok(HttpUrl {
+            path,
+            ..self.clone()
+        })
+    }
+
+    /// Returns the `Host` header value.
+    fn authority(&self) -> String {
+        let host = match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        };
+
+        match self.port == if self.tls { 443 } else { 80 } {
+            true => host,
+            false => format!("{}:{}", host, self.port),
+        }
+    }
+}
+
+/// Downloads the body of the URL, following redirects.
+///
+/// Fails if the final response isn't `200 OK`, or if it takes longer than 30 seconds.
+pub async fn download(url: &str) -> io::Result<Vec<u8>> {
+    let mut url: HttpUrl = url.parse()?;
+
+    let download = async {
+        for _ in 0..=constants::MAX_REDIRECTS {
+            let (status, location, body) = get(&url).await?;
+
+            match (status, location) {
+                (200, _) => return Ok(body),
+                (301 | 302 | 303 | 307 | 308, Some(location)) => url = url.join(&location)?,
+                _ => {
+                    return Err(io::Error::new(
+                        ErrorKind::Other,
+                        format!("HTTP status {}", status),
+                    ))
+                }
+            }
+        }
+
+        Err(io::Error::new(ErrorKind::Other, "too many redirects"))
+    };
+
+    match tokio::time::timeout(constants::TIMEOUT, download).await {
+        Ok(res) => res,
+        Err(_) => Err(io::Error::new(ErrorKind::TimedOut, "download timed out")),
+    }
+}
+
+/// Returns the default path a file downloaded from the URL is cached to, named after the URL
+/// in the `ss-rs` directory of the user's cache directory: `$XDG_CACHE_HOME`, `~/.cache`,
+/// or `%LOCALAPPDATA%`.
+///
+/// Returns `None` if there is no such directory. A shared one, like the temporary directory,
+/// isn't used instead, anyone could create the file there first.
+pub fn cache_path(url: &str) -> Option<PathBuf> {
+    let xdg = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute());
+    let home = std::env::var_os("HOME").map(|dir| PathBuf::from(dir).join(".cache"));
+    let local = std::env::var_os("LOCALAPPDATA").map(PathBuf::from);
+
+    let dir = xdg.or(home).or(local)?;
+    Some(
+        dir.join("ss-rs")
+            .join(format!("{:x}.acl", md5::compute(url))),
+    )
+}
+
+/// Sends a `GET` request, returning the status, the `Location` header and the body.
+async fn get(url: &HttpUrl) -> io::Result<Response> {
+    let stream = TcpStream::connect((url.host.as_str(), url.port)).await?;
+
+    match url.tls {
+        true => get_tls(stream, url).await,
+        false => request(stream, url).await,
+    }
+}
+
+#[cfg(feature = "tls")]
+async fn get_tls(stream: TcpStream, url: &HttpUrl) -> io::Result<Response> {
+    use crate::tls::Tls;
+
+    match Tls::client(&url.host, None)? {
+        Tls::Client(connector, server_name) => {
+            let stream = connector.connect(server_name, stream).await?;
+            request(stream, url).await
+        }
+        Tls::Server(_) => unreachable!(),
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+async fn get_tls(_stream: TcpStream, _url: &HttpUrl) -> io::Result<Response> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "https URLs require building with --features tls",
+    ))
+}
+
+async fn request<S>(mut stream: S, url: &HttpUrl) -> io::Result<Response>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: ss-rs/{}\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        url.path,
+        url.authority(),
+        env!("CARGO_PKG_VERSION")
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    let limit = constants::MAX_RESPONSE_SIZE as u64 + 1;
+    (&mut stream).take(limit).read_to_end(&mut response).await?;
+    if response.len() > constants::MAX_RESPONSE_SIZE {
+        return Err(io::Error::new(ErrorKind::InvalidData, "response too large"));
+    }
+
+    parse_response(response)
+}
+
+/// Parses a response read until the connection closed.
+fn parse_response(response: Vec<u8>) -> io::Result<Response> {
+    let invalid = |msg: &str| io::Error::new(ErrorKind::InvalidData, msg.to_owned());
+
+    let end = response
+        .windows(4)
+        .position(|x| x == b"\r\n\r\n")
+        .ok_or_else(|| invalid("incomplete response head"))?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let body = &response[end + 4..];
+
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid("invalid status line"))?;
+
+    let mut location = None;
+    let mut content_length = None;
+    let mut chunked = false;
+    for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+        let value = value.trim();
+
+        match name.trim().to_ascii_lowercase().as_str() {
+            "location" => location = Some(value.to_owned()),
+            "content-length" => content_length = value.parse::<usize>().ok(),
+            "transfer-encoding" => chunked = value.to_ascii_lowercase().contains("chunked"),
+            _ => {}
+        }
+    }
+
+    let body = match (chunked, content_length) {
+        (true, _) => decode_chunked(body)?,
+        (false, Some(len)) if len <= body.len() => body[..len].to_vec(),
+        (false, Some(_)) => return Err(invalid("incomplete response body")),
+        (false, None) => body.to_vec(),
+    };
+
+    Ok((status, location, body))
+}
+
+fn decode_chunked(mut data: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "invalid chunked body");
+    let mut body = Vec::new();
+
+    loop {
+        let end = data
+            .windows(2)
+            .position(|x| x == b"\r\n")
+            .ok_or_else(invalid)?;
+        let size = std::str::from_utf8(&data[..end]).map_err(|_| invalid())?;
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid())?;
+        data = &data[end + 2..];
+
+        if size == 0 {
+            return Ok(body);
+        }
+
+        if data.len() < size + 2 {
+            return Err(invalid());
+        }
+
+        body.extend_from_slice(&data[..size]);
+        data = &data[size + 2..];
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Serves the responses in order, one per connection.
+    pub(crate) async fn serve(responses: Vec<String>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut buf = Vec::new();
+                while !buf.windows(4).any(|x| x == b"\r\n\r\n") {
+                    let mut chunk = [0u8; 1024];
+                    let n = stream.read(&mut chunk).await.unwrap();
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        addr
+    }
+
+    #[test]
+    fn test_url() {
+        let url: HttpUrl = "http://example.com/a/b.acl?x=1#top".parse().unwrap();
+        assert_eq!(url.host, "example.com");
+        assert_eq!(url.port, 80);
+        assert_eq!(url.path, "/a/b.acl?x=1");
+        assert_eq!(url.authority(), "example.com");
+
+        let url: HttpUrl = "HTTPS://[::1]:8443".parse().unwrap();
+        assert!(url.tls);
+        assert_eq!(url.host, "::1");
+        assert_eq!(url.port, 8443);
+        assert_eq!(url.path, "/");
+        assert_eq!(url.authority(), "[::1]:8443");
+
+        assert_eq!(url.join("/c.acl").unwrap().path, "/c.acl");
+        let url: HttpUrl = "http://example.com/a/b.acl".parse().unwrap();
+        assert_eq!(url.join("c.acl").unwrap().path, "/a/c.acl");
+        assert_eq!(url.join("http://other.org/").unwrap().host, "other.org");
+
+        assert!("ftp://example.com/".parse::<HttpUrl>().is_err());
+        assert!("example.com/a.acl".parse::<HttpUrl>().is_err());
+        assert!("http://:80/".parse::<HttpUrl>().is_err());
+        assert!("http://example.com:port/".parse::<HttpUrl>().is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello".to_vec();
+        let (status, _, body) = parse_response(response).unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, b"hello");
+
+        let response =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n1;x=y\r\n!\r\n0\r\n\r\n";
+        let (_, _, body) = parse_response(response.to_vec()).unwrap();
+        assert_eq!(body, b"hello!");
+
+        let response = b"HTTP/1.1 302 Found\r\nlocation: /new\r\n\r\n".to_vec();
+        let (status, location, _) = parse_response(response).unwrap();
+        assert_eq!(status, 302);
+        assert_eq!(location.as_deref(), Some("/new"));
+
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello".to_vec();
+        assert!(parse_response(response).is_err());
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n".to_vec()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_download() {
+        let addr = serve(vec![
+            "HTTP/1.1 301 Moved Permanently\r\nLocation: /b.acl\r\n\r\n".to_owned(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 13\r\n\r\n[bypass_all]\n".to_owned(),
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_owned(),
+        ])
+        .await;
+
+        let body = download(&format!("http://{}/a.acl", addr)).await.unwrap();
+        assert_eq!(body, b"[bypass_all]\n");
+
+        assert!(download(&format!("http://{}/a.acl", addr)).await.is_err());
+    }
+}
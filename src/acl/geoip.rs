@@ -0,0 +1,207 @@
+//! GeoIP matching by country, with a MaxMind DB.
+//!
+//! Looking up countries needs the `geoip` feature, without it opening a database fails
+//! and `geoip:` rules are ignored.
+
+use std::{collections::HashSet, io, net::IpAddr, path::Path};
+
+/// A set of ISO 3166-1 country codes, such as `CN`.
+pub struct GeoIpSet {
+    countries: HashSet<String>,
+}
+
+impl GeoIpSet {
+    /// Creates a empty geoip set.
+    pub fn new() -> Self {
+        GeoIpSet {
+            countries: HashSet::new(),
+        }
+    }
+
+    /// Inserts a country code into the set.
+    pub fn insert(&mut self, country: &str) {
+        self.countries.insert(country.to_ascii_uppercase());
+    }
+
+    /// Returns true if the country code is in the set.
+    pub fn contains(&self, country: &str) -> bool {
+        self.countries.contains(&country.to_ascii_uppercase())
+    }
+
+    /// Returns true if the set has no countries.
+    pub fn is_empty(&self) -> bool {
+        self.countries.is_empty()
+    }
+}
+
+impl Default for GeoIpSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A MaxMind DB, such as GeoLite2-Country, mapping ips to countries.
+pub struct GeoIpDb {
+    #[cfg(feature = "geoip")]
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+impl GeoIpDb {
+    /// Opens a MaxMind DB file.
+    #[cfg(feature = "geoip")]
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let reader = maxminddb::Reader::open_readfile(path)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Ok(GeoIpDb { reader })
+    }
+
+    /// Opens a MaxMind DB file, always fails without the `geoip` feature.
+    #[cfg(not(feature = "geoip"))]
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "can't open {}, geoip support requires building with --features geoip",
+                path.display()
+            ),
+        ))
+    }
+
+    /// Returns the country code of the ip, if the database has it.
+    #[cfg(feature = "geoip")]
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        use maxminddb::PathElement;
+
+        let path = [PathElement::Key("country"), PathElement::Key("iso_code")];
+
+        match self.reader.lookup(ip).and_then(|x| x.decode_path(&path)) {
+            Ok(country) => country,
+            Err(e) => {
+                log::debug!("Look up country of {} failed: {}", ip, e);
+                None
+            }
+        }
+    }
+
+    /// Returns the country code of the ip, always `None` without the `geoip` feature.
+    #[cfg(not(feature = "geoip"))]
+    pub fn country(&self, _ip: IpAddr) -> Option<String> {
+        None
+    }
+}
+
+/// Parses a geoip rule: `geoip:CN`, returning the country code.
+pub fn parse_geoip(rule: &str) -> Option<&str> {
+    let country = rule.strip_prefix("geoip:")?;
+
+    match country.len() == 2 && country.chars().all(|c| c.is_ascii_alphabetic()) {
+        true => Some(country),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Builds an ipv4 MaxMind DB where `0.0.0.0/1` is in `low` and `128.0.0.0/1` in `high`.
+    #[cfg(feature = "geoip")]
+    pub(crate) fn build_db(low: &str, high: &str) -> Vec<u8> {
+        fn string(s: &str) -> Vec<u8> {
+            let mut buf = vec![0x40 | s.len() as u8];
+            buf.extend_from_slice(s.as_bytes());
+            buf
+        }
+
+        fn country(iso_code: &str) -> Vec<u8> {
+            let mut buf = vec![0xe1];
+            buf.extend(string("country"));
+            buf.push(0xe1);
+            buf.extend(string("iso_code"));
+            buf.extend(string(iso_code));
+            buf
+        }
+
+        let low = country(low);
+        let high = country(high);
+
+        // One node of two 24 bits records, pointing to the data section after 16 zeros.
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&17u32.to_be_bytes()[1..]);
+        buf.extend_from_slice(&(17 + low.len() as u32).to_be_bytes()[1..]);
+        buf.extend_from_slice(&[0; 16]);
+        buf.extend(low);
+        buf.extend(high);
+
+        buf.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+        buf.push(0xe9);
+        buf.extend(string("node_count"));
+        buf.extend_from_slice(&[0xc1, 1]);
+        buf.extend(string("record_size"));
+        buf.extend_from_slice(&[0xa1, 24]);
+        buf.extend(string("ip_version"));
+        buf.extend_from_slice(&[0xa1, 4]);
+        buf.extend(string("database_type"));
+        buf.extend(string("Test"));
+        buf.extend(string("languages"));
+        buf.extend_from_slice(&[0x00, 0x04]);
+        buf.extend(string("description"));
+        buf.push(0xe0);
+        buf.extend(string("binary_format_major_version"));
+        buf.extend_from_slice(&[0xa1, 2]);
+        buf.extend(string("binary_format_minor_version"));
+        buf.push(0xa0);
+        buf.extend(string("build_epoch"));
+        buf.extend_from_slice(&[0x00, 0x02]);
+
+        buf
+    }
+
+    #[test]
+    fn test_geoip_set() {
+        let mut geoip_set = GeoIpSet::new();
+        assert!(geoip_set.is_empty());
+
+        geoip_set.insert("cn");
+        assert!(geoip_set.contains("CN"));
+        assert!(geoip_set.contains("cn"));
+        assert!(!geoip_set.contains("US"));
+    }
+
+    #[test]
+    fn test_parse_geoip() {
+        assert_eq!(parse_geoip("geoip:CN"), Some("CN"));
+        assert_eq!(parse_geoip("geoip:us"), Some("us"));
+
+        assert_eq!(parse_geoip("geoip:"), None);
+        assert_eq!(parse_geoip("geoip:CHN"), None);
+        assert_eq!(parse_geoip("geoip:C1"), None);
+        assert_eq!(parse_geoip("CN"), None);
+    }
+
+    #[cfg(feature = "geoip")]
+    #[test]
+    fn test_geoip_db() {
+        let path = std::env::temp_dir().join(format!("ss-rs-test-{}.mmdb", std::process::id()));
+        std::fs::write(&path, build_db("CN", "US")).unwrap();
+
+        let db = GeoIpDb::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            db.country("1.2.4.8".parse().unwrap()).as_deref(),
+            Some("CN")
+        );
+        assert_eq!(
+            db.country("223.5.5.5".parse().unwrap()).as_deref(),
+            Some("US")
+        );
+        assert_eq!(db.country("::1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_open_error() {
+        assert!(GeoIpDb::open(Path::new("1234567890abcdefghijklmnopqrstuvwxyz.mmdb")).is_err());
+    }
+}
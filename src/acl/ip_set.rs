@@ -51,34 +51,33 @@ impl Trie {
         cur.is_complete = true;
     }
 
+    /// Returns true if a complete node is reached along the bits of the data,
+    /// that is, one of the inserted networks is a prefix of it.
     pub fn contains(&self, data: &[u8]) -> bool {
         let mut cur = &self.root;
         let bits = data.view_bits::<Msb0>();
 
         for bit in bits.iter() {
-            match *bit {
-                true => {
-                    if cur.right.is_none() {
-                        return false;
-                    }
-
-                    cur = unsafe { cur.right.as_ref().unwrap_unchecked().as_ref() };
-                }
-                false => {
-                    if cur.left.is_none() {
-                        return false;
-                    }
-
-                    cur = unsafe { cur.left.as_ref().unwrap_unchecked().as_ref() };
-                }
-            }
-
             if cur.is_complete {
-                break;
+                return true;
             }
+
+            let next = match *bit {
+                true => cur.right.as_deref(),
+                false => cur.left.as_deref(),
+            };
+
+            cur = match next {
+                Some(next) => next,
+                None => return false,
+            };
         }
 
-        true
+        cur.is_complete
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.left.is_none() && self.root.right.is_none() && !self.root.is_complete
     }
 
     pub fn clear(&mut self) {
@@ -141,6 +140,11 @@ impl IpSet {
         }
     }
 
+    /// Returns true if the ip set contains no ip networks.
+    pub fn is_empty(&self) -> bool {
+        self.ipv4.is_empty() && self.ipv6.is_empty()
+    }
+
     /// Clears the ip set.
     pub fn clear(&mut self) {
         self.ipv4.clear();
@@ -205,4 +209,50 @@ mod tests {
         assert_eq!(set.contains("fc00::ffff".parse().unwrap()), false);
         assert_eq!(set.contains("fe80::1234".parse().unwrap()), false);
     }
+
+    #[test]
+    fn test_prefix() {
+        let mut set = IpSet::new();
+        set.insert("10.0.0.0/9".parse().unwrap());
+
+        assert!(set.contains("10.0.0.1".parse().unwrap()));
+        assert!(set.contains("10.127.255.255".parse().unwrap()));
+        assert!(!set.contains("10.128.0.1".parse().unwrap()));
+        assert!(!set.contains("10.255.255.255".parse().unwrap()));
+
+        // Paths that go deeper than a network and diverge from it.
+        set.insert("10.192.168.0/24".parse().unwrap());
+
+        assert!(set.contains("10.192.168.1".parse().unwrap()));
+        assert!(!set.contains("10.192.169.1".parse().unwrap()));
+        assert!(!set.contains("10.128.0.1".parse().unwrap()));
+
+        set.insert("10.128.0.0/9".parse().unwrap());
+
+        assert!(set.contains("10.128.0.1".parse().unwrap()));
+        assert!(set.contains("10.255.255.255".parse().unwrap()));
+        assert!(!set.contains("11.0.0.1".parse().unwrap()));
+
+        // Prefixes off byte boundaries, and a network that is a single address.
+        set.clear();
+        set.insert("172.16.0.0/12".parse().unwrap());
+        set.insert("192.0.2.1/32".parse().unwrap());
+        set.insert("2001:db8::/33".parse().unwrap());
+
+        assert!(set.contains("172.31.255.255".parse().unwrap()));
+        assert!(!set.contains("172.32.0.0".parse().unwrap()));
+        assert!(!set.contains("172.15.255.255".parse().unwrap()));
+        assert!(set.contains("192.0.2.1".parse().unwrap()));
+        assert!(!set.contains("192.0.2.0".parse().unwrap()));
+        assert!(set.contains("2001:db8:7fff::1".parse().unwrap()));
+        assert!(!set.contains("2001:db8:8000::1".parse().unwrap()));
+
+        // The default route contains everything of its family.
+        set.clear();
+        set.insert("0.0.0.0/0".parse().unwrap());
+
+        assert!(set.contains("8.8.8.8".parse().unwrap()));
+        assert!(set.contains("255.255.255.255".parse().unwrap()));
+        assert!(!set.contains("::1".parse().unwrap()));
+    }
 }
@@ -1,29 +1,140 @@
 //! Access control list.
 
 pub mod cidr;
+pub mod domain_set;
+pub mod download;
+pub mod geoip;
 pub mod ip_set;
 pub mod rule_set;
 
-use std::{io, net::IpAddr, path::Path};
+use std::{
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
 use regex::Regex;
 
 use crate::{
     acl::cidr::Cidr,
-    acl::{ip_set::IpSet, rule_set::RuleSet},
+    acl::{
+        domain_set::{parse_domain, DomainSet},
+        geoip::{parse_geoip, GeoIpDb, GeoIpSet},
+        ip_set::IpSet,
+        rule_set::RuleSet,
+    },
 };
 
+mod constants {
+    pub const MAX_CACHED_DECISIONS: usize = 4096;
+}
+
 /// Access control list.
+///
+/// Besides the shadowsocks-rust sections, `[client_allow_list]` and `[client_reject_list]`
+/// list the client addresses ss-remote accepts and rejects, see [`Acl::is_client_allowed`].
+///
+/// Hosts are matched by domain rules, `||example.com` or a bare `example.com` matching
+/// the domain and its subdomains, `*.example.com` matching only the subdomains, and by
+/// regex rules for anything else.
+///
+/// In `[bypass_list]` and `[proxy_list]`, a rule prefixed by `!` is an exception: the list
+/// doesn't apply to the ips and hosts it matches, which are left to the other list and
+/// the mode. For instance, to proxy all of `.cn` but `gov.cn` in `[bypass_all]` mode:
+///
+/// ~~~text
+/// [bypass_all]
+///
+/// [proxy_list]
+/// *.cn
+/// !||gov.cn
+/// ~~~
+///
+/// Ips are also matched by country with `geoip:CN` rules, looked up in the MaxMind DB
+/// listed in the `[geoip]` section. A relative path is resolved from the directory of
+/// the ACL file. Without a database, these rules are ignored with a warning.
+///
+/// Hosts matching the domain and regex rules of `[remote_resolve_list]` are never resolved
+/// locally, they are proxied and ss-remote resolves them, see [`Acl::is_remote_resolve`].
+///
+/// Bypass decisions are cached, see [`Acl::is_bypass`]. An ACL never changes once parsed,
+/// so a reloaded one starts with an empty cache.
 pub struct Acl {
     bypass_list: IpSet,
     proxy_list: IpSet,
     outbound_block_list: IpSet,
+    client_allow_list: IpSet,
+    client_reject_list: IpSet,
+    client_lists: bool,
+
+    bypass_domains: DomainSet,
+    proxy_domains: DomainSet,
+    outbound_block_domains: DomainSet,
+
+    bypass_exceptions: Exceptions,
+    proxy_exceptions: Exceptions,
+
+    geoip_db: Option<GeoIpDb>,
+    bypass_geoip: GeoIpSet,
+    proxy_geoip: GeoIpSet,
+    outbound_block_geoip: GeoIpSet,
 
     bypass_rules: RuleSet,
     proxy_rules: RuleSet,
     outbound_block_rules: RuleSet,
 
+    remote_resolve_domains: DomainSet,
+    remote_resolve_rules: RuleSet,
+
     mode: Mode,
+
+    bypass_cache: Mutex<DecisionCache>,
+}
+
+type CacheKey = (IpAddr, Option<String>);
+
+/// Cached bypass decisions, in two generations.
+///
+/// Decisions are inserted in the current generation, and moved there when looked up in the
+/// previous one. Once the current one is full it becomes the previous one, dropping the
+/// decisions unused for a whole generation, so no scan for the least recently used is needed.
+struct DecisionCache {
+    current: HashMap<CacheKey, bool>,
+    previous: HashMap<CacheKey, bool>,
+}
+
+impl DecisionCache {
+    fn new() -> Self {
+        DecisionCache {
+            current: HashMap::new(),
+            previous: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &CacheKey) -> Option<bool> {
+        if let Some(&bypass) = self.current.get(key) {
+            return Some(bypass);
+        }
+
+        let (key, bypass) = self.previous.remove_entry(key)?;
+        self.insert(key, bypass);
+        Some(bypass)
+    }
+
+    fn insert(&mut self, key: CacheKey, bypass: bool) {
+        if self.current.len() >= constants::MAX_CACHED_DECISIONS / 2 {
+            self.previous = std::mem::take(&mut self.current);
+        }
+
+        self.current.insert(key, bypass);
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.current.len() + self.previous.len()
+    }
 }
 
 impl Acl {
@@ -33,21 +144,83 @@ impl Acl {
             bypass_list: IpSet::new(),
             proxy_list: IpSet::new(),
             outbound_block_list: IpSet::new(),
+            client_allow_list: IpSet::new(),
+            client_reject_list: IpSet::new(),
+            client_lists: false,
+            bypass_domains: DomainSet::new(),
+            proxy_domains: DomainSet::new(),
+            outbound_block_domains: DomainSet::new(),
+            bypass_exceptions: Exceptions::new(),
+            proxy_exceptions: Exceptions::new(),
+            geoip_db: None,
+            bypass_geoip: GeoIpSet::new(),
+            proxy_geoip: GeoIpSet::new(),
+            outbound_block_geoip: GeoIpSet::new(),
             bypass_rules: RuleSet::new(),
             proxy_rules: RuleSet::new(),
             outbound_block_rules: RuleSet::new(),
+            remote_resolve_domains: DomainSet::new(),
+            remote_resolve_rules: RuleSet::new(),
             mode: Mode::WhiteList,
+            bypass_cache: Mutex::new(DecisionCache::new()),
         }
     }
 
     /// Creates a new acl from a file.
     pub fn from_file(path: &Path) -> io::Result<Self> {
         let data = std::fs::read_to_string(path)?;
-        Ok(Self::from_str(&data))
+        Ok(Self::parse(&data, path.parent()))
+    }
+
+    /// Creates a new acl from a file downloaded from the URL, see [`download::download`].
+    ///
+    /// The file is saved to `cache_path`, creating its directory, and loaded from there if
+    /// the download fails, so the ACL is still available offline once downloaded. Relative
+    /// paths of `[geoip]` are resolved from the directory of `cache_path`.
+    pub async fn from_url(url: &str, cache_path: &Path) -> io::Result<Self> {
+        let downloaded = download::download(url).await.and_then(|data| {
+            String::from_utf8(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        });
+
+        let data = match downloaded {
+            Ok(data) => {
+                if let Err(e) = Self::cache(cache_path, &data).await {
+                    log::warn!("Cache ACL to {} failed: {}", cache_path.display(), e);
+                }
+                data
+            }
+            Err(e) => match tokio::fs::read_to_string(cache_path).await {
+                Ok(data) => {
+                    log::warn!(
+                        "Download ACL from {} failed: {}, using the cached {}",
+                        url,
+                        e,
+                        cache_path.display()
+                    );
+                    data
+                }
+                Err(_) => return Err(e),
+            },
+        };
+
+        Ok(Self::parse(&data, cache_path.parent()))
+    }
+
+    async fn cache(path: &Path, data: &str) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(path, data).await
     }
 
     /// Creates a new acl from a string.
+    ///
+    /// Relative paths of `[geoip]` are resolved from the current directory.
     pub fn from_str(data: &str) -> Self {
+        Self::parse(data, None)
+    }
+
+    fn parse(data: &str, base_dir: Option<&Path>) -> Self {
         // Trims whitespace and comments.
         let lines = data
             .lines()
@@ -59,19 +232,46 @@ impl Acl {
             .filter(|line| !line.is_empty());
 
         let mut acl = Acl::new();
-        let mut cur_ip_set = &mut acl.bypass_list;
-        let mut cur_rule_set = &mut acl.bypass_rules;
-
-        fn insert(record: &str, ip_set: &mut IpSet, rule_set: &mut RuleSet) -> bool {
+        let mut cur_ip_set = Some(&mut acl.bypass_list);
+        let mut cur_domain_set = Some(&mut acl.bypass_domains);
+        let mut cur_geoip_set = Some(&mut acl.bypass_geoip);
+        let mut cur_rule_set = Some(&mut acl.bypass_rules);
+        let mut cur_exceptions = Some(&mut acl.bypass_exceptions);
+        let mut geoip_paths = Vec::new();
+        let mut in_geoip = false;
+
+        // Client lists have no domain, geoip or rule sets, as clients are only matched by ip,
+        // and the remote resolve list has only domain and rule sets.
+        fn insert(
+            record: &str,
+            ip_set: Option<&mut IpSet>,
+            domain_set: Option<&mut DomainSet>,
+            geoip_set: Option<&mut GeoIpSet>,
+            rule_set: Option<&mut RuleSet>,
+        ) -> bool {
             let cidr = record.parse::<Cidr>();
-            if let Ok(cidr) = cidr {
+            if let (Ok(cidr), Some(ip_set)) = (cidr, ip_set) {
                 ip_set.insert(cidr);
                 log::trace!("Insert {} to the ip set", record);
                 return true;
             }
 
+            let country = parse_geoip(record);
+            if let (Some(country), Some(geoip_set)) = (country, geoip_set) {
+                geoip_set.insert(country);
+                log::trace!("Insert {} to the geoip set", record);
+                return true;
+            }
+
+            let domain = parse_domain(record);
+            if let (Some(domain), Some(domain_set)) = (domain, domain_set) {
+                domain_set.insert(domain);
+                log::trace!("Insert {} to the domain set", record);
+                return true;
+            }
+
             let regex = record.parse::<Regex>();
-            if let Ok(regex) = regex {
+            if let (Ok(regex), Some(rule_set)) = (regex, rule_set) {
                 rule_set.insert(regex);
                 log::trace!("Insert {} to the rule set", record);
                 return true;
@@ -81,83 +281,312 @@ impl Acl {
         }
 
         for line in lines {
+            if line.starts_with('[') && line.ends_with(']') {
+                in_geoip = line == "[geoip]";
+            }
+
             match line {
                 "[proxy_all]" | "[accept_all]" => acl.mode = Mode::WhiteList,
                 "[bypass_all]" | "[reject_all]" => acl.mode = Mode::BlackList,
                 "[bypass_list]" | "[black_list]" => {
-                    cur_ip_set = &mut acl.bypass_list;
-                    cur_rule_set = &mut acl.bypass_rules;
+                    cur_ip_set = Some(&mut acl.bypass_list);
+                    cur_domain_set = Some(&mut acl.bypass_domains);
+                    cur_geoip_set = Some(&mut acl.bypass_geoip);
+                    cur_rule_set = Some(&mut acl.bypass_rules);
+                    cur_exceptions = Some(&mut acl.bypass_exceptions);
                 }
                 "[proxy_list]" | "[white_list]" => {
-                    cur_ip_set = &mut acl.proxy_list;
-                    cur_rule_set = &mut acl.proxy_rules;
+                    cur_ip_set = Some(&mut acl.proxy_list);
+                    cur_domain_set = Some(&mut acl.proxy_domains);
+                    cur_geoip_set = Some(&mut acl.proxy_geoip);
+                    cur_rule_set = Some(&mut acl.proxy_rules);
+                    cur_exceptions = Some(&mut acl.proxy_exceptions);
                 }
                 "[outbound_block_list]" => {
-                    cur_ip_set = &mut acl.outbound_block_list;
-                    cur_rule_set = &mut acl.outbound_block_rules;
+                    cur_ip_set = Some(&mut acl.outbound_block_list);
+                    cur_domain_set = Some(&mut acl.outbound_block_domains);
+                    cur_geoip_set = Some(&mut acl.outbound_block_geoip);
+                    cur_rule_set = Some(&mut acl.outbound_block_rules);
+                    cur_exceptions = None;
+                }
+                "[client_allow_list]" => {
+                    acl.client_lists = true;
+                    cur_ip_set = Some(&mut acl.client_allow_list);
+                    cur_domain_set = None;
+                    cur_geoip_set = None;
+                    cur_rule_set = None;
+                    cur_exceptions = None;
+                }
+                "[client_reject_list]" => {
+                    acl.client_lists = true;
+                    cur_ip_set = Some(&mut acl.client_reject_list);
+                    cur_domain_set = None;
+                    cur_geoip_set = None;
+                    cur_rule_set = None;
+                    cur_exceptions = None;
+                }
+                "[remote_resolve_list]" => {
+                    cur_ip_set = None;
+                    cur_domain_set = Some(&mut acl.remote_resolve_domains);
+                    cur_geoip_set = None;
+                    cur_rule_set = Some(&mut acl.remote_resolve_rules);
+                    cur_exceptions = None;
                 }
+                "[geoip]" => {}
+                _ if in_geoip => geoip_paths.push(line),
+                _ if line.starts_with('!') => match cur_exceptions.as_deref_mut() {
+                    Some(exceptions) => {
+                        let ip_set = Some(&mut exceptions.ip_set);
+                        let domain_set = Some(&mut exceptions.domain_set);
+                        let rule_set = Some(&mut exceptions.rule_set);
+                        if !insert(&line[1..], ip_set, domain_set, None, rule_set) {
+                            log::warn!("Insert {} to the ACL failed", line);
+                        }
+                    }
+                    None => {
+                        log::warn!(
+                            "Exception {} is only allowed in bypass and proxy lists",
+                            line
+                        )
+                    }
+                },
                 _ => {
-                    if !insert(line, cur_ip_set, cur_rule_set) {
+                    let ip_set = cur_ip_set.as_deref_mut();
+                    let domain_set = cur_domain_set.as_deref_mut();
+                    let geoip_set = cur_geoip_set.as_deref_mut();
+                    let rule_set = cur_rule_set.as_deref_mut();
+                    if !insert(line, ip_set, domain_set, geoip_set, rule_set) {
                         log::warn!("Insert {} to the ACL failed", line);
                     }
                 }
             }
         }
 
+        for path in geoip_paths {
+            let path = match base_dir {
+                Some(base_dir) => base_dir.join(path),
+                None => PathBuf::from(path),
+            };
+
+            match GeoIpDb::open(&path) {
+                Ok(db) => {
+                    log::debug!("Load geoip database {}", path.display());
+                    acl.geoip_db = Some(db);
+                }
+                Err(e) => log::warn!("Load geoip database {} failed: {}", path.display(), e),
+            }
+        }
+
+        let has_geoip_rules = !acl.bypass_geoip.is_empty()
+            || !acl.proxy_geoip.is_empty()
+            || !acl.outbound_block_geoip.is_empty();
+        if has_geoip_rules && acl.geoip_db.is_none() {
+            log::warn!("No geoip database is loaded, geoip rules of the ACL are ignored");
+        }
+
         acl
     }
 
     /// Returns true if the given ip or host should be bypassed.
+    ///
+    /// The decisions of up to 4096 ip and host pairs are cached, evicting the ones unused for
+    /// the longest when full, so the rules only run once for hosts connected to repeatedly.
     pub fn is_bypass(&self, ip: IpAddr, host: Option<&str>) -> bool {
         let ip_str = ip.to_string();
+        let host = host.filter(|host| *host != ip_str);
+        let key = (ip, host.map(str::to_owned));
+
+        if let Some(bypass) = self.bypass_cache.lock().unwrap().get(&key) {
+            return bypass;
+        }
+
+        let bypass = self.match_bypass(ip, host);
+        self.bypass_cache.lock().unwrap().insert(key, bypass);
+
+        bypass
+    }
+
+    /// Returns true if the given ip or host should be bypassed, by running the rules.
+    fn match_bypass(&self, ip: IpAddr, host: Option<&str>) -> bool {
+        // Exceptions first, the lists don't apply to what they match
+        let bypass = !self.bypass_exceptions.contains(Some(ip), host);
+        let proxy = !self.proxy_exceptions.contains(Some(ip), host);
 
         if let Some(host) = host {
-            if host != ip_str {
-                if self.bypass_rules.contains(host) {
+            if let Some(bypass) = self.match_lists(host, bypass, proxy) {
+                return bypass;
+            }
+        }
+
+        if bypass && self.bypass_list.contains(ip) {
+            return true;
+        }
+
+        if proxy && self.proxy_list.contains(ip) {
+            return false;
+        }
+
+        if !self.bypass_geoip.is_empty() || !self.proxy_geoip.is_empty() {
+            if let Some(country) = self.country(ip) {
+                if bypass && self.bypass_geoip.contains(&country) {
                     return true;
                 }
 
-                if self.proxy_rules.contains(host) {
+                if proxy && self.proxy_geoip.contains(&country) {
                     return false;
                 }
             }
         }
 
-        if self.bypass_list.contains(ip) {
-            return true;
+        self.mode == Mode::BlackList
+    }
+
+    /// Returns true if the given host should be bypassed, without resolving it.
+    ///
+    /// Hosts that match no rules follow the mode.
+    pub fn is_bypass_host(&self, host: &str) -> bool {
+        self.match_host(host)
+            .unwrap_or(self.mode == Mode::BlackList)
+    }
+
+    /// Returns whether the given host matches a bypass rule (`Some(true)`),
+    /// a proxy rule (`Some(false)`), or neither (`None`).
+    pub fn match_host(&self, host: &str) -> Option<bool> {
+        let bypass = !self.bypass_exceptions.contains(None, Some(host));
+        let proxy = !self.proxy_exceptions.contains(None, Some(host));
+
+        self.match_lists(host, bypass, proxy)
+    }
+
+    /// Matches the host against the host rules of the bypass and proxy lists that apply.
+    fn match_lists(&self, host: &str, bypass: bool, proxy: bool) -> Option<bool> {
+        if bypass && (self.bypass_domains.contains(host) || self.bypass_rules.contains(host)) {
+            return Some(true);
         }
 
-        if self.proxy_list.contains(ip) {
+        if proxy && (self.proxy_domains.contains(host) || self.proxy_rules.contains(host)) {
+            return Some(false);
+        }
+
+        None
+    }
+
+    /// Returns true if the given host should be proxied without resolving it locally,
+    /// whatever the other rules, so its DNS queries don't leak.
+    pub fn is_remote_resolve(&self, host: &str) -> bool {
+        self.remote_resolve_domains.contains(host) || self.remote_resolve_rules.contains(host)
+    }
+
+    /// Returns true if ss-remote should accept clients from the given ip.
+    ///
+    /// Clients in `[client_reject_list]` are rejected. If `[client_allow_list]` has addresses,
+    /// only clients in it are accepted, otherwise all the others are.
+    ///
+    /// ACLs without client lists keep the former behavior of rejecting the clients that
+    /// [`Acl::is_bypass`] would bypass. To migrate, move these addresses from `[bypass_list]`
+    /// to `[client_reject_list]`, or list the accepted ones in `[client_allow_list]`.
+    pub fn is_client_allowed(&self, ip: IpAddr) -> bool {
+        if !self.client_lists {
+            return !self.is_bypass(ip, None);
+        }
+
+        if self.client_reject_list.contains(ip) {
             return false;
         }
 
-        self.mode == Mode::BlackList
+        self.client_allow_list.is_empty() || self.client_allow_list.contains(ip)
     }
 
     /// Returns true if the given ip or host should be block.
     pub fn is_block_outbound(&self, ip: IpAddr, host: Option<&str>) -> bool {
-        if self.outbound_block_list.contains(ip) {
-            return true;
-        }
+        self.match_block_outbound(Some(ip), host) || self.mode == Mode::BlackList
+    }
 
-        let ip = ip.to_string();
+    /// Returns true if the given ip or host matches `[outbound_block_list]`.
+    ///
+    /// Unlike [`Acl::is_block_outbound`], hosts that match no rules aren't blocked, as
+    /// `[reject_all]` is the same mode as `[bypass_all]`, which ss-local uses to bypass.
+    /// The ip is `None` for a host that isn't resolved.
+    pub fn match_block_outbound(&self, ip: Option<IpAddr>, host: Option<&str>) -> bool {
+        let mut ip_str = None;
 
-        if self.outbound_block_rules.contains(&ip) {
-            return true;
+        if let Some(ip) = ip {
+            if self.outbound_block_list.contains(ip) {
+                return true;
+            }
+
+            if !self.outbound_block_geoip.is_empty() {
+                if let Some(country) = self.country(ip) {
+                    if self.outbound_block_geoip.contains(&country) {
+                        return true;
+                    }
+                }
+            }
+
+            let ip = ip.to_string();
+
+            if self.outbound_block_rules.contains(&ip) {
+                return true;
+            }
+
+            ip_str = Some(ip);
         }
 
         if let Some(host) = host {
-            if host != ip {
-                if self.outbound_block_rules.contains(host) {
+            if Some(host) != ip_str.as_deref() {
+                if self.outbound_block_domains.contains(host)
+                    || self.outbound_block_rules.contains(host)
+                {
                     return true;
                 }
             }
         }
 
-        self.mode == Mode::BlackList
+        false
+    }
+
+    /// Returns the country code of the ip, if a geoip database is loaded and has it.
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        self.geoip_db.as_ref()?.country(ip)
+    }
+}
+
+/// Exception rules of a list, the `!` prefixed ones.
+struct Exceptions {
+    ip_set: IpSet,
+    domain_set: DomainSet,
+    rule_set: RuleSet,
+}
+
+impl Exceptions {
+    fn new() -> Self {
+        Exceptions {
+            ip_set: IpSet::new(),
+            domain_set: DomainSet::new(),
+            rule_set: RuleSet::new(),
+        }
+    }
+
+    /// Returns true if an exception matches the ip or the host.
+    fn contains(&self, ip: Option<IpAddr>, host: Option<&str>) -> bool {
+        let host_matched = |host| self.domain_set.contains(host) || self.rule_set.contains(host);
+
+        ip.is_some_and(|ip| self.ip_set.contains(ip)) || host.is_some_and(host_matched)
     }
 }
 
+/// Where domain names that match no host rules are resolved, see [`Acl::is_bypass_host`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResolvePolicy {
+    /// Resolves the domain name locally so ip rules can apply to it. (default)
+    #[default]
+    Local,
+
+    /// Never resolves the domain name for matching, so it follows the mode.
+    /// Proxied domain names are then only resolved by ss-remote.
+    Remote,
+}
+
 /// Access control list mode.
 #[derive(PartialEq, Eq)]
 pub enum Mode {
@@ -174,7 +603,7 @@ mod tests {
 
     #[test]
     fn test_acl() {
-        const DATA: &'static str = r"
+        const DATA: &str = r"
         [proxy_all]
 
         [bypass_list]
@@ -241,6 +670,284 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_cache() {
+        let acl = Acl::from_str("[bypass_list]\n(^|\\.)baidu\\.com$\n10.0.0.0/8");
+        let ip = "10.0.0.1".parse().unwrap();
+        let other = "8.8.8.8".parse().unwrap();
+
+        assert!(acl.is_bypass(ip, None));
+        assert!(acl.is_bypass(other, Some("www.baidu.com")));
+        assert!(!acl.is_bypass(other, Some("qq.com")));
+        assert!(!acl.is_bypass(other, Some("8.8.8.8")));
+        assert_eq!(acl.bypass_cache.lock().unwrap().len(), 4);
+
+        // Cached, a host equal to the ip is the same as none
+        assert!(acl.is_bypass(other, Some("www.baidu.com")));
+        assert!(!acl.is_bypass(other, None));
+        assert_eq!(acl.bypass_cache.lock().unwrap().len(), 4);
+
+        // Bounded, keeping the recently used decisions
+        let mut cache = acl.bypass_cache.lock().unwrap();
+        let used = (other, Some("www.baidu.com".to_owned()));
+        for i in 0..constants::MAX_CACHED_DECISIONS * 2 {
+            cache.insert((ip, Some(format!("{}.com", i))), false);
+            assert_eq!(cache.get(&used), Some(true));
+        }
+        assert!(cache.len() <= constants::MAX_CACHED_DECISIONS);
+        assert_eq!(cache.get(&(ip, Some("8191.com".to_owned()))), Some(false));
+        assert_eq!(cache.get(&(ip, Some("0.com".to_owned()))), None);
+    }
+
+    #[test]
+    fn test_host() {
+        const DATA: &str = r"
+        [proxy_all]
+
+        [bypass_list]
+        (^|\.)baidu\.com$
+
+        [proxy_list]
+        (^|\.)google\.com$
+        ";
+
+        let acl = Acl::from_str(DATA);
+
+        assert_eq!(acl.match_host("www.baidu.com"), Some(true));
+        assert_eq!(acl.match_host("www.google.com"), Some(false));
+        assert_eq!(acl.match_host("qq.com"), None);
+
+        assert!(acl.is_bypass_host("www.baidu.com"));
+        assert!(!acl.is_bypass_host("qq.com"));
+
+        let acl = Acl::from_str(&DATA.replace("[proxy_all]", "[bypass_all]"));
+
+        assert!(!acl.is_bypass_host("www.google.com"));
+        assert!(acl.is_bypass_host("qq.com"));
+    }
+
+    #[test]
+    fn test_domain() {
+        const DATA: &str = r"
+        [proxy_all]
+
+        [bypass_list]
+        ||baidu.com
+        qq.com
+        (^|\.)taobao\.com$
+
+        [proxy_list]
+        ||google.com
+
+        [outbound_block_list]
+        ||example.org
+        ";
+
+        let acl = Acl::from_str(DATA);
+
+        assert_eq!(acl.match_host("baidu.com"), Some(true));
+        assert_eq!(acl.match_host("www.BAIDU.com"), Some(true));
+        assert_eq!(acl.match_host("im.qq.com"), Some(true));
+        assert_eq!(acl.match_host("www.taobao.com"), Some(true));
+        assert_eq!(acl.match_host("mail.google.com"), Some(false));
+
+        // A bare domain isn't a regex any more, dots match only dots
+        assert_eq!(acl.match_host("qqxcom"), None);
+        assert_eq!(acl.match_host("qq.com.evil.net"), None);
+        assert_eq!(acl.match_host("notbaidu.com"), None);
+
+        let ip = "93.184.216.34".parse().unwrap();
+        assert!(acl.is_block_outbound(ip, Some("www.example.org")));
+        assert!(!acl.is_block_outbound(ip, Some("example.com")));
+        assert!(!acl.is_block_outbound(ip, None));
+
+        assert!(acl.match_block_outbound(None, Some("www.example.org")));
+        assert!(!acl.match_block_outbound(None, Some("example.com")));
+
+        // The mode doesn't apply without a match.
+        let acl = Acl::from_str(&DATA.replace("[proxy_all]", "[reject_all]"));
+        assert!(acl.is_block_outbound(ip, Some("example.com")));
+        assert!(!acl.match_block_outbound(Some(ip), Some("example.com")));
+        assert!(acl.match_block_outbound(Some(ip), Some("example.org")));
+    }
+
+    #[test]
+    fn test_exception() {
+        const DATA: &str = r"
+        [bypass_all]
+
+        [proxy_list]
+        *.cn
+        !||gov.cn
+        !(^|\.)edu\.cn$
+        10.0.0.0/8
+        !10.1.0.0/16
+
+        [outbound_block_list]
+        !||example.org
+        ";
+
+        let acl = Acl::from_str(DATA);
+
+        assert_eq!(acl.match_host("www.baidu.cn"), Some(false));
+        assert_eq!(acl.match_host("www.gov.cn"), None);
+        assert_eq!(acl.match_host("tsinghua.edu.cn"), None);
+        assert!(acl.is_bypass_host("www.gov.cn"));
+        assert!(!acl.is_bypass_host("www.baidu.cn"));
+
+        let ip = "10.0.0.1".parse().unwrap();
+        let excepted = "10.1.0.1".parse().unwrap();
+        assert!(!acl.is_bypass(ip, None));
+        assert!(acl.is_bypass(excepted, None));
+
+        // A host exception overrides the ip rules too.
+        assert!(acl.is_bypass(ip, Some("www.gov.cn")));
+        assert!(!acl.is_bypass(ip, Some("www.baidu.cn")));
+
+        // Only in bypass and proxy lists.
+        assert!(!acl.match_block_outbound(None, Some("!example.org")));
+        assert!(!acl.match_block_outbound(None, Some("example.org")));
+    }
+
+    #[test]
+    fn test_remote_resolve() {
+        const DATA: &str = r"
+        [bypass_all]
+
+        [bypass_list]
+        ||google.com
+
+        [remote_resolve_list]
+        ||google.com
+        (^|\.)example\.org$
+        10.0.0.0/8
+        ";
+
+        let acl = Acl::from_str(DATA);
+
+        assert!(acl.is_remote_resolve("www.google.com"));
+        assert!(acl.is_remote_resolve("example.org"));
+        assert!(!acl.is_remote_resolve("qq.com"));
+
+        // Ips aren't rules of the section.
+        assert!(!acl.is_remote_resolve("10.0.0.1"));
+        assert!(acl.is_bypass("10.0.0.1".parse().unwrap(), None));
+    }
+
+    #[test]
+    fn test_client() {
+        const DATA: &str = r"
+        [bypass_all]
+
+        [client_reject_list]
+        10.0.0.1/32
+
+        [client_allow_list]
+        10.0.0.0/8
+        (^|\.)baidu\.com$
+        ";
+
+        let acl = Acl::from_str(DATA);
+
+        assert!(acl.is_client_allowed("10.0.0.2".parse().unwrap()));
+        assert!(!acl.is_client_allowed("10.0.0.1".parse().unwrap()));
+        assert!(!acl.is_client_allowed("8.8.8.8".parse().unwrap()));
+
+        let acl = Acl::from_str("[client_reject_list]\n10.0.0.1/32");
+
+        assert!(!acl.is_client_allowed("10.0.0.1".parse().unwrap()));
+        assert!(acl.is_client_allowed("8.8.8.8".parse().unwrap()));
+
+        // Without client lists, bypassed clients are rejected.
+        let acl = Acl::from_str("[bypass_list]\n10.0.0.1/32");
+
+        assert!(!acl.is_client_allowed("10.0.0.1".parse().unwrap()));
+        assert!(acl.is_client_allowed("8.8.8.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_geoip_without_db() {
+        const DATA: &str = r"
+        [proxy_all]
+
+        [geoip]
+        1234567890abcdefghijklmnopqrstuvwxyz.mmdb
+
+        [bypass_list]
+        geoip:CN
+        10.0.0.0/8
+        ";
+
+        // Rules still parse, and ips fall through to the mode.
+        let acl = Acl::from_str(DATA);
+        assert!(acl.geoip_db.is_none());
+        assert!(acl.bypass_geoip.contains("CN"));
+
+        assert!(acl.is_bypass("10.0.0.1".parse().unwrap(), None));
+        assert!(!acl.is_bypass("1.2.4.8".parse().unwrap(), None));
+    }
+
+    #[cfg(feature = "geoip")]
+    #[test]
+    fn test_geoip() {
+        let dir = std::env::temp_dir().join(format!("ss-rs-test-acl-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("country.mmdb"), geoip::tests::build_db("CN", "US")).unwrap();
+
+        const DATA: &str = r"
+        [proxy_all]
+
+        [geoip]
+        country.mmdb
+
+        [bypass_list]
+        geoip:cn
+
+        [proxy_list]
+        8.0.0.0/8
+
+        [outbound_block_list]
+        geoip:US
+        ";
+        std::fs::write(dir.join("test.acl"), DATA).unwrap();
+
+        // The database is found next to the ACL file.
+        let acl = Acl::from_file(&dir.join("test.acl"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        let acl = acl.unwrap();
+
+        assert!(acl.is_bypass("1.2.4.8".parse().unwrap(), None));
+        assert!(!acl.is_bypass("8.8.8.8".parse().unwrap(), None));
+        assert!(!acl.is_bypass("223.5.5.5".parse().unwrap(), None));
+        assert!(!acl.is_bypass("::1".parse().unwrap(), None));
+
+        assert!(acl.is_block_outbound("223.5.5.5".parse().unwrap(), None));
+        assert!(!acl.is_block_outbound("1.2.4.8".parse().unwrap(), None));
+    }
+
+    #[tokio::test]
+    async fn test_from_url() {
+        let addr = download::tests::serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 25\r\n\r\n[bypass_list]\n10.0.0.0/8\n".to_owned(),
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n".to_owned(),
+        ])
+        .await;
+        let url = format!("http://{}/bypass.acl", addr);
+        let dir = std::env::temp_dir().join(format!("ss-rs-test-acl-{}", addr.port()));
+        let cache_path = dir.join("bypass.acl");
+        let ip = "10.0.0.1".parse().unwrap();
+
+        let acl = Acl::from_url(&url, &cache_path).await.unwrap();
+        assert!(acl.is_bypass(ip, None));
+
+        // Loaded from the cache when the download fails
+        let acl = Acl::from_url(&url, &cache_path).await.unwrap();
+        assert!(acl.is_bypass(ip, None));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(Acl::from_url(&url, &cache_path).await.is_err());
+    }
+
     #[test]
     fn test_error() {
         assert!(Acl::from_file(Path::new("1234567890abcdefghijklmnopqrstuvwxyz")).is_err());
@@ -22,6 +22,11 @@ impl RuleSet {
     pub fn contains(&self, data: &str) -> bool {
         self.rules.iter().any(|r| r.is_match(data))
     }
+
+    /// Returns the source patterns of all rules in the set, in insertion order.
+    pub fn patterns(&self) -> impl Iterator<Item = &str> {
+        self.rules.iter().map(|r| r.as_str())
+    }
 }
 
 #[cfg(test)]
@@ -51,4 +56,17 @@ mod tests {
         assert_eq!(rule_set.contains("2vpn.netccom"), false);
         assert_eq!(rule_set.contains("127.0.0.0"), false);
     }
+
+    #[test]
+    fn test_patterns() {
+        let rules = [r"(^|\.)030buy\.com$", r"(^|\.)12vpn\.com$", "127.0.0.1"];
+
+        let mut rule_set = RuleSet::new();
+
+        for rule in rules {
+            rule_set.insert(Regex::new(rule).unwrap());
+        }
+
+        assert_eq!(rule_set.patterns().collect::<Vec<_>>(), rules);
+    }
 }
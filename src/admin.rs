@@ -0,0 +1,237 @@
+//! Admin HTTP endpoint serving health, statistics and metrics.
+//!
+//! Only available with the `admin` feature. Routes:
+//!
+//! - `GET /health`: `ok` while the process is serving.
+//! - `GET /stats`: the statistics as JSON, like `--dump-metrics`.
+//! - `GET /metrics`: the statistics in the Prometheus text format.
+//!
+//! The endpoint has no authentication, so it should only listen on a loopback address.
+//! There is no `POST /reload`, as the configuration can't be reloaded without a restart.
+
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::context::Ctx;
+
+mod constants {
+    pub const MAX_REQUEST_SIZE: usize = 8192;
+    pub const METRIC_PREFIX: &str = "ss_rs_";
+}
+
+/// Serves the admin endpoint on the given address forever.
+pub async fn serve(addr: SocketAddr, ctx: Arc<Ctx>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("Admin endpoint listening on {}", addr);
+
+    run(listener, ctx).await
+}
+
+async fn run(listener: TcpListener, ctx: Arc<Ctx>) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let ctx = ctx.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &ctx).await {
+                log::debug!("Admin request failed: {}, peer {}", e, peer);
+            }
+        });
+    }
+}
+
+/// Answers a single request, then closes the connection.
+///
+/// The request head has to be read within the handshake timeout.
+async fn handle(mut stream: TcpStream, ctx: &Ctx) -> io::Result<()> {
+    let buf = match tokio::time::timeout(ctx.timeouts().handshake, read_head(&mut stream)).await {
+        Ok(Ok(Some(buf))) => buf,
+        Ok(Ok(None)) => {
+            return respond(&mut stream, 431, "text/plain", "request too large\n").await
+        }
+        Ok(Err(e)) => return Err(e),
+        Err(_) => {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "read request timed out",
+            ))
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buf);
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+
+    let (status, content_type, body) = route(method, path, ctx);
+    respond(&mut stream, status, content_type, &body).await
+}
+
+/// Reads the request head, the body of requests isn't needed.
+///
+/// Returns None if the head is too large.
+async fn read_head(stream: &mut TcpStream) -> io::Result<Option<Vec<u8>>> {
+    let mut buf = Vec::with_capacity(1024);
+
+    while !buf.windows(4).any(|x| x == b"\r\n\r\n") {
+        if buf.len() >= constants::MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+
+        let mut chunk = [0u8; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    Ok(Some(buf))
+}
+
+fn route(method: &str, path: &str, ctx: &Ctx) -> (u16, &'static str, String) {
+    let path = path.split('?').next().unwrap_or_default();
+
+    match (method, path) {
+        ("GET", "/health") => (200, "text/plain", "ok\n".to_owned()),
+        ("GET", "/stats") => match serde_json::to_string(&ctx.stats_snapshot()) {
+            Ok(json) => (200, "application/json", json + "\n"),
+            Err(e) => (500, "text/plain", format!("{}\n", e)),
+        },
+        ("GET", "/metrics") => (200, "text/plain; version=0.0.4", metrics(ctx)),
+        (_, "/health" | "/stats" | "/metrics") => {
+            (405, "text/plain", "method not allowed\n".to_owned())
+        }
+        _ => (404, "text/plain", "not found\n".to_owned()),
+    }
+}
+
+/// Formats the statistics in the Prometheus text format, counters and gauges.
+fn metrics(ctx: &Ctx) -> String {
+    const GAUGES: [&str; 2] = ["active_connections", "remote_connections"];
+
+    let mut body = String::new();
+
+    if let Ok(serde_json::Value::Object(stats)) = serde_json::to_value(ctx.stats_snapshot()) {
+        for (name, value) in stats {
+            let (name, kind) = if GAUGES.contains(&name.as_str()) {
+                (format!("{}{}", constants::METRIC_PREFIX, name), "gauge")
+            } else {
+                (
+                    format!("{}{}_total", constants::METRIC_PREFIX, name),
+                    "counter",
+                )
+            };
+            body.push_str(&format!("# TYPE {} {}\n{} {}\n", name, kind, name, value));
+        }
+    }
+
+    body
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    status: u16,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    };
+
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        content_type,
+        body.len(),
+        body
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{context::Timeouts, stats::Stats};
+
+    use super::*;
+
+    async fn get(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_admin() {
+        let ctx = Arc::new(Ctx::new());
+        Stats::incr(&ctx.stats().connections);
+        Stats::incr(&ctx.stats().active_connections);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run(listener, ctx));
+
+        let response = get(addr, "GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nok\n"));
+
+        let response = get(addr, "GET /stats HTTP/1.1\r\n\r\n").await;
+        assert!(response.contains("\"connections\":1,"));
+
+        let response = get(addr, "GET /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(response.contains("# TYPE ss_rs_connections_total counter\n"));
+        assert!(response.contains("\nss_rs_connections_total 1\n"));
+        assert!(response.contains("# TYPE ss_rs_active_connections gauge\n"));
+
+        let response = get(addr, "POST /stats HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 "));
+
+        let response = get(addr, "GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 "));
+    }
+
+    #[tokio::test]
+    async fn test_admin_timeout() {
+        let mut ctx = Ctx::new();
+        ctx.set_timeouts(Timeouts {
+            handshake: Duration::from_millis(50),
+            ..Default::default()
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run(listener, Arc::new(ctx)));
+
+        // Closed without a response if the request head never arrives
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /health HTTP/1.1\r\n").await.unwrap();
+
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+            .await
+            .expect("idle admin connection was kept open")
+            .unwrap();
+        assert!(response.is_empty());
+    }
+}
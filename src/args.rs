@@ -1,13 +1,24 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    num::{NonZeroU32, NonZeroU64, NonZeroUsize},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
 
-use clap::{command, Arg};
+use clap::{command, Arg, ArgGroup};
+use serde::Deserialize;
 
-use ss_rs::{crypto::cipher::Method, url::SsUrl};
+use ss_rs::{
+    crypto::cipher::Method, net::resolver::IpFamily, plugin::PluginOpts, remote::Balance,
+    socks5::Socks5Addr, url::SsUrl,
+};
 
 /// Command-line parameter definitions for the ss-rs program.
 // #[derive(Parser, Debug)]
 // #[clap(version, about)]
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Args {
     /// IP address and port of your remote server
     // #[clap(short = 's', long)]
@@ -17,25 +28,70 @@ pub struct Args {
     // #[clap(short = 'l', long)]
     pub local_addr: Option<String>,
 
-    /// Password of your shadowsocks
+    /// Password of your shadowsocks, a base64 encoded key for the 2022 methods
     // #[clap(short = 'k', long)]
     pub password: String,
 
+    /// Master key of your shadowsocks, base64 or hex encoded, used instead of the password
+    // #[clap(long, conflicts_with_all = &["password", "url"])]
+    pub key: Option<String>,
+
     /// Encryption method
     // #[clap(
     //     short = 'm',
     //     long,
     //     default_value = "chacha20-ietf-poly1305",
     //     possible_value = "chacha20-ietf-poly1305",
+    //     possible_value = "xchacha20-ietf-poly1305",
     //     possible_value = "aes-128-gcm",
-    //     possible_value = "aes-256-gcm"
+    //     possible_value = "aes-192-gcm",
+    //     possible_value = "aes-256-gcm",
+    //     possible_value = "2022-blake3-aes-128-gcm",
+    //     possible_value = "2022-blake3-aes-256-gcm",
+    //     possible_value = "2022-blake3-chacha20-poly1305"
     // )]
     pub method: Method,
 
+    /// Additional SS-URLs to listen on, or for ss-local to fail over to
+    // #[clap(long = "server-url")]
+    pub server_urls: Vec<SsUrl>,
+
+    /// Strategy to pick the server of a new connection among several (ss-local only)
+    // #[clap(long, default_value = "latency")]
+    pub balance: Balance,
+
     /// Access control list
     // #[clap(long = "acl")]
     pub acl_path: Option<PathBuf>,
 
+    /// Access control list downloaded from a URL, instead of a file
+    // #[clap(long = "acl-url", conflicts_with = "acl")]
+    pub acl_url: Option<String>,
+
+    /// Where the access control list downloaded from a URL is cached
+    // #[clap(long = "acl-cache", requires = "acl-url")]
+    pub acl_cache: Option<PathBuf>,
+
+    /// Routing rules deciding bypass, proxy or block, instead of an access control list
+    // #[clap(long = "route-script", conflicts_with = "acl")]
+    pub route_script: Option<PathBuf>,
+
+    /// Resolve domain names that match no ACL host rules on the remote server
+    // #[clap(long)]
+    pub remote_dns: bool,
+
+    /// Look up the domain names of ip targets to match them against ACL host rules
+    // #[clap(long)]
+    pub acl_reverse_dns: bool,
+
+    /// Address families domain names are resolved to
+    // #[clap(long, default_value = "v4-only")]
+    pub ip_family: IpFamily,
+
+    /// Seconds domain name lookups are cached for
+    // #[clap(long)]
+    pub dns_cache_ttl: Option<u64>,
+
     /// Plugin
     // #[clap(long)]
     pub plugin: Option<String>,
@@ -44,6 +100,170 @@ pub struct Args {
     // #[clap(long)]
     pub plugin_opts: Option<String>,
 
+    /// IP address and port the plugin listens on
+    // #[clap(long)]
+    pub plugin_bind: Option<String>,
+
+    /// Timeout in seconds for reading the target address
+    // #[clap(long)]
+    pub handshake_timeout: Option<u64>,
+
+    /// Timeout in seconds for connecting to the target or remote server
+    // #[clap(long)]
+    pub connect_timeout: Option<u64>,
+
+    /// Times to retry connecting to the target or remote server after a failure
+    // #[clap(long)]
+    pub connect_retries: Option<u32>,
+
+    /// Delay in milliseconds before the first connect retry, doubled for each of the next ones
+    // #[clap(long)]
+    pub retry_delay: Option<u64>,
+
+    /// Timeout in seconds for keeping a half-closed connection open
+    // #[clap(long)]
+    pub half_close_timeout: Option<u64>,
+
+    /// Timeout in seconds for closing idle connections
+    // #[clap(short = 't', long)]
+    pub timeout: Option<u64>,
+
+    /// Maximum time in seconds to wait for active connections on shutdown
+    // #[clap(long)]
+    pub shutdown_timeout: Option<u64>,
+
+    /// Certificate chain file in PEM format for accepting TLS (ss-remote only)
+    // #[clap(long)]
+    pub tls_cert: Option<PathBuf>,
+
+    /// Private key file in PEM format for accepting TLS (ss-remote only)
+    // #[clap(long)]
+    pub tls_key: Option<PathBuf>,
+
+    /// Server name for connecting to the remote server over TLS (ss-local only)
+    // #[clap(long)]
+    pub tls_sni: Option<String>,
+
+    /// Certificate file in PEM format to trust when connecting over TLS
+    // #[clap(long)]
+    pub tls_ca: Option<PathBuf>,
+
+    /// Log what clients sent and close without relaying (ss-remote only)
+    // #[clap(long)]
+    pub probe: bool,
+
+    /// Drain suspected probes for a random duration before closing (ss-remote only)
+    // #[clap(long = "mitigate-probing")]
+    pub mitigate_probing: bool,
+
+    /// Source address of outbound connections
+    // #[clap(long)]
+    pub outbound_bind: Option<IpAddr>,
+
+    /// Firewall mark of outbound connections (linux only)
+    // #[clap(long)]
+    pub fwmark: Option<u32>,
+
+    /// Allow targets that are the server's own listening addresses (ss-remote only)
+    // #[clap(long)]
+    pub allow_self_connect: bool,
+
+    /// Rotate connections to a domain name across up to this many of its addresses
+    // #[clap(long)]
+    pub max_resolved_addrs: Option<usize>,
+
+    /// Write the statistics as JSON to this file ('-' for stdout) on SIGUSR1
+    // #[clap(long)]
+    pub dump_metrics: Option<PathBuf>,
+
+    /// Log a summary of the statistics every this many seconds
+    // #[clap(long)]
+    pub summary_interval: Option<u64>,
+
+    /// Address of the admin endpoint serving health, statistics and metrics
+    // #[clap(long, default_missing_value = "127.0.0.1:9000")]
+    pub admin: Option<SocketAddr>,
+
+    /// Address of the manager adding and removing ports with the shadowsocks manager protocol
+    // #[clap(long = "manager-address", conflicts_with = "local-addr")]
+    pub manager_address: Option<SocketAddr>,
+
+    /// Limit of bytes per second of each connection, per direction
+    // #[clap(long)]
+    pub limit_rate: Option<u64>,
+
+    /// Maximum number of new connections per second from each client ip, or IPv6 /64 network
+    // #[clap(long = "max-conn-rate")]
+    pub max_conn_rate: Option<u32>,
+
+    /// Maximum number of connections handled at once
+    // #[clap(long = "max-connections")]
+    pub max_connections: Option<usize>,
+
+    /// Number of worker threads of the runtime, 0 for one per CPU core
+    // #[clap(long)]
+    pub threads: Option<usize>,
+
+    /// Maximum number of free stream buffers kept for reuse
+    // #[clap(long)]
+    pub buffer_pool_size: Option<usize>,
+
+    /// Compress chunks (experimental, not compatible with other implementations)
+    // #[clap(long)]
+    pub compress: bool,
+
+    /// Only remember the most recent salts for replay protection
+    // #[clap(long)]
+    pub replay_lite: bool,
+
+    /// Disable replay protection
+    // #[clap(long)]
+    pub no_replay_protection: bool,
+
+    /// File of hex-encoded salts to reject, one per line
+    // #[clap(long)]
+    pub replay_blocklist: Option<PathBuf>,
+
+    /// File the replay protection is loaded from on startup and saved to periodically and on exit
+    // #[clap(long)]
+    pub replay_state: Option<PathBuf>,
+
+    /// Check salts for replay attacks as soon as they are received
+    // #[clap(long)]
+    pub early_replay_check: bool,
+
+    /// Enable TCP Fast Open
+    // #[clap(long)]
+    pub fast_open: bool,
+
+    /// Set SO_REUSEPORT on the TCP listeners, to spread connections over several processes
+    // #[clap(long)]
+    pub reuse_port: bool,
+
+    /// Keep Nagle's algorithm on relayed sockets instead of setting TCP_NODELAY
+    // #[clap(long)]
+    pub nagle: bool,
+
+    /// Transparent proxy mode (ss-local only)
+    // #[clap(long)]
+    pub transparent: bool,
+
+    /// Forward every connection to this fixed target address instead (ss-local only)
+    // #[clap(long)]
+    pub tunnel_addr: Option<String>,
+
+    /// Relay udp too, via SOCKS5 UDP ASSOCIATE
+    // #[clap(long)]
+    pub udp: bool,
+
+    /// Allow the SOCKS5 BIND command
+    // #[clap(long = "socks5-bind")]
+    pub socks5_bind: bool,
+
+    /// Users allowed to use ss-local, requiring SOCKS5 or HTTP Basic authentication
+    // #[clap(long = "socks5-auth")]
+    pub socks5_users: Vec<(String, String)>,
+
     /// Debug mode
     // #[clap(short, long)]
     pub verbose: bool,
@@ -52,29 +272,65 @@ pub struct Args {
     // #[clap(long)]
     pub show_url: bool,
 
+    /// Tag of the printed SS-URL
+    // #[clap(long)]
+    pub url_tag: Option<String>,
+
     /// Print corresponding shadowsocks config and then exit
     // #[clap(long)]
     pub show_cfg: bool,
+
+    /// Check the configuration by relaying a test request, then exit
+    // #[clap(long)]
+    pub check: bool,
+
+    /// Target of the HTTP request sent by --check
+    // #[clap(long = "check-target", requires = "check")]
+    pub check_target: Option<String>,
 }
 
 impl From<Args> for SsUrl {
     fn from(args: Args) -> Self {
-        let (hostname, port) = args.remote_addr.split_once(':').unwrap();
+        let (hostname, port) = args.remote_addr.rsplit_once(':').unwrap();
 
-        SsUrl {
+        let mut url = SsUrl {
             method: args.method,
             password: args.password,
             hostname: hostname.to_owned(),
             port: port.parse().unwrap(),
-            plugin: args.plugin,
-            plugin_opts: args.plugin_opts,
+            plugin: None,
+            plugin_opts: None,
             tag: None,
+        };
+
+        if let Some(ref plugin) = args.plugin {
+            url.set_sip003_plugin(plugin, args.plugin_opts.as_deref());
+        }
+
+        if let Some(ref tag) = args.url_tag {
+            url.set_tag(tag);
         }
+
+        url
     }
 }
 
+/// A config file in the official shadowsocks JSON schema, unknown fields are ignored.
+#[derive(Debug, Deserialize)]
+struct Config {
+    server: Option<String>,
+    server_port: Option<u16>,
+    password: Option<String>,
+    port_password: Option<HashMap<String, String>>,
+    method: Option<String>,
+    plugin: Option<String>,
+    plugin_opts: Option<String>,
+    acl: Option<PathBuf>,
+    timeout: Option<u64>,
+}
+
 pub fn parse() -> Args {
-    let matches = command!()
+    let mut cmd = command!()
         .author("Repository: https://github.com/ocfbnj/ss-rs")
         .arg(
             Arg::new("remote-addr")
@@ -83,7 +339,7 @@ pub fn parse() -> Args {
                 .takes_value(true)
                 .value_name("REMOTE_ADDR")
                 .help("IP address and port of your remote server")
-                .required_unless_present("url"),
+                .required_unless_present_any(["url", "config"]),
         )
         .arg(
             Arg::new("local-addr")
@@ -99,8 +355,16 @@ pub fn parse() -> Args {
                 .long("password")
                 .takes_value(true)
                 .value_name("PASSWORD")
-                .help("Password of your shadowsocks")
-                .required_unless_present("url"),
+                .help("Password of your shadowsocks, a base64 encoded key of the key size for the 2022 methods")
+                .required_unless_present_any(["url", "config", "key"]),
+        )
+        .arg(
+            Arg::new("key")
+                .long("key")
+                .takes_value(true)
+                .value_name("KEY")
+                .help("Master key of your shadowsocks, base64 or hex encoded bytes of the key size, used verbatim instead of a password")
+                .conflicts_with_all(&["password", "url"]),
         )
         .arg(
             Arg::new("method")
@@ -110,7 +374,16 @@ pub fn parse() -> Args {
                 .value_name("METHOD")
                 .validator(|x| x.parse::<Method>())
                 .help("Encryption method")
-                .possible_values(["chacha20-ietf-poly1305", "aes-128-gcm", "aes-256-gcm"])
+                .possible_values([
+                    "chacha20-ietf-poly1305",
+                    "xchacha20-ietf-poly1305",
+                    "aes-128-gcm",
+                    "aes-192-gcm",
+                    "aes-256-gcm",
+                    "2022-blake3-aes-128-gcm",
+                    "2022-blake3-aes-256-gcm",
+                    "2022-blake3-chacha20-poly1305",
+                ])
                 .default_value("chacha20-ietf-poly1305"),
         )
         .arg(
@@ -120,6 +393,65 @@ pub fn parse() -> Args {
                 .value_name("ACL_PATH")
                 .help("Access control list"),
         )
+        .arg(
+            Arg::new("acl-url")
+                .long("acl-url")
+                .takes_value(true)
+                .value_name("URL")
+                .help("Access control list downloaded from an http or https URL, instead of a file"),
+        )
+        .arg(
+            Arg::new("acl-cache")
+                .long("acl-cache")
+                .takes_value(true)
+                .value_name("PATH")
+                .requires("acl-url")
+                .help("Where the downloaded access control list is cached, and loaded from if the download fails [default: a file in the ss-rs directory of the user's cache directory]"),
+        )
+        .arg(
+            Arg::new("route-script")
+                .long("route-script")
+                .takes_value(true)
+                .value_name("SCRIPT_PATH")
+                .help("Routing rules deciding bypass, proxy or block, instead of an access control list"),
+        )
+        .group(ArgGroup::new("router").args(&["acl", "acl-url", "route-script"]))
+        .arg(
+            Arg::new("remote-dns")
+                .long("remote-dns")
+                .requires("router")
+                .help("Resolve domain names that match no ACL host rules on the remote server, instead of locally for ip rules"),
+        )
+        .arg(
+            Arg::new("acl-reverse-dns")
+                .long("acl-reverse-dns")
+                .requires("router")
+                .help("Look up the domain names of ip targets (PTR records) to match them against ACL host rules, slowing down new connections"),
+        )
+        .arg(
+            Arg::new("ip-family")
+                .long("ip-family")
+                .takes_value(true)
+                .value_name("FAMILY")
+                .help("Address families domain names are resolved to, for both bypass decisions and connections")
+                .possible_values(["v4-only", "v6-only", "prefer-v4", "prefer-v6", "system"])
+                .default_value("v4-only"),
+        )
+        .arg(
+            Arg::new("ipv6-first")
+                .short('6')
+                .long("ipv6-first")
+                .conflicts_with("ip-family")
+                .help("Resolve domain names to ipv6 addresses first, same as --ip-family prefer-v6"),
+        )
+        .arg(
+            Arg::new("dns-cache-ttl")
+                .long("dns-cache-ttl")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .validator(|x| x.parse::<u64>())
+                .help("Cache domain name lookups for SECONDS, 0 to disable [default: 60]"),
+        )
         .arg(
             Arg::new("plugin")
                 .long("plugin")
@@ -133,9 +465,18 @@ pub fn parse() -> Args {
                 .long("plugin-opts")
                 .takes_value(true)
                 .value_name("PLUGIN_OPTS")
+                .validator(|x| x.parse::<PluginOpts>())
                 .conflicts_with("url")
                 .help("Plugin options"),
         )
+        .arg(
+            Arg::new("plugin-bind")
+                .long("plugin-bind")
+                .takes_value(true)
+                .value_name("PLUGIN_BIND")
+                .requires("plugin")
+                .help("IP address and port the plugin listens on"),
+        )
         .arg(
             Arg::new("url")
                 .long("url")
@@ -144,27 +485,392 @@ pub fn parse() -> Args {
                 .validator(|x| x.parse::<SsUrl>())
                 .help("Specify ss-remote SS-URL"),
         )
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .takes_value(true)
+                .value_name("CONFIG_PATH")
+                .conflicts_with("url")
+                .help("Load the server, password, method, plugin, ACL and timeout from a shadowsocks JSON config file, overridden by the given options"),
+        )
+        .arg(
+            Arg::new("server-url")
+                .long("server-url")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .value_name("SS_URL")
+                .validator(|x| x.parse::<SsUrl>())
+                .help("Additional SS-URL with its own method and password, may be repeated. ss-remote listens on each, ss-local fails over between them"),
+        )
+        .arg(
+            Arg::new("balance")
+                .long("balance")
+                .takes_value(true)
+                .value_name("STRATEGY")
+                .help("Strategy to pick the server of a new connection among the healthy ones (ss-local only)")
+                .possible_values(["latency", "round-robin", "least-conn"])
+                .default_value("latency"),
+        )
         .arg(
             Arg::new("show-url")
                 .long("show-url")
                 .help("Print corresponding SS-URL and then exit"),
         )
+        .arg(
+            Arg::new("url-tag")
+                .long("url-tag")
+                .takes_value(true)
+                .value_name("TAG")
+                .requires("show-url")
+                .help("Tag (profile name) of the printed SS-URL"),
+        )
         .arg(
             Arg::new("show-cfg")
                 .long("show-cfg")
                 .conflicts_with("show-url")
                 .help("Print corresponding shadowsocks config and then exit"),
         )
+        .arg(
+            Arg::new("check")
+                .long("check")
+                .conflicts_with_all(&["show-url", "show-cfg"])
+                .help("Check the configuration and then exit: ss-remote relays a message to itself, ss-local sends a request to example.com through the server (plugins and TLS aren't used)"),
+        )
+        .arg(
+            Arg::new("check-target")
+                .long("check-target")
+                .takes_value(true)
+                .value_name("HOST:PORT")
+                .validator(|x| x.parse::<Socks5Addr>())
+                .requires("check")
+                .help("Send an HTTP request to HOST:PORT for --check, through ss-remote itself for a server configuration [default: example.com:80 for ss-local]"),
+        )
+        .arg(
+            Arg::new("timeout")
+                .short('t')
+                .long("timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .validator(|x| x.parse::<u64>())
+                .help("Timeout in seconds for closing connections without any traffic in either direction [default: 60]"),
+        )
+        .arg(
+            Arg::new("handshake-timeout")
+                .long("handshake-timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .validator(|x| x.parse::<u64>())
+                .help("Timeout in seconds for reading the target address [default: 15]"),
+        )
+        .arg(
+            Arg::new("connect-timeout")
+                .long("connect-timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .validator(|x| x.parse::<u64>())
+                .help("Timeout in seconds for connecting to the target or remote server [default: 15]"),
+        )
+        .arg(
+            Arg::new("connect-retries")
+                .long("connect-retries")
+                .takes_value(true)
+                .value_name("NUM")
+                .validator(|x| x.parse::<u32>())
+                .help("Times ss-local retries connecting to the target or remote server after a failure, within the connect timeout, refused bypassed targets aren't retried [default: 0]"),
+        )
+        .arg(
+            Arg::new("retry-delay")
+                .long("retry-delay")
+                .takes_value(true)
+                .value_name("MILLISECONDS")
+                .validator(|x| x.parse::<u64>())
+                .requires("connect-retries")
+                .help("Delay in milliseconds before the first connect retry, doubled for each of the next ones up to 2s [default: 100]"),
+        )
+        .arg(
+            Arg::new("half-close-timeout")
+                .long("half-close-timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .validator(|x| x.parse::<u64>())
+                .help("Timeout in seconds for one direction relaying nothing after the other has closed [default: the idle timeout]"),
+        )
+        .arg(
+            Arg::new("shutdown-timeout")
+                .long("shutdown-timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .validator(|x| x.parse::<u64>())
+                .help("Maximum time in seconds to wait for active connections to finish on ctrl-c, new ones are refused meanwhile and idle or UDP ASSOCIATE ones closed [default: 30]"),
+        )
+        .arg(
+            Arg::new("tls-cert")
+                .long("tls-cert")
+                .takes_value(true)
+                .value_name("PATH")
+                .requires("tls-key")
+                .conflicts_with_all(&["local-addr", "plugin"])
+                .help("Accept TLS-wrapped connections with this certificate chain in PEM format (ss-remote only, requires the tls feature)"),
+        )
+        .arg(
+            Arg::new("tls-key")
+                .long("tls-key")
+                .takes_value(true)
+                .value_name("PATH")
+                .requires("tls-cert")
+                .help("Private key in PEM format for --tls-cert"),
+        )
+        .arg(
+            Arg::new("tls-sni")
+                .long("tls-sni")
+                .takes_value(true)
+                .value_name("NAME")
+                .requires("local-addr")
+                .conflicts_with("plugin")
+                .help("Connect to the remote server over TLS, verifying this server name (ss-local only, requires the tls feature)"),
+        )
+        .arg(
+            Arg::new("tls-ca")
+                .long("tls-ca")
+                .takes_value(true)
+                .value_name("PATH")
+                .requires("tls-sni")
+                .help("Trust the certificates in this PEM file instead of the Mozilla root certificates"),
+        )
+        .arg(
+            Arg::new("probe")
+                .long("probe")
+                .conflicts_with("local-addr")
+                .help("Log the target address and first bytes clients sent, then close without relaying (ss-remote only)"),
+        )
+        .arg(
+            Arg::new("mitigate-probing")
+                .long("mitigate-probing")
+                .conflicts_with("local-addr")
+                .help("Keep connections whose first chunk fails to decrypt or whose salt is replayed open, draining them for a random duration (ss-remote only)"),
+        )
+        .arg(
+            Arg::new("outbound-bind")
+                .long("outbound-bind")
+                .takes_value(true)
+                .value_name("IP")
+                .validator(|x| x.parse::<IpAddr>())
+                .help("Source address of outbound connections, to targets or to the remote server"),
+        )
+        .arg(
+            Arg::new("fwmark")
+                .long("fwmark")
+                .takes_value(true)
+                .value_name("MARK")
+                .validator(|x| x.parse::<u32>())
+                .help("Set the firewall mark (SO_MARK) of outbound connections and udp sockets, for policy routing, DNS lookups are not marked (linux only, needs CAP_NET_ADMIN)"),
+        )
+        .arg(
+            Arg::new("allow-self-connect")
+                .long("allow-self-connect")
+                .conflicts_with("local-addr")
+                .help("Allow targets that are the server's own listening addresses, which are rejected to prevent connection loops (ss-remote only)"),
+        )
+        .arg(
+            Arg::new("max-resolved-addrs")
+                .long("max-resolved-addrs")
+                .takes_value(true)
+                .value_name("NUM")
+                .validator(|x| x.parse::<usize>())
+                .help("Rotate successive connections to a domain name across up to NUM of its resolved addresses [default: 1]"),
+        )
+        .arg(
+            Arg::new("dump-metrics")
+                .long("dump-metrics")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Write the statistics as JSON to PATH ('-' for stdout) on SIGUSR1"),
+        )
+        .arg(
+            Arg::new("summary-interval")
+                .long("summary-interval")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .validator(|x| x.parse::<NonZeroU64>())
+                .help("Log connections, traffic and rejections over the last SECONDS, every SECONDS"),
+        )
+        .arg(
+            Arg::new("admin")
+                .long("admin")
+                .takes_value(true)
+                .min_values(0)
+                .value_name("ADDR")
+                .default_missing_value("127.0.0.1:9000")
+                .validator(|x| x.parse::<SocketAddr>())
+                .help("Serve /health, /stats and /metrics over HTTP on ADDR [default: 127.0.0.1:9000] (requires the admin feature)"),
+        )
+        .arg(
+            Arg::new("manager-address")
+                .long("manager-address")
+                .takes_value(true)
+                .value_name("ADDR")
+                .validator(|x| x.parse::<SocketAddr>())
+                .conflicts_with("local-addr")
+                .help("Add and remove ports with the shadowsocks manager protocol over UDP on ADDR, TCP only (ss-remote only)"),
+        )
+        .arg(
+            Arg::new("limit-rate")
+                .long("limit-rate")
+                .takes_value(true)
+                .value_name("BYTES")
+                .validator(|x| x.parse::<NonZeroU64>())
+                .help("Limit each relayed connection to BYTES per second in each direction, allowing bursts of a second's worth"),
+        )
+        .arg(
+            Arg::new("max-conn-rate")
+                .long("max-conn-rate")
+                .takes_value(true)
+                .value_name("NUM")
+                .validator(|x| x.parse::<NonZeroU32>())
+                .conflicts_with("local-addr")
+                .help("Drop connections of clients opening more than NUM per second from the same ip or IPv6 /64 network, allowing bursts of a second's worth (ss-remote only)"),
+        )
+        .arg(
+            Arg::new("max-connections")
+                .long("max-connections")
+                .takes_value(true)
+                .value_name("NUM")
+                .validator(|x| x.parse::<NonZeroUsize>())
+                .conflicts_with("local-addr")
+                .help("Handle at most NUM connections at once, leaving the others in the listen backlog until one finishes (ss-remote only)"),
+        )
+        .arg(
+            Arg::new("buffer-pool-size")
+                .long("buffer-pool-size")
+                .takes_value(true)
+                .value_name("NUM")
+                .validator(|x| x.parse::<usize>())
+                .help("Keep up to NUM free 16 KiB stream buffers for reuse across connections, 0 to disable [default: 1024]"),
+        )
+        .arg(
+            Arg::new("compress")
+                .long("compress")
+                .help("Compress chunks (EXPERIMENTAL, non-standard: both ends must enable it, and it doesn't work with other shadowsocks implementations)"),
+        )
+        .arg(
+            Arg::new("replay-lite")
+                .long("replay-lite")
+                .conflicts_with("no-replay-protection")
+                .help("Only remember the last 1024 salts for replay protection, instead of about a million"),
+        )
+        .arg(
+            Arg::new("no-replay-protection")
+                .long("no-replay-protection")
+                .help("Disable replay protection"),
+        )
+        .arg(
+            Arg::new("replay-blocklist")
+                .long("replay-blocklist")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("File of hex-encoded salts to reject as replays, one per line"),
+        )
+        .arg(
+            Arg::new("replay-state")
+                .long("replay-state")
+                .takes_value(true)
+                .value_name("PATH")
+                .conflicts_with_all(&["replay-lite", "no-replay-protection"])
+                .help("Load the salts seen for replay protection from PATH on startup, and save them every minute and on exit"),
+        )
+        .arg(
+            Arg::new("early-replay-check")
+                .long("early-replay-check")
+                .help("Check salts for replay attacks as soon as they are received, instead of after the first chunk is authenticated"),
+        )
+        .arg(
+            Arg::new("fast-open")
+                .long("fast-open")
+                .help("Enable TCP Fast Open for listening and connecting, saving a round trip on reconnections (linux only)"),
+        )
+        .arg(
+            Arg::new("nagle")
+                .long("nagle")
+                .help("Keep Nagle's algorithm on relayed sockets instead of setting TCP_NODELAY, trading latency for fewer packets"),
+        )
+        .arg(
+            Arg::new("reuse-port")
+                .long("reuse-port")
+                .help("Set SO_REUSEPORT on the TCP listeners, so several processes can listen on the same port and share its connections (unix only)"),
+        )
+        .arg(
+            Arg::new("transparent")
+                .long("transparent")
+                .alias("redir")
+                .requires("local-addr")
+                .help("Transparent proxy mode, reads the target from iptables REDIRECT (ss-local only)"),
+        )
+        .arg(
+            Arg::new("tunnel-addr")
+                .long("tunnel-addr")
+                .takes_value(true)
+                .value_name("HOST:PORT")
+                .validator(|x| x.parse::<Socks5Addr>())
+                .requires("local-addr")
+                .conflicts_with_all(&["transparent", "udp", "socks5-auth"])
+                .help("Tunnel mode, forwards every connection to HOST:PORT through ss-remote without a SOCKS5 handshake (ss-local only)"),
+        )
+        .arg(
+            Arg::new("udp")
+                .long("udp")
+                .conflicts_with_all(&["plugin", "transparent"])
+                .help("Relay udp too: ss-local accepts SOCKS5 UDP ASSOCIATE and relays packets of the client while its connection is open, ss-remote serves udp on the same port"),
+        )
+        .arg(
+            Arg::new("socks5-bind")
+                .long("socks5-bind")
+                .conflicts_with_all(&["transparent", "tunnel-addr"])
+                .help("Allow the SOCKS5 BIND command: ss-local accepts it, ss-remote listens on an ephemeral port for it, both need it"),
+        )
+        .arg(
+            Arg::new("socks5-auth")
+                .long("socks5-auth")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .value_name("USERNAME:PASSWORD")
+                .validator(parse_user)
+                .requires("local-addr")
+                .conflicts_with("transparent")
+                .help("Require SOCKS5 and HTTP CONNECT clients to authenticate as this user, may be repeated, udp is only relayed for authenticated UDP ASSOCIATE connections (ss-local only)"),
+        )
+        .arg(
+            Arg::new("threads")
+                .long("threads")
+                .takes_value(true)
+                .value_name("NUM")
+                .validator(|x| x.parse::<usize>())
+                .help("Number of worker threads, 0 or absent for one per CPU core"),
+        )
         .arg(
             Arg::new("verbose")
                 .short('v')
                 .long("verbose")
                 .help("Debug mode"),
-        )
-        .get_matches();
+        );
+    let matches = cmd.get_matches_mut();
+
+    let mut config = match matches.value_of("config") {
+        Some(path) => match from_config_file(Path::new(path)) {
+            Ok(args) => args,
+            Err(e) => cmd
+                .error(
+                    clap::ErrorKind::InvalidValue,
+                    format!("Unable to load config file {}: {}", path, e),
+                )
+                .exit(),
+        },
+        None => Args::default(),
+    };
 
     let remote_addr;
     let password;
+    let key;
     let method;
     let plugin;
     let plugin_opts;
@@ -173,34 +879,411 @@ pub fn parse() -> Args {
         let ss_url: SsUrl = url.parse().unwrap();
 
         remote_addr = format!("{}:{}", ss_url.hostname, ss_url.port);
+        (plugin, plugin_opts) = match ss_url.sip003_plugin() {
+            Some((name, opts)) => (Some(name), opts),
+            None => (None, None),
+        };
         password = ss_url.password;
+        key = None;
         method = ss_url.method;
-        plugin = ss_url.plugin;
-        plugin_opts = ss_url.plugin_opts;
     } else {
-        remote_addr = matches.value_of("remote-addr").unwrap().to_owned();
-        password = matches.value_of("password").unwrap().to_owned();
-        method = matches.value_of("method").unwrap().parse().unwrap();
-        plugin = matches.value_of("plugin").map(|x| x.to_owned());
-        plugin_opts = matches.value_of("plugin-opts").map(|x| x.to_owned());
+        // Options given explicitly override the config file, defaults don't.
+        remote_addr = match matches.value_of("remote-addr") {
+            Some(addr) => addr.to_owned(),
+            None => std::mem::take(&mut config.remote_addr),
+        };
+        key = matches.value_of("key").map(|x| x.to_owned());
+        password = match matches.value_of("password") {
+            Some(password) => password.to_owned(),
+            None if key.is_none() => std::mem::take(&mut config.password),
+            None => String::new(),
+        };
+        method = match matches.occurrences_of("method") {
+            0 => config.method,
+            _ => matches.value_of("method").unwrap().parse().unwrap(),
+        };
+        plugin = matches
+            .value_of("plugin")
+            .map(|x| x.to_owned())
+            .or(config.plugin.take());
+        plugin_opts = matches
+            .value_of("plugin-opts")
+            .map(|x| x.to_owned())
+            .or(config.plugin_opts.take());
+    }
+
+    if remote_addr.is_empty() {
+        cmd.error(
+            clap::ErrorKind::MissingRequiredArgument,
+            "The remote address is missing, give --remote-addr or both server and server_port in the config file",
+        )
+        .exit();
+    }
+    if password.is_empty() && key.is_none() {
+        cmd.error(
+            clap::ErrorKind::MissingRequiredArgument,
+            "The password is missing, give --password, --key or password in the config file",
+        )
+        .exit();
     }
 
     let local_addr = matches.value_of("local-addr").map(|x| x.to_owned());
-    let acl_path = matches.value_of("acl").map(|x| x.into());
+    let mut server_urls = std::mem::take(&mut config.server_urls);
+    if let Some(urls) = matches.values_of("server-url") {
+        server_urls.extend(urls.map(|url| url.parse().unwrap()));
+    }
+    let plugin_bind = matches.value_of("plugin-bind").map(|x| x.to_owned());
+    let acl_url = matches.value_of("acl-url").map(|x| x.to_owned());
+    let acl_path = match acl_url {
+        Some(_) => None,
+        None => matches
+            .value_of("acl")
+            .map(|x| x.into())
+            .or(config.acl_path),
+    };
+    let acl_cache = matches.value_of("acl-cache").map(|x| x.into());
+    let route_script = matches.value_of("route-script").map(|x| x.into());
+    let remote_dns = matches.is_present("remote-dns");
+    let acl_reverse_dns = matches.is_present("acl-reverse-dns");
+    let balance = match matches.value_of("balance").unwrap() {
+        "round-robin" => Balance::RoundRobin,
+        "least-conn" => Balance::LeastConn,
+        _ => Balance::Latency,
+    };
+    let ip_family = match matches.value_of("ip-family").unwrap() {
+        _ if matches.is_present("ipv6-first") => IpFamily::PreferV6,
+        "v6-only" => IpFamily::V6Only,
+        "prefer-v4" => IpFamily::PreferV4,
+        "prefer-v6" => IpFamily::PreferV6,
+        "system" => IpFamily::System,
+        _ => IpFamily::V4Only,
+    };
+    let dns_cache_ttl = matches
+        .value_of("dns-cache-ttl")
+        .map(|x| x.parse().unwrap());
+    let handshake_timeout = matches
+        .value_of("handshake-timeout")
+        .map(|x| x.parse().unwrap());
+    let connect_timeout = matches
+        .value_of("connect-timeout")
+        .map(|x| x.parse().unwrap());
+    let connect_retries = matches
+        .value_of("connect-retries")
+        .map(|x| x.parse().unwrap());
+    let retry_delay = matches.value_of("retry-delay").map(|x| x.parse().unwrap());
+    let half_close_timeout = matches
+        .value_of("half-close-timeout")
+        .map(|x| x.parse().unwrap());
+    let timeout = matches
+        .value_of("timeout")
+        .map(|x| x.parse().unwrap())
+        .or(config.timeout);
+    let shutdown_timeout = matches
+        .value_of("shutdown-timeout")
+        .map(|x| x.parse().unwrap());
+    let tls_cert = matches.value_of("tls-cert").map(|x| x.into());
+    let tls_key = matches.value_of("tls-key").map(|x| x.into());
+    let tls_sni = matches.value_of("tls-sni").map(|x| x.to_owned());
+    let tls_ca = matches.value_of("tls-ca").map(|x| x.into());
+    let probe = matches.is_present("probe");
+    let mitigate_probing = matches.is_present("mitigate-probing");
+    let outbound_bind = matches
+        .value_of("outbound-bind")
+        .map(|x| x.parse().unwrap());
+    let fwmark = matches.value_of("fwmark").map(|x| x.parse().unwrap());
+    let allow_self_connect = matches.is_present("allow-self-connect");
+    let max_resolved_addrs = matches
+        .value_of("max-resolved-addrs")
+        .map(|x| x.parse().unwrap());
+    let dump_metrics = matches.value_of("dump-metrics").map(|x| x.into());
+    let summary_interval = matches
+        .value_of("summary-interval")
+        .map(|x| x.parse().unwrap());
+    let admin = matches.value_of("admin").map(|x| x.parse().unwrap());
+    let manager_address = matches
+        .value_of("manager-address")
+        .map(|x| x.parse().unwrap());
+    let limit_rate = matches.value_of("limit-rate").map(|x| x.parse().unwrap());
+    let max_conn_rate = matches
+        .value_of("max-conn-rate")
+        .map(|x| x.parse().unwrap());
+    let max_connections = matches
+        .value_of("max-connections")
+        .map(|x| x.parse().unwrap());
+    let threads = matches.value_of("threads").map(|x| x.parse().unwrap());
+    let buffer_pool_size = matches
+        .value_of("buffer-pool-size")
+        .map(|x| x.parse().unwrap());
+    let compress = matches.is_present("compress");
+    let replay_lite = matches.is_present("replay-lite");
+    let no_replay_protection = matches.is_present("no-replay-protection");
+    let replay_blocklist = matches.value_of("replay-blocklist").map(|x| x.into());
+    let replay_state = matches.value_of("replay-state").map(|x| x.into());
+    let early_replay_check = matches.is_present("early-replay-check");
+    let fast_open = matches.is_present("fast-open");
+    let reuse_port = matches.is_present("reuse-port");
+    let nagle = matches.is_present("nagle");
+    let transparent = matches.is_present("transparent");
+    let tunnel_addr = matches.value_of("tunnel-addr").map(|x| x.to_owned());
+    let udp = matches.is_present("udp");
+    let socks5_bind = matches.is_present("socks5-bind");
+    let socks5_users = matches
+        .values_of("socks5-auth")
+        .map(|x| x.map(|user| parse_user(user).unwrap()).collect())
+        .unwrap_or_default();
     let verbose = matches.is_present("verbose");
     let show_url = matches.is_present("show-url");
+    let url_tag = matches.value_of("url-tag").map(|x| x.to_owned());
     let show_cfg = matches.is_present("show-cfg");
+    let check = matches.is_present("check");
+    let check_target = matches.value_of("check-target").map(|x| x.to_owned());
 
     Args {
         remote_addr,
         local_addr,
         password,
+        key,
         method,
+        server_urls,
+        balance,
         acl_path,
+        acl_url,
+        acl_cache,
+        route_script,
+        remote_dns,
+        acl_reverse_dns,
+        ip_family,
+        dns_cache_ttl,
         plugin,
         plugin_opts,
+        plugin_bind,
+        handshake_timeout,
+        connect_timeout,
+        connect_retries,
+        retry_delay,
+        half_close_timeout,
+        timeout,
+        shutdown_timeout,
+        tls_cert,
+        tls_key,
+        tls_sni,
+        tls_ca,
+        probe,
+        mitigate_probing,
+        outbound_bind,
+        fwmark,
+        allow_self_connect,
+        max_resolved_addrs,
+        dump_metrics,
+        summary_interval,
+        admin,
+        manager_address,
+        limit_rate,
+        max_conn_rate,
+        max_connections,
+        threads,
+        buffer_pool_size,
+        compress,
+        replay_lite,
+        no_replay_protection,
+        replay_blocklist,
+        replay_state,
+        early_replay_check,
+        fast_open,
+        reuse_port,
+        nagle,
+        transparent,
+        tunnel_addr,
+        udp,
+        socks5_bind,
+        socks5_users,
         verbose,
         show_url,
+        url_tag,
         show_cfg,
+        check,
+        check_target,
+    }
+}
+
+/// Loads the arguments from a config file in the official shadowsocks JSON schema,
+/// see [`parse_config`].
+pub fn from_config_file(path: &Path) -> io::Result<Args> {
+    let data = std::fs::read_to_string(path)?;
+    parse_config(&data)
+}
+
+/// Parses a config file in the official shadowsocks JSON schema.
+///
+/// Only `server`, `server_port`, `password`, `port_password`, `method`, `plugin`, `plugin_opts`,
+/// `acl` and `timeout` are supported, the other arguments are left at their defaults.
+///
+/// Every port, or range of ports like `8381-8383`, in `port_password` is an additional server
+/// with its own password. Without `server_port`, the lowest of them is the main server.
+fn parse_config(data: &str) -> io::Result<Args> {
+    let config: Config = serde_json::from_str(data)?;
+    let invalid = |msg: String| io::Error::new(ErrorKind::InvalidData, msg);
+
+    let mut args = Args {
+        password: config.password.unwrap_or_default(),
+        plugin: config.plugin,
+        plugin_opts: config.plugin_opts,
+        acl_path: config.acl,
+        timeout: config.timeout,
+        ..Default::default()
+    };
+
+    if let Some(method) = config.method {
+        args.method = method.parse().map_err(|e| invalid(format!("{}", e)))?;
+    }
+
+    let hostname = config.server.map(|server| match server.contains(':') {
+        true => format!("[{}]", server),
+        false => server,
+    });
+
+    if let (Some(hostname), Some(port)) = (&hostname, config.server_port) {
+        args.remote_addr = format!("{}:{}", hostname, port);
+    }
+
+    if let Some(port_password) = config.port_password {
+        let hostname =
+            hostname.ok_or_else(|| invalid("port_password requires server".to_owned()))?;
+
+        let mut servers = Vec::new();
+        for (ports, password) in port_password {
+            let ports = parse_port_range(&ports)
+                .ok_or_else(|| invalid(format!("invalid port or port range: {}", ports)))?;
+            servers.extend(ports.map(|port| (port, password.clone())));
+        }
+        servers.sort_unstable_by_key(|(port, _)| *port);
+
+        let mut servers = servers.into_iter();
+        if args.remote_addr.is_empty() {
+            if let Some((port, password)) = servers.next() {
+                args.remote_addr = format!("{}:{}", hostname, port);
+                args.password = password;
+            }
+        }
+
+        args.server_urls = servers
+            .map(|(port, password)| SsUrl {
+                method: args.method,
+                password,
+                hostname: hostname.clone(),
+                port,
+                plugin: None,
+                plugin_opts: None,
+                tag: None,
+            })
+            .collect();
+    }
+
+    Ok(args)
+}
+
+/// Parses a port, or an inclusive range of ports like `8381-8383`.
+fn parse_port_range(ports: &str) -> Option<RangeInclusive<u16>> {
+    let (start, end) = ports.split_once('-').unwrap_or((ports, ports));
+    let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+
+    match start <= end {
+        true => Some(start..=end),
+        false => None,
+    }
+}
+
+/// Parses a `USERNAME:PASSWORD` pair, each of 1 to 255 bytes as required by RFC 1929.
+fn parse_user(user: &str) -> Result<(String, String), String> {
+    match user.split_once(':') {
+        Some((username, password))
+            if (1..=255).contains(&username.len()) && (1..=255).contains(&password.len()) =>
+        {
+            Ok((username.to_owned(), password.to_owned()))
+        }
+        _ => Err("expected USERNAME:PASSWORD, each of 1 to 255 bytes".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let args = parse_config(
+            r#"{
+                "server": "::1",
+                "server_port": 8388,
+                "password": "barfoo!",
+                "method": "aes-256-gcm",
+                "plugin": "v2ray-plugin",
+                "plugin_opts": "server",
+                "acl": "chn.acl",
+                "timeout": 300
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(args.remote_addr, "[::1]:8388");
+        assert_eq!(args.password, "barfoo!");
+        assert_eq!(args.method, Method::Aes256Gcm);
+        assert_eq!(args.plugin.as_deref(), Some("v2ray-plugin"));
+        assert_eq!(args.plugin_opts.as_deref(), Some("server"));
+        assert_eq!(args.acl_path, Some(PathBuf::from("chn.acl")));
+        assert_eq!(args.timeout, Some(300));
+
+        let args = parse_config(r#"{"server": "127.0.0.1", "password": "barfoo!"}"#).unwrap();
+        assert_eq!(args.remote_addr, "");
+        assert_eq!(args.method, Method::ChaCha20Poly1305);
+
+        assert!(parse_config(r#"{"method": "rc4-md5"}"#).is_err());
+        assert!(parse_config(r#"{"port_password": {"8381": "foo"}}"#).is_err());
+        assert!(parse_config(r#"{"server_port": "8388"}"#).is_err());
+        assert!(parse_config("{").is_err());
+    }
+
+    #[test]
+    fn test_parse_port_password() {
+        let args = parse_config(
+            r#"{
+                "server": "0.0.0.0",
+                "port_password": {"8390": "bar", "8381-8382": "foo"},
+                "method": "aes-128-gcm"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(args.remote_addr, "0.0.0.0:8381");
+        assert_eq!(args.password, "foo");
+
+        let servers: Vec<_> = args
+            .server_urls
+            .iter()
+            .map(|url| (url.port, url.password.as_str()))
+            .collect();
+        assert_eq!(servers, vec![(8382, "foo"), (8390, "bar")]);
+        assert!(args
+            .server_urls
+            .iter()
+            .all(|url| url.method == Method::Aes128Gcm));
+
+        let args = parse_config(
+            r#"{
+                "server": "::",
+                "server_port": 8388,
+                "password": "main",
+                "port_password": {"8381": "foo"}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(args.remote_addr, "[::]:8388");
+        assert_eq!(args.server_urls[0].hostname, "[::]");
+
+        assert_eq!(parse_port_range("8381"), Some(8381..=8381));
+        assert_eq!(parse_port_range("8381-8383"), Some(8381..=8383));
+        assert_eq!(parse_port_range("8383-8381"), None);
+        assert_eq!(parse_port_range("8381-"), None);
+        assert_eq!(parse_port_range("65536"), None);
     }
 }
@@ -0,0 +1,338 @@
+//! Self-test of a configuration, telling which stage fails.
+//!
+//! For ss-remote, the server address is bound and a local client relays a message to a
+//! loopback echo server through it, exercising the whole encryption pipeline.
+//! For ss-local, a request is sent to the remote server, checking that its reply decrypts.
+//!
+//! Either can instead send an HTTP request to a given target, such as when the ACL of
+//! ss-remote blocks loopback targets. ss-local uses `example.com:80` by default.
+//!
+//! Plugins and TLS are not exercised, the connections are plain TCP.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::{
+    context::Ctx,
+    crypto::cipher::Method,
+    net::stream::{self, TcpStream as SsTcpStream},
+    socks5::Socks5Addr,
+    tcp::{serve_remote, SsTcpListener},
+};
+
+mod constants {
+    use std::time::Duration;
+
+    pub const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+    pub const ECHO_MESSAGE: &[u8] = b"ss-rs self-test";
+    pub const TARGET: &str = "example.com:80";
+    pub const HTTP_REPLY: &[u8] = b"HTTP/";
+}
+
+/// Stages of the self-test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Binding the server address.
+    Bind,
+
+    /// Connecting to the server.
+    Connect,
+
+    /// Sending the target address.
+    Handshake,
+
+    /// Decrypting the reply.
+    Cipher,
+
+    /// Relaying data to the target and back.
+    Relay,
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Bind => write!(f, "bind"),
+            Stage::Connect => write!(f, "connect"),
+            Stage::Handshake => write!(f, "handshake"),
+            Stage::Cipher => write!(f, "cipher"),
+            Stage::Relay => write!(f, "relay"),
+        }
+    }
+}
+
+/// A failed self-test, with the stage that failed.
+#[derive(Debug)]
+pub struct Error {
+    pub stage: Stage,
+    pub source: io::Error,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} stage failed: {}", self.stage, self.source)?;
+
+        match self.stage {
+            Stage::Cipher => write!(f, ", the method or password is likely wrong"),
+            Stage::Relay => write!(
+                f,
+                ", the method or password may be wrong, or the target is unreachable"
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Checks a ss-remote configuration: binds the address and relays a message to a loopback
+/// echo server through it, or an HTTP request to the target if given.
+pub async fn check_remote(
+    addr: SocketAddr,
+    method: Method,
+    key: &[u8],
+    target: Option<Socks5Addr>,
+    ctx: Arc<Ctx>,
+) -> Result<(), Error> {
+    let listener = SsTcpListener::bind(addr, method, key, ctx.clone())
+        .await
+        .map_err(at(Stage::Bind))?;
+    let mut server_addr = listener.local_addr().map_err(at(Stage::Bind))?;
+    if server_addr.ip().is_unspecified() {
+        server_addr.set_ip(loopback(server_addr.ip()));
+    }
+
+    if let Some(target) = target {
+        let server = tokio::spawn(serve_remote(listener, ctx.clone()));
+        let res = check_http(server_addr, target, method, key, ctx).await;
+        server.abort();
+        return res;
+    }
+
+    let echo = TcpListener::bind((loopback(server_addr.ip()), 0))
+        .await
+        .map_err(at(Stage::Bind))?;
+    let echo_addr = echo.local_addr().map_err(at(Stage::Bind))?;
+    let echo = tokio::spawn(async move {
+        let (mut stream, _) = echo.accept().await?;
+        let mut buf = [0u8; constants::ECHO_MESSAGE.len()];
+        stream.read_exact(&mut buf).await?;
+        stream.write_all(&buf).await
+    });
+    let server = tokio::spawn(serve_remote(listener, ctx.clone()));
+
+    let res = exchange(
+        server_addr,
+        echo_addr.into(),
+        method,
+        key,
+        ctx,
+        constants::ECHO_MESSAGE,
+        constants::ECHO_MESSAGE,
+    )
+    .await;
+
+    server.abort();
+    echo.abort();
+    res
+}
+
+/// Checks a ss-local configuration: sends an HTTP request to the target, `example.com:80`
+/// if not given, through the remote server, checking that its reply decrypts.
+pub async fn check_local(
+    remote_addr: SocketAddr,
+    method: Method,
+    key: &[u8],
+    target: Option<Socks5Addr>,
+    ctx: Arc<Ctx>,
+) -> Result<(), Error> {
+    let target = target.unwrap_or_else(|| constants::TARGET.parse().unwrap());
+    check_http(remote_addr, target, method, key, ctx).await
+}
+
+/// Sends an HTTP `HEAD` request to the target through ss-remote, expecting an HTTP reply.
+async fn check_http(
+    remote_addr: SocketAddr,
+    target: Socks5Addr,
+    method: Method,
+    key: &[u8],
+    ctx: Arc<Ctx>,
+) -> Result<(), Error> {
+    let host = match target {
+        Socks5Addr::DomainName((ref host, _)) => host.clone(),
+        Socks5Addr::Ipv4(addr) => addr.ip().to_string(),
+        Socks5Addr::Ipv6(addr) => format!("[{}]", addr.ip()),
+    };
+    let request = format!(
+        "HEAD / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        host
+    );
+
+    exchange(
+        remote_addr,
+        target,
+        method,
+        key,
+        ctx,
+        request.as_bytes(),
+        constants::HTTP_REPLY,
+    )
+    .await
+}
+
+/// Sends the message to the target through ss-remote and checks that the reply starts with
+/// the expected bytes.
+async fn exchange(
+    remote_addr: SocketAddr,
+    target: Socks5Addr,
+    method: Method,
+    key: &[u8],
+    ctx: Arc<Ctx>,
+    message: &[u8],
+    expected: &[u8],
+) -> Result<(), Error> {
+    let mut stream = SsTcpStream::connect(remote_addr, target, method, key, ctx)
+        .await
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidInput => Error {
+                stage: Stage::Handshake,
+                source: e,
+            },
+            _ => Error {
+                stage: Stage::Connect,
+                source: e,
+            },
+        })?;
+
+    stream
+        .write_all(message)
+        .await
+        .map_err(at(Stage::Handshake))?;
+    stream.flush().await.map_err(at(Stage::Handshake))?;
+
+    let mut reply = vec![0u8; expected.len()];
+    let res = tokio::time::timeout(constants::REPLY_TIMEOUT, stream.read_exact(&mut reply)).await;
+
+    match res {
+        Ok(Ok(_)) if reply == expected => Ok(()),
+        Ok(Ok(_)) => Err(Error {
+            stage: Stage::Relay,
+            source: io::Error::new(io::ErrorKind::InvalidData, "unexpected reply"),
+        }),
+        Ok(Err(e)) if is_decryption_error(&e) => Err(Error {
+            stage: Stage::Cipher,
+            source: e,
+        }),
+        Ok(Err(e)) => Err(Error {
+            stage: Stage::Relay,
+            source: e,
+        }),
+        Err(_) => Err(Error {
+            stage: Stage::Relay,
+            source: io::Error::new(io::ErrorKind::TimedOut, "no reply"),
+        }),
+    }
+}
+
+fn is_decryption_error(e: &io::Error) -> bool {
+    matches!(
+        e.get_ref().and_then(|x| x.downcast_ref::<stream::Error>()),
+        Some(stream::Error::Decryption | stream::Error::FirstChunkDecryption)
+    )
+}
+
+fn loopback(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+        IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+    }
+}
+
+fn at(stage: Stage) -> impl Fn(io::Error) -> Error {
+    move |source| Error { stage, source }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METHOD: Method = Method::ChaCha20Poly1305;
+    const KEY: [u8; 32] = [7u8; 32];
+
+    #[tokio::test]
+    async fn test_check_remote() {
+        let ctx = Arc::new(Ctx::new());
+        let addr = "127.0.0.1:0".parse().unwrap();
+        check_remote(addr, METHOD, &KEY, None, ctx.clone())
+            .await
+            .unwrap();
+
+        // The address is taken.
+        let taken = TcpListener::bind(addr).await.unwrap();
+        let e = check_remote(taken.local_addr().unwrap(), METHOD, &KEY, None, ctx)
+            .await
+            .unwrap_err();
+        assert_eq!(e.stage, Stage::Bind);
+    }
+
+    #[tokio::test]
+    async fn test_check_target() {
+        let ctx = Arc::new(Ctx::new());
+
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = Socks5Addr::from(http.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = http.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+            buf[..n].to_vec()
+        });
+
+        let addr = "127.0.0.1:0".parse().unwrap();
+        check_remote(addr, METHOD, &KEY, Some(target), ctx)
+            .await
+            .unwrap();
+
+        let request = handle.await.unwrap();
+        assert!(request.starts_with(b"HEAD / HTTP/1.1\r\nHost: 127.0.0.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_check_local() {
+        let ctx = Arc::new(Ctx::new());
+
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let e = check_local(closed, METHOD, &KEY, None, ctx.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(e.stage, Stage::Connect);
+
+        // A server with another key replies garbage.
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream.write_all(&[1u8; 64]).await.unwrap();
+        });
+
+        let e = check_local(server_addr, METHOD, &KEY, None, ctx)
+            .await
+            .unwrap_err();
+        assert_eq!(e.stage, Stage::Cipher);
+    }
+}
@@ -1,15 +1,86 @@
 //! Shadowsocks context.
 
-use std::net::IpAddr;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Duration,
+};
 
-use crate::{acl::Acl, security::ReplayProtection};
+use tokio::sync::{watch, Notify, Semaphore};
+
+use crate::{
+    acl::{Acl, ResolvePolicy},
+    net::{
+        constants::{MAXIMUM_MESSAGE_SIZE, MAXIMUM_PAYLOAD_SIZE},
+        pool::BufferPool,
+        resolver::{IpFamily, Resolver},
+        sys,
+    },
+    observer::{ConnectionObserver, NoopObserver},
+    remote::RemotePool,
+    router::Router,
+    security::{Admission, RateLimiter, ReplayCheck, ReplayMode, ReplayProtection},
+    socks5::Credentials,
+    stats::{Stats, StatsSnapshot},
+    udp::UdpClients,
+};
+
+#[cfg(feature = "tls")]
+use crate::tls::Tls;
+
+mod constants {
+    use std::time::Duration;
+
+    pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(15);
+    pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+    pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+    pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(100);
+    pub const DEFAULT_BUFFER_POOL_SIZE: usize = 1024;
+}
 
 /// Context for the shadowsocks communication.
 ///
-/// It provides replay protection and access control list.
+/// It provides replay protection, routing, name resolution, timeouts, statistics
+/// and the connection observer.
 pub struct Ctx {
     replay_protection: ReplayProtection,
-    acl: Option<Acl>,
+    replay_check: ReplayCheck,
+    router: Option<Arc<dyn Router>>,
+    resolve_policy: ResolvePolicy,
+    resolver: Resolver,
+    max_payload_size: usize,
+    compression: bool,
+    rate_limit: Option<(u64, u64)>,
+    conn_rate_limiter: Option<RateLimiter>,
+    connection_limit: Option<Arc<Semaphore>>,
+    fast_open: bool,
+    reuse_port: bool,
+    no_delay: bool,
+    outbound_bind: Option<IpAddr>,
+    fwmark: Option<u32>,
+    remote_host: Option<String>,
+    plugin: bool,
+    remote_pool: Option<RemotePool>,
+    connect_retries: (u32, Duration),
+    probe: bool,
+    mitigate_probing: bool,
+    udp: bool,
+    udp_clients: UdpClients,
+    socks5_bind: bool,
+    credentials: Option<Credentials>,
+    self_connect_guard: bool,
+    listen_addrs: Mutex<Vec<SocketAddr>>,
+    local_ips: Mutex<Vec<IpAddr>>,
+    #[cfg(feature = "tls")]
+    tls: Option<Tls>,
+    timeouts: Timeouts,
+    stats: Stats,
+    shutdown: watch::Sender<bool>,
+    drained: Notify,
+    buffer_pool: BufferPool,
+    observer: Arc<dyn ConnectionObserver>,
 }
 
 impl Ctx {
@@ -17,7 +88,41 @@ impl Ctx {
     pub fn new() -> Self {
         Ctx {
             replay_protection: ReplayProtection::new(),
-            acl: None,
+            replay_check: ReplayCheck::default(),
+            router: None,
+            resolve_policy: ResolvePolicy::default(),
+            resolver: Resolver::new(),
+            max_payload_size: MAXIMUM_PAYLOAD_SIZE,
+            compression: false,
+            rate_limit: None,
+            conn_rate_limiter: None,
+            connection_limit: None,
+            fast_open: false,
+            reuse_port: false,
+            no_delay: true,
+            outbound_bind: None,
+            fwmark: None,
+            remote_host: None,
+            plugin: false,
+            remote_pool: None,
+            connect_retries: (0, constants::DEFAULT_RETRY_DELAY),
+            probe: false,
+            mitigate_probing: false,
+            udp: false,
+            udp_clients: UdpClients::new(),
+            socks5_bind: false,
+            credentials: None,
+            self_connect_guard: true,
+            listen_addrs: Mutex::new(Vec::new()),
+            local_ips: Mutex::new(Vec::new()),
+            #[cfg(feature = "tls")]
+            tls: None,
+            timeouts: Timeouts::default(),
+            stats: Stats::new(),
+            shutdown: watch::channel(false).0,
+            drained: Notify::new(),
+            buffer_pool: BufferPool::new(MAXIMUM_MESSAGE_SIZE, constants::DEFAULT_BUFFER_POOL_SIZE),
+            observer: Arc::new(NoopObserver),
         }
     }
 
@@ -26,24 +131,559 @@ impl Ctx {
         self.replay_protection.check_and_insert(&salt)
     }
 
+    /// Sets how thoroughly replay attacks are detected, forgetting all seen salts.
+    pub fn set_replay_mode(&mut self, mode: ReplayMode) {
+        self.replay_protection = ReplayProtection::with_mode(mode);
+    }
+
+    /// Preloads salts into the replay protection, so they are rejected on first use.
+    pub fn preload_replay(&self, salts: &[Vec<u8>]) {
+        self.replay_protection.preload(salts);
+    }
+
+    /// Replaces the replay protection with the one saved by [`Ctx::save_replay`].
+    pub fn load_replay(&mut self, path: &Path) -> io::Result<()> {
+        self.replay_protection = ReplayProtection::load_from(path)?;
+        Ok(())
+    }
+
+    /// Saves the seen salts of the replay protection, see [`ReplayProtection::save_to`].
+    pub fn save_replay(&self, path: &Path) -> io::Result<()> {
+        self.replay_protection.save_to(path)
+    }
+
+    /// Saves the replay protection to the given path every period, forever.
+    pub async fn flush_replay(self: Arc<Self>, path: PathBuf, period: Duration) {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+        loop {
+            interval.tick().await;
+
+            let (ctx, task_path) = (self.clone(), path.clone());
+            let res = tokio::task::spawn_blocking(move || ctx.save_replay(&task_path))
+                .await
+                .unwrap_or_else(|e| Err(io::Error::other(e)));
+
+            match res {
+                Ok(_) => log::debug!("Saved replay protection to {}", path.display()),
+                Err(e) => log::warn!("Save replay protection to {} failed: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// Sets when to check incoming salts for replay attacks.
+    pub fn set_replay_check(&mut self, replay_check: ReplayCheck) {
+        self.replay_check = replay_check;
+    }
+
+    /// Returns when to check incoming salts for replay attacks.
+    pub fn replay_check(&self) -> ReplayCheck {
+        self.replay_check
+    }
+
     /// Set access control list.
     pub fn set_acl(&mut self, acl: Acl) {
-        self.acl = Some(acl);
+        self.router = Some(Arc::new(acl));
+    }
+
+    /// Sets the router deciding which connections are bypassed, proxied or blocked,
+    /// replacing the access control list.
+    pub fn set_router(&mut self, router: Arc<dyn Router>) {
+        self.router = Some(router);
+    }
+
+    /// Sets where domain names that match no ACL host rules are resolved.
+    pub fn set_resolve_policy(&mut self, resolve_policy: ResolvePolicy) {
+        self.resolve_policy = resolve_policy;
+    }
+
+    /// Sets how many resolved addresses of a domain name successive connections rotate across.
+    pub fn set_max_resolved_addrs(&mut self, max_addrs: usize) {
+        self.resolver.set_max_addrs(max_addrs);
+    }
+
+    /// Sets how long domain name lookups are cached, zero disables the cache.
+    pub fn set_dns_cache_ttl(&mut self, ttl: Duration) {
+        self.resolver.set_cache_ttl(ttl);
+    }
+
+    /// Sets the address families domain names are resolved to.
+    pub fn set_ip_family(&mut self, ip_family: IpFamily) {
+        self.resolver.set_ip_family(ip_family);
+    }
+
+    /// Sets whether ip targets are reverse looked up to match them against host rules.
+    pub fn set_reverse_dns(&mut self, reverse_dns: bool) {
+        self.resolver.set_reverse(reverse_dns);
+    }
+
+    /// Returns the domain name of the given ip to match against host rules,
+    /// see [`Resolver::reverse`].
+    pub async fn reverse_lookup(&self, ip: IpAddr) -> Option<String> {
+        self.resolver.reverse(ip).await
+    }
+
+    /// Resolves target socket address, see [`Resolver::resolve`].
+    pub async fn resolve(&self, host: &str) -> io::Result<SocketAddr> {
+        self.resolver.resolve(host).await
+    }
+
+    /// Resolves all target socket addresses to race connections to,
+    /// see [`Resolver::resolve_all`].
+    pub async fn resolve_all(&self, host: &str) -> io::Result<Vec<SocketAddr>> {
+        self.resolver.resolve_all(host).await
+    }
+
+    /// Sets the maximum payload size of a chunk.
+    ///
+    /// A shadowsocks stream buffers at most one chunk of plaintext at a time,
+    /// so this bounds the per-connection memory. The size is clamped to `1..=0x3FFF`.
+    pub fn set_max_payload_size(&mut self, size: usize) {
+        self.max_payload_size = size.clamp(1, MAXIMUM_PAYLOAD_SIZE);
+    }
+
+    /// Returns the maximum payload size of a chunk.
+    pub fn max_payload_size(&self) -> usize {
+        self.max_payload_size
+    }
+
+    /// Enables the experimental chunk compression.
+    ///
+    /// This is not part of the shadowsocks protocol, so both ends must enable it.
+    pub fn set_compression(&mut self, compression: bool) {
+        self.compression = compression;
+    }
+
+    /// Returns true if the experimental chunk compression is enabled.
+    pub fn compression(&self) -> bool {
+        self.compression
+    }
+
+    /// Limits the throughput of each relayed connection, per direction, to `rate` bytes
+    /// per second on average, with bursts of up to `burst` bytes.
+    pub fn set_rate_limit(&mut self, rate: u64, burst: u64) {
+        self.rate_limit = Some((rate, burst));
+    }
+
+    /// Returns the rate and burst size relayed connections are limited to, if any.
+    pub fn rate_limit(&self) -> Option<(u64, u64)> {
+        self.rate_limit
+    }
+
+    /// Enables TCP Fast Open for listening and connecting, falling back to normal connections
+    /// on sockets where it can't be enabled.
+    pub fn set_fast_open(&mut self, fast_open: bool) {
+        self.fast_open = fast_open;
+    }
+
+    /// Returns true if TCP Fast Open is enabled.
+    pub fn fast_open(&self) -> bool {
+        self.fast_open
+    }
+
+    /// Sets `SO_REUSEPORT` on listeners, so several processes can listen on the same port.
+    pub fn set_reuse_port(&mut self, reuse_port: bool) {
+        self.reuse_port = reuse_port;
+    }
+
+    /// Returns true if listeners set `SO_REUSEPORT`.
+    pub fn reuse_port(&self) -> bool {
+        self.reuse_port
+    }
+
+    /// Sets whether relayed sockets set `TCP_NODELAY`, disabling Nagle's algorithm,
+    /// enabled by default.
+    pub fn set_no_delay(&mut self, no_delay: bool) {
+        self.no_delay = no_delay;
+    }
+
+    /// Returns true if relayed sockets set `TCP_NODELAY`.
+    pub fn no_delay(&self) -> bool {
+        self.no_delay
+    }
+
+    /// Sets the source address of outbound connections, to targets or to ss-remote.
+    pub fn set_outbound_bind(&mut self, ip: IpAddr) {
+        self.outbound_bind = Some(ip);
+    }
+
+    /// Returns the source address of outbound connections, if set.
+    pub fn outbound_bind(&self) -> Option<IpAddr> {
+        self.outbound_bind
+    }
+
+    /// Sets the firewall mark of outbound connections, for policy routing.
+    pub fn set_fwmark(&mut self, mark: u32) {
+        self.fwmark = Some(mark);
+    }
+
+    /// Returns the firewall mark of outbound connections, if set.
+    pub fn fwmark(&self) -> Option<u32> {
+        self.fwmark
+    }
+
+    /// Sets the `host:port` of ss-remote, re-resolved by ss-local for each proxied connection.
+    pub fn set_remote_host(&mut self, host: String) {
+        self.remote_host = Some(host);
+    }
+
+    /// Returns the `host:port` of ss-remote if it is a domain name.
+    pub fn remote_host(&self) -> Option<&str> {
+        self.remote_host.as_deref()
+    }
+
+    /// Sets whether ss-local reaches ss-remote through a SIP003 plugin.
+    pub fn set_plugin(&mut self, plugin: bool) {
+        self.plugin = plugin;
+    }
+
+    /// Returns true if ss-local reaches ss-remote through a SIP003 plugin.
+    pub fn plugin(&self) -> bool {
+        self.plugin
+    }
+
+    /// Sets several ss-remote servers for ss-local to fail over between,
+    /// instead of the one it is started with.
+    pub fn set_remote_pool(&mut self, pool: RemotePool) {
+        self.remote_pool = Some(pool);
+    }
+
+    /// Returns the ss-remote servers of ss-local, if there are several.
+    pub fn remote_pool(&self) -> Option<&RemotePool> {
+        self.remote_pool.as_ref()
+    }
+
+    /// Sets how many times ss-local retries connecting after a failure, none by default,
+    /// and the delay before the first retry, doubled for each of the next ones.
+    pub fn set_connect_retries(&mut self, retries: u32, delay: Duration) {
+        self.connect_retries = (retries, delay);
+    }
+
+    /// Returns the number of connect retries and the delay before the first one.
+    pub fn connect_retries(&self) -> (u32, Duration) {
+        self.connect_retries
+    }
+
+    /// Enables probe mode: ss-remote logs what clients sent and closes without relaying.
+    pub fn set_probe(&mut self, probe: bool) {
+        self.probe = probe;
+    }
+
+    /// Returns true if probe mode is enabled.
+    pub fn probe(&self) -> bool {
+        self.probe
+    }
+
+    /// Enables probe mitigation: ss-remote drains connections that look like active probes
+    /// for a random duration, rather than closing them once the stream is unreadable.
+    pub fn set_mitigate_probing(&mut self, mitigate_probing: bool) {
+        self.mitigate_probing = mitigate_probing;
+    }
+
+    /// Returns true if probe mitigation is enabled.
+    pub fn mitigate_probing(&self) -> bool {
+        self.mitigate_probing
+    }
+
+    /// Enables the udp relay: ss-local accepts SOCKS5 UDP ASSOCIATE requests.
+    pub fn set_udp(&mut self, udp: bool) {
+        self.udp = udp;
+    }
+
+    /// Returns true if the udp relay is enabled.
+    pub fn udp(&self) -> bool {
+        self.udp
+    }
+
+    /// Returns the clients ss-local relays udp packets of.
+    pub fn udp_clients(&self) -> &UdpClients {
+        &self.udp_clients
+    }
+
+    /// Enables the SOCKS5 BIND command: ss-local accepts BIND requests,
+    /// ss-remote listens for them on an ephemeral port.
+    pub fn set_socks5_bind(&mut self, socks5_bind: bool) {
+        self.socks5_bind = socks5_bind;
+    }
+
+    /// Returns true if the SOCKS5 BIND command is enabled.
+    pub fn socks5_bind(&self) -> bool {
+        self.socks5_bind
+    }
+
+    /// Requires SOCKS5 clients of ss-local to authenticate with a username and password.
+    pub fn set_credentials(&mut self, credentials: Credentials) {
+        self.credentials = Some(credentials);
+    }
+
+    /// Returns the users allowed to use ss-local, if authentication is required.
+    pub fn credentials(&self) -> Option<&Credentials> {
+        self.credentials.as_ref()
+    }
+
+    /// Sets whether ss-remote rejects targets that are its own listening addresses, enabled by default.
+    pub fn set_self_connect_guard(&mut self, self_connect_guard: bool) {
+        self.self_connect_guard = self_connect_guard;
+    }
+
+    /// Records an address ss-remote listens on, or is reached at through a plugin.
+    ///
+    /// The ips of the local network interfaces are also recorded for an unspecified ip.
+    pub fn add_listen_addr(&self, addr: SocketAddr) {
+        let mut listen_addrs = self.listen_addrs.lock().unwrap();
+        if !listen_addrs.contains(&addr) {
+            listen_addrs.push(addr);
+        }
+        drop(listen_addrs);
+
+        if addr.ip().is_unspecified() {
+            match sys::local_ips() {
+                Ok(ips) => ips.into_iter().for_each(|ip| self.add_local_ip(ip)),
+                Err(e) => log::debug!("Unable to get the local ips: {}", e),
+            }
+        }
+    }
+
+    /// Records an ip of this host, e.g. the local address of an accepted connection,
+    /// which ss-remote is reached at when it listens on an unspecified ip.
+    pub fn add_local_ip(&self, ip: IpAddr) {
+        let ip = ip.to_canonical();
+        let mut local_ips = self.local_ips.lock().unwrap();
+        if !local_ips.contains(&ip) {
+            local_ips.push(ip);
+        }
+    }
+
+    /// Returns the addresses ss-remote listens on.
+    pub fn listen_addrs(&self) -> Vec<SocketAddr> {
+        self.listen_addrs.lock().unwrap().clone()
+    }
+
+    /// Returns true if connecting to the given target would connect ss-remote to itself,
+    /// unless the self-connection guard is disabled.
+    ///
+    /// Targets match a listening address on an unspecified ip (e.g. `0.0.0.0`)
+    /// if they have the same port and a loopback, unspecified or local ip, see [`Ctx::add_local_ip`].
+    pub fn is_self_connect(&self, target: SocketAddr) -> bool {
+        if !self.self_connect_guard {
+            return false;
+        }
+
+        let ip = target.ip().to_canonical();
+        let is_local = || {
+            ip.is_loopback() || ip.is_unspecified() || self.local_ips.lock().unwrap().contains(&ip)
+        };
+        self.listen_addrs.lock().unwrap().iter().any(|addr| {
+            addr.port() == target.port()
+                && (addr.ip() == ip || (addr.ip().is_unspecified() && is_local()))
+        })
+    }
+
+    /// Sets the TLS transport between ss-local and ss-remote.
+    #[cfg(feature = "tls")]
+    pub fn set_tls(&mut self, tls: Tls) {
+        self.tls = Some(tls);
+    }
+
+    /// Returns the TLS transport between ss-local and ss-remote, if enabled.
+    #[cfg(feature = "tls")]
+    pub fn tls(&self) -> Option<&Tls> {
+        self.tls.as_ref()
+    }
+
+    /// Sets the timeouts of connections.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Returns the timeouts of connections.
+    pub fn timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
+
+    /// Returns the statistics.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Returns a point-in-time copy of the statistics.
+    pub fn stats_snapshot(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Starts shutting down: connections close once idle, and UDP ASSOCIATE connections
+    /// right away, see [`Ctx::shutting_down`].
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Waits until shutting down starts, see [`Ctx::shutdown`].
+    pub async fn shutting_down(&self) {
+        let mut shutdown = self.shutdown.subscribe();
+        while !*shutdown.borrow_and_update() {
+            if shutdown.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Waits until there are no active connections, e.g. to shut down gracefully
+    /// once the listeners are closed.
+    pub async fn drained(&self) {
+        loop {
+            let notified = self.drained.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if self.stats.active_connections.load(Ordering::Relaxed) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Wakes up the tasks waiting in [`Ctx::drained`] to check the active connections again.
+    pub fn notify_closed(&self) {
+        self.drained.notify_waiters();
+    }
+
+    /// Sets how many free buffers of shadowsocks streams are kept for reuse, 1024 by default.
+    ///
+    /// Each is about 16 KiB, so this bounds the memory held by the pool.
+    pub fn set_buffer_pool_size(&mut self, max_buffers: usize) {
+        self.buffer_pool = BufferPool::new(MAXIMUM_MESSAGE_SIZE, max_buffers);
+    }
+
+    /// Returns the pool of buffers shared by shadowsocks streams.
+    pub fn buffer_pool(&self) -> &BufferPool {
+        &self.buffer_pool
+    }
+
+    /// Sets the observer of connection lifecycle events.
+    pub fn set_observer(&mut self, observer: Arc<dyn ConnectionObserver>) {
+        self.observer = observer;
+    }
+
+    /// Returns the observer of connection lifecycle events, a no-op by default.
+    pub fn observer(&self) -> &dyn ConnectionObserver {
+        self.observer.as_ref()
     }
 
     /// Returns true if the given ip or host should be bypassed.
     pub fn is_bypass(&self, ip: IpAddr, host: Option<&str>) -> bool {
-        match self.acl {
-            Some(ref acl) => acl.is_bypass(ip, host),
+        match self.router {
+            Some(ref router) => router.is_bypass(ip, host),
             _ => false,
         }
     }
 
+    /// Returns true if the given host should be bypassed, or `None` if it has to be
+    /// resolved to decide.
+    pub fn is_bypass_host(&self, host: &str) -> Option<bool> {
+        match self.router {
+            Some(ref router) => match self.resolve_policy {
+                ResolvePolicy::Local => router.match_host(host),
+                ResolvePolicy::Remote => Some(router.is_bypass_host(host)),
+            },
+            _ => Some(false),
+        }
+    }
+
+    /// Returns true if the given host should be proxied as a domain name, without resolving
+    /// it locally.
+    pub fn should_resolve_remotely(&self, host: &str) -> bool {
+        match self.router {
+            Some(ref router) => router.is_remote_resolve(host),
+            _ => false,
+        }
+    }
+
+    /// Limits how many connections each client ip can open per second on ss-remote.
+    pub fn set_max_conn_rate(&mut self, rate: u32) {
+        self.conn_rate_limiter = Some(RateLimiter::new(rate));
+    }
+
+    /// Takes a new connection from the client ip into account,
+    /// always allowing it if the connection rate isn't limited.
+    pub fn check_conn_rate(&self, ip: IpAddr) -> Admission {
+        match self.conn_rate_limiter {
+            Some(ref limiter) => limiter.check(ip),
+            None => Admission::Allowed,
+        }
+    }
+
+    /// Limits how many connections ss-remote handles at once,
+    /// beyond that it stops accepting until one finishes.
+    pub fn set_max_connections(&mut self, num: usize) {
+        self.connection_limit = Some(Arc::new(Semaphore::new(num)));
+    }
+
+    /// Returns the semaphore of the maximum connections of ss-remote, if limited.
+    pub fn connection_limit(&self) -> Option<&Arc<Semaphore>> {
+        self.connection_limit.as_ref()
+    }
+
+    /// Returns true if ss-remote should accept clients from the given ip.
+    pub fn is_client_allowed(&self, ip: IpAddr) -> bool {
+        match self.router {
+            Some(ref router) => router.is_client_allowed(ip),
+            _ => true,
+        }
+    }
+
     /// Returns true if the given ip or host should be block.
     pub fn is_block_outbound(&self, ip: IpAddr, host: Option<&str>) -> bool {
-        match self.acl {
-            Some(ref acl) => acl.is_block_outbound(ip, host),
+        match self.router {
+            Some(ref router) => router.is_block_outbound(ip, host),
+            _ => false,
+        }
+    }
+
+    /// Returns true if ss-local should block the given ip or host, see [`Router::is_block_local`].
+    pub fn is_block_local(&self, ip: Option<IpAddr>, host: Option<&str>) -> bool {
+        match self.router {
+            Some(ref router) => router.is_block_local(ip, host),
             _ => false,
         }
     }
 }
+
+/// Timeouts of a connection, from accepting it to closing it.
+#[derive(Debug, Clone)]
+pub struct Timeouts {
+    /// Maximum time to read the target address from the client,
+    /// i.e. the SOCKS5 handshake for ss-local and the first chunk for ss-remote.
+    pub handshake: Duration,
+
+    /// Maximum time to connect to the target or ss-remote.
+    pub connect: Duration,
+
+    /// Maximum time without any successful read or write once relaying.
+    pub idle: Duration,
+
+    /// Maximum lifetime of a relayed connection, unlimited if `None`.
+    pub max_duration: Option<Duration>,
+
+    /// Maximum time one direction may relay nothing after the other has reached EOF,
+    /// the idle timeout if `None`.
+    pub half_close: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Returns the half-close timeout, falling back to the idle timeout.
+    pub fn half_close(&self) -> Duration {
+        self.half_close.unwrap_or(self.idle)
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            handshake: constants::DEFAULT_HANDSHAKE_TIMEOUT,
+            connect: constants::DEFAULT_CONNECT_TIMEOUT,
+            idle: constants::DEFAULT_IDLE_TIMEOUT,
+            max_duration: None,
+            half_close: None,
+        }
+    }
+}
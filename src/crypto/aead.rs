@@ -1,15 +1,65 @@
 //! Authenticated Encryption with Associated Data (AEAD) algorithms.
 
-use aead::{Aead, Key, KeyInit, Nonce};
-use aes_gcm::{Aes128Gcm, Aes256Gcm};
-use chacha20poly1305::ChaCha20Poly1305;
+use aead::{
+    consts::{U12, U16},
+    generic_array::GenericArray,
+    Aead, AeadInPlace, Key, KeyInit, Nonce,
+};
+use aes_gcm::{aes::Aes192, Aes128Gcm, Aes256Gcm, AesGcm};
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
 
 use crate::crypto::cipher::Method;
 
+/// AES-192-GCM, which `aes_gcm` has no alias for.
+pub type Aes192Gcm = AesGcm<Aes192, U12>;
+
+/// Authentication tag, all variants have 16-byte tags.
+pub type Tag = GenericArray<u8, U16>;
+
+/// Returns the implementation the given method uses on this CPU, e.g. `"AES-NI"`.
+///
+/// Hardware acceleration is detected at runtime. The portable software implementations
+/// can be forced by building with `RUSTFLAGS="--cfg aes_force_soft --cfg chacha20_force_soft"`.
+pub fn backend(method: Method) -> &'static str {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    match method {
+        Method::Aes128Gcm
+        | Method::Aes192Gcm
+        | Method::Aes256Gcm
+        | Method::Blake3Aes128Gcm
+        | Method::Blake3Aes256Gcm => {
+            if cfg!(aes_force_soft) {
+                "software (forced)"
+            } else if is_x86_feature_detected!("aes") && is_x86_feature_detected!("pclmulqdq") {
+                "AES-NI"
+            } else {
+                "software (AES-NI not detected)"
+            }
+        }
+        Method::ChaCha20Poly1305 | Method::XChaCha20Poly1305 | Method::Blake3ChaCha20Poly1305 => {
+            if cfg!(chacha20_force_soft) {
+                "software (forced)"
+            } else if is_x86_feature_detected!("avx2") {
+                "AVX2"
+            } else {
+                "SSE2"
+            }
+        }
+    }
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        let _ = method;
+        "portable"
+    }
+}
+
 /// AEAD variants.
 pub enum Variant {
     ChaCha20Poly1305(ChaCha20Poly1305),
+    XChaCha20Poly1305(XChaCha20Poly1305),
     Aes128Gcm(Aes128Gcm),
+    Aes192Gcm(Aes192Gcm),
     Aes256Gcm(Aes256Gcm),
 }
 
@@ -17,13 +67,19 @@ impl Variant {
     /// Creates a new AEAD variant with method and key.
     pub fn new(method: Method, key: &[u8]) -> Self {
         match method {
-            Method::ChaCha20Poly1305 => Variant::ChaCha20Poly1305(ChaCha20Poly1305::new(
-                Key::<ChaCha20Poly1305>::from_slice(key),
+            Method::ChaCha20Poly1305 | Method::Blake3ChaCha20Poly1305 => Variant::ChaCha20Poly1305(
+                ChaCha20Poly1305::new(Key::<ChaCha20Poly1305>::from_slice(key)),
+            ),
+            Method::XChaCha20Poly1305 => Variant::XChaCha20Poly1305(XChaCha20Poly1305::new(
+                Key::<XChaCha20Poly1305>::from_slice(key),
             )),
-            Method::Aes128Gcm => {
+            Method::Aes128Gcm | Method::Blake3Aes128Gcm => {
                 Variant::Aes128Gcm(Aes128Gcm::new(Key::<Aes128Gcm>::from_slice(key)))
             }
-            Method::Aes256Gcm => {
+            Method::Aes192Gcm => {
+                Variant::Aes192Gcm(Aes192Gcm::new(Key::<Aes192Gcm>::from_slice(key)))
+            }
+            Method::Aes256Gcm | Method::Blake3Aes256Gcm => {
                 Variant::Aes256Gcm(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)))
             }
         }
@@ -35,18 +91,84 @@ impl Variant {
             Variant::ChaCha20Poly1305(c) => {
                 c.encrypt(Nonce::<ChaCha20Poly1305>::from_slice(nonce), plaintext)
             }
+            Variant::XChaCha20Poly1305(c) => {
+                c.encrypt(Nonce::<XChaCha20Poly1305>::from_slice(nonce), plaintext)
+            }
             Variant::Aes128Gcm(c) => c.encrypt(Nonce::<Aes128Gcm>::from_slice(nonce), plaintext),
+            Variant::Aes192Gcm(c) => c.encrypt(Nonce::<Aes192Gcm>::from_slice(nonce), plaintext),
             Variant::Aes256Gcm(c) => c.encrypt(Nonce::<Aes256Gcm>::from_slice(nonce), plaintext),
         }
     }
 
+    /// Encrypts the given buffer in place, returns the tag.
+    pub fn encrypt_in_place_detached(&self, nonce: &[u8], buffer: &mut [u8]) -> aead::Result<Tag> {
+        match self {
+            Variant::ChaCha20Poly1305(c) => c.encrypt_in_place_detached(
+                Nonce::<ChaCha20Poly1305>::from_slice(nonce),
+                &[],
+                buffer,
+            ),
+            Variant::XChaCha20Poly1305(c) => c.encrypt_in_place_detached(
+                Nonce::<XChaCha20Poly1305>::from_slice(nonce),
+                &[],
+                buffer,
+            ),
+            Variant::Aes128Gcm(c) => {
+                c.encrypt_in_place_detached(Nonce::<Aes128Gcm>::from_slice(nonce), &[], buffer)
+            }
+            Variant::Aes192Gcm(c) => {
+                c.encrypt_in_place_detached(Nonce::<Aes192Gcm>::from_slice(nonce), &[], buffer)
+            }
+            Variant::Aes256Gcm(c) => {
+                c.encrypt_in_place_detached(Nonce::<Aes256Gcm>::from_slice(nonce), &[], buffer)
+            }
+        }
+    }
+
+    /// Decrypts the given buffer in place, checking the given tag.
+    pub fn decrypt_in_place_detached(
+        &self,
+        nonce: &[u8],
+        buffer: &mut [u8],
+        tag: &[u8],
+    ) -> aead::Result<()> {
+        let tag = Tag::from_slice(tag);
+        match self {
+            Variant::ChaCha20Poly1305(c) => c.decrypt_in_place_detached(
+                Nonce::<ChaCha20Poly1305>::from_slice(nonce),
+                &[],
+                buffer,
+                tag,
+            ),
+            Variant::XChaCha20Poly1305(c) => c.decrypt_in_place_detached(
+                Nonce::<XChaCha20Poly1305>::from_slice(nonce),
+                &[],
+                buffer,
+                tag,
+            ),
+            Variant::Aes128Gcm(c) => {
+                c.decrypt_in_place_detached(Nonce::<Aes128Gcm>::from_slice(nonce), &[], buffer, tag)
+            }
+            Variant::Aes192Gcm(c) => {
+                c.decrypt_in_place_detached(Nonce::<Aes192Gcm>::from_slice(nonce), &[], buffer, tag)
+            }
+            Variant::Aes256Gcm(c) => {
+                c.decrypt_in_place_detached(Nonce::<Aes256Gcm>::from_slice(nonce), &[], buffer, tag)
+            }
+        }
+    }
+
     /// Decrypts the given ciphertext.
     pub fn decrypt(&self, nonce: &[u8], ciphertext: &[u8]) -> aead::Result<Vec<u8>> {
         match self {
             Variant::ChaCha20Poly1305(c) => {
                 c.decrypt(Nonce::<ChaCha20Poly1305>::from_slice(nonce), ciphertext)
             }
+            Variant::XChaCha20Poly1305(c) => {
+                c.decrypt(Nonce::<XChaCha20Poly1305>::from_slice(nonce), ciphertext)
+            }
             Variant::Aes128Gcm(c) => c.decrypt(Nonce::<Aes128Gcm>::from_slice(nonce), ciphertext),
+            Variant::Aes192Gcm(c) => c.decrypt(Nonce::<Aes192Gcm>::from_slice(nonce), ciphertext),
             Variant::Aes256Gcm(c) => c.decrypt(Nonce::<Aes256Gcm>::from_slice(nonce), ciphertext),
         }
     }
@@ -2,6 +2,7 @@
 
 use std::{
     fmt::{self, Display, Formatter},
+    io,
     str::FromStr,
 };
 
@@ -15,11 +16,15 @@ pub struct Cipher {
 
 impl Cipher {
     /// Creates a new Cipher with method and key.
-    pub fn new(method: Method, key: &[u8]) -> Self {
-        Cipher {
+    ///
+    /// Fails with [`Error::KeySize`] if the key isn't of the key size of the method.
+    pub fn new(method: Method, key: &[u8]) -> Result<Self, Error> {
+        method.validate_key(key)?;
+
+        Ok(Cipher {
             method,
             cipher: Variant::new(method, key),
-        }
+        })
     }
 
     /// Encrypts the given plaintext.
@@ -27,11 +32,65 @@ impl Cipher {
         self.cipher.encrypt(nonce, plaintext)
     }
 
+    /// Encrypts the given plaintext, appending the ciphertext and tag to `out`.
+    ///
+    /// Unlike [`Cipher::encrypt`], it doesn't allocate once `out` has enough capacity.
+    pub fn encrypt_to(
+        &self,
+        nonce: &[u8],
+        plaintext: &[u8],
+        out: &mut Vec<u8>,
+    ) -> aead::Result<()> {
+        let start = out.len();
+        out.extend_from_slice(plaintext);
+
+        self.encrypt_tail(nonce, out, start)
+    }
+
+    /// Encrypts the plaintext at the end of `buffer`, from `start`, in place and appends the tag.
+    ///
+    /// The plaintext is removed if the encryption fails.
+    pub fn encrypt_tail(
+        &self,
+        nonce: &[u8],
+        buffer: &mut Vec<u8>,
+        start: usize,
+    ) -> aead::Result<()> {
+        match self
+            .cipher
+            .encrypt_in_place_detached(nonce, &mut buffer[start..])
+        {
+            Ok(tag) => {
+                buffer.extend_from_slice(&tag);
+                Ok(())
+            }
+            Err(e) => {
+                buffer.truncate(start);
+                Err(e)
+            }
+        }
+    }
+
     /// Decrypts the given ciphertext.
     pub fn decrypt(&self, nonce: &[u8], ciphertext: &[u8]) -> aead::Result<Vec<u8>> {
         self.cipher.decrypt(nonce, ciphertext)
     }
 
+    /// Decrypts the ciphertext and tag in `buffer` in place, leaving the plaintext.
+    pub fn decrypt_in_place(&self, nonce: &[u8], buffer: &mut Vec<u8>) -> aead::Result<()> {
+        let len = match buffer.len().checked_sub(self.method.tag_size()) {
+            Some(len) => len,
+            None => return Err(aead::Error),
+        };
+
+        let (ciphertext, tag) = buffer.split_at_mut(len);
+        self.cipher
+            .decrypt_in_place_detached(nonce, ciphertext, tag)?;
+        buffer.truncate(len);
+
+        Ok(())
+    }
+
     /// Get the encryption method in use.
     pub fn method(&self) -> Method {
         self.method
@@ -43,24 +102,51 @@ impl Cipher {
 pub enum Error {
     /// Unsupported encryption method.
     Method(String),
+
+    /// The key isn't encoded bytes of the key size: base64 for the pre-shared key of a 2022
+    /// method, base64 or hex for a key given verbatim.
+    Key(usize),
+
+    /// The key isn't of the key size of the method, with the given length.
+    KeySize(Method, usize),
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
             Error::Method(name) => write!(f, "{} is unsupported encryption method", name),
+            Error::Key(size) => write!(f, "expected an encoded key of {} bytes", size),
+            Error::KeySize(method, len) => write!(
+                f,
+                "{} requires a key of {} bytes, got {} bytes",
+                method,
+                method.key_size(),
+                len
+            ),
         }
     }
 }
 
 impl std::error::Error for Error {}
 
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
 /// Encryption methods.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum Method {
+    #[default]
     ChaCha20Poly1305,
+    XChaCha20Poly1305,
     Aes128Gcm,
+    Aes192Gcm,
     Aes256Gcm,
+    Blake3Aes128Gcm,
+    Blake3Aes256Gcm,
+    Blake3ChaCha20Poly1305,
 }
 
 impl Method {
@@ -68,8 +154,13 @@ impl Method {
     #[inline(always)]
     pub const fn key_size(&self) -> usize {
         match self {
-            Method::ChaCha20Poly1305 | Method::Aes256Gcm => 32,
-            Method::Aes128Gcm => 16,
+            Method::ChaCha20Poly1305
+            | Method::XChaCha20Poly1305
+            | Method::Aes256Gcm
+            | Method::Blake3Aes256Gcm
+            | Method::Blake3ChaCha20Poly1305 => 32,
+            Method::Aes192Gcm => 24,
+            Method::Aes128Gcm | Method::Blake3Aes128Gcm => 16,
         }
     }
 
@@ -77,15 +168,41 @@ impl Method {
     #[inline(always)]
     pub const fn salt_size(&self) -> usize {
         match self {
-            Method::ChaCha20Poly1305 | Method::Aes256Gcm => 32,
-            Method::Aes128Gcm => 16,
+            Method::ChaCha20Poly1305
+            | Method::XChaCha20Poly1305
+            | Method::Aes256Gcm
+            | Method::Blake3Aes256Gcm
+            | Method::Blake3ChaCha20Poly1305 => 32,
+            Method::Aes192Gcm => 24,
+            Method::Aes128Gcm | Method::Blake3Aes128Gcm => 16,
         }
     }
 
     /// Returns required iv size of the method.
     #[inline(always)]
     pub const fn iv_size(&self) -> usize {
-        12
+        match self {
+            Method::XChaCha20Poly1305 => 24,
+            _ => 12,
+        }
+    }
+
+    /// Returns how many chunks a key of the method may encrypt or decrypt.
+    ///
+    /// Well within the usage limits of the AEAD: AES-GCM weakens after about 2^32 chunks,
+    /// ChaCha20-Poly1305 has no practical limit with a counter nonce.
+    #[inline(always)]
+    pub const fn max_chunks(&self) -> u64 {
+        match self {
+            Method::Aes128Gcm
+            | Method::Aes192Gcm
+            | Method::Aes256Gcm
+            | Method::Blake3Aes128Gcm
+            | Method::Blake3Aes256Gcm => 1 << 32,
+            Method::ChaCha20Poly1305
+            | Method::XChaCha20Poly1305
+            | Method::Blake3ChaCha20Poly1305 => 1 << 48,
+        }
     }
 
     /// Returns required tag size of the method.
@@ -93,14 +210,39 @@ impl Method {
     pub const fn tag_size(&self) -> usize {
         16
     }
+
+    /// Checks that the key is of the key size of the method.
+    ///
+    /// A key of another size would make creating a cipher with it panic.
+    pub fn validate_key(&self, key: &[u8]) -> Result<(), Error> {
+        match key.len() == self.key_size() {
+            true => Ok(()),
+            false => Err(Error::KeySize(*self, key.len())),
+        }
+    }
+
+    /// Returns true if the method is one of the shadowsocks 2022 methods (SIP022),
+    /// which use a pre-shared key, BLAKE3 subkeys and a different stream header.
+    #[inline(always)]
+    pub const fn is_2022(&self) -> bool {
+        matches!(
+            self,
+            Method::Blake3Aes128Gcm | Method::Blake3Aes256Gcm | Method::Blake3ChaCha20Poly1305
+        )
+    }
 }
 
 impl Display for Method {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
             Method::ChaCha20Poly1305 => write!(f, "chacha20-ietf-poly1305"),
+            Method::XChaCha20Poly1305 => write!(f, "xchacha20-ietf-poly1305"),
             Method::Aes128Gcm => write!(f, "aes-128-gcm"),
+            Method::Aes192Gcm => write!(f, "aes-192-gcm"),
             Method::Aes256Gcm => write!(f, "aes-256-gcm"),
+            Method::Blake3Aes128Gcm => write!(f, "2022-blake3-aes-128-gcm"),
+            Method::Blake3Aes256Gcm => write!(f, "2022-blake3-aes-256-gcm"),
+            Method::Blake3ChaCha20Poly1305 => write!(f, "2022-blake3-chacha20-poly1305"),
         }
     }
 }
@@ -111,8 +253,13 @@ impl FromStr for Method {
     fn from_str(name: &str) -> Result<Self, Self::Err> {
         match name {
             "chacha20-ietf-poly1305" => Ok(Method::ChaCha20Poly1305),
+            "xchacha20-ietf-poly1305" => Ok(Method::XChaCha20Poly1305),
             "aes-128-gcm" => Ok(Method::Aes128Gcm),
+            "aes-192-gcm" => Ok(Method::Aes192Gcm),
             "aes-256-gcm" => Ok(Method::Aes256Gcm),
+            "2022-blake3-aes-128-gcm" => Ok(Method::Blake3Aes128Gcm),
+            "2022-blake3-aes-256-gcm" => Ok(Method::Blake3Aes256Gcm),
+            "2022-blake3-chacha20-poly1305" => Ok(Method::Blake3ChaCha20Poly1305),
             s => Err(Error::Method(s.to_owned())),
         }
     }
@@ -3,11 +3,18 @@
 pub mod aead;
 pub mod cipher;
 
-use std::ops::Deref;
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+    ops::Deref,
+};
 
+use base64::{engine::general_purpose::STANDARD, Engine};
 use hkdf::Hkdf;
 use sha1::Sha1;
 
+use cipher::{Error, Method};
+
 /// A simple encapsulation of bytes array.
 #[derive(Debug)]
 pub struct Nonce {
@@ -22,14 +29,41 @@ impl Nonce {
         }
     }
 
-    /// Increment the nonce.
-    pub fn increment(&mut self) {
+    /// Creates a new nonce, starting at the given little-endian counter.
+    pub fn from_counter(len: usize, counter: u64) -> Self {
+        let mut value = vec![0; len];
+        let bytes = counter.to_le_bytes();
+        let n = len.min(bytes.len());
+        value[..n].copy_from_slice(&bytes[..n]);
+
+        Nonce { value }
+    }
+
+    /// Increment the nonce as a little-endian counter.
+    ///
+    /// Fails without changing the nonce if it would wrap around to zero, reusing a nonce.
+    pub fn increment(&mut self) -> Result<(), CryptoError> {
+        if self.value.iter().all(|&x| x == u8::MAX) {
+            return Err(CryptoError::NonceOverflow);
+        }
+
         for i in 0..self.value.len() {
             self.value[i] = self.value[i].wrapping_add(1);
             if self.value[i] != 0 {
                 break;
             }
         }
+
+        Ok(())
+    }
+
+    /// Returns true if the nonce, as a little-endian counter, is at least `counter`.
+    pub fn reached(&self, counter: u64) -> bool {
+        let n = self.value.len().min(8);
+        let mut low = [0u8; 8];
+        low[..n].copy_from_slice(&self.value[..n]);
+
+        u64::from_le_bytes(low) >= counter || self.value[n..].iter().any(|&x| x != 0)
     }
 }
 
@@ -41,19 +75,112 @@ impl Deref for Nonce {
     }
 }
 
+/// Errors when deriving keys or incrementing nonces.
+#[derive(Debug)]
+pub enum CryptoError {
+    /// A key of this length can't be derived.
+    KeyLength(usize),
+
+    /// Every value of the nonce has been used.
+    NonceOverflow,
+}
+
+impl Display for CryptoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::KeyLength(len) => write!(f, "{} is an invalid key length", len),
+            CryptoError::NonceOverflow => write!(f, "nonce overflow"),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+impl From<CryptoError> for io::Error {
+    fn from(e: CryptoError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
 /// Produces a subkey that is cryptographically strong even if the input secret key is weak.
-pub fn hkdf_sha1(key: &[u8], salt: &[u8], subkey: &mut [u8]) {
+///
+/// Fails if `subkey` is longer than HKDF-SHA1 can produce.
+pub fn hkdf_sha1(key: &[u8], salt: &[u8], subkey: &mut [u8]) -> Result<(), CryptoError> {
     let hkdf = Hkdf::<Sha1>::new(Some(salt), key);
-    hkdf.expand(b"ss-subkey", subkey).expect(&format!(
-        "{} is a invalid output length, expected {}",
-        subkey.len(),
-        key.len()
-    ));
+    hkdf.expand(b"ss-subkey", subkey)
+        .map_err(|_| CryptoError::KeyLength(subkey.len()))
+}
+
+/// Produces a session subkey of the shadowsocks 2022 methods, with BLAKE3 key derivation.
+pub fn blake3_subkey(key: &[u8], salt: &[u8], subkey: &mut [u8]) {
+    let mut hasher = blake3::Hasher::new_derive_key("shadowsocks 2022 session subkey");
+    hasher.update(key);
+    hasher.update(salt);
+    hasher.finalize_xof().fill(subkey);
+}
+
+/// Derives the master key of the method from the password, a pre-shared key for the 2022 methods.
+pub fn master_key(method: Method, password: &str) -> io::Result<Vec<u8>> {
+    let mut key = vec![0u8; method.key_size()];
+    match method.is_2022() {
+        true => derive_key_2022(password.as_bytes(), &mut key)?,
+        false => derive_key(password.as_bytes(), &mut key)?,
+    }
+
+    Ok(key)
+}
+
+/// Decodes the master key of the shadowsocks 2022 methods from a base64 encoded pre-shared key.
+///
+/// Unlike [`derive_key`], the decoded key must be exactly as long as `key`.
+pub fn derive_key_2022(password: &[u8], key: &mut [u8]) -> Result<(), Error> {
+    match STANDARD.decode(password) {
+        Ok(psk) if psk.len() == key.len() => {
+            key.copy_from_slice(&psk);
+            Ok(())
+        }
+        _ => Err(Error::Key(key.len())),
+    }
+}
+
+/// Decodes a master key given verbatim, hex encoded or else base64 encoded.
+///
+/// Fails if the decoded key isn't of the length of `key`.
+pub fn decode_key(encoded: &str, key: &mut [u8]) -> Result<(), Error> {
+    let decoded = match encoded.len() == 2 * key.len() {
+        true => decode_hex(encoded),
+        false => None,
+    };
+
+    match decoded.or_else(|| STANDARD.decode(encoded).ok()) {
+        Some(decoded) if decoded.len() == key.len() => {
+            key.copy_from_slice(&decoded);
+            Ok(())
+        }
+        _ => Err(Error::Key(key.len())),
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.bytes().all(|x| x.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
 }
 
 /// Generates the master key from a password.
-pub fn derive_key(password: &[u8], key: &mut [u8]) {
+///
+/// Fails if `key` is empty, as nothing would be derived.
+pub fn derive_key(password: &[u8], key: &mut [u8]) -> Result<(), CryptoError> {
     let key_size = key.len();
+    if key_size == 0 {
+        return Err(CryptoError::KeyLength(0));
+    }
+
     let mut md_buf: Vec<u8> = Vec::new();
 
     let mut j = 0;
@@ -72,6 +199,8 @@ pub fn derive_key(password: &[u8], key: &mut [u8]) {
 
         md_buf = md.to_vec();
     }
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -87,7 +216,7 @@ mod tests {
             176, 72, 135, 140, 255, 57, 14, 7, 193, 98, 58, 118, 112, 42, 119, 97,
         ];
 
-        hkdf_sha1(&key, salt, &mut subkey);
+        hkdf_sha1(&key, salt, &mut subkey).unwrap();
 
         assert_eq!(subkey, expected_subkey);
     }
@@ -102,7 +231,7 @@ mod tests {
             92, 5, 94, 252, 21, 4, 211, 164, 43, 251, 44, 61, 208,
         ];
 
-        hkdf_sha1(&key, salt, &mut subkey);
+        hkdf_sha1(&key, salt, &mut subkey).unwrap();
 
         assert_eq!(subkey, expected_subkey);
     }
@@ -115,7 +244,7 @@ mod tests {
             82, 156, 168, 5, 10, 0, 24, 7, 144, 207, 136, 182, 52, 104, 130, 106,
         ];
 
-        derive_key(password, &mut key);
+        derive_key(password, &mut key).unwrap();
 
         assert_eq!(key, expected_key);
     }
@@ -129,8 +258,190 @@ mod tests {
             207, 24, 87, 148, 16, 101, 57, 172, 239, 219, 100, 183, 95,
         ];
 
-        derive_key(password, &mut key);
+        derive_key(password, &mut key).unwrap();
+
+        assert_eq!(key, expected_key);
+    }
+
+    #[test]
+    fn test_derive_key192() {
+        let password = b"hehe";
+        let mut key = [0u8; 24];
+        let expected_key = [
+            82, 156, 168, 5, 10, 0, 24, 7, 144, 207, 136, 182, 52, 104, 130, 106, 109, 81, 225,
+            207, 24, 87, 148, 16,
+        ];
+
+        derive_key(password, &mut key).unwrap();
 
         assert_eq!(key, expected_key);
     }
+
+    #[test]
+    fn test_invalid_key_length() {
+        let mut subkey = vec![0u8; 255 * 20 + 1];
+        let err = hkdf_sha1(&[1u8; 32], &[2u8; 32], &mut subkey).unwrap_err();
+        assert!(matches!(err, CryptoError::KeyLength(5101)));
+
+        assert!(derive_key(b"hehe", &mut []).is_err());
+    }
+
+    #[test]
+    fn test_aes192gcm() {
+        // Test case 8 of the GCM specification
+        let cipher = cipher::Cipher::new(cipher::Method::Aes192Gcm, &[0u8; 24]).unwrap();
+        let expected = [
+            0x98, 0xe7, 0x24, 0x7c, 0x07, 0xf0, 0xfe, 0x41, 0x1c, 0x26, 0x7e, 0x43, 0x84, 0xb0,
+            0xf6, 0x00, 0x2f, 0xf5, 0x8d, 0x80, 0x03, 0x39, 0x27, 0xab, 0x8e, 0xf4, 0xd4, 0x58,
+            0x75, 0x14, 0xf0, 0xfb,
+        ];
+
+        let ciphertext = cipher.encrypt(&[0u8; 12], &[0u8; 16]).unwrap();
+        assert_eq!(ciphertext, expected);
+        assert_eq!(cipher.decrypt(&[0u8; 12], &ciphertext).unwrap(), [0u8; 16]);
+    }
+
+    #[test]
+    fn test_xchacha20poly1305() {
+        let method = cipher::Method::XChaCha20Poly1305;
+        assert_eq!(method.iv_size(), 24);
+        assert_eq!(
+            method.to_string().parse::<cipher::Method>().unwrap(),
+            method
+        );
+
+        let cipher = cipher::Cipher::new(method, &[7u8; 32]).unwrap();
+        let mut nonce = Nonce::new(method.iv_size());
+        assert_eq!(nonce.len(), 24);
+        let first = cipher.encrypt(&nonce, b"hello").unwrap();
+        assert_eq!(cipher.decrypt(&nonce, &first).unwrap(), b"hello");
+
+        nonce.increment().unwrap();
+        assert_ne!(cipher.encrypt(&nonce, b"hello").unwrap(), first);
+        assert!(cipher.decrypt(&nonce, &first).is_err());
+    }
+
+    #[test]
+    fn test_nonce_increment() {
+        let mut nonce = Nonce::new(12);
+        nonce.increment().unwrap();
+        assert_eq!(nonce[..], [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut nonce = Nonce::from_counter(12, 0x1ff);
+        nonce.increment().unwrap();
+        assert_eq!(nonce[..], [0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(nonce.reached(0x200));
+        assert!(!nonce.reached(0x201));
+
+        let mut nonce = Nonce::from_counter(12, u64::MAX - 1);
+        assert!(!nonce.reached(u64::MAX));
+        nonce.increment().unwrap();
+        assert!(nonce.reached(u64::MAX));
+        nonce.increment().unwrap();
+        assert_eq!(nonce[..], [0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0]);
+        assert!(nonce.reached(u64::MAX));
+
+        // Close to wrapping around.
+        let mut nonce = Nonce::new(12);
+        nonce.value.fill(u8::MAX);
+        nonce.value[0] = 0xfe;
+        nonce.increment().unwrap();
+        assert!(nonce.iter().all(|&x| x == u8::MAX));
+        assert!(matches!(nonce.increment(), Err(CryptoError::NonceOverflow)));
+        assert!(nonce.iter().all(|&x| x == u8::MAX));
+    }
+
+    #[test]
+    fn test_decode_key() {
+        let mut key = [0u8; 16];
+        decode_key("000102030405060708090a0b0c0d0e0F", &mut key).unwrap();
+        assert_eq!(key, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+
+        let mut key = [0u8; 16];
+        decode_key("AAECAwQFBgcICQoLDA0ODw==", &mut key).unwrap();
+        assert_eq!(key, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+
+        // Wrong length, or neither hex nor base64.
+        assert!(decode_key("000102030405060708090a0b0c0d0e", &mut key).is_err());
+        assert!(decode_key("AAECAwQFBgcICQoLDA0ODw==", &mut [0u8; 32]).is_err());
+        assert!(decode_key("000102030405060708090a0b0c0d0e0g", &mut key).is_err());
+    }
+
+    #[test]
+    fn test_encrypt_to() {
+        let cipher = cipher::Cipher::new(cipher::Method::ChaCha20Poly1305, &[7u8; 32]).unwrap();
+        let nonce = [1u8; 12];
+
+        let mut out = b"prefix".to_vec();
+        cipher.encrypt_to(&nonce, b"hello", &mut out).unwrap();
+        assert_eq!(out[..6], *b"prefix");
+        assert_eq!(out[6..], cipher.encrypt(&nonce, b"hello").unwrap());
+        assert_eq!(cipher.decrypt(&nonce, &out[6..]).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_decrypt_in_place() {
+        let cipher = cipher::Cipher::new(cipher::Method::Aes128Gcm, &[7u8; 16]).unwrap();
+        let nonce = [1u8; 12];
+        let ciphertext = cipher.encrypt(&nonce, b"hello").unwrap();
+
+        let mut buffer = ciphertext.clone();
+        cipher.decrypt_in_place(&nonce, &mut buffer).unwrap();
+        assert_eq!(buffer, b"hello");
+
+        let mut buffer = ciphertext;
+        buffer[0] ^= 1;
+        assert!(cipher.decrypt_in_place(&nonce, &mut buffer).is_err());
+
+        let mut buffer = vec![0u8; 15];
+        assert!(cipher.decrypt_in_place(&nonce, &mut buffer).is_err());
+    }
+
+    #[test]
+    fn test_blake3_subkey() {
+        let key = [1u8; 32];
+        let salt = [2u8; 32];
+
+        let mut subkey = [0u8; 32];
+        blake3_subkey(&key, &salt, &mut subkey);
+        let expected_subkey =
+            blake3::derive_key("shadowsocks 2022 session subkey", &[key, salt].concat());
+        assert_eq!(subkey, expected_subkey);
+
+        // Shorter subkeys are a prefix
+        let mut short_subkey = [0u8; 16];
+        blake3_subkey(&key, &salt, &mut short_subkey);
+        assert_eq!(short_subkey, subkey[..16]);
+    }
+
+    #[test]
+    fn test_validate_key() {
+        use cipher::Method;
+
+        assert!(Method::Aes128Gcm.validate_key(&[0u8; 16]).is_ok());
+        assert!(Method::Blake3Aes256Gcm.validate_key(&[0u8; 32]).is_ok());
+
+        let err = Method::Blake3Aes256Gcm
+            .validate_key(&[0u8; 16])
+            .unwrap_err();
+        assert!(matches!(err, Error::KeySize(Method::Blake3Aes256Gcm, 16)));
+        assert_eq!(
+            err.to_string(),
+            "2022-blake3-aes-256-gcm requires a key of 32 bytes, got 16 bytes"
+        );
+
+        // Instead of panicking
+        assert!(cipher::Cipher::new(Method::Aes256Gcm, &[0u8; 16]).is_err());
+    }
+
+    #[test]
+    fn test_derive_key_2022() {
+        let mut key = [0u8; 16];
+        derive_key_2022(b"AAECAwQFBgcICQoLDA0ODw==", &mut key).unwrap();
+        assert_eq!(key, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+
+        let mut key = [0u8; 32];
+        assert!(derive_key_2022(b"AAECAwQFBgcICQoLDA0ODw==", &mut key).is_err());
+        assert!(derive_key_2022(b"not base64", &mut key).is_err());
+    }
 }
@@ -0,0 +1,319 @@
+//! HTTP CONNECT proxy requests, served by ss-local alongside SOCKS5.
+//!
+//! Only the CONNECT method is supported, other requests are answered with an error.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::socks5::{Credentials, Socks5Addr};
+
+mod constants {
+    pub const MAX_REQUEST_SIZE: u64 = 8192;
+}
+
+/// Errors when handle HTTP CONNECT requests.
+#[derive(Debug)]
+pub enum Error {
+    /// The request isn't terminated by an empty line within the size limit.
+    Incomplete,
+
+    /// The request line isn't `METHOD TARGET HTTP/1.x`.
+    RequestLine(String),
+
+    /// Unsupported method.
+    Method(String),
+
+    /// The target isn't `host:port`.
+    Target(String),
+
+    /// The username or password is wrong, or missing.
+    Auth,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Incomplete => write!(f, "incomplete http request"),
+            Error::RequestLine(line) => write!(f, "invalid http request line {:?}", line),
+            Error::Method(method) => {
+                write!(f, "only support the CONNECT method, request {}", method)
+            }
+            Error::Target(target) => write!(f, "invalid http CONNECT target {:?}", target),
+            Error::Auth => write!(f, "http proxy authentication failed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Returns true if the first byte of a request is the one of an HTTP method, not SOCKS.
+pub fn is_http(first_byte: u8) -> bool {
+    first_byte.is_ascii_uppercase()
+}
+
+/// HTTP CONNECT handshake, returning the target address.
+///
+/// If `credentials` is given, clients must authenticate with the `Proxy-Authorization`
+/// header, the Basic scheme with the same users as SOCKS5.
+///
+/// The request isn't replied to on success, the caller replies once it knows whether
+/// the target could be connected to, see [`reply`] and [`reply_error`].
+/// Data the client sent after the request is left in the stream.
+pub async fn handshake<S>(
+    stream: &mut S,
+    credentials: Option<&Credentials>,
+) -> io::Result<Socks5Addr>
+where
+    S: AsyncBufRead + AsyncWrite + Unpin + ?Sized,
+{
+    let (request_line, headers) = read_request(stream).await?;
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method, target)
+        }
+        _ => {
+            respond(stream, "400 Bad Request").await?;
+            return Err(other(Error::RequestLine(request_line)));
+        }
+    };
+
+    if method != "CONNECT" {
+        respond(stream, "405 Method Not Allowed\r\nAllow: CONNECT").await?;
+        return Err(other(Error::Method(method.to_owned())));
+    }
+
+    let target_addr = match target.parse() {
+        Ok(addr) => addr,
+        Err(_) => {
+            respond(stream, "400 Bad Request").await?;
+            return Err(other(Error::Target(target.to_owned())));
+        }
+    };
+
+    if let Some(credentials) = credentials {
+        let authorization = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Proxy-Authorization"))
+            .map(|(_, value)| value.as_str());
+
+        if !authenticate(authorization, credentials) {
+            let status = "407 Proxy Authentication Required\r\n\
+                          Proxy-Authenticate: Basic realm=\"ss-rs\"";
+            respond(stream, status).await?;
+            return Err(other(Error::Auth));
+        }
+    }
+
+    Ok(target_addr)
+}
+
+/// Replies that the connection to the target is established.
+pub async fn reply<S>(stream: &mut S) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin + ?Sized,
+{
+    respond(stream, "200 Connection Established").await
+}
+
+/// Replies failure, with the status of the error connecting to the target.
+pub async fn reply_error<S>(stream: &mut S, e: &io::Error) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin + ?Sized,
+{
+    let status = match e.kind() {
+        io::ErrorKind::PermissionDenied => "403 Forbidden",
+        io::ErrorKind::TimedOut => "504 Gateway Timeout",
+        _ => "502 Bad Gateway",
+    };
+
+    respond(stream, status).await
+}
+
+/// Reads the request line and the headers, up to the empty line ending them.
+async fn read_request<S>(stream: &mut S) -> io::Result<(String, Vec<(String, String)>)>
+where
+    S: AsyncBufRead + Unpin + ?Sized,
+{
+    let mut limited = (&mut *stream).take(constants::MAX_REQUEST_SIZE);
+    let mut lines = Vec::new();
+
+    loop {
+        let mut line = Vec::new();
+        limited.read_until(b'\n', &mut line).await?;
+        if !line.ends_with(b"\n") {
+            return Err(other(Error::Incomplete));
+        }
+
+        let line = String::from_utf8_lossy(&line).trim_end().to_owned();
+        if line.is_empty() {
+            break;
+        }
+
+        lines.push(line);
+    }
+
+    let mut lines = lines.into_iter();
+    let request_line = lines.next().unwrap_or_default();
+    let headers = lines
+        .filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            Some((name.trim().to_owned(), value.trim().to_owned()))
+        })
+        .collect();
+
+    Ok((request_line, headers))
+}
+
+/// Returns true if the `Proxy-Authorization` header is of an allowed user.
+fn authenticate(authorization: Option<&str>, credentials: &Credentials) -> bool {
+    let decoded = authorization
+        .and_then(|x| x.strip_prefix("Basic "))
+        .and_then(|x| STANDARD.decode(x.trim()).ok())
+        .and_then(|x| String::from_utf8(x).ok());
+
+    match decoded.as_deref().and_then(|x| x.split_once(':')) {
+        Some((username, password)) => credentials.verify(username, password),
+        None => false,
+    }
+}
+
+async fn respond<S>(stream: &mut S, status: &str) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin + ?Sized,
+{
+    let rsp = format!("HTTP/1.1 {}\r\n\r\n", status);
+    stream.write_all(rsp.as_bytes()).await?;
+    stream.flush().await
+}
+
+fn other(e: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, BufReader};
+
+    use super::*;
+
+    async fn handshake_with(
+        request: &[u8],
+        credentials: Option<&Credentials>,
+    ) -> (io::Result<Socks5Addr>, String) {
+        let (client, server) = duplex(1024);
+        let mut server = BufReader::new(server);
+        let (mut reader, mut writer) = tokio::io::split(client);
+
+        writer.write_all(request).await.unwrap();
+        writer.shutdown().await.unwrap();
+        let res = handshake(&mut server, credentials).await;
+        drop(server);
+
+        let mut rsp = String::new();
+        reader.read_to_string(&mut rsp).await.unwrap();
+
+        (res, rsp)
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let request = b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\nhello";
+        let (client, server) = duplex(1024);
+        let mut server = BufReader::new(server);
+        let (_reader, mut writer) = tokio::io::split(client);
+
+        writer.write_all(request).await.unwrap();
+        let addr = handshake(&mut server, None).await.unwrap();
+        assert_eq!(addr.to_string(), "example.com:443");
+
+        // Data after the request is kept for relaying.
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let (res, _) = handshake_with(b"CONNECT [::1]:8080 HTTP/1.0\n\n", None).await;
+        assert_eq!(res.unwrap().to_string(), "[::1]:8080");
+    }
+
+    #[tokio::test]
+    async fn test_handshake_error() {
+        let (res, rsp) = handshake_with(b"GET http://example.com/ HTTP/1.1\r\n\r\n", None).await;
+        assert!(res.is_err());
+        assert!(rsp.starts_with("HTTP/1.1 405"));
+
+        let (res, rsp) = handshake_with(b"CONNECT example.com HTTP/1.1\r\n\r\n", None).await;
+        assert!(res.is_err());
+        assert!(rsp.starts_with("HTTP/1.1 400"));
+
+        let (res, rsp) = handshake_with(b"CONNECT example.com:443 HTTP/1.1\r\n", None).await;
+        assert!(res.is_err());
+        assert!(rsp.is_empty());
+
+        let mut request = b"CONNECT example.com:443 HTTP/1.1\r\nX: ".to_vec();
+        request.extend(vec![b'x'; constants::MAX_REQUEST_SIZE as usize]);
+        let (client, server) = duplex(2 * constants::MAX_REQUEST_SIZE as usize);
+        let mut server = BufReader::new(server);
+        let (_reader, mut writer) = tokio::io::split(client);
+        writer.write_all(&request).await.unwrap();
+        assert!(handshake(&mut server, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_authentication() {
+        let mut credentials = Credentials::new();
+        credentials.add("user", "pass");
+
+        let request = b"CONNECT example.com:443 HTTP/1.1\r\n\r\n";
+        let (res, rsp) = handshake_with(request, Some(&credentials)).await;
+        assert!(res.is_err());
+        assert!(rsp.starts_with("HTTP/1.1 407"));
+
+        let request = format!(
+            "CONNECT example.com:443 HTTP/1.1\r\nproxy-authorization: Basic {}\r\n\r\n",
+            STANDARD.encode("user:wrong")
+        );
+        let (res, _) = handshake_with(request.as_bytes(), Some(&credentials)).await;
+        assert!(res.is_err());
+
+        let request = format!(
+            "CONNECT example.com:443 HTTP/1.1\r\nProxy-Authorization: Basic {}\r\n\r\n",
+            STANDARD.encode("user:pass")
+        );
+        let (res, rsp) = handshake_with(request.as_bytes(), Some(&credentials)).await;
+        assert_eq!(res.unwrap().to_string(), "example.com:443");
+        assert!(rsp.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reply() {
+        let mut buf = Vec::new();
+        reply(&mut buf).await.unwrap();
+        assert_eq!(buf, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+
+        let mut buf = Vec::new();
+        reply_error(&mut buf, &io::ErrorKind::PermissionDenied.into())
+            .await
+            .unwrap();
+        assert!(buf.starts_with(b"HTTP/1.1 403"));
+
+        let mut buf = Vec::new();
+        reply_error(&mut buf, &io::ErrorKind::ConnectionRefused.into())
+            .await
+            .unwrap();
+        assert!(buf.starts_with(b"HTTP/1.1 502"));
+    }
+
+    #[test]
+    fn test_is_http() {
+        assert!(is_http(b'C'));
+        assert!(!is_http(0x05));
+        assert!(!is_http(0x04));
+    }
+}
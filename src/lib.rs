@@ -3,7 +3,10 @@
 //! # Features
 //!
 //! - [x] [SOCKS5](https://datatracker.ietf.org/doc/html/rfc1928) CONNECT command
+//! - [x] SOCKS5 UDP ASSOCIATE command (`--udp`)
+//! - [x] SOCKS5 [username/password authentication](https://datatracker.ietf.org/doc/html/rfc1929) (`--socks5-auth`)
 //! - [x] [AEAD](https://shadowsocks.org/en/wiki/AEAD-Ciphers.html) ciphers
+//! - [x] [Shadowsocks 2022](https://github.com/shadowsocks/shadowsocks-org/issues/196) ciphers (TCP only)
 //! - [x] Defend against [replay attacks](https://github.com/shadowsocks/shadowsocks-org/issues/44)
 //! - [x] [Access control list](https://github.com/shadowsocks/shadowsocks-rust#acl)
 //!
@@ -49,13 +52,50 @@
 //!     ~~~
 //!
 //!     Now you can find the binary in `./target/release/ss-rs`.
+//!
+//!     AES-NI and AVX2 are detected at runtime and the one in use is logged at startup. To force the portable software implementations, e.g. for reproducible benchmarks:
+//!
+//!     ~~~bash
+//!     RUSTFLAGS="--cfg aes_force_soft --cfg chacha20_force_soft" cargo b --release
+//!     ~~~
+//!
+//!     To wrap the connections between client and server in TLS without a plugin, enable the `tls` feature:
+//!
+//!     ~~~bash
+//!     cargo b --release --features tls
+//!
+//!     # server
+//!     ss-rs -s 0.0.0.0:5421 -k ocfbnj --tls-cert cert.pem --tls-key key.pem
+//!     # client
+//!     ss-rs -s ocfbnj.cn:5421 -l localhost:1080 -k ocfbnj --tls-sni ocfbnj.cn
+//!     ~~~
+//!
+//!     To serve health, statistics and Prometheus metrics over HTTP (`/health`, `/stats` and `/metrics`), enable the `admin` feature:
+//!
+//!     ~~~bash
+//!     cargo b --release --features admin
+//!
+//!     ss-rs -s 0.0.0.0:5421 -k ocfbnj --admin 127.0.0.1:9000
+//!     ~~~
 
 pub mod acl;
+#[cfg(feature = "admin")]
+pub mod admin;
+pub mod check;
 pub mod context;
 pub mod crypto;
+pub mod http_connect;
+pub mod manager;
 pub mod net;
+pub mod observer;
 pub mod plugin;
+pub mod remote;
+pub mod router;
 pub mod security;
 pub mod socks5;
+pub mod stats;
 pub mod tcp;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod udp;
 pub mod url;
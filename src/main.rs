@@ -1,26 +1,50 @@
-use std::{io::Write, sync::Arc};
+use std::{error::Error, io::Write, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use env_logger::{Builder, Env};
 use tokio::process::Child;
 
 use ss_rs::{
-    acl::Acl,
-    context::Ctx,
-    crypto::derive_key,
+    acl::{Acl, ResolvePolicy},
+    check,
+    context::{Ctx, Timeouts},
+    crypto::{aead, cipher::Method, decode_key, master_key},
+    manager,
+    net::sys,
     plugin::start_plugin,
-    tcp::{ss_local, ss_remote},
+    remote::{self, RemotePool, Server},
+    router::rules::RuleRouter,
+    security::{load_salts, ReplayCheck, ReplayMode},
+    socks5::{Credentials, Socks5Addr},
+    tcp::{ss_local, ss_redir, ss_remote, ss_tunnel},
+    udp::{ss_local_udp, ss_remote_udp},
     url::SsUrl,
 };
 
 mod args;
 
-#[tokio::main]
-async fn main() {
+use args::Args;
+
+const REPLAY_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn main() {
     // 1. Parses the command line arguments and initializes logger
     let args = args::parse();
 
     init_logger(args.verbose);
 
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    if let Some(threads) = args.threads.filter(|&x| x > 0) {
+        builder.worker_threads(threads);
+    }
+
+    match builder.enable_all().build() {
+        Ok(runtime) => runtime.block_on(run(args)),
+        Err(e) => log::error!("Unable to start the runtime: {}", e),
+    }
+}
+
+async fn run(args: Args) {
     let mut remote_addr = match ss_rs::net::lookup_host(&args.remote_addr).await {
         Ok(addr) => addr,
         Err(e) => {
@@ -40,7 +64,21 @@ async fn main() {
 
     let method = args.method;
     let password = args.password;
+    let raw_key = args.key;
     let is_server = args.local_addr.is_none();
+    let transparent = args.transparent;
+
+    let mut tunnel_addr = None;
+    if let Some(addr) = args.tunnel_addr {
+        match addr.parse::<Socks5Addr>() {
+            Ok(addr) => tunnel_addr = Some(addr),
+            Err(e) => {
+                log::error!("Invalid tunnel address {}: {}", addr, e);
+                return;
+            }
+        }
+    }
+    let tunnel = tunnel_addr.is_some();
 
     let mut local_addr = None;
     if let Some(addr) = args.local_addr {
@@ -53,9 +91,58 @@ async fn main() {
         };
     }
 
-    // 2. Derives a key from the given password
-    let mut key = vec![0u8; method.key_size()];
-    derive_key(password.as_bytes(), &mut key);
+    // 2. Derives a key from the given password, unless the key is given verbatim
+    let key = match raw_key {
+        Some(raw_key) => match decode_master_key(method, &raw_key) {
+            Ok(key) => key,
+            Err(e) => {
+                log::error!("Invalid key for {}: {}", method, e);
+                return;
+            }
+        },
+        None => match master_key(method, &password) {
+            Ok(key) => key,
+            Err(e) => {
+                log::error!("Invalid password for {}: {}", method, e);
+                return;
+            }
+        },
+    };
+
+    let mut extra_servers = Vec::new();
+    let mut extra_hosts = Vec::new();
+    for url in args.server_urls {
+        if url.plugin.is_some() {
+            log::error!("Plugin is not supported for additional server {}", url);
+            return;
+        }
+
+        let host = format!("{}:{}", url.hostname, url.port);
+        let addr = match ss_rs::net::lookup_host(&host).await {
+            Ok(addr) => addr,
+            Err(e) => {
+                log::error!("Resolve {} failed: {}", host, e);
+                return;
+            }
+        };
+
+        let key = match master_key(url.method, &url.password) {
+            Ok(key) => key,
+            Err(e) => {
+                log::error!("Invalid password of additional server {}: {}", url, e);
+                return;
+            }
+        };
+
+        extra_servers.push((addr, url.method, key));
+        extra_hosts.push(host.parse::<SocketAddr>().err().map(|_| host));
+    }
+
+    log::info!(
+        "Using {} implementation of {}",
+        aead::backend(method),
+        method
+    );
 
     // 3. Prepares shadowsocks context
     let mut ctx = Ctx::new();
@@ -70,16 +157,333 @@ async fn main() {
 
         ctx.set_acl(acl);
     }
+
+    if let Some(url) = args.acl_url {
+        let cache_path = match args
+            .acl_cache
+            .or_else(|| ss_rs::acl::download::cache_path(&url))
+        {
+            Some(path) => path,
+            None => {
+                log::error!("No cache directory for the ACL, set --acl-cache");
+                return;
+            }
+        };
+        let acl = match Acl::from_url(&url, &cache_path).await {
+            Ok(res) => res,
+            Err(e) => {
+                log::error!("Unable to download ACL from {}: {}", url, e);
+                return;
+            }
+        };
+
+        ctx.set_acl(acl);
+    }
+
+    if let Some(path) = args.route_script {
+        let router = match RuleRouter::from_file(&path) {
+            Ok(res) => res,
+            Err(e) => {
+                log::error!("Unable to load route script: {}", e);
+                return;
+            }
+        };
+
+        ctx.set_router(Arc::new(router));
+    }
+
+    #[cfg(feature = "tls")]
+    {
+        use ss_rs::tls::Tls;
+
+        if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+            match Tls::server(cert, key) {
+                Ok(tls) => ctx.set_tls(tls),
+                Err(e) => {
+                    log::error!("Unable to load TLS certificate: {}", e);
+                    return;
+                }
+            }
+        }
+
+        if let Some(sni) = &args.tls_sni {
+            match Tls::client(sni, args.tls_ca.as_deref()) {
+                Ok(tls) => ctx.set_tls(tls),
+                Err(e) => {
+                    log::error!("Unable to configure TLS: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    #[cfg(not(feature = "tls"))]
+    if args.tls_cert.is_some() || args.tls_sni.is_some() {
+        log::error!("TLS is not supported, rebuild with `--features tls`");
+        return;
+    }
+
+    if args.probe {
+        log::warn!("Probe mode: connections are logged and closed without relaying");
+        ctx.set_probe(true);
+    }
+
+    ctx.set_mitigate_probing(args.mitigate_probing);
+
+    if !args.socks5_users.is_empty() {
+        let mut credentials = Credentials::new();
+        for (username, password) in &args.socks5_users {
+            credentials.add(username, password);
+        }
+
+        ctx.set_credentials(credentials);
+    }
+
+    if args.socks5_bind {
+        ctx.set_socks5_bind(true);
+    }
+
+    let udp = args.udp;
+    if udp {
+        if method.is_2022() || extra_servers.iter().any(|(_, method, _)| method.is_2022()) {
+            log::error!("UDP relay is not supported with the 2022 methods yet");
+            return;
+        }
+
+        ctx.set_udp(true);
+    }
+
+    if args.fast_open {
+        // Checked once here, sockets silently fall back to normal connections.
+        match tokio::net::TcpSocket::new_v4().and_then(|x| sys::set_tcp_fastopen_connect(&x)) {
+            Ok(_) => ctx.set_fast_open(true),
+            Err(e) => log::warn!(
+                "TCP Fast Open is unavailable, using normal connections: {}",
+                e
+            ),
+        }
+    }
+
+    if args.reuse_port {
+        match tokio::net::TcpSocket::new_v4().and_then(|x| sys::set_reuseport(&x)) {
+            Ok(_) => ctx.set_reuse_port(true),
+            Err(e) => log::warn!("SO_REUSEPORT is unavailable, listening without it: {}", e),
+        }
+    }
+
+    if args.nagle {
+        ctx.set_no_delay(false);
+    }
+
+    if let Some(ip) = args.outbound_bind {
+        // A source address that isn't assigned to this host can't be bound.
+        if let Err(e) = std::net::TcpListener::bind((ip, 0)) {
+            log::error!("Unable to use {} as the outbound bind address: {}", ip, e);
+            return;
+        }
+
+        ctx.set_outbound_bind(ip);
+    }
+
+    if let Some(mark) = args.fwmark {
+        // Unlike TCP Fast Open, connections without the mark would be routed the wrong way.
+        if let Err(e) = tokio::net::TcpSocket::new_v4().and_then(|x| sys::set_mark(&x, mark)) {
+            log::error!("Unable to set the firewall mark {}: {}", mark, e);
+            return;
+        }
+
+        ctx.set_fwmark(mark);
+    }
+
+    if args.allow_self_connect {
+        ctx.set_self_connect_guard(false);
+    }
+
+    if !is_server && args.plugin.is_some() {
+        ctx.set_plugin(true);
+    }
+
+    if is_server {
+        // With a plugin, clients reach ss-remote on this address rather than the bound one.
+        ctx.add_listen_addr(remote_addr);
+    } else if args.plugin.is_none() && args.remote_addr.parse::<SocketAddr>().is_err() {
+        // A plugin connects to ss-remote itself, ss-local only reaches the plugin.
+        ctx.set_remote_host(args.remote_addr.clone());
+    }
+
+    if !is_server && !extra_servers.is_empty() {
+        if args.plugin.is_some() {
+            log::error!("Plugin is not supported with additional servers to fail over to");
+            return;
+        }
+
+        let host = ctx.remote_host().map(str::to_owned);
+        let mut servers = vec![Server::new(remote_addr, host, method, key.clone())];
+        for ((addr, method, key), host) in extra_servers.iter().zip(&extra_hosts) {
+            servers.push(Server::new(*addr, host.clone(), *method, key.clone()));
+        }
+
+        let mut pool = RemotePool::new(servers);
+        pool.set_balance(args.balance);
+        ctx.set_remote_pool(pool);
+    }
+
+    if let Some(num) = args.max_resolved_addrs {
+        ctx.set_max_resolved_addrs(num);
+    }
+
+    if args.remote_dns {
+        ctx.set_resolve_policy(ResolvePolicy::Remote);
+    }
+
+    ctx.set_ip_family(args.ip_family);
+
+    if let Some(secs) = args.dns_cache_ttl {
+        ctx.set_dns_cache_ttl(Duration::from_secs(secs));
+    }
+
+    if args.acl_reverse_dns {
+        ctx.set_reverse_dns(true);
+    }
+
+    let mut timeouts = Timeouts::default();
+    if let Some(secs) = args.handshake_timeout {
+        timeouts.handshake = Duration::from_secs(secs);
+    }
+    if let Some(secs) = args.connect_timeout {
+        timeouts.connect = Duration::from_secs(secs);
+    }
+    if let Some(secs) = args.half_close_timeout {
+        timeouts.half_close = Some(Duration::from_secs(secs));
+    }
+    if let Some(secs) = args.timeout {
+        timeouts.idle = Duration::from_secs(secs);
+    }
+    ctx.set_timeouts(timeouts);
+
+    if let Some(retries) = args.connect_retries {
+        let (_, delay) = ctx.connect_retries();
+        let delay = args.retry_delay.map(Duration::from_millis).unwrap_or(delay);
+        ctx.set_connect_retries(retries, delay);
+    }
+
+    if let Some(rate) = args.limit_rate {
+        ctx.set_rate_limit(rate, rate);
+    }
+
+    if let Some(rate) = args.max_conn_rate {
+        ctx.set_max_conn_rate(rate);
+    }
+
+    if let Some(num) = args.max_connections {
+        ctx.set_max_connections(num);
+    }
+
+    if let Some(num) = args.buffer_pool_size {
+        ctx.set_buffer_pool_size(num);
+    }
+
+    if args.compress {
+        if method.is_2022() {
+            log::error!("Chunk compression is not supported with the 2022 methods");
+            return;
+        }
+
+        log::warn!(
+            "Chunk compression is experimental and non-standard, the peer must enable it too"
+        );
+        ctx.set_compression(true);
+    }
+
+    if args.replay_lite {
+        ctx.set_replay_mode(ReplayMode::Lite);
+    }
+    if args.no_replay_protection {
+        ctx.set_replay_mode(ReplayMode::Off);
+    }
+
+    if let Some(ref path) = args.replay_state {
+        match ctx.load_replay(path) {
+            Ok(_) => log::info!("Loaded replay protection from {}", path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::info!("No replay protection saved at {} yet", path.display())
+            }
+            Err(e) => {
+                log::error!("Unable to load replay protection: {}", e);
+                return;
+            }
+        }
+    }
+
+    if args.early_replay_check {
+        ctx.set_replay_check(ReplayCheck::OnSalt);
+    }
+
+    if let Some(path) = args.replay_blocklist {
+        match load_salts(&path) {
+            Ok(salts) => {
+                ctx.preload_replay(&salts);
+                log::info!("Loaded {} salts from the replay blocklist", salts.len());
+            }
+            Err(e) => {
+                log::error!("Unable to load replay blocklist: {}", e);
+                return;
+            }
+        }
+    }
+
     let ctx = Arc::new(ctx);
 
+    if args.check {
+        let mut check_target = None;
+        if let Some(addr) = args.check_target {
+            match addr.parse::<Socks5Addr>() {
+                Ok(addr) => check_target = Some(addr),
+                Err(e) => {
+                    log::error!("Invalid check target {}: {}", addr, e);
+                    return;
+                }
+            }
+        }
+
+        let res = match is_server {
+            true => check::check_remote(remote_addr, method, &key, check_target, ctx).await,
+            false => check::check_local(remote_addr, method, &key, check_target, ctx).await,
+        };
+
+        match res {
+            Ok(_) => log::info!("Check passed"),
+            Err(e) => {
+                log::error!("Check failed, {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // Ports added by the manager are bound on the server address, not the one of the plugin.
+    let manager_ip = remote_addr.ip();
+
     // 4. Starts plugin
     let mut plugin = None;
 
     if let Some(plugin_name) = args.plugin {
+        let mut plugin_bind = None;
+        if let Some(addr) = args.plugin_bind {
+            match ss_rs::net::lookup_host(&addr).await {
+                Ok(addr) => plugin_bind = Some(addr),
+                Err(e) => {
+                    log::error!("Resolve {} failed: {}", addr, e);
+                    return;
+                }
+            };
+        }
+
         let (addr, process) = match start_plugin(
             &plugin_name,
             &args.plugin_opts.unwrap_or_default(),
             remote_addr,
+            plugin_bind,
             is_server,
         ) {
             Ok(res) => res,
@@ -93,6 +497,48 @@ async fn main() {
         plugin = Some(process);
     }
 
+    if ctx.remote_pool().is_some() {
+        tokio::spawn(remote::check_health(ctx.clone()));
+    }
+
+    if let Some(path) = args.dump_metrics {
+        tokio::spawn(dump_metrics(path, ctx.clone()));
+    }
+
+    if let Some(ref path) = args.replay_state {
+        tokio::spawn(
+            ctx.clone()
+                .flush_replay(path.clone(), REPLAY_FLUSH_INTERVAL),
+        );
+    }
+
+    if let Some(secs) = args.summary_interval {
+        tokio::spawn(log_summary(Duration::from_secs(secs), ctx.clone()));
+    }
+
+    #[cfg(feature = "admin")]
+    if let Some(addr) = args.admin {
+        if !addr.ip().is_loopback() {
+            log::warn!(
+                "Admin endpoint on {} has no authentication, prefer a loopback address",
+                addr
+            );
+        }
+
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = ss_rs::admin::serve(addr, ctx).await {
+                log::error!("Unable to serve admin endpoint: {}", e);
+            }
+        });
+    }
+
+    #[cfg(not(feature = "admin"))]
+    if args.admin.is_some() {
+        log::error!("Admin endpoint is not supported, rebuild with `--features admin`");
+        return;
+    }
+
     // 5. Starts shadowsocks server
     if let Some(local_addr) = local_addr {
         tokio::select! {
@@ -105,14 +551,34 @@ async fn main() {
 
                 return;
             }
-            res = ss_local(local_addr, remote_addr, method, key, ctx) => {
+            res = ss_redir(local_addr, remote_addr, method, key.clone(), ctx.clone()), if transparent => {
+                match res {
+                    Ok(_) => {}
+                    Err(e) => log::error!("Unable to start ss-redir: {}", e),
+                }
+            }
+            res = async { ss_tunnel(local_addr, tunnel_addr.unwrap(), remote_addr, method, key.clone(), ctx.clone()).await }, if tunnel => {
+                match res {
+                    Ok(_) => {}
+                    Err(e) => log::error!("Unable to start ss-tunnel: {}", e),
+                }
+            }
+            res = ss_local(local_addr, remote_addr, method, key.clone(), ctx.clone()), if !transparent && !tunnel => {
                 match res {
                     Ok(_) => {}
                     Err(e) => log::error!("Unable to start ss-local: {}", e),
                 }
             }
+            res = ss_local_udp(local_addr, remote_addr, method, key.clone(), ctx.clone()), if udp => {
+                match res {
+                    Ok(_) => {}
+                    Err(e) => log::error!("Unable to start ss-local udp relay: {}", e),
+                }
+            }
         }
     } else {
+        let servers = [vec![(remote_addr, method, key)], extra_servers].concat();
+
         tokio::select! {
             _ = tokio::signal::ctrl_c() => {}
             res = async { plugin.as_mut().map(|p| p.wait()).unwrap().await }, if plugin.is_some() => {
@@ -123,18 +589,75 @@ async fn main() {
 
                 return;
             }
-            res = ss_remote(remote_addr, method, key, ctx) => {
+            res = ss_remote(servers.clone(), ctx.clone()) => {
                 match res {
                     Ok(_) => {}
                     Err(e) => log::error!("Unable to start ss-remote: {}", e),
                 }
             }
+            res = ss_remote_udp(servers, ctx.clone()), if udp => {
+                match res {
+                    Ok(_) => {}
+                    Err(e) => log::error!("Unable to start ss-remote udp relay: {}", e),
+                }
+            }
+            res = async { manager::serve(args.manager_address.unwrap(), manager_ip, method, ctx.clone()).await }, if args.manager_address.is_some() => {
+                match res {
+                    Ok(_) => {}
+                    Err(e) => log::error!("Unable to start manager: {}", e),
+                }
+            }
+        }
+    }
+
+    // The listeners are closed, the plugin is still needed by the remaining connections.
+    ctx.shutdown();
+    let shutdown_timeout = args.shutdown_timeout.map(Duration::from_secs);
+    drain(&ctx, shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT)).await;
+
+    if let Some(path) = args.replay_state {
+        match ctx.save_replay(&path) {
+            Ok(_) => log::info!("Saved replay protection to {}", path.display()),
+            Err(e) => log::error!("Save replay protection to {} failed: {}", path.display(), e),
         }
     }
 
     kill_plugin(plugin).await;
 }
 
+/// Decodes the master key of the method given verbatim, base64 or hex encoded.
+fn decode_master_key(method: Method, encoded: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut key = vec![0u8; method.key_size()];
+    decode_key(encoded, &mut key)?;
+
+    Ok(key)
+}
+
+/// Waits for the active connections to finish, at most the timeout or until ctrl-c again.
+async fn drain(ctx: &Ctx, timeout: Duration) {
+    let active = ctx.stats_snapshot().active_connections;
+    if active == 0 {
+        return;
+    }
+
+    log::info!(
+        "Waiting up to {}s for {} active connections, ctrl-c again to exit now",
+        timeout.as_secs(),
+        active
+    );
+
+    tokio::select! {
+        res = tokio::time::timeout(timeout, ctx.drained()) => match res {
+            Ok(_) => log::info!("All connections finished"),
+            Err(_) => log::warn!(
+                "Exiting with {} active connections",
+                ctx.stats_snapshot().active_connections
+            ),
+        },
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
 fn init_logger(verbose: bool) {
     let fallback_filter = match verbose {
         true => "ss_rs=debug",
@@ -161,6 +684,87 @@ fn init_logger(verbose: bool) {
         .init();
 }
 
+/// Writes the statistics as JSON to the given path, or stdout if it is `-`, on every SIGUSR1.
+#[cfg(unix)]
+async fn dump_metrics(path: PathBuf, ctx: Arc<Ctx>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signal = match signal(SignalKind::user_defined1()) {
+        Ok(signal) => signal,
+        Err(e) => {
+            log::error!("Unable to listen for SIGUSR1: {}", e);
+            return;
+        }
+    };
+
+    while signal.recv().await.is_some() {
+        let json = match serde_json::to_string(&ctx.stats_snapshot()) {
+            Ok(json) => json,
+            Err(e) => {
+                log::error!("Serialize statistics failed: {}", e);
+                continue;
+            }
+        };
+
+        let res = match path.to_str() {
+            Some("-") => writeln!(std::io::stdout(), "{}", json),
+            _ => tokio::fs::write(&path, json + "\n").await,
+        };
+
+        match res {
+            Ok(_) => log::debug!("Dumped statistics to {}", path.display()),
+            Err(e) => log::error!("Dump statistics to {} failed: {}", path.display(), e),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn dump_metrics(_path: PathBuf, _ctx: Arc<Ctx>) {
+    log::warn!("Dumping statistics on signal is only supported on unix");
+}
+
+/// Logs the increase of the statistics over every interval.
+async fn log_summary(period: Duration, ctx: Arc<Ctx>) {
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    let mut last = ctx.stats_snapshot();
+
+    loop {
+        interval.tick().await;
+
+        let now = ctx.stats_snapshot();
+        let delta = now.since(&last);
+        last = now;
+
+        log::info!(
+            "In the last {}s: {} connections ({} active), {} up, {} down, {} rejected",
+            period.as_secs(),
+            delta.connections,
+            delta.active_connections,
+            format_bytes(delta.bytes_up),
+            format_bytes(delta.bytes_down),
+            delta.rejected
+        );
+    }
+}
+
+/// Formats a number of bytes with a decimal unit, e.g. `3.2 GB`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KB", "MB", "GB", "TB", "PB"];
+
+    if bytes < 1000 {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
 async fn kill_plugin(process: Option<Child>) {
     if let Some(mut child) = process {
         match child.kill().await {
@@ -0,0 +1,362 @@
+//! Manager of ss-remote ports, speaking the shadowsocks manager protocol over UDP.
+//!
+//! Each datagram is a command, answered by one datagram:
+//!
+//! - `add: {"server_port": 8381, "password": "foobar", "method": "aes-256-gcm"}`:
+//!   listens on the port, with the method of ss-remote if none is given.
+//!   Replies `ok`, or `err` if the port is already added or can't be bound.
+//! - `remove: {"server_port": 8381}`: stops listening on the port, replies `ok` or `err`.
+//! - `ping`: replies `stat: {"8381": 1024}`, the bytes relayed by each added port.
+//! - `list`: replies the added ports as a JSON array.
+//!
+//! Added ports only serve TCP. Like the admin endpoint, the manager has no authentication,
+//! so it should only listen on a loopback address.
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use serde_json::{json, Map, Value};
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+use crate::{
+    context::Ctx,
+    crypto::{cipher::Method, master_key},
+    tcp::{serve_remote, SsTcpListener},
+};
+
+mod constants {
+    pub const MAX_COMMAND_SIZE: usize = 4096;
+}
+
+/// A command of the manager protocol.
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Add {
+        port: u16,
+        password: String,
+        method: Option<Method>,
+    },
+    Remove {
+        port: u16,
+    },
+    Ping,
+    List,
+}
+
+/// A port added by the manager.
+struct Port {
+    method: Method,
+    password: String,
+    task: JoinHandle<()>,
+}
+
+/// Serves the manager on the given address forever.
+///
+/// Added ports are bound on `ip`, with `method` if the command doesn't name one.
+pub async fn serve(addr: SocketAddr, ip: IpAddr, method: Method, ctx: Arc<Ctx>) -> io::Result<()> {
+    let socket = UdpSocket::bind(addr).await?;
+    log::info!("Manager listening on {}", addr);
+
+    let mut manager = Manager {
+        ip,
+        method,
+        ports: HashMap::new(),
+        ctx,
+    };
+
+    let mut buf = vec![0u8; constants::MAX_COMMAND_SIZE];
+    loop {
+        let (n, peer) = socket.recv_from(&mut buf).await?;
+
+        let reply = match parse_command(&buf[..n]) {
+            Ok(command) => manager.execute(command).await,
+            Err(e) => {
+                log::debug!("Invalid manager command: {}, peer {}", e, peer);
+                "err".to_owned()
+            }
+        };
+
+        if let Err(e) = socket.send_to(reply.as_bytes(), peer).await {
+            log::debug!("Reply to manager command failed: {}, peer {}", e, peer);
+        }
+    }
+}
+
+struct Manager {
+    ip: IpAddr,
+    method: Method,
+    ports: HashMap<u16, Port>,
+    ctx: Arc<Ctx>,
+}
+
+impl Manager {
+    /// Executes the command, returning the reply.
+    async fn execute(&mut self, command: Command) -> String {
+        match command {
+            Command::Add {
+                port,
+                password,
+                method,
+            } => match self
+                .add(port, password, method.unwrap_or(self.method))
+                .await
+            {
+                Ok(_) => "ok".to_owned(),
+                Err(e) => {
+                    log::error!("Unable to add port {}: {}", port, e);
+                    "err".to_owned()
+                }
+            },
+            Command::Remove { port } => match self.remove(port) {
+                true => "ok".to_owned(),
+                false => "err".to_owned(),
+            },
+            Command::Ping => self.stat(),
+            Command::List => self.list(),
+        }
+    }
+
+    async fn add(&mut self, port: u16, password: String, method: Method) -> io::Result<()> {
+        if self.ports.contains_key(&port) {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                "port is already added",
+            ));
+        }
+
+        let key = master_key(method, &password)?;
+        let addr = SocketAddr::new(self.ip, port);
+        let listener = SsTcpListener::bind(addr, method, &key, self.ctx.clone()).await?;
+        let addr = listener.local_addr()?;
+        log::info!("ss-remote listening on {} ({})", addr, method);
+        self.ctx.add_listen_addr(addr);
+
+        self.ctx.stats().add_port(port);
+        let task = tokio::spawn(serve_remote(listener, self.ctx.clone()));
+        self.ports.insert(
+            port,
+            Port {
+                method,
+                password,
+                task,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn remove(&mut self, port: u16) -> bool {
+        let removed = match self.ports.remove(&port) {
+            Some(removed) => removed,
+            None => return false,
+        };
+
+        // Stops accepting, the established connections of the port are kept but not counted.
+        removed.task.abort();
+        self.ctx.stats().remove_port(port);
+        log::info!("ss-remote stopped listening on port {}", port);
+
+        true
+    }
+
+    fn stat(&self) -> String {
+        let bytes = self.ctx.stats().port_bytes();
+        let stat: Map<String, Value> = self
+            .ports
+            .keys()
+            .map(|port| {
+                (
+                    port.to_string(),
+                    bytes.get(port).copied().unwrap_or(0).into(),
+                )
+            })
+            .collect();
+
+        format!("stat: {}", Value::Object(stat))
+    }
+
+    fn list(&self) -> String {
+        let mut ports: Vec<_> = self.ports.iter().collect();
+        ports.sort_by_key(|(port, _)| **port);
+
+        let list: Vec<_> = ports
+            .into_iter()
+            .map(|(port, x)| {
+                json!({
+                    "server_port": port,
+                    "password": x.password,
+                    "method": x.method.to_string(),
+                })
+            })
+            .collect();
+
+        Value::Array(list).to_string()
+    }
+}
+
+/// Parses a command: a name, and for `add` and `remove` a JSON object after a colon.
+fn parse_command(data: &[u8]) -> io::Result<Command> {
+    let invalid = |msg: &str| io::Error::new(ErrorKind::InvalidData, msg.to_owned());
+
+    let data = std::str::from_utf8(data).map_err(|_| invalid("command isn't utf-8"))?;
+    let (name, body) = match data.split_once(':') {
+        Some((name, body)) => (name.trim(), Some(body.trim())),
+        None => (data.trim(), None),
+    };
+
+    match (name, body) {
+        ("ping", _) => Ok(Command::Ping),
+        ("list", _) => Ok(Command::List),
+        ("add", Some(body)) => {
+            let body: Value = serde_json::from_str(body)?;
+            let password = body["password"]
+                .as_str()
+                .ok_or_else(|| invalid("password is missing"))?;
+            let method = match body["method"].as_str() {
+                Some(method) => Some(
+                    method
+                        .parse()
+                        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?,
+                ),
+                None => None,
+            };
+
+            Ok(Command::Add {
+                port: parse_port(&body["server_port"])?,
+                password: password.to_owned(),
+                method,
+            })
+        }
+        ("remove", Some(body)) => {
+            let body: Value = serde_json::from_str(body)?;
+
+            Ok(Command::Remove {
+                port: parse_port(&body["server_port"])?,
+            })
+        }
+        _ => Err(invalid("unknown command")),
+    }
+}
+
+/// Parses a port, given as a number or a string.
+fn parse_port(value: &Value) -> io::Result<u16> {
+    let port = match value {
+        Value::Number(port) => port.as_u64().and_then(|x| u16::try_from(x).ok()),
+        Value::String(port) => port.parse().ok(),
+        _ => None,
+    };
+
+    port.ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "invalid server_port"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::stats::Stats;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command(b"ping").unwrap(), Command::Ping);
+        assert_eq!(parse_command(b"list\n").unwrap(), Command::List);
+
+        assert_eq!(
+            parse_command(br#"add: {"server_port": 8381, "password": "foobar"}"#).unwrap(),
+            Command::Add {
+                port: 8381,
+                password: "foobar".to_owned(),
+                method: None,
+            }
+        );
+        assert_eq!(
+            parse_command(br#"add:{"server_port":"8381","password":"x","method":"aes-256-gcm"}"#)
+                .unwrap(),
+            Command::Add {
+                port: 8381,
+                password: "x".to_owned(),
+                method: Some(Method::Aes256Gcm),
+            }
+        );
+        assert_eq!(
+            parse_command(br#"remove: {"server_port": 8381}"#).unwrap(),
+            Command::Remove { port: 8381 }
+        );
+
+        assert!(parse_command(b"add").is_err());
+        assert!(parse_command(br#"add: {"server_port": 8381}"#).is_err());
+        assert!(parse_command(br#"add: {"server_port": 65536, "password": "x"}"#).is_err());
+        assert!(
+            parse_command(br#"add: {"server_port": 1, "password": "x", "method": "x"}"#).is_err()
+        );
+        assert!(parse_command(br#"remove: {}"#).is_err());
+        assert!(parse_command(b"stat").is_err());
+    }
+
+    async fn request(client: &UdpSocket, command: &str) -> String {
+        let mut buf = [0u8; 1024];
+        client.send(command.as_bytes()).await.unwrap();
+        let n = client.recv(&mut buf).await.unwrap();
+
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let manager_addr = UdpSocket::bind(addr).await.unwrap().local_addr().unwrap();
+        let port = std::net::TcpListener::bind(addr)
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let ctx = Arc::new(Ctx::new());
+        tokio::spawn(serve(
+            manager_addr,
+            addr.ip(),
+            Method::Aes128Gcm,
+            ctx.clone(),
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let client = UdpSocket::bind(addr).await.unwrap();
+        client.connect(manager_addr).await.unwrap();
+
+        let add = format!(r#"add: {{"server_port": {}, "password": "foobar"}}"#, port);
+        assert_eq!(request(&client, &add).await, "ok");
+        assert_eq!(request(&client, &add).await, "err");
+        assert!(tokio::net::TcpStream::connect((addr.ip(), port))
+            .await
+            .is_ok());
+
+        Stats::add(&ctx.stats().port_counter(port).unwrap(), 1024);
+        assert_eq!(
+            request(&client, "ping").await,
+            format!(r#"stat: {{"{}":1024}}"#, port)
+        );
+        assert_eq!(
+            request(&client, "list").await,
+            format!(
+                r#"[{{"method":"aes-128-gcm","password":"foobar","server_port":{}}}]"#,
+                port
+            )
+        );
+
+        let remove = format!(r#"remove: {{"server_port": {}}}"#, port);
+        assert_eq!(request(&client, &remove).await, "ok");
+        assert_eq!(request(&client, &remove).await, "err");
+        assert_eq!(request(&client, "ping").await, "stat: {}");
+
+        // Added again, without the bytes counted before
+        assert_eq!(request(&client, &add).await, "ok");
+        assert_eq!(
+            request(&client, "ping").await,
+            format!(r#"stat: {{"{}":0}}"#, port)
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! Conversion of internationalized domain names to the ascii form resolvers expect.
+//!
+//! Only the ToASCII step is done: labels are lowercased and punycode encoded (RFC 3492).
+//! The full UTS #46 mapping and normalization are not, names are expected to be given
+//! in their usual form.
+
+use std::io;
+
+mod constants {
+    pub const BASE: u32 = 36;
+    pub const TMIN: u32 = 1;
+    pub const TMAX: u32 = 26;
+    pub const SKEW: u32 = 38;
+    pub const DAMP: u32 = 700;
+    pub const INITIAL_BIAS: u32 = 72;
+    pub const INITIAL_N: u32 = 128;
+
+    pub const ACE_PREFIX: &str = "xn--";
+    pub const MAX_LABEL_LEN: usize = 63;
+    pub const MAX_DOMAIN_LEN: usize = 253;
+
+    /// The full stops of other scripts, label separators like `.`.
+    pub const DOTS: [char; 4] = ['.', '\u{3002}', '\u{ff0e}', '\u{ff61}'];
+}
+
+/// Converts a domain name to ascii, encoding its non-ascii labels as `xn--` punycode.
+///
+/// Ascii domain names are returned unchanged.
+pub fn to_ascii(domain: &str) -> io::Result<String> {
+    if domain.is_ascii() {
+        return Ok(domain.to_owned());
+    }
+
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid domain name");
+
+    let mut labels = Vec::new();
+    for label in domain.split(constants::DOTS) {
+        let label = label.to_lowercase();
+        let label = match label.is_ascii() {
+            true => label,
+            false => format!(
+                "{}{}",
+                constants::ACE_PREFIX,
+                encode(&label).ok_or_else(invalid)?
+            ),
+        };
+
+        if label.len() > constants::MAX_LABEL_LEN {
+            return Err(invalid());
+        }
+        labels.push(label);
+    }
+
+    let ascii = labels.join(".");
+    match ascii.trim_end_matches('.').len() {
+        0..=constants::MAX_DOMAIN_LEN => Ok(ascii),
+        _ => Err(invalid()),
+    }
+}
+
+/// Encodes a label with punycode, returning None on overflow.
+fn encode(label: &str) -> Option<String> {
+    let input: Vec<u32> = label.chars().map(u32::from).collect();
+
+    let mut output: String = label.chars().filter(char::is_ascii).collect();
+    let basic = output.len() as u32;
+    if basic > 0 {
+        output.push('-');
+    }
+
+    let mut n = constants::INITIAL_N;
+    let mut delta: u32 = 0;
+    let mut bias = constants::INITIAL_BIAS;
+    let mut handled = basic;
+
+    while (handled as usize) < input.len() {
+        let m = input.iter().copied().filter(|&c| c >= n).min()?;
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+
+        for &c in &input {
+            if c < n {
+                delta = delta.checked_add(1)?;
+            }
+            if c != n {
+                continue;
+            }
+
+            // Delta as a variable-length integer, see RFC 3492 section 3.3
+            let mut q = delta;
+            let mut k = constants::BASE;
+            loop {
+                let t = threshold(k, bias);
+                if q < t {
+                    break;
+                }
+                output.push(digit(t + (q - t) % (constants::BASE - t)));
+                q = (q - t) / (constants::BASE - t);
+                k += constants::BASE;
+            }
+            output.push(digit(q));
+
+            bias = adapt(delta, handled + 1, handled == basic);
+            delta = 0;
+            handled += 1;
+        }
+
+        delta = delta.checked_add(1)?;
+        n += 1;
+    }
+
+    Some(output)
+}
+
+fn threshold(k: u32, bias: u32) -> u32 {
+    if k <= bias {
+        constants::TMIN
+    } else if k >= bias + constants::TMAX {
+        constants::TMAX
+    } else {
+        k - bias
+    }
+}
+
+fn adapt(delta: u32, num_points: u32, first: bool) -> u32 {
+    let mut delta = if first {
+        delta / constants::DAMP
+    } else {
+        delta / 2
+    };
+    delta += delta / num_points;
+
+    let mut k = 0;
+    while delta > ((constants::BASE - constants::TMIN) * constants::TMAX) / 2 {
+        delta /= constants::BASE - constants::TMIN;
+        k += constants::BASE;
+    }
+
+    k + (constants::BASE - constants::TMIN + 1) * delta / (delta + constants::SKEW)
+}
+
+fn digit(d: u32) -> char {
+    match d {
+        0..=25 => (b'a' + d as u8) as char,
+        _ => (b'0' + (d - 26) as u8) as char,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        // Samples of RFC 3492 section 7.1
+        assert_eq!(
+            encode("3年B組金八先生").unwrap(),
+            "3B-ww4c5e180e575a65lsy2b"
+        );
+        assert_eq!(
+            encode("Hello-Another-Way-それぞれの場所").unwrap(),
+            "Hello-Another-Way--fc4qua05auwb3674vfr0b"
+        );
+        assert_eq!(encode("ひとつ屋根の下2").unwrap(), "2-u9tlzr9756bt3uc0v");
+        assert_eq!(encode("bücher").unwrap(), "bcher-kva");
+    }
+
+    #[test]
+    fn test_to_ascii() {
+        assert_eq!(to_ascii("example.com").unwrap(), "example.com");
+        assert_eq!(to_ascii("Example.COM").unwrap(), "Example.COM");
+        assert_eq!(to_ascii("Bücher.example").unwrap(), "xn--bcher-kva.example");
+        assert_eq!(to_ascii("例え.テスト").unwrap(), "xn--r8jz45g.xn--zckzah");
+
+        // Mixed scripts in a label and across labels, with an ideographic full stop
+        assert_eq!(
+            to_ascii("Hello-Another-Way-それぞれの場所。mañana.com").unwrap(),
+            "xn--hello-another-way--fc4qua05auwb3674vfr0b.xn--maana-pta.com"
+        );
+
+        let long = format!("{}é", "a".repeat(63));
+        assert_eq!(
+            to_ascii(&long).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+}
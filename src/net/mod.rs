@@ -1,6 +1,11 @@
 //! Networking facilities for shadowsocks communication.
 
+pub mod idna;
+pub mod packet;
+pub mod pool;
+pub mod resolver;
 pub mod stream;
+pub mod sys;
 
 mod buf;
 
@@ -20,13 +25,16 @@ use crate::net::buf::OwnedReadBuf;
 pub mod constants {
     /// The maximum payload size of shadowsocks.
     pub const MAXIMUM_PAYLOAD_SIZE: usize = 0x3FFF;
-    // const MAXIMUM_TAG_SIZE: usize = 16;
-    // const MAXIMUM_MESSAGE_SIZE: usize = 2 + MAXIMUM_PAYLOAD_SIZE + 2 * MAXIMUM_TAG_SIZE;
+    /// The maximum tag size of the supported ciphers.
+    pub const MAXIMUM_TAG_SIZE: usize = 16;
+    /// The maximum size of an encrypted chunk, its length and payload.
+    pub const MAXIMUM_MESSAGE_SIZE: usize = 2 + MAXIMUM_PAYLOAD_SIZE + 2 * MAXIMUM_TAG_SIZE;
 }
 
 /// Resolves target socket address.
 ///
-/// Returns the first resolved ipv4 socket address.
+/// Returns the first resolved ipv4 socket address. It is only used at startup,
+/// connections resolve their targets with the cached [`resolver::Resolver`] of the context.
 pub async fn lookup_host(host: &str) -> io::Result<SocketAddr> {
     tokio::net::lookup_host(host)
         .await?
@@ -0,0 +1,89 @@
+//! Shadowsocks AEAD packets, for the udp relay.
+//!
+//! Every packet is `[salt][encrypted payload][tag]`: the payload is encrypted with a subkey
+//! derived from the salt of the packet, and a zero nonce.
+
+use std::io;
+
+use rand::RngCore;
+
+use crate::{
+    crypto::{
+        cipher::{Cipher, Method},
+        hkdf_sha1, Nonce,
+    },
+    net::stream::Error,
+};
+
+/// Encrypts a payload into a packet, with a random salt.
+pub fn encrypt(method: Method, key: &[u8], payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut packet = vec![0u8; method.salt_size()];
+    rand::thread_rng().fill_bytes(&mut packet);
+
+    let cipher = cipher(method, key, &packet)?;
+    match cipher.encrypt(&Nonce::new(method.iv_size()), payload) {
+        Ok(mut data) => {
+            packet.append(&mut data);
+            Ok(packet)
+        }
+        Err(_) => Err(io::Error::new(io::ErrorKind::Other, Error::Encryption)),
+    }
+}
+
+/// Decrypts the payload of a packet.
+pub fn decrypt(method: Method, key: &[u8], packet: &[u8]) -> io::Result<Vec<u8>> {
+    let salt_size = method.salt_size();
+    if packet.len() < salt_size {
+        return Err(io::Error::new(io::ErrorKind::Other, Error::Decryption));
+    }
+
+    let cipher = cipher(method, key, &packet[..salt_size])?;
+    match cipher.decrypt(&Nonce::new(method.iv_size()), &packet[salt_size..]) {
+        Ok(data) => Ok(data),
+        Err(_) => Err(io::Error::new(io::ErrorKind::Other, Error::Decryption)),
+    }
+}
+
+fn cipher(method: Method, key: &[u8], salt: &[u8]) -> io::Result<Cipher> {
+    let mut subkey = vec![0u8; method.key_size()];
+    hkdf_sha1(key, salt, &mut subkey)?;
+
+    Ok(Cipher::new(method, &subkey)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet() {
+        for method in [
+            Method::ChaCha20Poly1305,
+            Method::XChaCha20Poly1305,
+            Method::Aes128Gcm,
+            Method::Aes192Gcm,
+            Method::Aes256Gcm,
+        ] {
+            let key = vec![7u8; method.key_size()];
+            let payload = b"hello";
+
+            let packet = encrypt(method, &key, payload).unwrap();
+            assert_eq!(
+                packet.len(),
+                method.salt_size() + payload.len() + method.tag_size()
+            );
+            assert_eq!(decrypt(method, &key, &packet).unwrap(), payload);
+
+            // Salts are random
+            assert_ne!(encrypt(method, &key, payload).unwrap(), packet);
+
+            let mut tampered = packet.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert!(decrypt(method, &key, &tampered).is_err());
+
+            let wrong_key = vec![8u8; method.key_size()];
+            assert!(decrypt(method, &wrong_key, &packet).is_err());
+            assert!(decrypt(method, &key, &packet[..method.salt_size() - 1]).is_err());
+        }
+    }
+}
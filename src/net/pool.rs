@@ -0,0 +1,97 @@
+//! Buffer pool shared across connections.
+
+use std::sync::Mutex;
+
+/// A pool of byte buffers, reused across connections to cut allocations.
+///
+/// Buffers are handed out empty, with a capacity of at least the buffer size.
+/// At most `max_buffers` returned buffers are kept, the others are freed.
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    buffer_size: usize,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    /// Creates a new empty pool of buffers of `buffer_size` bytes, keeping at most `max_buffers`.
+    pub fn new(buffer_size: usize, max_buffers: usize) -> Self {
+        BufferPool {
+            buffers: Mutex::new(Vec::new()),
+            buffer_size,
+            max_buffers,
+        }
+    }
+
+    /// Takes a buffer from the pool, or allocates a new one if the pool is empty.
+    pub fn get(&self) -> Vec<u8> {
+        match self.buffers.lock().unwrap().pop() {
+            Some(buf) => buf,
+            None => Vec::with_capacity(self.buffer_size),
+        }
+    }
+
+    /// Returns a buffer to the pool.
+    ///
+    /// It is freed instead if the pool is full, or if it is too small or has grown
+    /// much larger than the buffer size.
+    pub fn put(&self, mut buf: Vec<u8>) {
+        let capacity = buf.capacity();
+        if capacity < self.buffer_size || capacity > 2 * self.buffer_size {
+            return;
+        }
+
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buf.clear();
+            buffers.push(buf);
+        }
+    }
+
+    /// Returns the number of buffers in the pool.
+    pub fn len(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+
+    /// Returns true if there are no buffers in the pool.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool() {
+        let pool = BufferPool::new(16, 2);
+        assert!(pool.is_empty());
+
+        let mut buf = pool.get();
+        assert!(buf.capacity() >= 16);
+        buf.extend_from_slice(b"hello");
+        let ptr = buf.as_ptr();
+
+        pool.put(buf);
+        assert_eq!(pool.len(), 1);
+
+        // The same buffer, emptied
+        let buf = pool.get();
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+        assert!(pool.is_empty());
+
+        // At most 2 buffers are kept
+        pool.put(buf);
+        pool.put(pool.get());
+        pool.put(Vec::with_capacity(16));
+        pool.put(Vec::with_capacity(16));
+        assert_eq!(pool.len(), 2);
+
+        // Too small or too large buffers aren't kept
+        let pool = BufferPool::new(16, 2);
+        pool.put(Vec::with_capacity(8));
+        pool.put(Vec::with_capacity(64));
+        assert!(pool.is_empty());
+    }
+}
@@ -0,0 +1,460 @@
+//! Domain name resolution with caching and per-host rotation, and cached reverse lookups.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::net::sys;
+
+mod constants {
+    use std::time::Duration;
+
+    pub const DEFAULT_MAX_ADDRS: usize = 1;
+    pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+    pub const MAX_CACHED_HOSTS: usize = 4096;
+    pub const MAX_ROTATED_HOSTS: usize = 4096;
+    pub const MAX_CACHED_PTRS: usize = 4096;
+    pub const PTR_TTL: Duration = Duration::from_secs(300);
+    pub const NEGATIVE_PTR_TTL: Duration = Duration::from_secs(30);
+}
+
+/// Address families resolved hosts are connected to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IpFamily {
+    /// Only ipv4 addresses.
+    #[default]
+    V4Only,
+
+    /// Only ipv6 addresses.
+    V6Only,
+
+    /// Ipv4 addresses first, then ipv6 ones.
+    PreferV4,
+
+    /// Ipv6 addresses first, then ipv4 ones.
+    PreferV6,
+
+    /// Addresses of both families, in the order of the system resolver.
+    System,
+}
+
+impl IpFamily {
+    /// Returns the addresses of this family, preferred ones first, otherwise in order.
+    fn select(self, addrs: impl Iterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+        let addrs: Vec<SocketAddr> = addrs.collect();
+        let v4 = || addrs.iter().copied().filter(SocketAddr::is_ipv4);
+        let v6 = || addrs.iter().copied().filter(SocketAddr::is_ipv6);
+
+        match self {
+            IpFamily::V4Only => v4().collect(),
+            IpFamily::V6Only => v6().collect(),
+            IpFamily::PreferV4 => v4().chain(v6()).collect(),
+            IpFamily::PreferV6 => v6().chain(v4()).collect(),
+            IpFamily::System => addrs,
+        }
+    }
+}
+
+/// A cached lookup result.
+struct CacheEntry {
+    addrs: Vec<SocketAddr>,
+    expires: Instant,
+    last_used: Instant,
+}
+
+/// A cached reverse lookup result, `None` if there was no domain name.
+struct PtrEntry {
+    host: Option<String>,
+    expires: Instant,
+}
+
+/// Resolves hosts, rotating successive lookups of the same host across its addresses.
+pub struct Resolver {
+    max_addrs: usize,
+    ip_family: IpFamily,
+    cache_ttl: Duration,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+    rotation: Mutex<HashMap<String, usize>>,
+    reverse: bool,
+    ptr_cache: Mutex<HashMap<IpAddr, PtrEntry>>,
+}
+
+impl Resolver {
+    /// Creates a new resolver that always returns the first address.
+    pub fn new() -> Self {
+        Resolver {
+            max_addrs: constants::DEFAULT_MAX_ADDRS,
+            ip_family: IpFamily::default(),
+            cache_ttl: constants::DEFAULT_CACHE_TTL,
+            cache: Mutex::new(HashMap::new()),
+            rotation: Mutex::new(HashMap::new()),
+            reverse: false,
+            ptr_cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets how many resolved addresses of a host are rotated across, at least 1.
+    pub fn set_max_addrs(&mut self, max_addrs: usize) {
+        self.max_addrs = max_addrs.max(1);
+    }
+
+    /// Sets the address families resolved hosts are connected to, only ipv4 by default.
+    pub fn set_ip_family(&mut self, ip_family: IpFamily) {
+        self.ip_family = ip_family;
+    }
+
+    /// Sets how long lookup results are cached, 60 seconds by default, zero disables the cache.
+    ///
+    /// The cache holds up to 4096 hosts, evicting the least recently used one when full.
+    pub fn set_cache_ttl(&mut self, cache_ttl: Duration) {
+        self.cache_ttl = cache_ttl;
+    }
+
+    /// Sets whether [`Resolver::reverse`] looks up domain names, disabled by default.
+    pub fn set_reverse(&mut self, reverse: bool) {
+        self.reverse = reverse;
+    }
+
+    /// Resolves target socket address.
+    ///
+    /// Returns one of the first `max_addrs` resolved socket addresses of the address family,
+    /// the next one on each call for the same host.
+    ///
+    /// The returned address is both matched against the access control list and connected to,
+    /// so they never disagree on the address family.
+    pub async fn resolve(&self, host: &str) -> io::Result<SocketAddr> {
+        self.resolve_all(host).await.map(|addrs| addrs[0])
+    }
+
+    /// Resolves all socket addresses of the address family to race connections to them.
+    ///
+    /// The first one is the address [`Resolver::resolve`] returns, the others follow
+    /// alternating between the families, as recommended by RFC 8305.
+    /// The returned addresses are never empty.
+    pub async fn resolve_all(&self, host: &str) -> io::Result<Vec<SocketAddr>> {
+        let addrs = self.ip_family.select(self.lookup(host).await?.into_iter());
+        let first = self
+            .pick(host, &addrs[..addrs.len().min(self.max_addrs)])
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+
+        Ok(interleave(first, addrs))
+    }
+
+    /// Looks up all socket addresses of the host, from the cache if not expired.
+    async fn lookup(&self, host: &str) -> io::Result<Vec<SocketAddr>> {
+        // Ip addresses are resolved without any lookup.
+        if self.cache_ttl.is_zero() || host.parse::<SocketAddr>().is_ok() {
+            return Ok(tokio::net::lookup_host(host).await?.collect());
+        }
+
+        let now = Instant::now();
+        if let Some(entry) = self.cache.lock().unwrap().get_mut(host) {
+            if entry.expires > now {
+                entry.last_used = now;
+                return Ok(entry.addrs.clone());
+            }
+        }
+
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host(host).await?.collect();
+        if !addrs.is_empty() {
+            self.insert_cache(host, addrs.clone(), now);
+        }
+
+        Ok(addrs)
+    }
+
+    fn insert_cache(&self, host: &str, addrs: Vec<SocketAddr>, now: Instant) {
+        let mut cache = self.cache.lock().unwrap();
+
+        if cache.len() >= constants::MAX_CACHED_HOSTS && !cache.contains_key(host) {
+            cache.retain(|_, entry| entry.expires > now);
+        }
+
+        if cache.len() >= constants::MAX_CACHED_HOSTS && !cache.contains_key(host) {
+            let lru = cache
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(host, _)| host.clone());
+
+            if let Some(lru) = lru {
+                cache.remove(&lru);
+            }
+        }
+
+        let entry = CacheEntry {
+            addrs,
+            expires: now + self.cache_ttl,
+            last_used: now,
+        };
+        cache.insert(host.to_owned(), entry);
+    }
+
+    /// Returns the domain name of the given ip from a reverse DNS (PTR) lookup,
+    /// or `None` if there is none, the lookup failed or reverse lookups are disabled.
+    ///
+    /// Results are cached for 5 minutes, the lack of one for 30 seconds. The cache holds up to
+    /// 4096 ips, expired ones are dropped when it is full, then all of them if still full.
+    pub async fn reverse(&self, ip: IpAddr) -> Option<String> {
+        if !self.reverse {
+            return None;
+        }
+
+        if let Some(entry) = self.ptr_cache.lock().unwrap().get(&ip) {
+            if entry.expires > Instant::now() {
+                return entry.host.clone();
+            }
+        }
+
+        let host = match tokio::task::spawn_blocking(move || sys::reverse_lookup(ip)).await {
+            Ok(Ok(host)) => host,
+            Ok(Err(e)) => {
+                log::debug!("Reverse lookup {} failed: {}", ip, e);
+                None
+            }
+            Err(e) => {
+                log::debug!("Reverse lookup {} failed: {}", ip, e);
+                None
+            }
+        };
+
+        let now = Instant::now();
+        let ttl = match host {
+            Some(_) => constants::PTR_TTL,
+            None => constants::NEGATIVE_PTR_TTL,
+        };
+
+        let mut ptr_cache = self.ptr_cache.lock().unwrap();
+        if ptr_cache.len() >= constants::MAX_CACHED_PTRS && !ptr_cache.contains_key(&ip) {
+            ptr_cache.retain(|_, entry| entry.expires > now);
+        }
+        if ptr_cache.len() >= constants::MAX_CACHED_PTRS && !ptr_cache.contains_key(&ip) {
+            ptr_cache.clear();
+        }
+
+        let entry = PtrEntry {
+            host: host.clone(),
+            expires: now + ttl,
+        };
+        ptr_cache.insert(ip, entry);
+
+        host
+    }
+
+    fn pick(&self, host: &str, addrs: &[SocketAddr]) -> Option<SocketAddr> {
+        if addrs.len() <= 1 {
+            return addrs.first().copied();
+        }
+
+        let mut rotation = self.rotation.lock().unwrap();
+        if rotation.len() >= constants::MAX_ROTATED_HOSTS && !rotation.contains_key(host) {
+            rotation.clear();
+        }
+
+        let index = rotation.entry(host.to_owned()).or_insert(0);
+        let addr = addrs[*index % addrs.len()];
+        *index = index.wrapping_add(1);
+
+        Some(addr)
+    }
+}
+
+/// Orders the addresses after the first one alternating between the families,
+/// starting with the other family.
+fn interleave(first: SocketAddr, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (same, other): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .into_iter()
+        .filter(|x| *x != first)
+        .partition(|x| x.is_ipv4() == first.is_ipv4());
+    let (mut same, mut other) = (same.into_iter(), other.into_iter());
+
+    let mut addrs = vec![first];
+    loop {
+        match (other.next(), same.next()) {
+            (None, None) => break,
+            (a, b) => addrs.extend(a.into_iter().chain(b)),
+        }
+    }
+
+    addrs
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick() {
+        let resolver = Resolver::new();
+        let addrs: Vec<SocketAddr> = ["1.1.1.1:80", "2.2.2.2:80", "3.3.3.3:80"]
+            .iter()
+            .map(|x| x.parse().unwrap())
+            .collect();
+
+        assert_eq!(resolver.pick("a.com:80", &[]), None);
+        assert_eq!(resolver.pick("a.com:80", &addrs[..1]), Some(addrs[0]));
+
+        for i in 0..6 {
+            assert_eq!(resolver.pick("a.com:80", &addrs), Some(addrs[i % 3]));
+        }
+        assert_eq!(resolver.pick("b.com:80", &addrs), Some(addrs[0]));
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let mut resolver = Resolver::new();
+        resolver.set_max_addrs(4);
+
+        let addr = resolver.resolve("127.0.0.1:80").await.unwrap();
+        assert_eq!(addr, "127.0.0.1:80".parse().unwrap());
+    }
+
+    #[test]
+    fn test_interleave() {
+        let addrs: Vec<SocketAddr> = ["[::1]:80", "[::2]:80", "[::3]:80", "127.0.0.1:80"]
+            .iter()
+            .map(|x| x.parse().unwrap())
+            .collect();
+
+        assert_eq!(
+            interleave(addrs[1], addrs.clone()),
+            [addrs[1], addrs[3], addrs[0], addrs[2]]
+        );
+        assert_eq!(
+            interleave(addrs[3], addrs.clone()),
+            [addrs[3], addrs[0], addrs[1], addrs[2]]
+        );
+        assert_eq!(interleave(addrs[0], vec![addrs[0]]), [addrs[0]]);
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let resolver = Resolver::new();
+        let addr: SocketAddr = "1.2.3.4:80".parse().unwrap();
+        let now = Instant::now();
+
+        resolver.insert_cache("cached.invalid:80", vec![addr], now);
+        assert_eq!(resolver.resolve("cached.invalid:80").await.unwrap(), addr);
+
+        resolver
+            .cache
+            .lock()
+            .unwrap()
+            .get_mut("cached.invalid:80")
+            .unwrap()
+            .expires = now;
+        assert!(resolver.resolve("cached.invalid:80").await.is_err());
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        let resolver = Resolver::new();
+        let addrs = vec!["1.2.3.4:80".parse().unwrap()];
+        let now = Instant::now();
+
+        for i in 0..constants::MAX_CACHED_HOSTS {
+            let last_used = now + Duration::from_millis(i as u64);
+            resolver.insert_cache(&format!("{}.com:80", i), addrs.clone(), last_used);
+        }
+        let mut cache = resolver.cache.lock().unwrap();
+        cache.get_mut("0.com:80").unwrap().last_used = now + Duration::from_secs(1);
+        drop(cache);
+
+        resolver.insert_cache("new.com:80", addrs, now);
+
+        let cache = resolver.cache.lock().unwrap();
+        assert_eq!(cache.len(), constants::MAX_CACHED_HOSTS);
+        assert!(cache.contains_key("0.com:80"));
+        assert!(!cache.contains_key("1.com:80"));
+        assert!(cache.contains_key("new.com:80"));
+    }
+
+    #[test]
+    fn test_ip_family() {
+        let addrs: Vec<SocketAddr> = ["[::1]:80", "127.0.0.1:80", "[::2]:80", "127.0.0.2:80"]
+            .iter()
+            .map(|x| x.parse().unwrap())
+            .collect();
+        let select = |ip_family: IpFamily| ip_family.select(addrs.iter().copied());
+
+        assert_eq!(select(IpFamily::V4Only), [addrs[1], addrs[3]]);
+        assert_eq!(select(IpFamily::V6Only), [addrs[0], addrs[2]]);
+        assert_eq!(
+            select(IpFamily::PreferV4),
+            [addrs[1], addrs[3], addrs[0], addrs[2]]
+        );
+        assert_eq!(
+            select(IpFamily::PreferV6),
+            [addrs[0], addrs[2], addrs[1], addrs[3]]
+        );
+        assert_eq!(select(IpFamily::System), addrs);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_ip_family() {
+        let mut resolver = Resolver::new();
+        resolver.set_max_addrs(4);
+
+        for _ in 0..4 {
+            assert!(resolver.resolve("localhost:80").await.unwrap().is_ipv4());
+        }
+
+        resolver.set_ip_family(IpFamily::V6Only);
+        assert_eq!(
+            resolver.resolve("[::1]:80").await.unwrap(),
+            "[::1]:80".parse().unwrap()
+        );
+        assert!(resolver.resolve("127.0.0.1:80").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reverse() {
+        let ip = "127.0.0.1".parse().unwrap();
+
+        let mut resolver = Resolver::new();
+        assert_eq!(resolver.reverse(ip).await, None);
+
+        resolver.set_reverse(true);
+        let entry = |host: &str, expires| PtrEntry {
+            host: Some(host.to_owned()),
+            expires,
+        };
+        let later = Instant::now() + Duration::from_secs(60);
+        resolver
+            .ptr_cache
+            .lock()
+            .unwrap()
+            .insert(ip, entry("localhost", later));
+        assert_eq!(resolver.reverse(ip).await, Some("localhost".to_owned()));
+
+        // Expired results are looked up again
+        resolver
+            .ptr_cache
+            .lock()
+            .unwrap()
+            .insert(ip, entry("stale", Instant::now()));
+        assert_ne!(resolver.reverse(ip).await, Some("stale".to_owned()));
+
+        // A full cache drops the expired results first
+        {
+            let mut ptr_cache = resolver.ptr_cache.lock().unwrap();
+            ptr_cache.clear();
+            for i in 0..constants::MAX_CACHED_PTRS as u32 {
+                let expires = if i == 0 { later } else { Instant::now() };
+                let ip = IpAddr::from(std::net::Ipv6Addr::from(i as u128));
+                ptr_cache.insert(ip, entry("host", expires));
+            }
+        }
+        resolver.reverse(ip).await;
+        assert_eq!(resolver.ptr_cache.lock().unwrap().len(), 2);
+    }
+}
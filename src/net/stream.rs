@@ -1,31 +1,45 @@
 //! Shadowsocks streams.
 
 use std::{
+    borrow::Cow,
     fmt::{self, Display, Formatter},
-    io,
+    io::{self, IoSlice},
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use futures_core::{ready, Future};
+use miniz_oxide::{deflate::compress_to_vec, inflate::decompress_to_vec_with_limit};
 use pin_project_lite::pin_project;
+use rand::{rngs::StdRng, Rng, RngCore, SeedableRng};
 use tokio::{
-    io::{AsyncRead, AsyncWrite, ReadBuf},
+    io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf},
     time::{Instant, Sleep},
 };
 
 use crate::{
     context::Ctx,
     crypto::{
+        blake3_subkey,
         cipher::{Cipher, Method},
-        hkdf_sha1, Nonce,
+        hkdf_sha1, CryptoError, Nonce,
     },
     net::{buf::OwnedReadBuf, constants::MAXIMUM_PAYLOAD_SIZE, poll_read_exact},
+    security::ReplayCheck,
+    socks5::Socks5Addr,
+    stats::Stats,
 };
 
 /// A shadowsocks tcp stream.
+///
+/// A stream fails after [`Method::max_chunks`] chunks in a direction, before its key becomes
+/// unsafe. There is no way to rekey a stream, the connection has to be reopened.
+///
+/// With the 2022 methods (SIP022), the stream starts with a request header if it is written
+/// before being read, as ss-local does, and with a response header otherwise, as ss-remote does.
+/// The first write of a request must start with the target address.
 pub struct TcpStream<T> {
     inner_stream: T,
 
@@ -40,21 +54,61 @@ pub struct TcpStream<T> {
 
     incoming_salt: Option<Vec<u8>>, // for replay protection
 
+    // 2022 methods only: a response header carries the salt of its request.
+    sent_salt: Option<Vec<u8>>,
+    received_salt: Option<Vec<u8>>,
+
     read_state: ReadState,
     write_state: WriteState,
 
-    in_payload: Vec<u8>,  // decrypted payload
-    out_payload: Vec<u8>, // encrypted payload
+    // Both buffers hold at most one chunk: a new chunk is only read after `in_payload`
+    // has been drained, and `poll_write` only returns after `out_payload` has been flushed.
+    in_payload: Vec<u8>,  // decrypted payload, reused across chunks
+    in_pos: usize,        // how much of `in_payload` has been read
+    out_payload: Vec<u8>, // encrypted payload, reused across chunks
+    out_pos: usize,       // how much of `out_payload` has been written
+    max_payload_size: usize,
+
+    // Experimental and non-standard: every chunk starts with a flag byte telling
+    // whether the rest of it is deflate-compressed. Both ends must enable it.
+    compression: bool,
+    out_chunk: Vec<u8>, // compressed plaintext chunk
 
     read_buf: OwnedReadBuf,
 
+    rng: Box<dyn RngCore + Send>, // for salt generation
+
     ctx: Arc<Ctx>,
 }
 
 impl<T> TcpStream<T> {
     /// Creates a new shadowsocks tcp stream from a stream.
-    pub fn new(inner_stream: T, cipher_method: Method, cipher_key: &[u8], ctx: Arc<Ctx>) -> Self {
-        TcpStream {
+    ///
+    /// Fails with `InvalidInput` if the key isn't of the key size of the method.
+    pub fn new(
+        inner_stream: T,
+        cipher_method: Method,
+        cipher_key: &[u8],
+        ctx: Arc<Ctx>,
+    ) -> io::Result<Self> {
+        let rng = Box::new(StdRng::from_entropy());
+        Self::with_rng(inner_stream, cipher_method, cipher_key, ctx, rng)
+    }
+
+    /// Creates a new shadowsocks tcp stream from a stream,
+    /// generating the salt with the given random number generator.
+    ///
+    /// Mainly for tests, a seeded generator makes the output deterministic.
+    pub fn with_rng(
+        inner_stream: T,
+        cipher_method: Method,
+        cipher_key: &[u8],
+        ctx: Arc<Ctx>,
+        rng: Box<dyn RngCore + Send>,
+    ) -> io::Result<Self> {
+        cipher_method.validate_key(cipher_key)?;
+
+        Ok(TcpStream {
             inner_stream,
             cipher_method,
             cipher_key: cipher_key.to_owned(),
@@ -63,33 +117,107 @@ impl<T> TcpStream<T> {
             enc_nonce: Nonce::new(cipher_method.iv_size()),
             dec_nonce: Nonce::new(cipher_method.iv_size()),
             incoming_salt: None,
+            sent_salt: None,
+            received_salt: None,
             read_state: ReadState::ReadSalt,
             write_state: WriteState::WriteSalt,
-            in_payload: Vec::new(),
-            out_payload: Vec::new(),
+            in_payload: ctx.buffer_pool().get(),
+            in_pos: 0,
+            out_payload: ctx.buffer_pool().get(),
+            out_pos: 0,
+            max_payload_size: ctx.max_payload_size(),
+            // Not defined for the 2022 methods.
+            compression: ctx.compression() && !cipher_method.is_2022(),
+            out_chunk: Vec::new(),
             read_buf: OwnedReadBuf::new(),
-            ctx: ctx.clone(),
-        }
+            rng,
+            ctx,
+        })
+    }
+
+    /// Get the encryption method in use.
+    pub fn method(&self) -> Method {
+        self.cipher_method
+    }
+
+    /// Get the key size of the encryption method in use.
+    pub fn key_size(&self) -> usize {
+        self.cipher_method.key_size()
+    }
+
+    /// Get the salt size of the encryption method in use.
+    pub fn salt_size(&self) -> usize {
+        self.cipher_method.salt_size()
+    }
+
+    /// Get a reference to the underlying stream.
+    pub fn get_ref(&self) -> &T {
+        &self.inner_stream
+    }
+
+    /// Get a mutable reference to the underlying stream.
+    ///
+    /// Reading or writing it directly corrupts the shadowsocks stream.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner_stream
     }
 }
 
 impl<T> TcpStream<T> {
-    fn encrypt(&mut self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    /// Encrypts the plaintext into `out_payload`.
+    fn encrypt(&mut self, plaintext: &[u8]) -> io::Result<()> {
+        self.encrypt_vectored(&[IoSlice::new(plaintext)], plaintext.len())
+    }
+
+    /// Encrypts the first `len` bytes of the slices into `out_payload` as one chunk.
+    ///
+    /// The slices are copied straight into `out_payload` and encrypted there in place.
+    fn encrypt_vectored(&mut self, plaintext: &[IoSlice<'_>], len: usize) -> io::Result<()> {
+        if self.enc_nonce.reached(self.cipher_method.max_chunks()) {
+            return Err(chunk_limit());
+        }
+
+        let start = self.out_payload.len();
+        let mut remaining = len;
+        for buf in plaintext {
+            let n = usize::min(buf.len(), remaining);
+            self.out_payload.extend_from_slice(&buf[..n]);
+            remaining -= n;
+        }
+
         match self
             .enc_cipher
             .as_ref()
             .expect("no salt received")
-            .encrypt(&self.enc_nonce, plaintext)
+            .encrypt_tail(&self.enc_nonce, &mut self.out_payload, start)
         {
-            Ok(data) => {
-                self.enc_nonce.increment();
-                Ok(data)
-            }
+            Ok(_) => self.enc_nonce.increment().map_err(|_| chunk_limit()),
             Err(_) => Err(io::Error::new(io::ErrorKind::Other, Error::Encryption)),
         }
     }
 
+    /// Decrypts the chunk read into `in_payload` in place.
+    fn decrypt_in_payload(&mut self) -> io::Result<()> {
+        if self.dec_nonce.reached(self.cipher_method.max_chunks()) {
+            return Err(chunk_limit());
+        }
+
+        match self
+            .dec_cipher
+            .as_ref()
+            .expect("no salt received")
+            .decrypt_in_place(&self.dec_nonce, &mut self.in_payload)
+        {
+            Ok(_) => self.dec_nonce.increment().map_err(|_| chunk_limit()),
+            Err(_) => Err(self.decryption_error()),
+        }
+    }
+
     fn decrypt(&mut self, ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        if self.dec_nonce.reached(self.cipher_method.max_chunks()) {
+            return Err(chunk_limit());
+        }
+
         match self
             .dec_cipher
             .as_ref()
@@ -97,12 +225,31 @@ impl<T> TcpStream<T> {
             .decrypt(&self.dec_nonce, ciphertext)
         {
             Ok(data) => {
-                self.dec_nonce.increment();
+                self.dec_nonce.increment().map_err(|_| chunk_limit())?;
                 Ok(data)
             }
-            Err(_) => Err(io::Error::new(io::ErrorKind::Other, Error::Decryption)),
+            Err(_) => Err(self.decryption_error()),
         }
     }
+
+    /// Returns the error of a chunk failing to decrypt, telling apart the first chunk.
+    fn decryption_error(&self) -> io::Error {
+        Stats::incr(&self.ctx.stats().decryption_failures);
+
+        let first_chunk = self.dec_nonce.iter().all(|&x| x == 0);
+        let e = match first_chunk {
+            true => Error::FirstChunkDecryption,
+            false => Error::Decryption,
+        };
+
+        io::Error::new(io::ErrorKind::Other, e)
+    }
+
+    /// Returns the error of a replayed stream.
+    fn replay_error(&self, e: Error) -> io::Error {
+        Stats::incr(&self.ctx.stats().replays);
+        io::Error::new(io::ErrorKind::Other, e)
+    }
 }
 
 impl<T> TcpStream<T>
@@ -130,40 +277,75 @@ where
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
+        ready!(self.poll_fill_payload(cx))?;
+
+        let remaining = &self.in_payload[self.in_pos..];
+        let n = usize::min(buf.remaining(), remaining.len());
+        buf.put_slice(&remaining[..n]);
+        self.consume_payload(n);
+
+        Ok(()).into()
+    }
+
+    /// Reads chunks until `in_payload` has unconsumed payload.
+    fn poll_fill_payload(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         loop {
             match self.read_state {
                 ReadState::ReadSalt => {
                     ready!(self.poll_read_salt(cx))?;
-                    self.read_state = ReadState::ReadLength;
+                    self.read_state = match self.cipher_method.is_2022() {
+                        true => ReadState::ReadHeader,
+                        false => ReadState::ReadLength,
+                    };
+                }
+                ReadState::ReadHeader => {
+                    let len = ready!(self.poll_read_header(cx))?;
+                    self.read_state = match self.sent_salt {
+                        // A response, followed by its first chunk
+                        Some(_) => ReadState::ReadPayload(len),
+                        None => ReadState::ReadRequestHeader(len),
+                    };
+                }
+                ReadState::ReadRequestHeader(header_len) => {
+                    self.in_payload = ready!(self.poll_read_request_header(cx, header_len))?;
+                    self.read_state = ReadState::ReadPayloadOut;
                 }
                 ReadState::ReadLength => {
                     let len = ready!(self.poll_read_length(cx))?;
                     self.read_state = ReadState::ReadPayload(len);
                 }
                 ReadState::ReadPayload(payload_len) => {
-                    self.in_payload = ready!(self.poll_read_payload(cx, payload_len))?;
-                    self.read_state = ReadState::ReadPayloadOut;
-                }
-                ReadState::ReadPayloadOut => {
-                    let buf_remaining = buf.remaining();
-                    let payload_len = self.in_payload.len();
-
-                    if buf_remaining >= payload_len {
-                        buf.put_slice(&self.in_payload);
-                        self.read_state = ReadState::ReadLength;
-                    } else {
-                        let (data, remaining) = self.in_payload.split_at(buf_remaining);
-                        buf.put_slice(data);
-                        self.in_payload = remaining.to_owned();
-                        self.read_state = ReadState::ReadPayloadOut;
+                    ready!(self.poll_read_payload(cx, payload_len))?;
+                    if self.compression {
+                        self.in_payload = decompress_chunk(&self.in_payload)?;
                     }
 
-                    return Ok(()).into();
+                    // An empty chunk must not be mistaken for EOF.
+                    self.read_state = match self.in_payload.is_empty() {
+                        true => ReadState::ReadLength,
+                        false => ReadState::ReadPayloadOut,
+                    };
                 }
+                ReadState::ReadPayloadOut => return Ok(()).into(),
             }
         }
     }
 
+    /// Marks `amt` bytes of `in_payload` as read, and the next chunk to be read once all are.
+    fn consume_payload(&mut self, amt: usize) {
+        if !matches!(self.read_state, ReadState::ReadPayloadOut) {
+            return;
+        }
+
+        self.in_pos = usize::min(self.in_pos + amt, self.in_payload.len());
+        if self.in_pos == self.in_payload.len() {
+            // Keeps the capacity for the next chunk.
+            self.in_payload.clear();
+            self.in_pos = 0;
+            self.read_state = ReadState::ReadLength;
+        }
+    }
+
     fn poll_read_salt(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         if self.dec_cipher.is_none() {
             let mut salt = vec![0u8; self.cipher_method.salt_size()];
@@ -174,20 +356,56 @@ where
                 &mut salt
             ))?;
 
-            self.incoming_salt = Some(salt.clone());
+            match self.ctx.replay_check() {
+                ReplayCheck::OnSalt => {
+                    if !self.ctx.check_replay(&salt) {
+                        return Err(self.replay_error(Error::DuplicateSalt)).into();
+                    }
+                }
+                ReplayCheck::OnFirstChunk => self.incoming_salt = Some(salt.clone()),
+            }
 
-            let mut subkey = vec![0u8; self.cipher_method.key_size()];
-            hkdf_sha1(&self.cipher_key, &salt, &mut subkey);
+            let subkey = subkey(self.cipher_method, &self.cipher_key, &salt)?;
+            self.dec_cipher
+                .replace(Cipher::new(self.cipher_method, &subkey)?);
 
-            let cipher = Cipher::new(self.cipher_method, &mut subkey);
-            self.dec_cipher.replace(cipher);
+            if self.cipher_method.is_2022() {
+                self.received_salt = Some(salt);
+            }
         }
 
         Ok(()).into()
     }
 
     fn poll_read_length(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
-        let mut buf = vec![0u8; 2 + self.cipher_method.tag_size()];
+        // `in_payload` is drained here, so it is the scratch buffer.
+        ready!(self.poll_read_in_payload(cx, 2 + self.cipher_method.tag_size()))?;
+        self.decrypt_in_payload()?;
+
+        let len = u16::from_be_bytes([self.in_payload[0], self.in_payload[1]]) as usize;
+        self.in_payload.clear();
+        let payload_len = match self.cipher_method.is_2022() {
+            // Chunks of the 2022 methods may be up to 0xFFFF bytes.
+            true => len,
+            false => len & MAXIMUM_PAYLOAD_SIZE,
+        };
+
+        self.check_first_chunk_replay()?;
+
+        Ok(payload_len).into()
+    }
+
+    /// Reads the fixed-length header of the 2022 methods, returns the length it carries.
+    fn poll_read_header(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let is_response = self.sent_salt.is_some();
+        let header_len = match is_response {
+            // Type, timestamp, request salt and length of the first chunk
+            true => 1 + 8 + self.cipher_method.salt_size() + 2,
+            // Type, timestamp and length of the variable-length header
+            false => 1 + 8 + 2,
+        };
+
+        let mut buf = vec![0u8; header_len + self.cipher_method.tag_size()];
         ready!(poll_read_exact(
             &mut self.inner_stream,
             &mut self.read_buf,
@@ -195,51 +413,130 @@ where
             &mut buf
         ))?;
 
-        let len = self.decrypt(&buf)?;
-        let len = [len[0], len[1]];
-        let payload_len = (u16::from_be_bytes(len) as usize) & MAXIMUM_PAYLOAD_SIZE;
+        let header = self.decrypt(&buf)?;
+        self.check_first_chunk_replay()?;
 
-        if let Some(salt) = self.incoming_salt.take() {
-            if !self.ctx.check_replay(&salt) {
-                return Err(io::Error::new(io::ErrorKind::Other, Error::DuplicateSalt)).into();
-            }
+        let header_type = match is_response {
+            true => sip022::HEADER_TYPE_SERVER,
+            false => sip022::HEADER_TYPE_CLIENT,
+        };
+        if header[0] != header_type {
+            return Err(io::Error::new(io::ErrorKind::Other, Error::Header)).into();
         }
 
-        Ok(payload_len).into()
+        let timestamp = u64::from_be_bytes(header[1..9].try_into().unwrap());
+        if unix_time().abs_diff(timestamp) > sip022::MAX_TIME_DIFF {
+            return Err(self.replay_error(Error::Timestamp)).into();
+        }
+
+        if is_response && self.sent_salt.as_deref() != Some(&header[9..header_len - 2]) {
+            return Err(io::Error::new(io::ErrorKind::Other, Error::Header)).into();
+        }
+
+        let len = u16::from_be_bytes([header[header_len - 2], header[header_len - 1]]);
+        if !is_response && len == 0 {
+            return Err(io::Error::new(io::ErrorKind::Other, Error::Header)).into();
+        }
+
+        Ok(len as usize).into()
     }
 
-    fn poll_read_payload(
+    /// Reads the variable-length header of a 2022 request,
+    /// returns the target address followed by the initial payload.
+    fn poll_read_request_header(
         &mut self,
         cx: &mut Context<'_>,
-        payload_len: usize,
+        header_len: usize,
     ) -> Poll<io::Result<Vec<u8>>> {
-        let mut buf = vec![0u8; payload_len + self.cipher_method.tag_size()];
+        let mut buf = vec![0u8; header_len + self.cipher_method.tag_size()];
         ready!(poll_read_exact(
             &mut self.inner_stream,
             &mut self.read_buf,
             cx,
             &mut buf
         ))?;
-        let payload = self.decrypt(&buf)?;
+
+        // Target address, padding length, padding and initial payload
+        let header = self.decrypt(&buf)?;
+        let err = || io::Error::new(io::ErrorKind::Other, Error::Header);
+
+        let addr_len = Socks5Addr::raw_len(&header).ok_or_else(err)?;
+        let padding_len = match header.get(addr_len..addr_len + 2) {
+            Some(len) => u16::from_be_bytes([len[0], len[1]]) as usize,
+            None => return Err(err()).into(),
+        };
+
+        let payload_start = addr_len + 2 + padding_len;
+        if payload_start > header.len() {
+            return Err(err()).into();
+        }
+
+        let mut payload = header[..addr_len].to_vec();
+        payload.extend_from_slice(&header[payload_start..]);
 
         Ok(payload).into()
     }
+
+    /// Checks the salt for replay once the first chunk is authenticated, if not checked yet.
+    fn check_first_chunk_replay(&mut self) -> io::Result<()> {
+        if let Some(salt) = self.incoming_salt.take() {
+            if !self.ctx.check_replay(&salt) {
+                return Err(self.replay_error(Error::DuplicateSalt));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a chunk of the payload, and decrypts it into `in_payload`.
+    fn poll_read_payload(
+        &mut self,
+        cx: &mut Context<'_>,
+        payload_len: usize,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_read_in_payload(cx, payload_len + self.cipher_method.tag_size()))?;
+        self.decrypt_in_payload()?;
+
+        Ok(()).into()
+    }
+
+    /// Reads exactly `len` bytes into `in_payload`, reusing its capacity.
+    fn poll_read_in_payload(&mut self, cx: &mut Context<'_>, len: usize) -> Poll<io::Result<()>> {
+        self.in_payload.resize(len, 0);
+        poll_read_exact(
+            &mut self.inner_stream,
+            &mut self.read_buf,
+            cx,
+            &mut self.in_payload,
+        )
+    }
 }
 
 impl<T> TcpStream<T>
 where
     T: AsyncWrite + Unpin,
 {
+    /// Writes the slices as one chunk, or as much of them as fits in one, returning its length.
+    ///
+    /// Once encrypted, the chunk is kept until written out, so a pending write must be retried
+    /// with the same slices.
     fn poll_write_encrypt(
         &mut self,
         cx: &mut Context<'_>,
-        payload: &[u8],
+        payload: &[IoSlice<'_>],
     ) -> Poll<io::Result<usize>> {
         loop {
             match self.write_state {
                 WriteState::WriteSalt => {
                     ready!(self.poll_write_salt(cx))?;
-                    self.write_state = WriteState::WriteLength;
+                    self.write_state = match self.cipher_method.is_2022() {
+                        true => WriteState::WriteHeader,
+                        false => WriteState::WriteLength,
+                    };
+                }
+                WriteState::WriteHeader => {
+                    ready!(self.poll_write_header(cx, payload))?;
+                    self.write_state = WriteState::WritePayloadOut;
                 }
                 WriteState::WriteLength => {
                     ready!(self.poll_write_length(cx, payload))?;
@@ -250,36 +547,41 @@ where
                     self.write_state = WriteState::WritePayloadOut;
                 }
                 WriteState::WritePayloadOut => {
-                    while !self.out_payload.is_empty() {
-                        let nwrite = ready!(
-                            Pin::new(&mut self.inner_stream).poll_write(cx, &self.out_payload)
-                        )?;
-
-                        self.out_payload = self.out_payload[nwrite..].to_vec();
+                    while self.out_pos < self.out_payload.len() {
+                        let out_payload = &self.out_payload[self.out_pos..];
+                        let nwrite =
+                            ready!(Pin::new(&mut self.inner_stream).poll_write(cx, out_payload))?;
+                        if nwrite == 0 {
+                            return Err(io::ErrorKind::WriteZero.into()).into();
+                        }
+
+                        self.out_pos += nwrite;
                     }
 
+                    // Keeps the capacity for the next chunk.
+                    self.out_payload.clear();
+                    self.out_pos = 0;
+
                     self.write_state = WriteState::WriteLength;
 
-                    let length = usize::min(payload.len(), MAXIMUM_PAYLOAD_SIZE);
-                    return Ok(length).into();
+                    return Ok(self.chunk_len(payload)).into();
                 }
             }
         }
     }
 
     fn poll_write_salt(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        use rand::prelude::*;
-
         if self.enc_cipher.is_none() {
             let mut salt = vec![0u8; self.cipher_method.salt_size()];
-            let mut rng = StdRng::from_entropy();
-            rng.fill_bytes(&mut salt);
+            self.rng.fill_bytes(&mut salt);
 
-            let mut subkey = vec![0u8; self.cipher_method.key_size()];
-            hkdf_sha1(&self.cipher_key, &salt, &mut subkey);
+            let subkey = subkey(self.cipher_method, &self.cipher_key, &salt)?;
+            self.enc_cipher
+                .replace(Cipher::new(self.cipher_method, &subkey)?);
 
-            let cipher = Cipher::new(self.cipher_method, &mut subkey);
-            self.enc_cipher.replace(cipher);
+            if self.cipher_method.is_2022() {
+                self.sent_salt = Some(salt.clone());
+            }
 
             self.out_payload.append(&mut salt);
         }
@@ -287,12 +589,70 @@ where
         Ok(()).into()
     }
 
-    fn poll_write_length(&mut self, _cx: &mut Context<'_>, payload: &[u8]) -> Poll<io::Result<()>> {
-        let length = usize::min(payload.len(), MAXIMUM_PAYLOAD_SIZE);
-        let len = (length as u16).to_be_bytes();
+    /// Writes the headers of the 2022 methods, along with the first chunk of the payload.
+    fn poll_write_header(
+        &mut self,
+        _cx: &mut Context<'_>,
+        payload: &[IoSlice<'_>],
+    ) -> Poll<io::Result<()>> {
+        let len = self.chunk_len(payload);
+        let payload = gather(payload, len);
+        let timestamp = unix_time().to_be_bytes();
+
+        match self.received_salt.take() {
+            // A response: type, timestamp, request salt and length, then the first chunk
+            Some(request_salt) => {
+                let mut header = vec![sip022::HEADER_TYPE_SERVER];
+                header.extend_from_slice(&timestamp);
+                header.extend_from_slice(&request_salt);
+                header.extend_from_slice(&(len as u16).to_be_bytes());
+
+                self.encrypt(&header)?;
+                self.encrypt(&payload[..len])?;
+            }
+            // A request: type, timestamp and length, then the variable-length header
+            // with the target address, padding and initial payload
+            None => {
+                let addr_len = match Socks5Addr::raw_len(&payload) {
+                    Some(addr_len) if addr_len <= len => addr_len,
+                    _ => return Err(io::Error::new(io::ErrorKind::Other, Error::Header)).into(),
+                };
+
+                // Hides the length of the address if there is no payload.
+                let padding_len = match addr_len == len {
+                    true => self.rng.gen_range(1..=sip022::MAX_PADDING_SIZE),
+                    false => 0,
+                };
+
+                let mut variable_header = payload[..addr_len].to_vec();
+                variable_header.extend_from_slice(&(padding_len as u16).to_be_bytes());
+                variable_header.resize(variable_header.len() + padding_len, 0);
+                variable_header.extend_from_slice(&payload[addr_len..len]);
+
+                let mut header = vec![sip022::HEADER_TYPE_CLIENT];
+                header.extend_from_slice(&timestamp);
+                header.extend_from_slice(&(variable_header.len() as u16).to_be_bytes());
+
+                self.encrypt(&header)?;
+                self.encrypt(&variable_header)?;
+            }
+        }
 
-        let mut buf = self.encrypt(&len)?;
-        self.out_payload.append(&mut buf);
+        Ok(()).into()
+    }
+
+    fn poll_write_length(
+        &mut self,
+        _cx: &mut Context<'_>,
+        payload: &[IoSlice<'_>],
+    ) -> Poll<io::Result<()>> {
+        let mut length = self.chunk_len(payload);
+        if self.compression {
+            self.out_chunk = compress_chunk(&gather(payload, length));
+            length = self.out_chunk.len();
+        }
+        let len = (length as u16).to_be_bytes();
+        self.encrypt(&len)?;
 
         Ok(()).into()
     }
@@ -300,15 +660,117 @@ where
     fn poll_write_payload(
         &mut self,
         _cx: &mut Context<'_>,
-        payload: &[u8],
+        payload: &[IoSlice<'_>],
     ) -> Poll<io::Result<()>> {
-        let length = usize::min(payload.len(), MAXIMUM_PAYLOAD_SIZE);
-
-        let mut buf = self.encrypt(&payload[..length])?;
-        self.out_payload.append(&mut buf);
+        match self.compression {
+            true => {
+                let chunk = std::mem::take(&mut self.out_chunk);
+                self.encrypt(&chunk)?;
+            }
+            false => {
+                let length = self.chunk_len(payload);
+                self.encrypt_vectored(payload, length)?;
+            }
+        }
 
         Ok(()).into()
     }
+
+    /// Returns how many bytes of the payload fit in the next chunk.
+    fn chunk_len(&self, payload: &[IoSlice<'_>]) -> usize {
+        let max_len = match self.compression {
+            // Leaves room for the flag byte.
+            true => usize::max(self.max_payload_size - 1, 1),
+            false => self.max_payload_size,
+        };
+
+        let len = payload.iter().map(|x| x.len()).sum();
+        usize::min(len, max_len)
+    }
+}
+
+/// Returns the first `len` bytes of the slices, copied only if they span several of them.
+fn gather<'a>(bufs: &'a [IoSlice<'_>], len: usize) -> Cow<'a, [u8]> {
+    match bufs.first() {
+        Some(buf) if buf.len() >= len => Cow::Borrowed(&buf[..len]),
+        _ => {
+            let mut gathered = Vec::with_capacity(len);
+            for buf in bufs {
+                let n = usize::min(buf.len(), len - gathered.len());
+                gathered.extend_from_slice(&buf[..n]);
+            }
+
+            Cow::Owned(gathered)
+        }
+    }
+}
+
+mod sip022 {
+    pub const HEADER_TYPE_CLIENT: u8 = 0;
+    pub const HEADER_TYPE_SERVER: u8 = 1;
+    pub const MAX_TIME_DIFF: u64 = 30;
+    pub const MAX_PADDING_SIZE: usize = 900;
+}
+
+/// Derives the session subkey from the master key and salt.
+fn subkey(method: Method, key: &[u8], salt: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let mut subkey = vec![0u8; method.key_size()];
+    match method.is_2022() {
+        true => blake3_subkey(key, salt, &mut subkey),
+        false => hkdf_sha1(key, salt, &mut subkey)?,
+    }
+
+    Ok(subkey)
+}
+
+/// Returns the current unix time in seconds.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs())
+}
+
+mod compression {
+    pub const RAW: u8 = 0;
+    pub const DEFLATE: u8 = 1;
+    pub const LEVEL: u8 = 1;
+}
+
+/// Compresses a plaintext chunk, keeping it raw if it didn't shrink.
+fn compress_chunk(data: &[u8]) -> Vec<u8> {
+    let compressed = compress_to_vec(data, compression::LEVEL);
+
+    let mut chunk = Vec::with_capacity(1 + usize::min(compressed.len(), data.len()));
+    if compressed.len() < data.len() {
+        chunk.push(compression::DEFLATE);
+        chunk.extend_from_slice(&compressed);
+    } else {
+        chunk.push(compression::RAW);
+        chunk.extend_from_slice(data);
+    }
+
+    chunk
+}
+
+/// Decompresses a plaintext chunk made by [`compress_chunk`].
+fn decompress_chunk(chunk: &[u8]) -> io::Result<Vec<u8>> {
+    let err = || io::Error::new(io::ErrorKind::Other, Error::Decompression);
+
+    match chunk.split_first() {
+        Some((&compression::RAW, data)) => Ok(data.to_owned()),
+        Some((&compression::DEFLATE, data)) => {
+            decompress_to_vec_with_limit(data, MAXIMUM_PAYLOAD_SIZE).map_err(|_| err())
+        }
+        _ => Err(err()),
+    }
+}
+
+impl<T> Drop for TcpStream<T> {
+    fn drop(&mut self) {
+        let pool = self.ctx.buffer_pool();
+        pool.put(std::mem::take(&mut self.in_payload));
+        pool.put(std::mem::take(&mut self.out_payload));
+    }
 }
 
 impl<T> AsyncRead for TcpStream<T>
@@ -324,6 +786,27 @@ where
     }
 }
 
+/// Exposes the decrypted chunk being read, saving the copy a `BufReader` would make.
+impl<T> AsyncBufRead for TcpStream<T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+
+        match ready!(this.poll_fill_payload(cx)) {
+            Ok(_) => Ok(&this.in_payload[this.in_pos..]).into(),
+            // Like `poll_read`, EOF while reading a chunk is the end of the stream.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(&[][..]).into(),
+            Err(e) => Err(e).into(),
+        }
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.get_mut().consume_payload(amt);
+    }
+}
+
 impl<T> AsyncWrite for TcpStream<T>
 where
     T: AsyncWrite + Unpin,
@@ -333,7 +816,20 @@ where
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
-        self.get_mut().poll_write_encrypt(cx, buf)
+        self.get_mut().poll_write_encrypt(cx, &[IoSlice::new(buf)])
+    }
+
+    /// Encrypts the slices into one chunk, without concatenating them first.
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_write_encrypt(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
@@ -369,6 +865,16 @@ impl<T> TimeoutStream<T> {
             sleep: None,
         }
     }
+
+    /// Get a reference to the underlying stream.
+    pub fn get_ref(&self) -> &T {
+        &self.inner_stream
+    }
+
+    /// Get a mutable reference to the underlying stream, not subject to the timeout.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner_stream
+    }
 }
 
 impl<T> TimeoutStream<T> {
@@ -439,6 +945,33 @@ where
         ret
     }
 
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.project();
+        let ret = this.inner_stream.poll_write_vectored(cx, bufs);
+
+        let sleep = unsafe {
+            Pin::new_unchecked(
+                this.sleep
+                    .get_or_insert(tokio::time::sleep_until(Instant::now() + *this.duration)),
+            )
+        };
+
+        match ret {
+            Poll::Ready(_) => Self::reset_timeout(sleep, *this.duration),
+            Poll::Pending => Self::check_timeout(sleep, cx)?,
+        }
+
+        ret
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner_stream.is_write_vectored()
+    }
+
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
         let this = self.project();
         this.inner_stream.poll_flush(cx)
@@ -450,6 +983,173 @@ where
     }
 }
 
+pin_project! {
+    /// A stream with its throughput limited by token buckets, one per direction.
+    ///
+    /// Each direction allows `rate` bytes per second on average, and bursts of up to `burst`
+    /// bytes after being idle.
+    pub struct ThrottledStream<T> {
+        #[pin]
+        inner_stream: T,
+
+        read_bucket: TokenBucket,
+        write_bucket: TokenBucket,
+    }
+}
+
+impl<T> ThrottledStream<T> {
+    /// Creates a new throttled stream with the given rate in bytes per second and burst size.
+    pub fn new(inner_stream: T, rate: u64, burst: u64) -> Self {
+        ThrottledStream {
+            inner_stream,
+            read_bucket: TokenBucket::new(rate, burst),
+            write_bucket: TokenBucket::new(rate, burst),
+        }
+    }
+
+    /// Get a reference to the underlying stream.
+    pub fn get_ref(&self) -> &T {
+        &self.inner_stream
+    }
+}
+
+impl<T> AsyncRead for ThrottledStream<T>
+where
+    T: AsyncRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+
+        let n = ready!(this.read_bucket.poll_acquire(cx)).min(buf.remaining());
+        let mut limited_buf = ReadBuf::new(buf.initialize_unfilled_to(n));
+        ready!(this.inner_stream.poll_read(cx, &mut limited_buf))?;
+
+        let nread = limited_buf.filled().len();
+        buf.advance(nread);
+        this.read_bucket.consume(nread);
+
+        Ok(()).into()
+    }
+}
+
+impl<T> AsyncWrite for ThrottledStream<T>
+where
+    T: AsyncWrite,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.project();
+
+        let n = ready!(this.write_bucket.poll_acquire(cx)).min(buf.len());
+        let nwritten = ready!(this.inner_stream.poll_write(cx, &buf[..n]))?;
+        this.write_bucket.consume(nwritten);
+
+        Ok(nwritten).into()
+    }
+
+    /// Writes all slices at once if the tokens cover them, otherwise part of the first one.
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.project();
+
+        let len: usize = bufs.iter().map(|x| x.len()).sum();
+        let n = ready!(this.write_bucket.poll_acquire(cx)).min(len);
+        let nwritten = match n == len {
+            true => ready!(this.inner_stream.poll_write_vectored(cx, bufs))?,
+            false => {
+                let buf = bufs
+                    .iter()
+                    .find(|x| !x.is_empty())
+                    .map_or(&[][..], |x| &x[..]);
+                let n = n.min(buf.len());
+                ready!(this.inner_stream.poll_write(cx, &buf[..n]))?
+            }
+        };
+        this.write_bucket.consume(nwritten);
+
+        Ok(nwritten).into()
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner_stream.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.project();
+        this.inner_stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let this = self.project();
+        this.inner_stream.poll_shutdown(cx)
+    }
+}
+
+/// A token bucket of bytes, refilled continuously.
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl TokenBucket {
+    /// Waits for at least this many tokens, unless the burst is smaller,
+    /// to avoid tiny reads and writes.
+    const MIN_ACQUIRE: f64 = 4096.0;
+
+    fn new(rate: u64, burst: u64) -> Self {
+        let rate = rate.max(1) as f64;
+        let burst = burst.max(1) as f64;
+
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            last_refill: Instant::now(),
+            sleep: None,
+        }
+    }
+
+    /// Returns the number of available tokens, once there are enough.
+    fn poll_acquire(&mut self, cx: &mut Context<'_>) -> Poll<usize> {
+        let wanted = Self::MIN_ACQUIRE.min(self.burst);
+
+        loop {
+            let now = Instant::now();
+            let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+            self.last_refill = now;
+
+            if self.tokens >= wanted {
+                return Poll::Ready(self.tokens as usize);
+            }
+
+            let deadline = now + Duration::from_secs_f64((wanted - self.tokens) / self.rate);
+            let sleep = self
+                .sleep
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+            sleep.as_mut().reset(deadline);
+            ready!(sleep.as_mut().poll(cx));
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.tokens -= n as f64;
+    }
+}
+
 /// Errors during shadowsocks communication.
 #[derive(Debug)]
 pub enum Error {
@@ -459,8 +1159,23 @@ pub enum Error {
     /// Decryption error.
     Decryption,
 
+    /// Decryption error of the first chunk, the key is wrong or the peer is probing.
+    FirstChunkDecryption,
+
     /// Duplicate salt received, possible replay attack.
     DuplicateSalt,
+
+    /// Decompression error, possibly the peer has not enabled compression.
+    Decompression,
+
+    /// Invalid header of the 2022 methods.
+    Header,
+
+    /// The timestamp of a 2022 header is too far from now, possibly a replay attack.
+    Timestamp,
+
+    /// Too many chunks in a direction for the key to stay safe.
+    ChunkLimit,
 }
 
 impl Display for Error {
@@ -468,15 +1183,39 @@ impl Display for Error {
         match self {
             Error::Encryption => write!(f, "encryption error"),
             Error::Decryption => write!(f, "decryption error"),
+            Error::FirstChunkDecryption => write!(f, "decryption error of the first chunk"),
             Error::DuplicateSalt => write!(f, "duplicate salt received, possible replay attack"),
+            Error::Decompression => write!(f, "decompression error"),
+            Error::Header => write!(f, "invalid header"),
+            Error::Timestamp => write!(
+                f,
+                "header timestamp is off by more than {} seconds, possible replay attack",
+                sip022::MAX_TIME_DIFF
+            ),
+            Error::ChunkLimit => write!(f, "too many chunks encrypted with one key"),
         }
     }
 }
 
 impl std::error::Error for Error {}
 
+fn chunk_limit() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, Error::ChunkLimit)
+}
+
+/// Returns true if the error is likely caused by an active probe rather than a client:
+/// the first chunk failing to decrypt, or a replayed salt.
+pub fn is_probing(e: &io::Error) -> bool {
+    matches!(
+        e.get_ref().and_then(|x| x.downcast_ref::<Error>()),
+        Some(Error::FirstChunkDecryption | Error::DuplicateSalt)
+    )
+}
+
 enum ReadState {
     ReadSalt,
+    ReadHeader,
+    ReadRequestHeader(usize),
     ReadLength,
     ReadPayload(usize),
     ReadPayloadOut,
@@ -484,11 +1223,494 @@ enum ReadState {
 
 enum WriteState {
     WriteSalt,
+    WriteHeader,
     WriteLength,
     WritePayload,
     WritePayloadOut,
 }
 
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    const METHOD: Method = Method::ChaCha20Poly1305;
+    const KEY: [u8; 32] = [7u8; 32];
+
+    #[tokio::test]
+    async fn test_small_chunks() {
+        let mut ctx = Ctx::new();
+        ctx.set_max_payload_size(1000);
+
+        let (a, b) = tokio::io::duplex(0x10000);
+        let mut writer = TcpStream::new(a, METHOD, &KEY, Arc::new(ctx)).unwrap();
+        let mut reader = TcpStream::new(b, METHOD, &KEY, Arc::new(Ctx::new())).unwrap();
+
+        let data = vec![1u8; 3000];
+        writer.write_all(&data).await.unwrap();
+        drop(writer);
+
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[tokio::test]
+    async fn test_extended_nonce() {
+        const METHOD: Method = Method::XChaCha20Poly1305;
+
+        let (a, b) = tokio::io::duplex(0x10000);
+        let mut writer = TcpStream::new(a, METHOD, &KEY, Arc::new(Ctx::new())).unwrap();
+        let mut reader = TcpStream::new(b, METHOD, &KEY, Arc::new(Ctx::new())).unwrap();
+
+        for i in 0..300u32 {
+            writer.write_all(&i.to_be_bytes()).await.unwrap();
+        }
+        drop(writer);
+
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await.unwrap();
+        let expected: Vec<u8> = (0..300u32).flat_map(|x| x.to_be_bytes()).collect();
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn test_key_size() {
+        let e = TcpStream::new(tokio::io::sink(), METHOD, &KEY[..16], Arc::new(Ctx::new()))
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_chunk_limit() {
+        const METHOD: Method = Method::Aes128Gcm;
+
+        let (a, _b) = tokio::io::duplex(0x10000);
+        let mut writer = TcpStream::new(a, METHOD, &KEY[..16], Arc::new(Ctx::new())).unwrap();
+        writer.enc_nonce = Nonce::from_counter(METHOD.iv_size(), METHOD.max_chunks() - 2);
+
+        // The length and the payload take the last two nonces.
+        writer.write_all(b"hello").await.unwrap();
+
+        let e = writer.write_all(b"hello").await.unwrap_err();
+        assert!(matches!(
+            e.get_ref().and_then(|x| x.downcast_ref::<Error>()),
+            Some(Error::ChunkLimit)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_chunk_size() {
+        let mut ctx = Ctx::new();
+        ctx.set_max_payload_size(1000);
+
+        let (a, mut b) = tokio::io::duplex(0x10000);
+        let mut writer = TcpStream::new(a, METHOD, &KEY, Arc::new(ctx)).unwrap();
+
+        writer.write_all(&[1u8; 3000]).await.unwrap();
+        drop(writer);
+
+        let mut buf = Vec::new();
+        b.read_to_end(&mut buf).await.unwrap();
+
+        let chunk_size = 2 + METHOD.tag_size() + 1000 + METHOD.tag_size();
+        assert_eq!(buf.len(), METHOD.salt_size() + 3 * chunk_size);
+    }
+
+    #[tokio::test]
+    async fn test_buf_read() {
+        let mut ctx = Ctx::new();
+        ctx.set_max_payload_size(8);
+        let ctx = Arc::new(ctx);
+
+        let (a, b) = tokio::io::duplex(0x10000);
+        let mut writer = TcpStream::new(a, METHOD, &KEY, ctx.clone()).unwrap();
+        let mut reader = TcpStream::new(b, METHOD, &KEY, ctx).unwrap();
+
+        // Lines span chunks of 8 bytes.
+        writer.write_all(b"hello world\nfoo\nbar").await.unwrap();
+        drop(writer);
+
+        // A chunk at a time
+        assert_eq!(reader.fill_buf().await.unwrap(), b"hello wo");
+        reader.consume(6);
+        assert_eq!(reader.fill_buf().await.unwrap(), b"wo");
+
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "world\n");
+
+        let mut segments = reader.split(b'\n');
+        assert_eq!(segments.next_segment().await.unwrap().unwrap(), b"foo");
+        assert_eq!(segments.next_segment().await.unwrap().unwrap(), b"bar");
+        assert_eq!(segments.next_segment().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_buffer_pool() {
+        let ctx = Arc::new(Ctx::new());
+
+        let (a, b) = tokio::io::duplex(0x10000);
+        let mut writer = TcpStream::new(a, METHOD, &KEY, ctx.clone()).unwrap();
+        let mut reader = TcpStream::new(b, METHOD, &KEY, ctx.clone()).unwrap();
+
+        let data = vec![1u8; 50000];
+        writer.write_all(&data).await.unwrap();
+        drop(writer);
+        assert_eq!(ctx.buffer_pool().len(), 2);
+
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, data);
+        drop(reader);
+        assert_eq!(ctx.buffer_pool().len(), 4);
+
+        // A new stream takes its buffers from the pool.
+        let _stream = TcpStream::new(tokio::io::sink(), METHOD, &KEY, ctx.clone()).unwrap();
+        assert_eq!(ctx.buffer_pool().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_throttled_stream() {
+        let (a, b) = tokio::io::duplex(0x10000);
+        let mut writer = ThrottledStream::new(a, 10_000, 1000);
+        let mut reader = ThrottledStream::new(b, 10_000, 1000);
+
+        let start = std::time::Instant::now();
+        let data = vec![1u8; 3000];
+        writer.write_all(&data).await.unwrap();
+        drop(writer);
+
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, data);
+
+        // 1000 bytes of burst, then 2000 bytes at 10 KB/s
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+
+    #[tokio::test]
+    async fn test_deterministic_salt() {
+        async fn output(seed: u64) -> Vec<u8> {
+            let (a, mut b) = tokio::io::duplex(0x10000);
+            let rng = Box::new(StdRng::seed_from_u64(seed));
+            let mut writer =
+                TcpStream::with_rng(a, METHOD, &KEY, Arc::new(Ctx::new()), rng).unwrap();
+
+            writer.write_all(b"hello").await.unwrap();
+            drop(writer);
+
+            let mut buf = Vec::new();
+            b.read_to_end(&mut buf).await.unwrap();
+            buf
+        }
+
+        let mut salt = vec![0u8; METHOD.salt_size()];
+        StdRng::seed_from_u64(1).fill_bytes(&mut salt);
+
+        let buf = output(1).await;
+        assert_eq!(buf[..METHOD.salt_size()], salt);
+        assert_eq!(buf, output(1).await);
+        assert_ne!(buf, output(2).await);
+    }
+
+    #[tokio::test]
+    async fn test_write_vectored() {
+        async fn output(
+            bufs: &[&[u8]],
+            max_size: usize,
+            compression: bool,
+        ) -> (Vec<usize>, Vec<u8>) {
+            let mut ctx = Ctx::new();
+            ctx.set_max_payload_size(max_size);
+            ctx.set_compression(compression);
+            let ctx = Arc::new(ctx);
+
+            let (a, mut b) = tokio::io::duplex(0x10000);
+            let rng = Box::new(StdRng::seed_from_u64(1));
+            let mut writer = TcpStream::with_rng(a, METHOD, &KEY, ctx.clone(), rng).unwrap();
+            assert!(writer.is_write_vectored());
+
+            // Writes the rest of the slices after the part of them the last chunk took
+            let mut written = Vec::new();
+            let data = bufs.concat();
+            let mut pos = 0;
+            while pos < data.len() {
+                let mut skip = pos;
+                let slices: Vec<IoSlice> = bufs
+                    .iter()
+                    .filter_map(|x| match skip >= x.len() {
+                        true => {
+                            skip -= x.len();
+                            None
+                        }
+                        false => Some(IoSlice::new(&x[std::mem::take(&mut skip)..])),
+                    })
+                    .collect();
+                let n = writer.write_vectored(&slices).await.unwrap();
+                written.push(n);
+                pos += n;
+            }
+            drop(writer);
+
+            let mut wire = Vec::new();
+            b.read_to_end(&mut wire).await.unwrap();
+
+            let mut buf = Vec::new();
+            let mut reader = TcpStream::new(&wire[..], METHOD, &KEY, ctx).unwrap();
+            reader.read_to_end(&mut buf).await.unwrap();
+            drop(reader);
+            assert_eq!(buf, data);
+
+            (written, wire)
+        }
+
+        // The same chunk as written from one slice
+        let (written, wire) = output(&[b"hel", b"", b"lo"], 1000, false).await;
+        assert_eq!(written, [5]);
+        assert_eq!(wire, output(&[b"hello"], 1000, false).await.1);
+
+        // A chunk ends inside a slice
+        let (written, _) = output(&[b"ab", b"cdef", b"g"], 4, false).await;
+        assert_eq!(written, [4, 3]);
+
+        let (written, _) = output(&[b"hello ", b"world"], 1000, true).await;
+        assert_eq!(written, [11]);
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        async fn transfer(data: &[u8]) -> (Vec<u8>, usize) {
+            let mut ctx = Ctx::new();
+            ctx.set_compression(true);
+            let ctx = Arc::new(ctx);
+
+            let (a, mut b) = tokio::io::duplex(0x10000);
+            let mut writer = TcpStream::new(a, METHOD, &KEY, ctx.clone()).unwrap();
+            writer.write_all(data).await.unwrap();
+            drop(writer);
+
+            let mut wire = Vec::new();
+            b.read_to_end(&mut wire).await.unwrap();
+
+            let (mut a, b) = tokio::io::duplex(0x10000);
+            let mut reader = TcpStream::new(b, METHOD, &KEY, ctx).unwrap();
+            a.write_all(&wire).await.unwrap();
+            drop(a);
+
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).await.unwrap();
+            (buf, wire.len())
+        }
+
+        let data = b"hello world ".repeat(2000);
+        let (buf, wire_len) = transfer(&data).await;
+        assert_eq!(buf, data);
+        assert!(wire_len < data.len() / 10);
+
+        let mut data = vec![0u8; 20000];
+        StdRng::seed_from_u64(1).fill_bytes(&mut data);
+        let (buf, wire_len) = transfer(&data).await;
+        assert_eq!(buf, data);
+        assert!(wire_len > data.len());
+    }
+
+    #[test]
+    fn test_method() {
+        let (a, _) = tokio::io::duplex(64);
+        let stream = TcpStream::new(a, METHOD, &KEY, Arc::new(Ctx::new())).unwrap();
+
+        assert_eq!(stream.method(), METHOD);
+        assert_eq!(stream.key_size(), METHOD.key_size());
+        assert_eq!(stream.salt_size(), METHOD.salt_size());
+    }
+
+    #[tokio::test]
+    async fn test_write_zero() {
+        struct ZeroWriter;
+
+        impl AsyncWrite for ZeroWriter {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                _buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                Ok(0).into()
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Ok(()).into()
+            }
+
+            fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Ok(()).into()
+            }
+        }
+
+        let mut writer = TcpStream::new(ZeroWriter, METHOD, &KEY, Arc::new(Ctx::new())).unwrap();
+        let e = writer.write(b"hello").await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[tokio::test]
+    async fn test_replay_check() {
+        async fn read(input: &[u8], ctx: Arc<Ctx>) -> io::Result<Vec<u8>> {
+            let (mut a, b) = tokio::io::duplex(0x10000);
+            let mut reader = TcpStream::new(b, METHOD, &KEY, ctx).unwrap();
+
+            a.write_all(input).await.unwrap();
+            drop(a);
+
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).await?;
+            Ok(buf)
+        }
+
+        let (a, mut b) = tokio::io::duplex(0x10000);
+        let rng = Box::new(StdRng::seed_from_u64(1));
+        let mut writer = TcpStream::with_rng(a, METHOD, &KEY, Arc::new(Ctx::new()), rng).unwrap();
+        writer.write_all(b"hello").await.unwrap();
+        drop(writer);
+
+        let mut session = Vec::new();
+        b.read_to_end(&mut session).await.unwrap();
+        let salt = &session[..METHOD.salt_size()];
+
+        for replay_check in [ReplayCheck::OnSalt, ReplayCheck::OnFirstChunk] {
+            let mut ctx = Ctx::new();
+            ctx.set_replay_check(replay_check);
+            let ctx = Arc::new(ctx);
+
+            // A bare salt is only recorded when checking on salt.
+            assert!(read(salt, ctx.clone()).await.unwrap().is_empty());
+            assert_eq!(
+                read(&session, ctx.clone()).await.is_err(),
+                replay_check == ReplayCheck::OnSalt
+            );
+
+            // A replayed session is always rejected.
+            assert!(is_probing(&read(&session, ctx.clone()).await.unwrap_err()));
+
+            let replays = match replay_check {
+                ReplayCheck::OnSalt => 2,
+                ReplayCheck::OnFirstChunk => 1,
+            };
+            assert_eq!(ctx.stats_snapshot().replays, replays);
+            assert_eq!(ctx.stats_snapshot().decryption_failures, 0);
+        }
+
+        // A preloaded salt is rejected on first use.
+        let ctx = Ctx::new();
+        ctx.preload_replay(&[salt.to_vec()]);
+        assert!(read(&session, Arc::new(ctx)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_is_probing() {
+        async fn read_err(input: &[u8]) -> io::Error {
+            let ctx = Arc::new(Ctx::new());
+            let (mut a, b) = tokio::io::duplex(0x10000);
+            let mut reader = TcpStream::new(b, METHOD, &KEY, ctx.clone()).unwrap();
+
+            a.write_all(input).await.unwrap();
+            drop(a);
+
+            let mut buf = Vec::new();
+            let e = reader.read_to_end(&mut buf).await.unwrap_err();
+            assert_eq!(ctx.stats_snapshot().decryption_failures, 1);
+            e
+        }
+
+        let (a, mut b) = tokio::io::duplex(0x10000);
+        let mut writer = TcpStream::new(a, METHOD, &KEY, Arc::new(Ctx::new())).unwrap();
+        writer.write_all(b"hello").await.unwrap();
+        writer.write_all(b"world").await.unwrap();
+        drop(writer);
+
+        let mut session = Vec::new();
+        b.read_to_end(&mut session).await.unwrap();
+
+        // Garbage fails on the first chunk.
+        let mut garbage = session.clone();
+        garbage[METHOD.salt_size()] ^= 1;
+        assert!(is_probing(&read_err(&garbage).await));
+
+        // A client with a corrupted stream fails later.
+        let last = session.len() - 1;
+        session[last] ^= 1;
+        assert!(!is_probing(&read_err(&session).await));
+        assert!(!is_probing(&io::ErrorKind::TimedOut.into()));
+    }
+
+    #[tokio::test]
+    async fn test_2022() {
+        for method in [
+            Method::Blake3Aes128Gcm,
+            Method::Blake3Aes256Gcm,
+            Method::Blake3ChaCha20Poly1305,
+        ] {
+            let key = vec![7u8; method.key_size()];
+            let ctx = Arc::new(Ctx::new());
+            let target_addr = Socks5Addr::DomainName(("example.com".to_owned(), 443));
+
+            let (a, b) = tokio::io::duplex(0x10000);
+            let (mut b, mut wire) = tokio::io::split(b);
+
+            // The request has the target address, padded as there is no payload
+            let mut client = TcpStream::new(a, method, &key, ctx.clone()).unwrap();
+            client
+                .write_all(&target_addr.get_raw_parts())
+                .await
+                .unwrap();
+            client.write_all(b"hello").await.unwrap();
+
+            let mut request = vec![0u8; 0x10000];
+            let n = b.read(&mut request).await.unwrap();
+            request.truncate(n);
+            let addr_len = target_addr.get_raw_parts().len();
+            assert!(n > method.salt_size() + 11 + addr_len + 2 + 5 + 4 * method.tag_size());
+
+            let (mut c, d) = tokio::io::duplex(0x10000);
+            let mut server = TcpStream::new(d, method, &key, ctx.clone()).unwrap();
+            c.write_all(&request).await.unwrap();
+
+            let addr = Socks5Addr::construct(&mut server).await.unwrap();
+            assert_eq!(addr.to_string(), target_addr.to_string());
+            let mut buf = [0u8; 5];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            // The response carries the salt of the request
+            server.write_all(b"world").await.unwrap();
+            let mut response = vec![0u8; 0x10000];
+            let n = c.read(&mut response).await.unwrap();
+            wire.write_all(&response[..n]).await.unwrap();
+
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"world");
+
+            // A replayed request is rejected
+            let (mut c, d) = tokio::io::duplex(0x10000);
+            let mut server = TcpStream::new(d, method, &key, ctx.clone()).unwrap();
+            c.write_all(&request).await.unwrap();
+            assert!(server.read(&mut buf).await.is_err());
+
+            // A response to another request is rejected
+            let (e, f) = tokio::io::duplex(0x10000);
+            let (_, mut wire) = tokio::io::split(f);
+            let mut client = TcpStream::new(e, method, &key, ctx.clone()).unwrap();
+            client
+                .write_all(&target_addr.get_raw_parts())
+                .await
+                .unwrap();
+            wire.write_all(&response[..n]).await.unwrap();
+            assert!(client.read(&mut buf).await.is_err());
+        }
+    }
+}
+
 // #[cfg(test)]
 // mod tests {
 //     use std::{pin::Pin, time::Duration};
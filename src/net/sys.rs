@@ -0,0 +1,320 @@
+//! Platform specific socket facilities.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+};
+
+use tokio::net::{TcpSocket, TcpStream};
+
+/// Returns the original destination of a connection redirected by iptables (`REDIRECT`).
+#[cfg(target_os = "linux")]
+pub fn original_dst(stream: &TcpStream) -> io::Result<SocketAddr> {
+    use std::{
+        mem,
+        net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6},
+        os::unix::io::AsRawFd,
+    };
+
+    // Defined in <linux/netfilter_ipv4.h> and <linux/netfilter_ipv6/ip6_tables.h>.
+    const SO_ORIGINAL_DST: libc::c_int = 80;
+    const IP6T_SO_ORIGINAL_DST: libc::c_int = 80;
+
+    let fd = stream.as_raw_fd();
+
+    match stream.local_addr()? {
+        SocketAddr::V4(_) => {
+            let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+            let mut len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+
+            let ret = unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_IP,
+                    SO_ORIGINAL_DST,
+                    &mut addr as *mut _ as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            let port = u16::from_be(addr.sin_port);
+
+            Ok(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+        }
+        SocketAddr::V6(_) => {
+            let mut addr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            let mut len = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+
+            let ret = unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_IPV6,
+                    IP6T_SO_ORIGINAL_DST,
+                    &mut addr as *mut _ as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            let port = u16::from_be(addr.sin6_port);
+
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                ip,
+                port,
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+    }
+}
+
+/// Returns the original destination of a connection redirected by iptables (`REDIRECT`).
+#[cfg(not(target_os = "linux"))]
+pub fn original_dst(_stream: &TcpStream) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "transparent proxy is only supported on linux",
+    ))
+}
+
+/// Returns the domain name of the given ip from a reverse DNS (PTR) lookup, if any.
+///
+/// This blocks the calling thread.
+#[cfg(target_os = "linux")]
+pub fn reverse_lookup(ip: IpAddr) -> io::Result<Option<String>> {
+    use std::{ffi::CStr, mem};
+
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match ip {
+        IpAddr::V4(ip) => {
+            let addr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            addr.sin_family = libc::AF_INET as libc::sa_family_t;
+            addr.sin_addr.s_addr = u32::from(ip).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        IpAddr::V6(ip) => {
+            let addr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            addr.sin6_addr.s6_addr = ip.octets();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    let mut host = [0 as libc::c_char; libc::NI_MAXHOST as usize];
+    let ret = unsafe {
+        libc::getnameinfo(
+            &storage as *const _ as *const libc::sockaddr,
+            len as libc::socklen_t,
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+
+    match ret {
+        0 => {
+            let host = unsafe { CStr::from_ptr(host.as_ptr()) };
+            Ok(Some(host.to_string_lossy().into_owned()))
+        }
+        libc::EAI_NONAME => Ok(None),
+        libc::EAI_SYSTEM => Err(io::Error::last_os_error()),
+        _ => {
+            let reason = unsafe { CStr::from_ptr(libc::gai_strerror(ret)) };
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                reason.to_string_lossy().into_owned(),
+            ))
+        }
+    }
+}
+
+/// Returns the domain name of the given ip from a reverse DNS (PTR) lookup, if any.
+///
+/// This blocks the calling thread.
+#[cfg(not(target_os = "linux"))]
+pub fn reverse_lookup(_ip: IpAddr) -> io::Result<Option<String>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reverse DNS lookup is only supported on linux",
+    ))
+}
+
+/// Returns the ips of the local network interfaces (`getifaddrs`).
+#[cfg(target_os = "linux")]
+pub fn local_ips() -> io::Result<Vec<IpAddr>> {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut ips = Vec::new();
+    let mut cur = ifaddrs;
+    while !cur.is_null() {
+        let ifaddr = unsafe { &*cur };
+        cur = ifaddr.ifa_next;
+
+        if ifaddr.ifa_addr.is_null() {
+            continue;
+        }
+        let ip: IpAddr = match unsafe { (*ifaddr.ifa_addr).sa_family } as libc::c_int {
+            libc::AF_INET => {
+                let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).into()
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in6) };
+                Ipv6Addr::from(addr.sin6_addr.s6_addr).into()
+            }
+            _ => continue,
+        };
+        if !ips.contains(&ip) {
+            ips.push(ip);
+        }
+    }
+
+    unsafe { libc::freeifaddrs(ifaddrs) };
+
+    Ok(ips)
+}
+
+/// Returns the ips of the local network interfaces.
+#[cfg(not(target_os = "linux"))]
+pub fn local_ips() -> io::Result<Vec<IpAddr>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "listing interface addresses is only supported on linux",
+    ))
+}
+
+/// Enables TCP Fast Open on a socket to listen on, with the given maximum number of
+/// pending Fast Open requests.
+#[cfg(target_os = "linux")]
+pub fn set_tcp_fastopen(socket: &TcpSocket, queue_len: i32) -> io::Result<()> {
+    setsockopt(socket, libc::IPPROTO_TCP, libc::TCP_FASTOPEN, queue_len)
+}
+
+/// Enables TCP Fast Open on a socket to listen on, with the given maximum number of
+/// pending Fast Open requests.
+#[cfg(not(target_os = "linux"))]
+pub fn set_tcp_fastopen(_socket: &TcpSocket, _queue_len: i32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP Fast Open is only supported on linux",
+    ))
+}
+
+/// Enables TCP Fast Open on a socket to connect, so the first write is sent with the SYN.
+#[cfg(target_os = "linux")]
+pub fn set_tcp_fastopen_connect(socket: &TcpSocket) -> io::Result<()> {
+    setsockopt(socket, libc::IPPROTO_TCP, libc::TCP_FASTOPEN_CONNECT, 1)
+}
+
+/// Enables TCP Fast Open on a socket to connect, so the first write is sent with the SYN.
+#[cfg(not(target_os = "linux"))]
+pub fn set_tcp_fastopen_connect(_socket: &TcpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP Fast Open is only supported on linux",
+    ))
+}
+
+/// Sets the firewall mark (`SO_MARK`) of a TCP or UDP socket, for policy routing.
+///
+/// Requires the `CAP_NET_ADMIN` capability.
+#[cfg(target_os = "linux")]
+pub fn set_mark<S: std::os::unix::io::AsRawFd>(socket: &S, mark: u32) -> io::Result<()> {
+    setsockopt(socket, libc::SOL_SOCKET, libc::SO_MARK, mark as libc::c_int)
+}
+
+/// Sets the firewall mark (`SO_MARK`) of a TCP or UDP socket, for policy routing.
+#[cfg(not(target_os = "linux"))]
+pub fn set_mark<S>(_socket: &S, _mark: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "firewall marks are only supported on linux",
+    ))
+}
+
+/// Enables `SO_REUSEPORT` on a socket to listen on, so several processes can listen on the
+/// same port, with the kernel spreading connections among them.
+#[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
+pub fn set_reuseport(socket: &TcpSocket) -> io::Result<()> {
+    socket.set_reuseport(true)
+}
+
+/// Enables `SO_REUSEPORT` on a socket to listen on, so several processes can listen on the
+/// same port, with the kernel spreading connections among them.
+#[cfg(not(all(unix, not(target_os = "solaris"), not(target_os = "illumos"))))]
+pub fn set_reuseport(_socket: &TcpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is only supported on unix",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn setsockopt<S: std::os::unix::io::AsRawFd>(
+    socket: &S,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    use std::mem;
+
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_ips() {
+        #[cfg(target_os = "linux")]
+        assert!(local_ips().unwrap().contains(&IpAddr::from([127, 0, 0, 1])));
+
+        #[cfg(not(target_os = "linux"))]
+        assert_eq!(local_ips().unwrap_err().kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_set_mark() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        // Allowed with CAP_NET_ADMIN only
+        #[cfg(target_os = "linux")]
+        if let Err(e) = set_mark(&socket, 1) {
+            assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        assert_eq!(
+            set_mark(&socket, 1).unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+    }
+}
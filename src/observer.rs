@@ -0,0 +1,50 @@
+//! Hooks into the lifecycle of connections.
+
+use std::net::SocketAddr;
+
+use crate::socks5::Socks5Addr;
+
+/// Observes the lifecycle of connections handled by ss-local and ss-remote.
+///
+/// For every accepted connection, `on_accept` is called first, then `on_target` once its
+/// target address is known, and `on_close` last, however the connection ends.
+///
+/// Methods are called from the tasks handling the connections, i.e. concurrently from many
+/// tasks and threads, so they should return quickly and use their own synchronization.
+/// All methods do nothing by default.
+pub trait ConnectionObserver: Send + Sync {
+    /// Called when a connection from `peer` is accepted.
+    fn on_accept(&self, _peer: SocketAddr) {}
+
+    /// Called when the target address requested by `peer` is known.
+    fn on_target(&self, _peer: SocketAddr, _target: &Socks5Addr) {}
+
+    /// Called when the connection from `peer` is closed.
+    ///
+    /// `bytes_up` were relayed from the peer to the target, and `bytes_down` back.
+    fn on_close(&self, _peer: SocketAddr, _bytes_up: u64, _bytes_down: u64, _outcome: Outcome) {}
+}
+
+/// An observer that does nothing.
+pub struct NoopObserver;
+
+impl ConnectionObserver for NoopObserver {}
+
+/// How a connection ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Relayed until both sides closed.
+    Closed,
+
+    /// Rejected by the access control list or checks, before relaying.
+    Rejected,
+
+    /// Closed by a timeout.
+    TimedOut,
+
+    /// Closed by an error.
+    Error,
+
+    /// Logged and closed in probe mode.
+    Probed,
+}
@@ -1,12 +1,113 @@
 //! SIP 003 plugin implementation.
+//!
+//! # Timeouts
+//!
+//! With a plugin, ss-local and ss-remote only see their loopback connections to the plugin,
+//! so their timeouts apply to those and not to the connection between the plugins.
+//! A stalled connection there is only detected by the idle timeout of the loopback one.
+//!
+//! When a connection to the plugin times out, ss-local resets it rather than closing it,
+//! so the plugin fails fast and closes its own connection instead of waiting for a reply
+//! from the stalled side.
 
 use std::{
+    fmt::{self, Display, Formatter},
     io::{self, ErrorKind},
-    net::{SocketAddr, TcpListener},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener},
+    path::Path,
     process::Stdio,
+    str::FromStr,
 };
 
-use tokio::process::{Child, Command};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::{Child, Command},
+};
+
+/// Plugin options as defined by SIP003.
+///
+/// Options are separated by `;`, each a `key=value` pair or a bare `key`.
+/// A `\` escapes the next character, so `;`, `=` and `\` can be in keys and values.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PluginOpts {
+    opts: Vec<(String, Option<String>)>,
+}
+
+impl PluginOpts {
+    /// Returns the value of the key, `Some("")` for a bare key.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.opts
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_deref().unwrap_or_default())
+    }
+}
+
+impl FromStr for PluginOpts {
+    type Err = io::Error;
+
+    /// Parses options, empty ones like a trailing `;` are skipped.
+    ///
+    /// An unescaped `=` in a value is kept, as in `path=/ws?ed=2048`, which plugins accept.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: &str| io::Error::new(ErrorKind::InvalidInput, msg.to_owned());
+
+        let mut opts = Vec::new();
+        let mut key = String::new();
+        let mut value: Option<String> = None;
+        let mut chars = s.chars();
+
+        loop {
+            let c = chars.next();
+            match c {
+                Some('\\') => {
+                    let c = chars
+                        .next()
+                        .ok_or_else(|| invalid("plugin options end with an escape"))?;
+                    value.as_mut().unwrap_or(&mut key).push(c);
+                }
+                Some('=') if value.is_none() => value = Some(String::new()),
+                Some(';') | None => {
+                    match (key.is_empty(), value.is_some()) {
+                        (true, true) => return Err(invalid("plugin option without a key")),
+                        (true, false) => {}
+                        (false, _) => opts.push((std::mem::take(&mut key), value.take())),
+                    }
+
+                    if c.is_none() {
+                        break;
+                    }
+                }
+                Some(c) => value.as_mut().unwrap_or(&mut key).push(c),
+            }
+        }
+
+        Ok(PluginOpts { opts })
+    }
+}
+
+impl Display for PluginOpts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let escape = |s: &str| {
+            s.replace('\\', "\\\\")
+                .replace(';', "\\;")
+                .replace('=', "\\=")
+        };
+
+        for (i, (key, value)) in self.opts.iter().enumerate() {
+            if i > 0 {
+                write!(f, ";")?;
+            }
+
+            write!(f, "{}", escape(key))?;
+            if let Some(value) = value {
+                write!(f, "={}", escape(value))?;
+            }
+        }
+
+        Ok(())
+    }
+}
 
 /// Starts a plugin with the given options.
 ///
@@ -15,10 +116,24 @@ use tokio::process::{Child, Command};
 /// For ss-local: the listening address is plugin address.
 ///
 /// For ss-remote: the listening address is ss-remote address (ss-remote is behind the plugin).
+///
+/// `plugin_bind` optionally sets the address the plugin itself listens on,
+/// independently of the shadowsocks address:
+///
+/// - For ss-local, the plugin listens on `plugin_bind` instead of a free loopback port,
+///   and still connects to `raw_addr`.
+/// - For ss-remote, the plugin listens on `plugin_bind` and ss-remote listens on `raw_addr`
+///   as given, instead of both sharing the ip of `raw_addr`.
+///
+/// For ss-remote, the two addresses must not overlap, otherwise an error of kind `InvalidInput`
+/// is returned. For ss-local they may, `raw_addr` is the address of the remote server.
+/// Options that aren't valid [`PluginOpts`] are rejected the same way, they are passed to
+/// the plugin as given.
 pub fn start_plugin(
     plugin: &str,
     plugin_opts: &str,
     raw_addr: SocketAddr,
+    plugin_bind: Option<SocketAddr>,
     is_server: bool,
 ) -> io::Result<(SocketAddr, Child)> {
     log::info!(
@@ -27,20 +142,31 @@ pub fn start_plugin(
         plugin_opts
     );
 
-    let free_port = match find_free_port() {
-        Some(port) => port,
+    plugin_opts.parse::<PluginOpts>()?;
+
+    let (local_addr, remote_addr) = match plugin_bind {
+        Some(bind_addr) => match is_server {
+            true => {
+                check_compatible(bind_addr, raw_addr)?;
+                (raw_addr, bind_addr)
+            }
+            false => (bind_addr, raw_addr),
+        },
         None => {
-            return Err(io::Error::new(ErrorKind::Other, "no free port available"));
-        }
-    };
+            let ip = match is_server {
+                true => raw_addr.ip(),
+                false => Ipv4Addr::LOCALHOST.into(),
+            };
+
+            let free_port = find_free_port(ip).map_err(|e| {
+                io::Error::new(e.kind(), format!("no free port available on {}: {}", ip, e))
+            })?;
+            let listening_addr = SocketAddr::new(ip, free_port);
 
-    let listening_addr: SocketAddr = match is_server {
-        true => format!("{}:{}", raw_addr.ip(), free_port).parse().unwrap(),
-        false => format!("127.0.0.1:{}", free_port).parse().unwrap(),
+            (listening_addr, raw_addr)
+        }
     };
 
-    let local_addr = listening_addr.clone();
-    let remote_addr = raw_addr;
     let plugin = exec_plugin(plugin, plugin_opts, local_addr, remote_addr)?;
 
     match is_server {
@@ -48,16 +174,37 @@ pub fn start_plugin(
         false => log::info!("Plugin listening on {}", local_addr),
     }
 
-    Ok((listening_addr, plugin))
+    Ok((local_addr, plugin))
 }
 
+/// Checks that the plugin address and the shadowsocks address can be bound at the same time.
+fn check_compatible(plugin_addr: SocketAddr, ss_addr: SocketAddr) -> io::Result<()> {
+    let overlapped = plugin_addr.port() == ss_addr.port()
+        && (plugin_addr.ip() == ss_addr.ip()
+            || (plugin_addr.is_ipv4() == ss_addr.is_ipv4()
+                && (plugin_addr.ip().is_unspecified() || ss_addr.ip().is_unspecified())));
+
+    if overlapped {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "plugin address {} overlaps with shadowsocks address {}",
+                plugin_addr, ss_addr
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Spawns the plugin, its stderr is logged, see [`log_stderr`].
 fn exec_plugin(
     plugin: &str,
     plugin_opts: &str,
     local_addr: SocketAddr,
     remote_addr: SocketAddr,
 ) -> io::Result<Child> {
-    Command::new(plugin)
+    let mut child = Command::new(plugin)
         .env("SS_LOCAL_HOST", local_addr.ip().to_string())
         .env("SS_LOCAL_PORT", local_addr.port().to_string())
         .env("SS_REMOTE_HOST", remote_addr.ip().to_string())
@@ -65,17 +212,124 @@ fn exec_plugin(
         .env("SS_PLUGIN_OPTIONS", plugin_opts)
         .stdin(Stdio::null())
         .stdout(Stdio::null())
-        // .stderr(Stdio::null())
-        .spawn()
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    if let Some(stderr) = child.stderr.take() {
+        let name = Path::new(plugin)
+            .file_name()
+            .map_or(plugin.into(), |x| x.to_string_lossy());
+        tokio::spawn(log_stderr(name.into_owned(), stderr));
+    }
+
+    Ok(child)
 }
 
-fn find_free_port() -> Option<u16> {
-    for port in (1025..=u16::MAX).rev() {
-        match TcpListener::bind(format!("127.0.0.1:{}", port)) {
-            Ok(_) => return Some(port),
-            Err(_) => continue,
+/// Logs each line the plugin writes to stderr, prefixed with its name, until it closes it.
+async fn log_stderr<R>(name: String, stderr: R)
+where
+    R: AsyncRead + Unpin,
+{
+    let mut lines = BufReader::new(stderr).split(b'\n');
+
+    loop {
+        match lines.next_segment().await {
+            Ok(Some(line)) => {
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim_end();
+                if !line.is_empty() {
+                    log::warn!("[{}] {}", name, line);
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                log::debug!("Read stderr of plugin {} failed: {}", name, e);
+                break;
+            }
         }
     }
+}
+
+/// Returns a free port on the ip, picked by the OS from its ephemeral range.
+///
+/// SIP003 plugins bind their port themselves, so it is released before they start. Another
+/// process could take it meanwhile, but the OS doesn't hand out the same port again right away.
+fn find_free_port(ip: IpAddr) -> io::Result<u16> {
+    let listener = TcpListener::bind((ip, 0))?;
+    Ok(listener.local_addr()?.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plugin_opts() {
+        let opts: PluginOpts = "obfs=http;obfs-host=www.bing.com".parse().unwrap();
+        assert_eq!(opts.get("obfs"), Some("http"));
+        assert_eq!(opts.get("obfs-host"), Some("www.bing.com"));
+        assert_eq!(opts.get("mode"), None);
+
+        // Bare keys, and empty options skipped
+        let opts: PluginOpts = "server;tls;host=example.com;".parse().unwrap();
+        assert_eq!(opts.get("server"), Some(""));
+        assert_eq!(opts.get("tls"), Some(""));
+        assert_eq!(opts.to_string(), "server;tls;host=example.com");
 
-    None
+        // Escaped separators and backslashes, round-tripped
+        let s = r"path=/a\;b;key\=1=x\=y;dir=C:\\tmp";
+        let opts: PluginOpts = s.parse().unwrap();
+        assert_eq!(opts.get("path"), Some("/a;b"));
+        assert_eq!(opts.get("key=1"), Some("x=y"));
+        assert_eq!(opts.get("dir"), Some(r"C:\tmp"));
+        assert_eq!(opts.to_string(), s);
+        assert_eq!(opts.to_string().parse::<PluginOpts>().unwrap(), opts);
+
+        assert_eq!("".parse::<PluginOpts>().unwrap(), PluginOpts::default());
+
+        // Unescaped in values, escaped once serialized
+        let opts: PluginOpts = "path=/ws?ed=2048".parse().unwrap();
+        assert_eq!(opts.get("path"), Some("/ws?ed=2048"));
+        assert_eq!(opts.to_string(), r"path=/ws?ed\=2048");
+
+        assert!("=b".parse::<PluginOpts>().is_err());
+        assert!(r"a=b\".parse::<PluginOpts>().is_err());
+    }
+
+    #[test]
+    fn test_find_free_port() {
+        let ip = Ipv4Addr::LOCALHOST.into();
+        let port = find_free_port(ip).unwrap();
+        assert_ne!(port, 0);
+        assert!(TcpListener::bind((ip, port)).is_ok());
+
+        // Only on the given ip
+        assert!(find_free_port("192.0.2.1".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_check_compatible() {
+        let check = |a: &str, b: &str| check_compatible(a.parse().unwrap(), b.parse().unwrap());
+
+        assert!(check("0.0.0.0:443", "127.0.0.1:8388").is_ok());
+        assert!(check("192.168.1.2:8388", "127.0.0.1:8388").is_ok());
+        assert!(check("[::]:8388", "127.0.0.1:8388").is_ok());
+
+        assert!(check("127.0.0.1:8388", "127.0.0.1:8388").is_err());
+        assert!(check("0.0.0.0:8388", "127.0.0.1:8388").is_err());
+        assert!(check("[::1]:8388", "[::]:8388").is_err());
+    }
+
+    #[test]
+    fn test_start_plugin_bind() {
+        let plugin = "ss-rs-no-such-plugin";
+        let addr: SocketAddr = "127.0.0.1:8388".parse().unwrap();
+
+        let e = start_plugin(plugin, "", addr, Some(addr), true).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+
+        // ss-local connects to the remote server, the same address is no conflict
+        let e = start_plugin(plugin, "", addr, Some(addr), false).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+    }
 }
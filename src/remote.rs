@@ -0,0 +1,398 @@
+//! Several ss-remote servers for ss-local, with health checks and failover.
+//!
+//! Each server is checked periodically by probing it, see [`check_health`].
+//! New connections go to a healthy server picked by the [`Balance`] strategy, and a server
+//! that can't be connected to is marked unhealthy until it passes a check again.
+//!
+//! Only TCP fails over, the UDP relay always uses the first server.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::io::AsyncReadExt;
+
+use crate::{context::Ctx, crypto::cipher::Method, net::stream::TcpStream as SsTcpStream};
+
+mod constants {
+    use std::time::Duration;
+
+    pub const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+    pub const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+}
+
+/// Strategies to pick the server of a new connection among the healthy ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Balance {
+    /// The one with the lowest latency, those not measured yet last.
+    #[default]
+    Latency,
+
+    /// Each one in turn.
+    RoundRobin,
+
+    /// The one with the fewest active connections.
+    LeastConn,
+}
+
+/// A ss-remote server, with its health as last seen.
+pub struct Server {
+    addr: SocketAddr,
+    host: Option<String>,
+    method: Method,
+    key: Vec<u8>,
+    healthy: AtomicBool,
+    // In microseconds, `u64::MAX` if not measured yet.
+    latency: AtomicU64,
+    active: AtomicU64,
+}
+
+impl Server {
+    /// Creates a server, healthy until a check or connection fails.
+    ///
+    /// If `host` is a domain name, it is resolved again for each connection.
+    pub fn new(addr: SocketAddr, host: Option<String>, method: Method, key: Vec<u8>) -> Self {
+        Server {
+            addr,
+            host,
+            method,
+            key,
+            healthy: AtomicBool::new(true),
+            latency: AtomicU64::new(u64::MAX),
+            active: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the address resolved at startup.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the `host:port` of the server if it is a domain name.
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    /// Returns the method of the server.
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    /// Returns the master key of the server.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Returns true unless the last check or connection failed.
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Marks the server healthy or not.
+    pub fn set_healthy(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::Relaxed);
+    }
+
+    /// Returns the latency of the last successful check, if any.
+    pub fn latency(&self) -> Option<Duration> {
+        match self.latency.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    /// Records the latency of a successful check, marking the server healthy.
+    pub fn record_latency(&self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX - 1);
+        self.latency.store(micros, Ordering::Relaxed);
+        self.set_healthy(true);
+    }
+
+    /// Returns the number of active connections to the server.
+    pub fn active(&self) -> u64 {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Counts a connection to the server as active until the returned guard is dropped.
+    pub fn track(&self) -> Active<'_> {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Active(self)
+    }
+}
+
+/// An active connection to a server, see [`Server::track`].
+pub struct Active<'a>(&'a Server);
+
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Display for Server {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.host {
+            Some(host) => write!(f, "{}", host),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+/// The ss-remote servers of ss-local.
+pub struct RemotePool {
+    servers: Vec<Server>,
+    balance: Balance,
+    next: AtomicUsize,
+}
+
+impl RemotePool {
+    /// Creates a pool of the given servers, balanced by latency by default.
+    pub fn new(servers: Vec<Server>) -> Self {
+        RemotePool {
+            servers,
+            balance: Balance::default(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets the strategy to pick the server of a new connection.
+    pub fn set_balance(&mut self, balance: Balance) {
+        self.balance = balance;
+    }
+
+    /// Returns the strategy to pick the server of a new connection.
+    pub fn balance(&self) -> Balance {
+        self.balance
+    }
+
+    /// Returns all servers, in the order they were given.
+    pub fn servers(&self) -> &[Server] {
+        &self.servers
+    }
+
+    /// Returns the servers in the order to try them with the strategy of the pool,
+    /// see [`RemotePool::pick`].
+    pub fn candidates(&self) -> Vec<&Server> {
+        self.pick(self.balance)
+    }
+
+    /// Returns the servers in the order to try them: the healthy ones as picked by the strategy,
+    /// then the unhealthy ones in case they have recovered. Ties are kept in the given order.
+    ///
+    /// Round-robin starts from the next healthy server on each call, so each of them gets
+    /// the same share, and the unhealthy ones are left in the given order.
+    pub fn pick(&self, balance: Balance) -> Vec<&Server> {
+        let (mut healthy, mut unhealthy): (Vec<&Server>, Vec<&Server>) =
+            self.servers.iter().partition(|x| x.is_healthy());
+
+        match balance {
+            Balance::Latency => {
+                for candidates in [&mut healthy, &mut unhealthy] {
+                    candidates.sort_by_key(|x| x.latency().unwrap_or(Duration::MAX));
+                }
+            }
+            Balance::RoundRobin if !healthy.is_empty() => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                let len = healthy.len();
+                healthy.rotate_left(next % len);
+            }
+            Balance::RoundRobin => {}
+            Balance::LeastConn => {
+                for candidates in [&mut healthy, &mut unhealthy] {
+                    candidates.sort_by_key(|x| x.active());
+                }
+            }
+        }
+
+        healthy.append(&mut unhealthy);
+        healthy
+    }
+}
+
+/// Checks the health of the servers of the pool forever, one round per interval.
+///
+/// A check probes the server with a request for its own port on loopback, which it won't
+/// relay. Its latency is the time until the server responds to the probe.
+pub async fn check_health(ctx: Arc<Ctx>) {
+    let pool = match ctx.remote_pool() {
+        Some(pool) => pool,
+        None => return,
+    };
+
+    loop {
+        for server in pool.servers() {
+            let addr = match server.host() {
+                Some(host) => ctx.resolve(host).await.unwrap_or(server.addr()),
+                None => server.addr(),
+            };
+
+            let start = Instant::now();
+            match probe(addr, server.method(), server.key(), ctx.clone()).await {
+                Ok(_) => {
+                    let latency = start.elapsed();
+                    log::debug!("Remote server {} is healthy, latency {:?}", server, latency);
+                    server.record_latency(latency);
+                }
+                Err(e) => {
+                    if server.is_healthy() {
+                        log::warn!("Remote server {} is unhealthy: {}", server, e);
+                    }
+                    server.set_healthy(false);
+                }
+            }
+        }
+
+        tokio::time::sleep(constants::CHECK_INTERVAL).await;
+    }
+}
+
+/// Probes a server without involving any other host: sends it a request for a target it
+/// won't relay, its own port on loopback, and waits for its first response.
+///
+/// ss-remote responds by closing the connection once it has read the request and rejected
+/// the target, or failed to connect to it. A server that accepts connections but doesn't
+/// respond in time fails the probe.
+async fn probe(addr: SocketAddr, method: Method, key: &[u8], ctx: Arc<Ctx>) -> io::Result<()> {
+    let loopback: IpAddr = match addr {
+        SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+        SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+    };
+    let target = SocketAddr::new(loopback, addr.port());
+    let mut stream = SsTcpStream::connect(addr, target.into(), method, key, ctx).await?;
+
+    let mut buf = [0u8; 1];
+    match tokio::time::timeout(constants::PROBE_TIMEOUT, stream.read(&mut buf)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) if e.kind() == ErrorKind::ConnectionReset => Ok(()),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(io::Error::new(
+            ErrorKind::TimedOut,
+            "no response to the probe",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(port: u16) -> Server {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        Server::new(addr, None, Method::ChaCha20Poly1305, vec![0u8; 32])
+    }
+
+    fn ports(pool: &RemotePool) -> Vec<u16> {
+        pool.candidates().iter().map(|x| x.addr().port()).collect()
+    }
+
+    fn picked(pool: &RemotePool, balance: Balance) -> Vec<u16> {
+        pool.pick(balance).iter().map(|x| x.addr().port()).collect()
+    }
+
+    #[test]
+    fn test_candidates() {
+        let pool = RemotePool::new(vec![server(1), server(2), server(3)]);
+        assert_eq!(ports(&pool), [1, 2, 3]);
+
+        pool.servers()[1].record_latency(Duration::from_millis(20));
+        pool.servers()[2].record_latency(Duration::from_millis(10));
+        assert_eq!(ports(&pool), [3, 2, 1]);
+
+        // Unhealthy servers are tried last
+        pool.servers()[2].set_healthy(false);
+        assert_eq!(ports(&pool), [2, 1, 3]);
+
+        // A successful check makes it healthy again
+        pool.servers()[2].record_latency(Duration::from_millis(30));
+        assert_eq!(ports(&pool), [2, 3, 1]);
+        assert_eq!(pool.servers()[2].latency(), Some(Duration::from_millis(30)));
+        assert_eq!(pool.servers()[0].latency(), None);
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let pool = RemotePool::new(vec![server(1), server(2), server(3)]);
+        assert_eq!(picked(&pool, Balance::RoundRobin), [1, 2, 3]);
+        assert_eq!(picked(&pool, Balance::RoundRobin), [2, 3, 1]);
+
+        // The healthy servers take turns evenly, the unhealthy ones are still tried last
+        pool.servers()[2].set_healthy(false);
+        assert_eq!(picked(&pool, Balance::RoundRobin), [1, 2, 3]);
+        assert_eq!(picked(&pool, Balance::RoundRobin), [2, 1, 3]);
+        assert_eq!(picked(&pool, Balance::RoundRobin), [1, 2, 3]);
+        assert_eq!(picked(&pool, Balance::RoundRobin), [2, 1, 3]);
+
+        pool.servers()[0].set_healthy(false);
+        assert_eq!(picked(&pool, Balance::RoundRobin), [2, 1, 3]);
+        assert_eq!(picked(&pool, Balance::RoundRobin), [2, 1, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        use crate::tcp::{serve_remote, SsTcpListener};
+
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+
+        let ctx = Arc::new(Ctx::new());
+        let listener = SsTcpListener::bind("127.0.0.1:0", METHOD, &KEY, ctx.clone())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        ctx.add_listen_addr(addr);
+        tokio::spawn(serve_remote(listener, ctx.clone()));
+
+        probe(addr, METHOD, &KEY, ctx.clone()).await.unwrap();
+        assert_eq!(ctx.stats_snapshot().self_connects, 1);
+
+        // Nothing listening
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        assert!(probe(closed, METHOD, &KEY, ctx).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_probe_no_response() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+            drop(stream);
+        });
+
+        let ctx = Arc::new(Ctx::new());
+        let e = probe(addr, Method::ChaCha20Poly1305, &[7u8; 32], ctx)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        handle.abort();
+    }
+
+    #[test]
+    fn test_least_conn() {
+        let pool = RemotePool::new(vec![server(1), server(2), server(3)]);
+        let a = pool.servers()[0].track();
+        let b = pool.servers()[0].track();
+        let c = pool.servers()[1].track();
+        assert_eq!(pool.servers()[0].active(), 2);
+        assert_eq!(picked(&pool, Balance::LeastConn), [3, 2, 1]);
+
+        pool.servers()[2].set_healthy(false);
+        assert_eq!(picked(&pool, Balance::LeastConn), [2, 1, 3]);
+
+        // Connections are no longer counted once done
+        drop((a, b, c));
+        assert_eq!(pool.servers()[0].active(), 0);
+        assert_eq!(picked(&pool, Balance::LeastConn), [1, 2, 3]);
+    }
+}
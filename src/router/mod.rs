@@ -0,0 +1,83 @@
+//! Routing decisions: bypass, proxy or block.
+//!
+//! The access control list is the default [`Router`], and [`rules::RuleRouter`]
+//! is a scriptable one with more context, like the time of day.
+
+pub mod rules;
+
+use std::net::IpAddr;
+
+use crate::acl::Acl;
+
+/// Decides how to route connections.
+///
+/// On ss-local, bypassed targets are connected directly and the others are proxied.
+/// On ss-remote, disallowed clients are rejected and blocked targets aren't connected.
+///
+/// Routers are shared by all connections, so they are called concurrently from many tasks.
+pub trait Router: Send + Sync {
+    /// Returns true if the given ip or host should be bypassed.
+    fn is_bypass(&self, ip: IpAddr, host: Option<&str>) -> bool;
+
+    /// Returns whether the given host is bypassed (`Some(true)`) or proxied (`Some(false)`)
+    /// regardless of its ip, or `None` if it has to be resolved to decide.
+    fn match_host(&self, host: &str) -> Option<bool>;
+
+    /// Returns true if the given host should be bypassed, without resolving it.
+    fn is_bypass_host(&self, host: &str) -> bool;
+
+    /// Returns true if the given host should always be proxied and resolved by ss-remote.
+    ///
+    /// No host is by default.
+    fn is_remote_resolve(&self, _host: &str) -> bool {
+        false
+    }
+
+    /// Returns true if the given ip or host should be blocked.
+    fn is_block_outbound(&self, ip: IpAddr, host: Option<&str>) -> bool;
+
+    /// Returns true if ss-local should block the given ip or host, only by explicit rules.
+    ///
+    /// The ip is `None` for a domain name that isn't resolved locally.
+    /// Blocks the resolved targets ss-remote blocks by default.
+    fn is_block_local(&self, ip: Option<IpAddr>, host: Option<&str>) -> bool {
+        ip.is_some_and(|ip| self.is_block_outbound(ip, host))
+    }
+
+    /// Returns true if ss-remote should accept clients from the given ip.
+    ///
+    /// Rejects the clients that would be bypassed by default.
+    fn is_client_allowed(&self, ip: IpAddr) -> bool {
+        !self.is_bypass(ip, None)
+    }
+}
+
+impl Router for Acl {
+    fn is_bypass(&self, ip: IpAddr, host: Option<&str>) -> bool {
+        Acl::is_bypass(self, ip, host)
+    }
+
+    fn match_host(&self, host: &str) -> Option<bool> {
+        Acl::match_host(self, host)
+    }
+
+    fn is_bypass_host(&self, host: &str) -> bool {
+        Acl::is_bypass_host(self, host)
+    }
+
+    fn is_remote_resolve(&self, host: &str) -> bool {
+        Acl::is_remote_resolve(self, host)
+    }
+
+    fn is_block_outbound(&self, ip: IpAddr, host: Option<&str>) -> bool {
+        Acl::is_block_outbound(self, ip, host)
+    }
+
+    fn is_block_local(&self, ip: Option<IpAddr>, host: Option<&str>) -> bool {
+        Acl::match_block_outbound(self, ip, host)
+    }
+
+    fn is_client_allowed(&self, ip: IpAddr) -> bool {
+        Acl::is_client_allowed(self, ip)
+    }
+}
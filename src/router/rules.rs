@@ -0,0 +1,328 @@
+//! A router evaluating a list of rules, first match wins.
+//!
+//! Each line is a rule `ACTION MATCHER [@ HH:MM-HH:MM]`, where:
+//!
+//! - `ACTION` is `bypass`, `proxy` or `block`;
+//! - `MATCHER` is `ip CIDR`, `host REGEX` or `all`;
+//! - the optional time window, in local time, limits when the rule applies.
+//!   It may wrap around midnight, e.g. `@ 22:00-06:00`.
+//!
+//! A line `default bypass` or `default proxy` sets the action when no rule matches, `proxy` by default.
+//! Everything after `#` is a comment.
+//!
+//! ~~~text
+//! bypass ip 192.168.0.0/16
+//! block  host (^|\.)ads\.example\.com$
+//! proxy  host (^|\.)example\.com$
+//! bypass all @ 09:00-18:00
+//! default proxy
+//! ~~~
+//!
+//! Block rules only decide what ss-remote blocks, bypass and proxy rules decide the rest.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+    net::IpAddr,
+    path::Path,
+};
+
+use chrono::{Local, NaiveTime};
+use regex::Regex;
+
+use crate::{
+    acl::{cidr::Cidr, ip_set::IpSet},
+    router::Router,
+};
+
+/// A router evaluating a list of rules.
+pub struct RuleRouter {
+    rules: Vec<Rule>,
+    default_bypass: bool,
+}
+
+struct Rule {
+    action: Action,
+    matcher: Matcher,
+    window: Option<(NaiveTime, NaiveTime)>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Bypass,
+    Proxy,
+    Block,
+}
+
+enum Matcher {
+    Ip(IpSet),
+    Host(Regex),
+    All,
+}
+
+impl RuleRouter {
+    /// Creates a new router from a file.
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let data = std::fs::read_to_string(path)?;
+        data.parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn is_bypass_at(&self, ip: IpAddr, host: Option<&str>, now: NaiveTime) -> bool {
+        let ip_str = ip.to_string();
+        let host = host.filter(|host| *host != ip_str);
+
+        self.routes(now)
+            .find(|rule| match rule.matcher {
+                Matcher::Ip(ref ip_set) => ip_set.contains(ip),
+                Matcher::Host(ref regex) => host.is_some_and(|host| regex.is_match(host)),
+                Matcher::All => true,
+            })
+            .map_or(self.default_bypass, |rule| rule.action == Action::Bypass)
+    }
+
+    fn match_host_at(&self, host: &str, now: NaiveTime) -> Option<bool> {
+        for rule in self.routes(now) {
+            match rule.matcher {
+                // An earlier ip rule could match once resolved.
+                Matcher::Ip(_) => return None,
+                Matcher::Host(ref regex) if !regex.is_match(host) => continue,
+                _ => return Some(rule.action == Action::Bypass),
+            }
+        }
+
+        None
+    }
+
+    fn is_bypass_host_at(&self, host: &str, now: NaiveTime) -> bool {
+        self.routes(now)
+            .find(|rule| match rule.matcher {
+                Matcher::Ip(_) => false,
+                Matcher::Host(ref regex) => regex.is_match(host),
+                Matcher::All => true,
+            })
+            .map_or(self.default_bypass, |rule| rule.action == Action::Bypass)
+    }
+
+    fn is_block_outbound_at(&self, ip: Option<IpAddr>, host: Option<&str>, now: NaiveTime) -> bool {
+        self.active(now)
+            .filter(|rule| rule.action == Action::Block)
+            .any(|rule| match rule.matcher {
+                Matcher::Ip(ref ip_set) => ip.is_some_and(|ip| ip_set.contains(ip)),
+                Matcher::Host(ref regex) => host.is_some_and(|host| regex.is_match(host)),
+                Matcher::All => true,
+            })
+    }
+
+    /// Returns the rules that apply at the given time.
+    fn active(&self, now: NaiveTime) -> impl Iterator<Item = &Rule> {
+        self.rules.iter().filter(move |rule| match rule.window {
+            Some((start, end)) if start <= end => start <= now && now < end,
+            Some((start, end)) => start <= now || now < end,
+            None => true,
+        })
+    }
+
+    /// Returns the bypass and proxy rules that apply at the given time.
+    fn routes(&self, now: NaiveTime) -> impl Iterator<Item = &Rule> {
+        self.active(now).filter(|rule| rule.action != Action::Block)
+    }
+}
+
+impl Router for RuleRouter {
+    fn is_bypass(&self, ip: IpAddr, host: Option<&str>) -> bool {
+        self.is_bypass_at(ip, host, Local::now().time())
+    }
+
+    fn match_host(&self, host: &str) -> Option<bool> {
+        self.match_host_at(host, Local::now().time())
+    }
+
+    fn is_bypass_host(&self, host: &str) -> bool {
+        self.is_bypass_host_at(host, Local::now().time())
+    }
+
+    fn is_block_outbound(&self, ip: IpAddr, host: Option<&str>) -> bool {
+        self.is_block_outbound_at(Some(ip), host, Local::now().time())
+    }
+
+    fn is_block_local(&self, ip: Option<IpAddr>, host: Option<&str>) -> bool {
+        self.is_block_outbound_at(ip, host, Local::now().time())
+    }
+}
+
+impl std::str::FromStr for RuleRouter {
+    type Err = Error;
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        let mut router = RuleRouter {
+            rules: Vec::new(),
+            default_bypass: false,
+        };
+
+        for (i, line) in data.lines().enumerate() {
+            let end = line.find('#').unwrap_or(line.len());
+            let line = line[..end].trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let err = |reason: &str| Error {
+                line: i + 1,
+                reason: format!("{}: {}", reason, line),
+            };
+
+            let (rule, window) = match line.split_once('@') {
+                Some((rule, window)) => (
+                    rule,
+                    Some(parse_window(window).ok_or_else(|| err("invalid time window"))?),
+                ),
+                None => (line, None),
+            };
+
+            let mut words = rule.split_whitespace();
+            let action = match words.next() {
+                Some("default") => {
+                    router.default_bypass = match (words.next(), words.next()) {
+                        (Some("bypass"), None) => true,
+                        (Some("proxy"), None) => false,
+                        _ => return Err(err("invalid default action")),
+                    };
+                    continue;
+                }
+                Some("bypass") => Action::Bypass,
+                Some("proxy") => Action::Proxy,
+                Some("block") => Action::Block,
+                _ => return Err(err("invalid action")),
+            };
+
+            let matcher = match (words.next(), words.next(), words.next()) {
+                (Some("ip"), Some(cidr), None) => {
+                    let cidr = cidr.parse::<Cidr>().map_err(|_| err("invalid cidr"))?;
+                    let mut ip_set = IpSet::new();
+                    ip_set.insert(cidr);
+                    Matcher::Ip(ip_set)
+                }
+                (Some("host"), Some(regex), None) => {
+                    Matcher::Host(regex.parse().map_err(|_| err("invalid regex"))?)
+                }
+                (Some("all"), None, None) => Matcher::All,
+                _ => return Err(err("invalid matcher")),
+            };
+
+            router.rules.push(Rule {
+                action,
+                matcher,
+                window,
+            });
+        }
+
+        Ok(router)
+    }
+}
+
+fn parse_window(window: &str) -> Option<(NaiveTime, NaiveTime)> {
+    let (start, end) = window.trim().split_once('-')?;
+    let start = NaiveTime::parse_from_str(start.trim(), "%H:%M").ok()?;
+    let end = NaiveTime::parse_from_str(end.trim(), "%H:%M").ok()?;
+    Some((start, end))
+}
+
+/// Errors when parsing rules.
+#[derive(Debug)]
+pub struct Error {
+    line: usize,
+    reason: String,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_rules() {
+        const DATA: &str = r"
+        # local network
+        bypass ip 192.168.0.0/16
+        block  host (^|\.)ads\.example\.com$
+        proxy  host (^|\.)example\.com$
+        bypass host (^|\.)example\.cn$
+        default bypass
+        ";
+
+        let router: RuleRouter = DATA.parse().unwrap();
+        let noon = at("12:00");
+        let ip = "8.8.8.8".parse().unwrap();
+
+        assert!(router.is_bypass_at("192.168.1.1".parse().unwrap(), None, noon));
+        assert!(!router.is_bypass_at(ip, Some("www.example.com"), noon));
+        assert!(router.is_bypass_at(ip, Some("www.example.cn"), noon));
+        assert!(router.is_bypass_at(ip, Some("qq.com"), noon));
+
+        // An earlier ip rule needs the host resolved.
+        assert_eq!(router.match_host_at("www.example.com", noon), None);
+        assert!(!router.is_bypass_host_at("www.example.com", noon));
+        assert!(router.is_bypass_host_at("qq.com", noon));
+
+        assert!(router.is_block_outbound_at(Some(ip), Some("ads.example.com"), noon));
+        assert!(!router.is_block_outbound_at(Some(ip), Some("www.example.com"), noon));
+        assert!(router.is_block_outbound_at(None, Some("ads.example.com"), noon));
+    }
+
+    #[test]
+    fn test_window() {
+        const DATA: &str = r"
+        proxy  host (^|\.)example\.com$
+        bypass all @ 09:00-18:00
+        block  ip 10.0.0.0/8 @ 22:00-06:00
+        ";
+
+        let router: RuleRouter = DATA.parse().unwrap();
+        let ip = "8.8.8.8".parse().unwrap();
+        let private = "10.0.0.1".parse().unwrap();
+
+        assert!(router.is_bypass_at(ip, Some("qq.com"), at("09:00")));
+        assert!(!router.is_bypass_at(ip, Some("qq.com"), at("18:00")));
+        assert!(!router.is_bypass_at(ip, Some("www.example.com"), at("12:00")));
+
+        assert_eq!(
+            router.match_host_at("www.example.com", at("12:00")),
+            Some(false)
+        );
+        assert_eq!(router.match_host_at("qq.com", at("12:00")), Some(true));
+        assert_eq!(router.match_host_at("qq.com", at("20:00")), None);
+
+        assert!(router.is_block_outbound_at(Some(private), None, at("23:00")));
+        assert!(router.is_block_outbound_at(Some(private), None, at("05:59")));
+        assert!(!router.is_block_outbound_at(Some(private), None, at("06:00")));
+    }
+
+    #[test]
+    fn test_error() {
+        for data in [
+            "bypas all",
+            "bypass ip 300.0.0.0/8",
+            "bypass host (",
+            "bypass all @ 9-18",
+            "proxy everything",
+            "default block",
+        ] {
+            assert!(data.parse::<RuleRouter>().is_err(), "{}", data);
+        }
+
+        let err = "bypass all\nproxy".parse::<RuleRouter>().err().unwrap();
+        assert_eq!(err.to_string(), "line 2: invalid matcher: proxy");
+    }
+}
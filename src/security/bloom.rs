@@ -0,0 +1,110 @@
+//! Bloom filter with a persistable state.
+
+use bitvec::{order::Lsb0, vec::BitVec};
+
+/// A bloom filter hashing elements with keyed BLAKE3.
+///
+/// Unlike filters seeded per process, its bits stay valid across restarts
+/// as long as the key is kept along with them.
+pub struct BloomFilter {
+    bits: BitVec<u8, Lsb0>,
+    num_hashes: u32,
+    key: [u8; 32],
+}
+
+impl BloomFilter {
+    /// Creates a new bloom filter with the given false positive rate
+    /// for the expected number of items.
+    pub fn with_rate(rate: f64, expected_num_items: u32, key: [u8; 32]) -> Self {
+        let num_bits = needed_bits(rate, expected_num_items);
+        let num_hashes = optimal_num_hashes(num_bits, expected_num_items);
+
+        BloomFilter {
+            bits: BitVec::repeat(false, num_bits),
+            num_hashes,
+            key,
+        }
+    }
+
+    /// Restores a bloom filter from its raw bits, see [`BloomFilter::as_raw_bytes`].
+    ///
+    /// Returns `None` if there are too few bytes for `num_bits`.
+    pub fn from_raw_bytes(
+        bytes: Vec<u8>,
+        num_bits: usize,
+        num_hashes: u32,
+        key: [u8; 32],
+    ) -> Option<Self> {
+        if num_bits == 0 || bytes.len() < num_bits.div_ceil(8) {
+            return None;
+        }
+
+        let mut bits = BitVec::from_vec(bytes);
+        bits.truncate(num_bits);
+
+        Some(BloomFilter {
+            bits,
+            num_hashes,
+            key,
+        })
+    }
+
+    /// Returns the bits packed into bytes, least significant bit first.
+    pub fn as_raw_bytes(&self) -> &[u8] {
+        self.bits.as_raw_slice()
+    }
+
+    /// Returns the number of bits.
+    pub fn num_bits(&self) -> usize {
+        self.bits.len()
+    }
+
+    /// Returns the key of the hashes.
+    pub fn key(&self) -> &[u8; 32] {
+        &self.key
+    }
+
+    /// Returns the number of hashes per element.
+    pub fn num_hashes(&self) -> u32 {
+        self.num_hashes
+    }
+
+    /// Returns true if the element may have been inserted.
+    pub fn contains(&self, element: &[u8]) -> bool {
+        self.indexes(element).all(|i| self.bits[i])
+    }
+
+    /// Inserts the element.
+    pub fn insert(&mut self, element: &[u8]) {
+        for i in self.indexes(element) {
+            self.bits.set(i, true);
+        }
+    }
+
+    /// Removes all elements.
+    pub fn clear(&mut self) {
+        self.bits.fill(false);
+    }
+
+    // Double hashing: the i-th index is h1 + i * h2.
+    fn indexes(&self, element: &[u8]) -> impl Iterator<Item = usize> {
+        let hash = blake3::keyed_hash(&self.key, element);
+        let hash = hash.as_bytes();
+
+        let h1 = u64::from_le_bytes(hash[..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(hash[8..16].try_into().unwrap());
+        let num_bits = self.bits.len() as u64;
+
+        (0..self.num_hashes as u64)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+}
+
+fn needed_bits(rate: f64, num_items: u32) -> usize {
+    let ln22 = std::f64::consts::LN_2 * std::f64::consts::LN_2;
+    (num_items as f64 * ((1.0 / rate).ln() / ln22)).round() as usize
+}
+
+fn optimal_num_hashes(num_bits: usize, num_items: u32) -> u32 {
+    ((num_bits as f64 / num_items as f64 * std::f64::consts::LN_2).round() as u32).clamp(2, 200)
+}
@@ -1,11 +1,26 @@
 //! Networking security facilities for shadowsocks communication.
 
-use std::sync::Mutex;
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind},
+    path::Path,
+    sync::Mutex,
+};
 
-use bloom::{BloomFilter, ASMS};
+use self::bloom::BloomFilter;
+
+pub use self::rate_limit::{Admission, RateLimiter};
+
+mod bloom;
+mod rate_limit;
 
 mod constants {
     pub const EXPECTED_NUM_ITEMS: u32 = 1_000_000;
+    pub const FALSE_POSITIVE_RATE: f64 = 1e-6;
+    pub const RING_SIZE: usize = 1024;
+
+    /// Header of saved bloom filters, with the format version.
+    pub const BLOOM_MAGIC: &[u8; 8] = b"SSRSBLM1";
 }
 
 struct Bloom {
@@ -16,44 +31,181 @@ struct Bloom {
 
 impl Bloom {
     fn new() -> Self {
+        let key = rand::random();
+        let filter = || {
+            BloomFilter::with_rate(
+                constants::FALSE_POSITIVE_RATE,
+                constants::EXPECTED_NUM_ITEMS,
+                key,
+            )
+        };
+
         Bloom {
-            filters: [
-                BloomFilter::with_rate(1e-6, constants::EXPECTED_NUM_ITEMS),
-                BloomFilter::with_rate(1e-6, constants::EXPECTED_NUM_ITEMS),
-            ],
+            filters: [filter(), filter()],
             current: 0,
             count: 0,
         }
     }
 
     fn check_and_insert(&mut self, element: &[u8]) -> bool {
-        if self.filters.iter().any(|x| x.contains(&element)) {
+        if self.filters.iter().any(|x| x.contains(element)) {
             return false;
         }
 
         let filter = &mut self.filters[self.current];
-        filter.insert(&element);
+        filter.insert(element);
 
         self.count += 1;
         if self.count == constants::EXPECTED_NUM_ITEMS {
             self.current = (self.current + 1) % 2;
             self.filters[self.current].clear();
+            self.count = 0;
+        }
+
+        true
+    }
+
+    /// Serializes the filters and their rotation state.
+    ///
+    /// The layout is the magic, the hash key, `current` (1 byte), `count`, the number of hashes
+    /// (4 bytes each), the number of bits (8 bytes), then the bits of both filters.
+    /// Integers are little endian.
+    fn to_bytes(&self) -> Vec<u8> {
+        let [first, second] = &self.filters;
+
+        let mut data = Vec::with_capacity(57 + 2 * first.as_raw_bytes().len());
+        data.extend_from_slice(constants::BLOOM_MAGIC);
+        data.extend_from_slice(first.key());
+        data.push(self.current as u8);
+        data.extend_from_slice(&self.count.to_le_bytes());
+        data.extend_from_slice(&first.num_hashes().to_le_bytes());
+        data.extend_from_slice(&(first.num_bits() as u64).to_le_bytes());
+        data.extend_from_slice(first.as_raw_bytes());
+        data.extend_from_slice(second.as_raw_bytes());
+
+        data
+    }
+
+    /// Deserializes the filters saved by [`Bloom::to_bytes`].
+    fn from_bytes(mut data: &[u8]) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(ErrorKind::InvalidData, msg.to_owned());
+
+        let mut take = |n: usize| match data.len() >= n {
+            true => {
+                let (head, tail) = data.split_at(n);
+                data = tail;
+                Ok(head)
+            }
+            false => Err(invalid("truncated bloom filters")),
+        };
+
+        if take(8)? != constants::BLOOM_MAGIC {
+            return Err(invalid("not saved bloom filters, or of another version"));
+        }
+
+        let key: [u8; 32] = take(32)?.try_into().unwrap();
+        let current = take(1)?[0] as usize;
+        let count = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        let num_hashes = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        let num_bits = u64::from_le_bytes(take(8)?.try_into().unwrap()) as usize;
+
+        if current > 1 || count >= constants::EXPECTED_NUM_ITEMS || num_hashes == 0 {
+            return Err(invalid("invalid bloom filter state"));
+        }
+
+        let len = num_bits.div_ceil(8);
+        let mut filter = || {
+            let bytes = take(len)?.to_vec();
+            BloomFilter::from_raw_bytes(bytes, num_bits, num_hashes, key)
+                .ok_or_else(|| invalid("invalid bloom filter size"))
+        };
+
+        Ok(Bloom {
+            filters: [filter()?, filter()?],
+            current,
+            count,
+        })
+    }
+}
+
+/// Remembers only the most recent elements, exactly.
+struct Ring {
+    elements: VecDeque<Vec<u8>>,
+}
+
+impl Ring {
+    fn new() -> Self {
+        Ring {
+            elements: VecDeque::with_capacity(constants::RING_SIZE),
+        }
+    }
+
+    fn check_and_insert(&mut self, element: &[u8]) -> bool {
+        if self.elements.iter().any(|x| x == element) {
+            return false;
         }
 
+        if self.elements.len() == constants::RING_SIZE {
+            self.elements.pop_front();
+        }
+        self.elements.push_back(element.to_owned());
+
         true
     }
 }
 
+enum Filter {
+    Bloom(Bloom),
+    Ring(Ring),
+    Off,
+}
+
+impl Filter {
+    fn check_and_insert(&mut self, element: &[u8]) -> bool {
+        match self {
+            Filter::Bloom(bloom) => bloom.check_and_insert(element),
+            Filter::Ring(ring) => ring.check_and_insert(element),
+            Filter::Off => true,
+        }
+    }
+}
+
+/// How thoroughly replay attacks are detected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplayMode {
+    /// Remembers about the last million salts in bloom filters. (default)
+    #[default]
+    Full,
+
+    /// Remembers only the last 1024 salts, catching immediate duplicate bursts
+    /// without the memory of the bloom filters.
+    Lite,
+
+    /// Doesn't detect replay attacks.
+    Off,
+}
+
 /// Replay attack protection that serves as a set.
 pub struct ReplayProtection {
-    bloom: Mutex<Bloom>,
+    filter: Mutex<Filter>,
 }
 
 impl ReplayProtection {
     /// Creates a new instance of the ReplayProtection.
     pub fn new() -> Self {
+        Self::with_mode(ReplayMode::Full)
+    }
+
+    /// Creates a new instance of the ReplayProtection in the given mode.
+    pub fn with_mode(mode: ReplayMode) -> Self {
+        let filter = match mode {
+            ReplayMode::Full => Filter::Bloom(Bloom::new()),
+            ReplayMode::Lite => Filter::Ring(Ring::new()),
+            ReplayMode::Off => Filter::Off,
+        };
+
         ReplayProtection {
-            bloom: Mutex::new(Bloom::new()),
+            filter: Mutex::new(filter),
         }
     }
 
@@ -64,6 +216,176 @@ impl ReplayProtection {
     ///
     /// Returns true if the element was successfully inserted.
     pub fn check_and_insert(&self, element: &[u8]) -> bool {
-        self.bloom.lock().unwrap().check_and_insert(&element)
+        self.filter.lock().unwrap().check_and_insert(&element)
+    }
+
+    /// Inserts the given elements to the set, e.g. salts captured during a known compromise.
+    pub fn preload(&self, elements: &[Vec<u8>]) {
+        let mut filter = self.filter.lock().unwrap();
+        for element in elements {
+            filter.check_and_insert(element);
+        }
+    }
+
+    /// Loads the set saved by [`ReplayProtection::save_to`], in the full mode.
+    pub fn load_from(path: &Path) -> io::Result<Self> {
+        let data = std::fs::read(path)?;
+        let bloom = Bloom::from_bytes(&data)?;
+
+        Ok(ReplayProtection {
+            filter: Mutex::new(Filter::Bloom(bloom)),
+        })
+    }
+
+    /// Saves the set to a file, replacing it atomically.
+    ///
+    /// Only the full mode can be saved.
+    pub fn save_to(&self, path: &Path) -> io::Result<()> {
+        let data = match *self.filter.lock().unwrap() {
+            Filter::Bloom(ref bloom) => bloom.to_bytes(),
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::Unsupported,
+                    "only the full replay protection can be saved",
+                ))
+            }
+        };
+
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        std::fs::write(&tmp_path, data)?;
+        std::fs::rename(&tmp_path, path)
+    }
+}
+
+/// Loads salts from a file, see [`parse_salts`].
+pub fn load_salts(path: &Path) -> io::Result<Vec<Vec<u8>>> {
+    let data = std::fs::read_to_string(path)?;
+    parse_salts(&data)
+}
+
+/// Parses hex-encoded salts, one per line.
+///
+/// Empty lines and comments starting with `#` are ignored.
+pub fn parse_salts(data: &str) -> io::Result<Vec<Vec<u8>>> {
+    data.lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let end = line.find('#').unwrap_or(line.len());
+            (i, line[..end].trim())
+        })
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| {
+            decode_hex(line).ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid salt at line {}: {}", i + 1, line),
+                )
+            })
+        })
+        .collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    let digit = |x: &u8| (*x as char).to_digit(16);
+
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [hi, lo] => Some((digit(hi)? << 4 | digit(lo)?) as u8),
+            _ => None,
+        })
+        .collect()
+}
+
+/// When to check an incoming salt for replay attacks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplayCheck {
+    /// Checks as soon as the salt is received, before deriving the subkey.
+    ///
+    /// Replays are rejected earliest and without wasting a key derivation,
+    /// but any salt is recorded, even one sent by someone who doesn't know the key.
+    /// An on-path observer could thus race a legitimate client by sending its salt first.
+    OnSalt,
+
+    /// Checks after the first chunk has been decrypted. (default)
+    ///
+    /// This is the earliest safe point: only salts of clients that know the key are recorded.
+    #[default]
+    OnFirstChunk,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lite() {
+        let replay_protection = ReplayProtection::with_mode(ReplayMode::Lite);
+
+        assert!(replay_protection.check_and_insert(b"salt"));
+        assert!(!replay_protection.check_and_insert(b"salt"));
+
+        for i in 0..constants::RING_SIZE as u32 {
+            assert!(replay_protection.check_and_insert(&i.to_be_bytes()));
+        }
+        assert!(replay_protection.check_and_insert(b"salt"));
+    }
+
+    #[test]
+    fn test_off() {
+        let replay_protection = ReplayProtection::with_mode(ReplayMode::Off);
+
+        assert!(replay_protection.check_and_insert(b"salt"));
+        assert!(replay_protection.check_and_insert(b"salt"));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("ss-rs-replay-{}", std::process::id()));
+
+        let replay_protection = ReplayProtection::new();
+        assert!(replay_protection.check_and_insert(b"salt"));
+        replay_protection.save_to(&path).unwrap();
+
+        let replay_protection = ReplayProtection::load_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!replay_protection.check_and_insert(b"salt"));
+        assert!(replay_protection.check_and_insert(b"pepper"));
+
+        let lite = ReplayProtection::with_mode(ReplayMode::Lite);
+        assert_eq!(
+            lite.save_to(&path).unwrap_err().kind(),
+            ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn test_bloom_from_bytes() {
+        let mut bloom = Bloom::new();
+        bloom.check_and_insert(b"salt");
+        let data = bloom.to_bytes();
+
+        let mut bloom = Bloom::from_bytes(&data).unwrap();
+        assert!(!bloom.check_and_insert(b"salt"));
+        assert_eq!(bloom.count, 1);
+
+        assert!(Bloom::from_bytes(&data[..data.len() - 1]).is_err());
+        assert!(Bloom::from_bytes(&data[1..]).is_err());
+
+        let mut data = data;
+        data[40] = 2;
+        assert!(Bloom::from_bytes(&data).is_err());
+    }
+
+    #[test]
+    fn test_parse_salts() {
+        let salts = parse_salts("# known-bad salts\n\n00ff10 # comment\n  ABcd  \n").unwrap();
+        assert_eq!(salts, vec![vec![0x00, 0xff, 0x10], vec![0xab, 0xcd]]);
+
+        assert!(parse_salts("00f").is_err());
+        assert!(parse_salts("0g").is_err());
+        assert!(parse_salts("+1").is_err());
     }
 }
@@ -0,0 +1,236 @@
+//! Limits how many connections each peer ip can open per second.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, Ipv6Addr},
+    sync::Mutex,
+};
+
+use tokio::time::Instant;
+
+mod constants {
+    use std::time::Duration;
+
+    pub const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+    pub const MAX_TRACKED_IPS: usize = 65536;
+    pub const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+}
+
+/// Whether a new connection is within the rate of its peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// The connection is allowed.
+    Allowed,
+
+    /// The connection is over the rate. If rejections should be reported now,
+    /// it has the number of connections rejected since they were last reported.
+    Rejected(Option<u64>),
+}
+
+/// A token bucket of connections per peer ip, allowing bursts of a second's worth.
+///
+/// IPv6 peers share a bucket per /64 network, as a host usually has a whole /64 to pick
+/// addresses from.
+///
+/// Buckets of ips that have been idle long enough to be full again are pruned from time to time.
+/// Past the maximum number of tracked ips, the oldest bucket is evicted for a new one.
+pub struct RateLimiter {
+    rate: f64,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    buckets: HashMap<IpAddr, Bucket>,
+    // The tracked ips, from the oldest
+    order: VecDeque<IpAddr>,
+    last_prune: Instant,
+    last_report: Option<Instant>,
+    rejected: u64,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter allowing each ip `rate` new connections per second.
+    pub fn new(rate: u32) -> Self {
+        RateLimiter {
+            rate: rate.max(1) as f64,
+            inner: Mutex::new(Inner {
+                buckets: HashMap::new(),
+                order: VecDeque::new(),
+                last_prune: Instant::now(),
+                last_report: None,
+                rejected: 0,
+            }),
+        }
+    }
+
+    /// Takes a token for a new connection from the ip.
+    pub fn check(&self, ip: IpAddr) -> Admission {
+        let ip = bucket_key(ip);
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+
+        if now.duration_since(inner.last_prune) >= constants::PRUNE_INTERVAL {
+            inner.last_prune = now;
+            inner
+                .buckets
+                .retain(|_, x| x.refill(now, self.rate) < self.rate);
+            inner.order.retain(|ip| inner.buckets.contains_key(ip));
+        }
+
+        if !inner.buckets.contains_key(&ip) {
+            if inner.buckets.len() >= constants::MAX_TRACKED_IPS {
+                if let Some(oldest) = inner.order.pop_front() {
+                    inner.buckets.remove(&oldest);
+                }
+            }
+            inner.order.push_back(ip);
+        }
+
+        let bucket = inner.buckets.entry(ip).or_insert(Bucket {
+            tokens: self.rate,
+            last_refill: now,
+        });
+
+        if bucket.refill(now, self.rate) >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Admission::Allowed;
+        }
+
+        inner.rejected += 1;
+        let report = match inner.last_report {
+            Some(last) => now.duration_since(last) >= constants::REPORT_INTERVAL,
+            None => true,
+        };
+        if !report {
+            return Admission::Rejected(None);
+        }
+
+        inner.last_report = Some(now);
+        Admission::Rejected(Some(std::mem::take(&mut inner.rejected)))
+    }
+
+    /// Returns the number of tracked ips.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().buckets.len()
+    }
+
+    /// Returns true if no ip is tracked.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Returns the ip whose bucket the ip uses, its /64 network for IPv6.
+fn bucket_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !(u64::MAX as u128))),
+        ip => ip,
+    }
+}
+
+impl Bucket {
+    /// Adds the tokens earned since the last refill, up to a second's worth, returning them.
+    fn refill(&mut self, now: Instant, rate: f64) -> f64 {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.last_refill = now;
+        self.tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_check() {
+        let limiter = RateLimiter::new(2);
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+
+        assert_eq!(limiter.check(a), Admission::Allowed);
+        assert_eq!(limiter.check(a), Admission::Allowed);
+        assert_eq!(limiter.check(a), Admission::Rejected(Some(1)));
+        assert_eq!(limiter.check(a), Admission::Rejected(None));
+
+        // Other ips have their own bucket.
+        assert_eq!(limiter.check(b), Admission::Allowed);
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(limiter.check(a), Admission::Allowed);
+        assert_eq!(limiter.check(a), Admission::Rejected(None));
+
+        // Rejections are reported once per interval, with the count since the last report.
+        tokio::time::advance(constants::REPORT_INTERVAL).await;
+        for _ in 0..2 {
+            assert_eq!(limiter.check(a), Admission::Allowed);
+        }
+        assert_eq!(limiter.check(a), Admission::Rejected(Some(3)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_prune() {
+        let limiter = RateLimiter::new(1);
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+
+        limiter.check(a);
+        tokio::time::advance(constants::PRUNE_INTERVAL - Duration::from_millis(500)).await;
+        limiter.check(b);
+        assert_eq!(limiter.len(), 2);
+
+        // The idle ip is full again and pruned, the busy one is kept.
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(limiter.check(b), Admission::Rejected(Some(1)));
+        assert_eq!(limiter.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_evict() {
+        let limiter = RateLimiter::new(1);
+        let first: IpAddr = "10.0.0.0".parse().unwrap();
+
+        for i in 0..constants::MAX_TRACKED_IPS as u32 {
+            let ip = IpAddr::V4((u32::from_be_bytes([10, 0, 0, 0]) + i).into());
+            assert_eq!(limiter.check(ip), Admission::Allowed);
+        }
+        assert_eq!(limiter.len(), constants::MAX_TRACKED_IPS);
+        assert!(matches!(limiter.check(first), Admission::Rejected(_)));
+
+        // The oldest bucket makes room for a new ip
+        let new: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(limiter.check(new), Admission::Allowed);
+        assert_eq!(limiter.len(), constants::MAX_TRACKED_IPS);
+        assert_eq!(limiter.check(first), Admission::Allowed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ipv6_network() {
+        let limiter = RateLimiter::new(1);
+        let a: IpAddr = "2001:db8::1".parse().unwrap();
+        let b: IpAddr = "2001:db8::ffff:2".parse().unwrap();
+        let other: IpAddr = "2001:db8:0:1::1".parse().unwrap();
+
+        // Addresses of the same /64 share a bucket
+        assert_eq!(limiter.check(a), Admission::Allowed);
+        assert!(matches!(limiter.check(b), Admission::Rejected(_)));
+        assert_eq!(limiter.check(other), Admission::Allowed);
+        assert_eq!(limiter.len(), 2);
+
+        // IPv4-mapped addresses are IPv4 ones
+        let mapped: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        assert_eq!(limiter.check(mapped), Admission::Allowed);
+        assert!(matches!(
+            limiter.check("192.0.2.1".parse().unwrap()),
+            Admission::Rejected(_)
+        ));
+    }
+}
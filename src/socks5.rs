@@ -1,9 +1,11 @@
 //! SOCKS5 protocol implementation.
 
 use std::{
+    collections::HashMap,
     fmt::{self, Display, Formatter},
     io,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    str::FromStr,
 };
 
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
@@ -19,12 +21,30 @@ pub mod constants {
 
     // Method
     pub const METHOD_NO_AUTHENTICATION: u8 = 0x00;
+    pub const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+    pub const METHOD_NO_ACCEPTABLE: u8 = 0xFF;
+
+    // Username/password authentication (RFC 1929)
+    pub const AUTH_VERSION: u8 = 0x01;
+    pub const AUTH_SUCCEEDED: u8 = 0x00;
+    pub const AUTH_FAILED: u8 = 0x01;
 
     // Command
     pub const COMMAND_CONNECT: u8 = 0x01;
+    pub const COMMAND_BIND: u8 = 0x02;
+    pub const COMMAND_UDP_ASSOCIATE: u8 = 0x03;
+
+    // Reply
+    pub const REPLY_SUCCEEDED: u8 = 0x00;
+    pub const REPLY_GENERAL_FAILURE: u8 = 0x01;
+    pub const REPLY_NOT_ALLOWED: u8 = 0x02;
+    pub const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+    pub const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+    pub const REPLY_CONNECTION_REFUSED: u8 = 0x05;
 }
 
 /// Represents a SOCKS5 address.
+#[derive(Clone)]
 pub enum Socks5Addr {
     Ipv4(SocketAddrV4),
     Ipv6(SocketAddrV6),
@@ -33,6 +53,9 @@ pub enum Socks5Addr {
 
 impl Socks5Addr {
     /// Constructs a new SOCKS5 address from a async input stream.
+    ///
+    /// Truncated input fails with `UnexpectedEof`, an empty or non-utf-8 domain name
+    /// with [`Error::DomainName`].
     pub async fn construct<R>(reader: &mut R) -> io::Result<Self>
     where
         R: AsyncRead + Unpin + ?Sized,
@@ -55,7 +78,11 @@ impl Socks5Addr {
                 let mut buf = [0u8];
                 reader.read_exact(&mut buf).await?;
                 let len = buf[0] as usize;
+                if len == 0 {
+                    return Err(io::Error::new(io::ErrorKind::Other, Error::DomainName));
+                }
 
+                // The domain name and the port
                 let mut buf = vec![0u8; len + 2];
                 reader.read_exact(&mut buf).await?;
 
@@ -94,6 +121,34 @@ impl Socks5Addr {
         }
     }
 
+    /// Returns the port.
+    pub fn port(&self) -> u16 {
+        match self {
+            Socks5Addr::Ipv4(addr) => addr.port(),
+            Socks5Addr::Ipv6(addr) => addr.port(),
+            Socks5Addr::DomainName((_, port)) => *port,
+        }
+    }
+
+    /// Returns the length of the raw representation at the start of the buffer,
+    /// or `None` if it is invalid or incomplete.
+    pub fn raw_len(buf: &[u8]) -> Option<usize> {
+        let len = match *buf.first()? {
+            constants::ATYP_IPV4 => 1 + 4 + 2,
+            constants::ATYP_IPV6 => 1 + 16 + 2,
+            constants::ATYP_DOMAIN_NAME => match *buf.get(1)? {
+                0 => return None,
+                len => 1 + 1 + len as usize + 2,
+            },
+            _ => return None,
+        };
+
+        match buf.len() >= len {
+            true => Some(len),
+            false => None,
+        }
+    }
+
     /// Returns SOCKS5 address raw representation.
     pub fn get_raw_parts(&self) -> Vec<u8> {
         let mut addr = Vec::<u8>::new();
@@ -121,6 +176,35 @@ impl Socks5Addr {
     }
 }
 
+impl From<SocketAddr> for Socks5Addr {
+    fn from(addr: SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(v4) => Socks5Addr::Ipv4(v4),
+            SocketAddr::V6(v6) => Socks5Addr::Ipv6(v6),
+        }
+    }
+}
+
+impl FromStr for Socks5Addr {
+    type Err = io::Error;
+
+    /// Parses `ip:port`, `[ipv6]:port` or `host:port`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(addr.into());
+        }
+
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "expected HOST:PORT");
+        let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse().map_err(|_| invalid())?;
+
+        match host.len() {
+            1..=255 if !host.contains(':') => Ok(Socks5Addr::DomainName((host.to_owned(), port))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
 impl Display for Socks5Addr {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
@@ -143,10 +227,16 @@ pub enum Error {
     /// No supported socks method found.
     Method,
 
+    /// Unsupported version of the username/password authentication.
+    AuthVersion(u8),
+
+    /// The username or password is wrong.
+    Auth(String),
+
     /// Unsupported socks command.
     Command(u8),
 
-    /// The requested domain name is not a string.
+    /// The requested domain name is empty or not a string.
     DomainName,
 }
 
@@ -161,17 +251,82 @@ impl Display for Error {
                     now, before
                 )
             }
-            Error::Method => write!(f, "only support the NO AUTHENTICATION method"),
-            Error::Command(cmd) => write!(f, "only support the CONNECT method, request {}", cmd),
-            Error::DomainName => write!(f, "the requested domain name is not a string"),
+            Error::Method => write!(f, "no acceptable authentication method offered"),
+            Error::AuthVersion(v) => {
+                write!(
+                    f,
+                    "{} is the unsupported username/password authentication version",
+                    v
+                )
+            }
+            Error::Auth(username) => write!(f, "authentication failed for user {:?}", username),
+            Error::Command(cmd) => write!(
+                f,
+                "only support the CONNECT, BIND and UDP ASSOCIATE (if enabled) commands, \
+                 request {}",
+                cmd
+            ),
+            Error::DomainName => write!(f, "the requested domain name is empty or not a string"),
         }
     }
 }
 
 impl std::error::Error for Error {}
 
+/// Users allowed by the username/password authentication.
+#[derive(Debug, Default)]
+pub struct Credentials {
+    users: HashMap<String, String>,
+}
+
+impl Credentials {
+    /// Creates an empty credential store.
+    pub fn new() -> Self {
+        Credentials::default()
+    }
+
+    /// Allows a user, replacing its password if it already exists.
+    pub fn add(&mut self, username: &str, password: &str) {
+        self.users.insert(username.to_owned(), password.to_owned());
+    }
+
+    /// Returns true if the username and password match an allowed user.
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        self.users.get(username).is_some_and(|x| x == password)
+    }
+}
+
+/// A SOCKS5 request.
+pub enum Request {
+    /// Connects to the address.
+    Connect(Socks5Addr),
+
+    /// Accepts a connection from the address, replies are left to the caller, see [`reply`].
+    Bind(Socks5Addr),
+
+    /// Relays udp packets, the address is where the client sends them from, if known.
+    UdpAssociate(Socks5Addr),
+}
+
 /// SOCKS5 handshake.
-pub async fn handshake<S>(stream: &mut S) -> io::Result<Socks5Addr>
+///
+/// If `credentials` is given, clients must authenticate with a username and password,
+/// otherwise no authentication is required.
+///
+/// CONNECT requests aren't replied to, the caller replies once it knows whether the target
+/// could be connected to, see [`reply`] and [`reply_error`].
+///
+/// UDP ASSOCIATE requests are only accepted if `udp_addr`, the address of the udp relay,
+/// is given. It is then replied to the client.
+///
+/// BIND requests are only accepted if `bind` is true. They aren't replied to either,
+/// the caller replies twice once it knows the addresses.
+pub async fn handshake<S>(
+    stream: &mut S,
+    udp_addr: Option<SocketAddr>,
+    bind: bool,
+    credentials: Option<&Credentials>,
+) -> io::Result<Request>
 where
     S: AsyncRead + AsyncWrite + Unpin + ?Sized,
 {
@@ -187,16 +342,25 @@ where
     let mut methods = vec![0u8; buf[1] as usize];
     stream.read_exact(&mut methods).await?;
 
-    if !methods
-        .iter()
-        .any(|&x| x == constants::METHOD_NO_AUTHENTICATION)
-    {
+    let method = match credentials {
+        Some(_) => constants::METHOD_USERNAME_PASSWORD,
+        None => constants::METHOD_NO_AUTHENTICATION,
+    };
+
+    if !methods.contains(&method) {
+        let rsp = [constants::VERSION, constants::METHOD_NO_ACCEPTABLE];
+        stream.write_all(&rsp).await?;
+
         return Err(io::Error::new(io::ErrorKind::Other, Error::Method));
     }
 
-    let rsp = [constants::VERSION, constants::METHOD_NO_AUTHENTICATION];
+    let rsp = [constants::VERSION, method];
     stream.write_all(&rsp).await?;
 
+    if let Some(credentials) = credentials {
+        authenticate(stream, credentials).await?;
+    }
+
     // Stage 2
     let mut buf = [0u8; 3];
     stream.read_exact(&mut buf).await?;
@@ -213,25 +377,292 @@ where
     }
 
     let cmd = buf[1];
-    if cmd != constants::COMMAND_CONNECT {
-        return Err(io::Error::new(io::ErrorKind::Other, Error::Command(cmd)));
-    }
-
-    let addr = Socks5Addr::construct(stream).await?;
-
-    let rsp = [
-        constants::VERSION,
-        0x00,
-        0x00,
-        constants::ATYP_IPV4,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-    ];
+    match (cmd, udp_addr) {
+        (constants::COMMAND_CONNECT, _) => {
+            let addr = Socks5Addr::construct(stream).await?;
+            Ok(Request::Connect(addr))
+        }
+        (constants::COMMAND_UDP_ASSOCIATE, Some(udp_addr)) => {
+            let addr = Socks5Addr::construct(stream).await?;
+            reply(stream, &udp_addr.into()).await?;
+            Ok(Request::UdpAssociate(addr))
+        }
+        (constants::COMMAND_BIND, _) if bind => {
+            let addr = Socks5Addr::construct(stream).await?;
+            Ok(Request::Bind(addr))
+        }
+        _ => Err(io::Error::new(io::ErrorKind::Other, Error::Command(cmd))),
+    }
+}
+
+/// Replies success with the bound address.
+pub async fn reply<S>(stream: &mut S, bound_addr: &Socks5Addr) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin + ?Sized,
+{
+    let mut rsp = vec![constants::VERSION, constants::REPLY_SUCCEEDED, 0x00];
+    rsp.append(&mut bound_addr.get_raw_parts());
+    stream.write_all(&rsp).await
+}
+
+/// Replies failure, with the reply code of the error, see [`reply_code`].
+pub async fn reply_error<S>(stream: &mut S, e: &io::Error) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin + ?Sized,
+{
+    let mut rsp = vec![constants::VERSION, reply_code(e), 0x00];
+    rsp.append(&mut Socks5Addr::from(SocketAddr::from(([0, 0, 0, 0], 0))).get_raw_parts());
+    stream.write_all(&rsp).await
+}
+
+/// Returns the reply code for an error connecting to the target.
+///
+/// Timeouts are reported as host unreachable, errors without a matching code as general failure.
+pub fn reply_code(e: &io::Error) -> u8 {
+    match e.kind() {
+        io::ErrorKind::NetworkUnreachable => constants::REPLY_NETWORK_UNREACHABLE,
+        io::ErrorKind::HostUnreachable | io::ErrorKind::TimedOut => {
+            constants::REPLY_HOST_UNREACHABLE
+        }
+        io::ErrorKind::ConnectionRefused => constants::REPLY_CONNECTION_REFUSED,
+        io::ErrorKind::PermissionDenied => constants::REPLY_NOT_ALLOWED,
+        _ => constants::REPLY_GENERAL_FAILURE,
+    }
+}
+
+/// Username/password authentication, see RFC 1929.
+async fn authenticate<S>(stream: &mut S, credentials: &Credentials) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).await?;
+
+    let ver = buf[0];
+    if ver != constants::AUTH_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            Error::AuthVersion(ver),
+        ));
+    }
+
+    let mut username = vec![0u8; buf[1] as usize];
+    stream.read_exact(&mut username).await?;
+
+    let mut len = [0u8];
+    stream.read_exact(&mut len).await?;
+    let mut password = vec![0u8; len[0] as usize];
+    stream.read_exact(&mut password).await?;
+
+    let username = String::from_utf8_lossy(&username);
+    let password = String::from_utf8_lossy(&password);
+
+    if !credentials.verify(&username, &password) {
+        let rsp = [constants::AUTH_VERSION, constants::AUTH_FAILED];
+        stream.write_all(&rsp).await?;
+
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            Error::Auth(username.into_owned()),
+        ));
+    }
+
+    let rsp = [constants::AUTH_VERSION, constants::AUTH_SUCCEEDED];
     stream.write_all(&rsp).await?;
 
-    Ok(addr)
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn auth(
+        greeting: &[u8],
+        auth: &[u8],
+        credentials: &Credentials,
+    ) -> (io::Result<Request>, Vec<u8>) {
+        let (mut client, mut server) = tokio::io::duplex(0x10000);
+
+        client.write_all(greeting).await.unwrap();
+        client.write_all(auth).await.unwrap();
+        client
+            .write_all(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0, 80])
+            .await
+            .unwrap();
+
+        let res = handshake(&mut server, None, false, Some(credentials)).await;
+        drop(server);
+
+        let mut rsp = Vec::new();
+        client.read_to_end(&mut rsp).await.unwrap();
+        (res, rsp)
+    }
+
+    #[test]
+    fn test_parse_addr() {
+        let addr: Socks5Addr = "127.0.0.1:22".parse().unwrap();
+        assert!(matches!(addr, Socks5Addr::Ipv4(_)));
+
+        let addr: Socks5Addr = "[::1]:22".parse().unwrap();
+        assert!(matches!(addr, Socks5Addr::Ipv6(_)));
+
+        let addr: Socks5Addr = "example.com:25".parse().unwrap();
+        assert_eq!(addr.to_string(), "example.com:25");
+
+        assert!("example.com".parse::<Socks5Addr>().is_err());
+        assert!("example.com:http".parse::<Socks5Addr>().is_err());
+        assert!("::1:22".parse::<Socks5Addr>().is_err());
+        assert!(":22".parse::<Socks5Addr>().is_err());
+    }
+
+    fn is_domain_name_error(e: &io::Error) -> bool {
+        matches!(
+            e.get_ref().and_then(|x| x.downcast_ref::<Error>()),
+            Some(Error::DomainName)
+        )
+    }
+
+    #[tokio::test]
+    async fn test_construct() {
+        let addrs: [Socks5Addr; 3] = [
+            "127.0.0.1:80".parse().unwrap(),
+            "[::1]:443".parse().unwrap(),
+            "example.com:8080".parse().unwrap(),
+        ];
+
+        for addr in addrs {
+            let raw = addr.get_raw_parts();
+            let constructed = Socks5Addr::construct(&mut &raw[..]).await.unwrap();
+            assert_eq!(constructed.to_string(), addr.to_string());
+            assert_eq!(Socks5Addr::raw_len(&raw), Some(raw.len()));
+
+            // Every truncation fails cleanly, including a missing port
+            for len in 0..raw.len() {
+                let e = Socks5Addr::construct(&mut &raw[..len]).await.err().unwrap();
+                assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+                assert_eq!(Socks5Addr::raw_len(&raw[..len]), None);
+            }
+        }
+
+        let empty = [constants::ATYP_DOMAIN_NAME, 0, 0, 80];
+        let e = Socks5Addr::construct(&mut &empty[..]).await.err().unwrap();
+        assert!(is_domain_name_error(&e));
+        assert_eq!(Socks5Addr::raw_len(&empty), None);
+
+        let not_utf8 = [constants::ATYP_DOMAIN_NAME, 2, 0xff, 0xfe, 0, 80];
+        let e = Socks5Addr::construct(&mut &not_utf8[..])
+            .await
+            .err()
+            .unwrap();
+        assert!(is_domain_name_error(&e));
+
+        assert!(Socks5Addr::construct(&mut &[0x02, 0, 0][..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_construct_random() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..10000 {
+            let mut buf = vec![0u8; rng.gen_range(0..64)];
+            rng.fill(&mut buf[..]);
+            // Mostly valid address types, so the lengths are what is exercised
+            if let Some(atyp) = buf.first_mut() {
+                *atyp = [1, 3, 4, *atyp][rng.gen_range(0..4)];
+            }
+
+            // Never panics, and agrees with `raw_len` on what is a complete address
+            match Socks5Addr::construct(&mut &buf[..]).await {
+                Ok(addr) => {
+                    let raw = addr.get_raw_parts();
+                    assert_eq!(Socks5Addr::raw_len(&buf), Some(raw.len()));
+                    assert_eq!(buf[..raw.len()], raw);
+                }
+                Err(e) if is_domain_name_error(&e) => {}
+                Err(_) => assert_eq!(Socks5Addr::raw_len(&buf), None),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind() {
+        let request = [0x05, 0x01, 0x00, 0x05, 0x02, 0x00, 0x01, 10, 0, 0, 1, 0, 21];
+
+        // Not replied to, until the caller does
+        let (mut client, mut server) = tokio::io::duplex(0x10000);
+        client.write_all(&request).await.unwrap();
+        match handshake(&mut server, None, true, None).await.unwrap() {
+            Request::Bind(addr) => assert_eq!(addr.to_string(), "10.0.0.1:21"),
+            _ => unreachable!(),
+        }
+        reply(
+            &mut server,
+            &Socks5Addr::Ipv4("1.2.3.4:5678".parse().unwrap()),
+        )
+        .await
+        .unwrap();
+        drop(server);
+
+        let mut rsp = Vec::new();
+        client.read_to_end(&mut rsp).await.unwrap();
+        assert_eq!(
+            rsp,
+            [0x05, 0x00, 0x05, 0x00, 0x00, 0x01, 1, 2, 3, 4, 0x16, 0x2e]
+        );
+
+        // Rejected unless enabled
+        let (mut client, mut server) = tokio::io::duplex(0x10000);
+        client.write_all(&request).await.unwrap();
+        assert!(handshake(&mut server, None, false, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reply_error() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert_eq!(reply_code(&refused), constants::REPLY_CONNECTION_REFUSED);
+        let e = io::Error::from(io::ErrorKind::NetworkUnreachable);
+        assert_eq!(reply_code(&e), constants::REPLY_NETWORK_UNREACHABLE);
+        let e = io::Error::from(io::ErrorKind::HostUnreachable);
+        assert_eq!(reply_code(&e), constants::REPLY_HOST_UNREACHABLE);
+        let e = io::Error::from(io::ErrorKind::TimedOut);
+        assert_eq!(reply_code(&e), constants::REPLY_HOST_UNREACHABLE);
+        let e = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_eq!(reply_code(&e), constants::REPLY_NOT_ALLOWED);
+        let e = io::Error::from(io::ErrorKind::UnexpectedEof);
+        assert_eq!(reply_code(&e), constants::REPLY_GENERAL_FAILURE);
+
+        let mut rsp = Vec::new();
+        reply_error(&mut rsp, &refused).await.unwrap();
+        assert_eq!(rsp, [0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        let mut credentials = Credentials::new();
+        credentials.add("user", "pass");
+        assert!(credentials.verify("user", "pass"));
+        assert!(!credentials.verify("user", "wrong"));
+        assert!(!credentials.verify("other", "pass"));
+
+        let greeting = [0x05, 0x02, 0x00, 0x02];
+
+        let (res, rsp) = auth(&greeting, b"\x01\x04user\x04pass", &credentials).await;
+        assert!(matches!(res, Ok(Request::Connect(_))));
+        assert_eq!(rsp[..4], [0x05, 0x02, 0x01, 0x00]);
+
+        let (res, rsp) = auth(&greeting, b"\x01\x04user\x05wrong", &credentials).await;
+        assert!(res.is_err());
+        assert_eq!(rsp, [0x05, 0x02, 0x01, 0x01]);
+
+        let (res, rsp) = auth(&greeting, b"\x02\x04user\x04pass", &credentials).await;
+        assert!(res.is_err());
+        assert_eq!(rsp, [0x05, 0x02]);
+
+        // Clients only offering NO AUTHENTICATION are rejected
+        let (res, rsp) = auth(&[0x05, 0x01, 0x00], b"", &credentials).await;
+        assert!(res.is_err());
+        assert_eq!(rsp, [0x05, 0xFF]);
+    }
 }
@@ -0,0 +1,245 @@
+//! Shadowsocks statistics.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use serde::Serialize;
+
+/// Statistics of shadowsocks services.
+///
+/// All counters except [`Stats::active_connections`] are monotonically increasing
+/// and safe to update from many tasks.
+#[derive(Default)]
+pub struct Stats {
+    /// Number of accepted connections.
+    pub connections: AtomicU64,
+
+    /// Number of connections being handled, a gauge.
+    pub active_connections: AtomicU64,
+
+    /// Number of connections accepted by ss-remote listeners and not finished yet,
+    /// counted against the maximum connections, a gauge.
+    pub remote_connections: AtomicU64,
+
+    /// Number of bytes relayed from clients to targets, counted when connections close.
+    pub bytes_up: AtomicU64,
+
+    /// Number of bytes relayed from targets to clients, counted when connections close.
+    pub bytes_down: AtomicU64,
+
+    /// Number of connections rejected by the access control list or checks.
+    pub rejected: AtomicU64,
+
+    /// Number of connections whose handshake timed out.
+    pub handshake_timeouts: AtomicU64,
+
+    /// Number of connections whose outbound connect timed out.
+    pub connect_timeouts: AtomicU64,
+
+    /// Number of connections closed for being idle.
+    pub idle_timeouts: AtomicU64,
+
+    /// Number of connections closed for exceeding the maximum duration.
+    pub max_duration_timeouts: AtomicU64,
+
+    /// Number of connections logged and closed in probe mode.
+    pub probes: AtomicU64,
+
+    /// Number of connections rejected for targeting ss-remote itself.
+    pub self_connects: AtomicU64,
+
+    /// Number of replayed streams detected, by a duplicate salt or a stale 2022 header.
+    pub replays: AtomicU64,
+
+    /// Number of streams whose chunks failed to decrypt.
+    pub decryption_failures: AtomicU64,
+
+    /// Number of bytes relayed in both directions by each ss-remote port added with
+    /// [`Stats::add_port`], counted as they are relayed.
+    ports: Mutex<HashMap<u16, Arc<AtomicU64>>>,
+}
+
+impl Stats {
+    /// Creates a new statistics with all counters set to zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Increments the given counter by one.
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Increments the given counter by `n`.
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Decrements the given gauge by one.
+    pub fn decr(gauge: &AtomicU64) {
+        gauge.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Starts counting the bytes of the ss-remote port, from zero.
+    pub fn add_port(&self, port: u16) {
+        self.ports.lock().unwrap().insert(port, Arc::default());
+    }
+
+    /// Returns the byte counter of the ss-remote port, if it is added.
+    pub fn port_counter(&self, port: u16) -> Option<Arc<AtomicU64>> {
+        self.ports.lock().unwrap().get(&port).cloned()
+    }
+
+    /// Returns the bytes relayed by each ss-remote port.
+    pub fn port_bytes(&self) -> HashMap<u16, u64> {
+        let ports = self.ports.lock().unwrap();
+        ports
+            .iter()
+            .map(|(port, bytes)| (*port, bytes.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Removes the counter of the ss-remote port.
+    ///
+    /// The connections of the port still open keep counting to the removed counter,
+    /// so the port starts from zero if added again.
+    pub fn remove_port(&self, port: u16) {
+        self.ports.lock().unwrap().remove(&port);
+    }
+
+    /// Returns a point-in-time copy of all counters.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            connections: self.connections.load(Ordering::Relaxed),
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            remote_connections: self.remote_connections.load(Ordering::Relaxed),
+            bytes_up: self.bytes_up.load(Ordering::Relaxed),
+            bytes_down: self.bytes_down.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            handshake_timeouts: self.handshake_timeouts.load(Ordering::Relaxed),
+            connect_timeouts: self.connect_timeouts.load(Ordering::Relaxed),
+            idle_timeouts: self.idle_timeouts.load(Ordering::Relaxed),
+            max_duration_timeouts: self.max_duration_timeouts.load(Ordering::Relaxed),
+            probes: self.probes.load(Ordering::Relaxed),
+            self_connects: self.self_connects.load(Ordering::Relaxed),
+            replays: self.replays.load(Ordering::Relaxed),
+            decryption_failures: self.decryption_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A point-in-time copy of [`Stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StatsSnapshot {
+    pub connections: u64,
+    pub active_connections: u64,
+    pub remote_connections: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub rejected: u64,
+    pub handshake_timeouts: u64,
+    pub connect_timeouts: u64,
+    pub idle_timeouts: u64,
+    pub max_duration_timeouts: u64,
+    pub probes: u64,
+    pub self_connects: u64,
+    pub replays: u64,
+    pub decryption_failures: u64,
+}
+
+impl StatsSnapshot {
+    /// Returns the increase of all counters since the `earlier` snapshot.
+    ///
+    /// Gauges are not counters, they are copied from this snapshot.
+    pub fn since(&self, earlier: &StatsSnapshot) -> StatsSnapshot {
+        StatsSnapshot {
+            connections: self.connections.saturating_sub(earlier.connections),
+            active_connections: self.active_connections,
+            remote_connections: self.remote_connections,
+            bytes_up: self.bytes_up.saturating_sub(earlier.bytes_up),
+            bytes_down: self.bytes_down.saturating_sub(earlier.bytes_down),
+            rejected: self.rejected.saturating_sub(earlier.rejected),
+            handshake_timeouts: self
+                .handshake_timeouts
+                .saturating_sub(earlier.handshake_timeouts),
+            connect_timeouts: self
+                .connect_timeouts
+                .saturating_sub(earlier.connect_timeouts),
+            idle_timeouts: self.idle_timeouts.saturating_sub(earlier.idle_timeouts),
+            max_duration_timeouts: self
+                .max_duration_timeouts
+                .saturating_sub(earlier.max_duration_timeouts),
+            probes: self.probes.saturating_sub(earlier.probes),
+            self_connects: self.self_connects.saturating_sub(earlier.self_connects),
+            replays: self.replays.saturating_sub(earlier.replays),
+            decryption_failures: self
+                .decryption_failures
+                .saturating_sub(earlier.decryption_failures),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_since() {
+        let stats = Stats::new();
+        Stats::incr(&stats.connections);
+        Stats::add(&stats.bytes_up, 100);
+        let earlier = stats.snapshot();
+
+        Stats::incr(&stats.connections);
+        Stats::incr(&stats.rejected);
+        Stats::add(&stats.bytes_up, 20);
+        Stats::add(&stats.bytes_down, 300);
+
+        let delta = stats.snapshot().since(&earlier);
+        assert_eq!(delta.connections, 1);
+        assert_eq!(delta.bytes_up, 20);
+        assert_eq!(delta.bytes_down, 300);
+        assert_eq!(delta.rejected, 1);
+        assert_eq!(delta.probes, 0);
+    }
+
+    #[test]
+    fn test_gauge() {
+        let stats = Stats::new();
+        Stats::incr(&stats.active_connections);
+        Stats::incr(&stats.active_connections);
+        let earlier = stats.snapshot();
+
+        Stats::decr(&stats.active_connections);
+
+        let delta = stats.snapshot().since(&earlier);
+        assert_eq!(delta.active_connections, 1);
+    }
+
+    #[test]
+    fn test_port_bytes() {
+        let stats = Stats::new();
+        assert!(stats.port_counter(8381).is_none());
+
+        stats.add_port(8381);
+        stats.add_port(8382);
+        let counter = stats.port_counter(8381).unwrap();
+        Stats::add(&counter, 100);
+        Stats::add(&counter, 20);
+        Stats::add(&stats.port_counter(8382).unwrap(), 3);
+        assert_eq!(stats.port_bytes(), HashMap::from([(8381, 120), (8382, 3)]));
+
+        stats.remove_port(8381);
+        assert_eq!(stats.port_bytes(), HashMap::from([(8382, 3)]));
+
+        // Bytes of connections of the removed port aren't counted to the one added again
+        stats.add_port(8381);
+        Stats::add(&counter, 5);
+        assert_eq!(stats.port_bytes(), HashMap::from([(8381, 0), (8382, 3)]));
+    }
+}
@@ -1,30 +1,84 @@
 //! Shadowsocks tcp services.
 
 use std::{
+    future::{self, Future},
     io::{self, ErrorKind},
-    net::SocketAddr,
-    sync::Arc,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
 };
 
+use futures_core::ready;
+use rand::Rng;
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    net::{TcpListener as TokioTcpListener, TcpStream as TokioTcpStream, ToSocketAddrs},
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    net::{TcpListener as TokioTcpListener, TcpSocket, TcpStream as TokioTcpStream, ToSocketAddrs},
+    sync::OwnedSemaphorePermit,
+    task::JoinSet,
+    time::{error::Elapsed, Instant},
 };
 
 use crate::{
     context::Ctx,
     crypto::cipher::Method,
+    http_connect,
     net::{
-        lookup_host,
-        stream::{TcpStream as SsTcpStream, TimeoutStream},
+        idna,
+        stream::{self, TcpStream as SsTcpStream, ThrottledStream, TimeoutStream},
+        sys,
     },
-    socks5::{self, Socks5Addr},
+    observer::Outcome,
+    remote::{RemotePool, Server},
+    security::Admission,
+    socks5::{self, Request, Socks5Addr},
+    stats::Stats,
 };
 
+#[cfg(feature = "tls")]
+use crate::tls::Tls;
+
 mod constants {
     use std::time::Duration;
 
-    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+    pub const PROBE_SIZE: usize = 64;
+    pub const HANDSHAKE_BUFFER_SIZE: usize = 1024;
+    pub const LISTEN_BACKLOG: u32 = 1024;
+    pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+    pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(2);
+    pub const FAST_OPEN_QUEUE_LEN: i32 = 1024;
+    pub const MIN_PROBE_DRAIN: Duration = Duration::from_secs(30);
+    pub const MAX_PROBE_DRAIN: Duration = Duration::from_secs(90);
+    pub const SHUTDOWN_IDLE: Duration = Duration::from_secs(1);
+    pub const FIRST_DATA_WAIT: Duration = Duration::from_millis(20);
+
+    // Precedes the target address of BIND requests to ss-remote.
+    // It isn't an address type, so CONNECT requests are still plain target addresses.
+    pub const COMMAND_BIND: u8 = 0x02;
+}
+
+/// What ss-local requests ss-remote to do with the target address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    /// Connects to the target, the plain shadowsocks request.
+    Connect,
+
+    /// Accepts a connection from the target, for the SOCKS5 BIND command.
+    Bind,
+}
+
+/// The protocol ss-local replies to CONNECT requests with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reply {
+    Socks5,
+    Http,
 }
 
 /// TCP Listener for incoming shadowsocks connection.
@@ -38,13 +92,17 @@ pub struct SsTcpListener {
 impl SsTcpListener {
     /// Creates a new TcpListener for incoming shadowsocks connection,
     /// which will be bound to the specified address.
+    ///
+    /// Fails with `InvalidInput` if the key isn't of the key size of the method.
     pub async fn bind<A: ToSocketAddrs>(
         addr: A,
         cipher_method: Method,
         cipher_key: &[u8],
         ctx: Arc<Ctx>,
     ) -> io::Result<Self> {
-        let inner_listener = TokioTcpListener::bind(addr).await?;
+        cipher_method.validate_key(cipher_key)?;
+
+        let inner_listener = bind(addr, &ctx).await?;
         Ok(SsTcpListener {
             inner_listener,
             cipher_method,
@@ -56,40 +114,199 @@ impl SsTcpListener {
     /// Accepts a new incoming shadowsocks connection from this listener.
     pub async fn accept(&self) -> io::Result<(SsTcpStream<TokioTcpStream>, SocketAddr)> {
         let (stream, addr) = self.inner_listener.accept().await?;
-        Ok((
-            SsTcpStream::new(
-                stream,
-                self.cipher_method,
-                &self.cipher_key,
-                self.ctx.clone(),
-            ),
-            addr,
-        ))
+        set_nodelay(&stream, self.ctx.no_delay());
+        let stream = SsTcpStream::new(
+            stream,
+            self.cipher_method,
+            &self.cipher_key,
+            self.ctx.clone(),
+        )?;
+
+        Ok((stream, addr))
+    }
+
+    /// Returns the local address that this listener is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner_listener.local_addr()
+    }
+}
+
+impl SsTcpStream<TokioTcpStream> {
+    /// Connects to the target address through ss-remote, as a client of it.
+    ///
+    /// The target address is sent right away, the stream is then ready to relay data to
+    /// and from the target. The connection to ss-remote is plain TCP, even if TLS is enabled.
+    pub async fn connect(
+        remote_addr: SocketAddr,
+        target_addr: Socks5Addr,
+        cipher_method: Method,
+        cipher_key: &[u8],
+        ctx: Arc<Ctx>,
+    ) -> io::Result<Self> {
+        cipher_method.validate_key(cipher_key)?;
+
+        let stream = connect(remote_addr, &ctx).await?;
+        let mut stream = SsTcpStream::new(stream, cipher_method, cipher_key, ctx)?;
+
+        // Flushed, ss-remote times out waiting for the target address otherwise.
+        stream.write_all(&target_addr.get_raw_parts()).await?;
+        stream.flush().await?;
+
+        Ok(stream)
     }
 }
 
 /// Starts a shadowsocks remote server.
+///
+/// Listens on every given address with its own cipher method and key.
+/// All listeners share the same context, and thus the same replay protection.
+///
+/// Addresses that can't be bound are logged and skipped, it fails only if none can.
 pub async fn ss_remote(
-    addr: SocketAddr,
-    method: Method,
-    key: Vec<u8>,
+    servers: Vec<(SocketAddr, Method, Vec<u8>)>,
     ctx: Arc<Ctx>,
 ) -> io::Result<()> {
-    let listener = SsTcpListener::bind(addr, method, &key, ctx.clone()).await?;
+    let mut listeners = Vec::with_capacity(servers.len());
+    let mut bind_error = None;
+
+    for (addr, method, key) in servers {
+        if let Err(e) = method.validate_key(&key) {
+            let e = format!("invalid key of {}: {}", addr, e);
+            return Err(io::Error::new(ErrorKind::InvalidInput, e));
+        }
+
+        // One port in use shouldn't take the others down.
+        let listener = match SsTcpListener::bind(addr, method, &key, ctx.clone()).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!("Unable to listen on {}: {}", addr, e);
+                bind_error = Some(e);
+                continue;
+            }
+        };
+        log::info!("ss-remote listening on {} ({})", addr, method);
+        ctx.add_listen_addr(listener.local_addr()?);
+
+        listeners.push(listener);
+    }
 
-    log::info!("ss-remote listening on {}", addr);
+    if let (true, Some(e)) = (listeners.is_empty(), bind_error) {
+        return Err(e);
+    }
+
+    let mut tasks = JoinSet::new();
+    for listener in listeners {
+        tasks.spawn(serve_remote(listener, ctx.clone()));
+    }
+
+    while let Some(res) = tasks.join_next().await {
+        res.map_err(|e| io::Error::new(ErrorKind::Other, e))?;
+    }
+
+    Ok(())
+}
+
+/// Accepts incoming shadowsocks connections from the listener forever.
+///
+/// The bytes relayed by the connections are also counted per port of the listener.
+pub async fn serve_remote(listener: SsTcpListener, ctx: Arc<Ctx>) {
+    let port = listener.local_addr().ok().map(|addr| addr.port());
 
     loop {
-        match listener.accept().await {
-            Ok((encrypted_stream, peer)) => {
+        // At the maximum connections, new ones wait in the listen backlog until one finishes.
+        let permit = match ctx.connection_limit() {
+            Some(limit) => {
+                if limit.available_permits() == 0 {
+                    log::debug!("Reached the maximum connections, stop accepting");
+                }
+                Some(limit.clone().acquire_owned().await.unwrap())
+            }
+            None => None,
+        };
+
+        match listener.inner_listener.accept().await {
+            Ok((stream, peer)) => {
                 log::debug!("Accept {}", peer);
-                tokio::spawn(handle_ss_remote(encrypted_stream, peer, ctx.clone()));
+                set_nodelay(&stream, ctx.no_delay());
+                if let Ok(addr) = stream.local_addr() {
+                    ctx.add_local_ip(addr.ip());
+                }
+                let slot = Slot::new(permit, ctx.clone());
+                tokio::spawn(accept_remote(
+                    stream,
+                    peer,
+                    listener.cipher_method,
+                    listener.cipher_key.clone(),
+                    port,
+                    slot,
+                    ctx.clone(),
+                ));
             }
             Err(e) => log::warn!("Accept error: {}", e),
         }
     }
 }
 
+/// A connection accepted by a ss-remote listener, holding a slot of the maximum connections
+/// until it is dropped.
+struct Slot {
+    _permit: Option<OwnedSemaphorePermit>,
+    ctx: Arc<Ctx>,
+}
+
+impl Slot {
+    fn new(permit: Option<OwnedSemaphorePermit>, ctx: Arc<Ctx>) -> Self {
+        Stats::incr(&ctx.stats().remote_connections);
+        Slot {
+            _permit: permit,
+            ctx,
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        Stats::decr(&self.ctx.stats().remote_connections);
+    }
+}
+
+/// Handles an accepted connection of ss-remote, over TLS if enabled.
+///
+/// The TLS handshake and reading the request share one handshake timeout.
+async fn accept_remote(
+    stream: TokioTcpStream,
+    peer: SocketAddr,
+    method: Method,
+    key: Vec<u8>,
+    port: Option<u16>,
+    slot: Slot,
+    ctx: Arc<Ctx>,
+) {
+    let deadline = Instant::now() + ctx.timeouts().handshake;
+
+    #[cfg(feature = "tls")]
+    if let Some(Tls::Server(acceptor)) = ctx.tls() {
+        let stream = match handshake_until(acceptor.accept(stream), deadline, &ctx).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::debug!("TLS handshake failed: {}, peer {}", e, peer);
+                return;
+            }
+        };
+
+        match SsTcpStream::new(stream, method, &key, ctx.clone()) {
+            Ok(stream) => handle_remote(stream, peer, port, Some(slot), deadline, ctx).await,
+            Err(e) => log::error!("Handle {} failed: {}", peer, e),
+        }
+        return;
+    }
+
+    match SsTcpStream::new(stream, method, &key, ctx.clone()) {
+        Ok(stream) => handle_remote(stream, peer, port, Some(slot), deadline, ctx).await,
+        Err(e) => log::error!("Handle {} failed: {}", peer, e),
+    }
+}
+
 /// Starts a shadowsocks local server.
 pub async fn ss_local(
     local_addr: SocketAddr,
@@ -98,7 +315,7 @@ pub async fn ss_local(
     key: Vec<u8>,
     ctx: Arc<Ctx>,
 ) -> io::Result<()> {
-    let listener = TokioTcpListener::bind(local_addr).await?;
+    let listener = bind(local_addr, &ctx).await?;
 
     log::info!("ss-local listening on {}", local_addr);
     log::info!("The remote server address is {}", remote_addr);
@@ -107,6 +324,7 @@ pub async fn ss_local(
         match listener.accept().await {
             Ok((stream, peer)) => {
                 log::debug!("Accept {}", peer);
+                set_nodelay(&stream, ctx.no_delay());
                 tokio::spawn(handle_ss_local(
                     stream,
                     peer,
@@ -121,54 +339,215 @@ pub async fn ss_local(
     }
 }
 
+/// Starts a shadowsocks local server for connections redirected by iptables (`REDIRECT`).
+///
+/// Instead of a SOCKS5 handshake, the target address is the original destination of
+/// the redirected connection. Only supported on linux, fails right away elsewhere.
+pub async fn ss_redir(
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+    method: Method,
+    key: Vec<u8>,
+    ctx: Arc<Ctx>,
+) -> io::Result<()> {
+    if !cfg!(target_os = "linux") {
+        return Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "transparent proxy is only supported on linux",
+        ));
+    }
+
+    let listener = bind(local_addr, &ctx).await?;
+
+    log::info!("ss-redir listening on {}", local_addr);
+    log::info!("The remote server address is {}", remote_addr);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                log::debug!("Accept {}", peer);
+                set_nodelay(&stream, ctx.no_delay());
+                tokio::spawn(handle_ss_redir(
+                    stream,
+                    peer,
+                    remote_addr,
+                    method,
+                    key.clone(),
+                    ctx.clone(),
+                ));
+            }
+            Err(e) => log::warn!("Accept error: {}", e),
+        }
+    }
+}
+
+/// Starts a shadowsocks local server forwarding every connection to a fixed target address,
+/// like `ss-tunnel`.
+///
+/// Instead of a SOCKS5 handshake, the target address is given, e.g. to forward ssh or smtp.
+pub async fn ss_tunnel(
+    local_addr: SocketAddr,
+    target_addr: Socks5Addr,
+    remote_addr: SocketAddr,
+    method: Method,
+    key: Vec<u8>,
+    ctx: Arc<Ctx>,
+) -> io::Result<()> {
+    let listener = bind(local_addr, &ctx).await?;
+
+    log::info!("ss-tunnel listening on {}", local_addr);
+    log::info!("The remote server address is {}", remote_addr);
+    log::info!("The tunnel target address is {}", target_addr);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                log::debug!("Accept {}", peer);
+                set_nodelay(&stream, ctx.no_delay());
+                tokio::spawn(handle_ss_tunnel(
+                    stream,
+                    peer,
+                    target_addr.clone(),
+                    remote_addr,
+                    method,
+                    key.clone(),
+                    ctx.clone(),
+                ));
+            }
+            Err(e) => log::warn!("Accept error: {}", e),
+        }
+    }
+}
+
 /// Handles incoming connection from ss-remote.
 pub async fn handle_ss_remote<T>(stream: SsTcpStream<T>, peer: SocketAddr, ctx: Arc<Ctx>)
 where
     T: AsyncRead + AsyncWrite + Unpin + Send,
 {
-    let mut stream = make_timed_stream(stream);
+    let deadline = Instant::now() + ctx.timeouts().handshake;
+    handle_remote(stream, peer, None, None, deadline, ctx).await
+}
+
+/// Handles incoming connection from ss-remote, counting its bytes to the port if given.
+///
+/// The request must be read by the deadline, from the salt to the target address.
+/// The slot of the maximum connections, if given, is released before draining a probe.
+async fn handle_remote<T>(
+    stream: SsTcpStream<T>,
+    peer: SocketAddr,
+    port: Option<u16>,
+    slot: Option<Slot>,
+    deadline: Instant,
+    ctx: Arc<Ctx>,
+) where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut stream = make_timed_stream(stream, &ctx);
+    let mut conn = Connection::accept(peer, ctx.clone());
+    conn.port_bytes = port.and_then(|port| ctx.stats().port_counter(port));
 
     // 1. Checks whether or not to reject the client
-    if ctx.is_bypass(peer.ip(), None) {
+    if !ctx.is_client_allowed(peer.ip()) {
         log::warn!("Reject the client: peer {}", peer);
+        conn.outcome = Outcome::Rejected;
         return;
     }
 
-    // 2. Constructs a socks5 address with timeout
-    let target_addr = match Socks5Addr::construct(&mut stream).await {
-        Ok(addr) => addr,
-        Err(e) => {
-            match e.kind() {
-                ErrorKind::Other => {
-                    log::warn!("Read target address failed: {}, peer {}", e, peer);
-                    // We shouldn't close the connection,
-                    // See https://github.com/shadowsocks/shadowsocks-rust/issues/292
-                    read_to_end(&mut stream).await.unwrap_or_default();
+    if let Admission::Rejected(reported) = ctx.check_conn_rate(peer.ip()) {
+        if let Some(num) = reported {
+            log::warn!(
+                "Dropped {} connections over the rate limit, last peer {}",
+                num,
+                peer
+            );
+        }
+        conn.outcome = Outcome::Rejected;
+        return;
+    }
+
+    // 2. Constructs a socks5 address with timeout, the salt is read along with it
+    let (command, target_addr) =
+        match handshake_until(read_request(&mut stream), deadline, &ctx).await {
+            Ok(request) => request,
+            Err(e) => {
+                match e.kind() {
+                    ErrorKind::Other if ctx.mitigate_probing() && stream::is_probing(&e) => {
+                        log::warn!("Suspected probe: {}, peer {}", e, peer);
+                        drop(slot);
+                        drain_probe(stream.get_mut().get_mut()).await;
+                    }
+                    ErrorKind::Other => {
+                        log::warn!("Read target address failed: {}, peer {}", e, peer);
+                        // We shouldn't close the connection,
+                        // See https://github.com/shadowsocks/shadowsocks-rust/issues/292
+                        read_to_end(&mut stream).await.unwrap_or_default();
+                    }
+                    _ => log::debug!("Read target address failed: {}, peer {}", e, peer),
                 }
-                _ => log::debug!("Read target address failed: {}, peer {}", e, peer),
+                conn.fail(&e);
+                return;
             }
+        };
+    conn.target(&target_addr);
+
+    if ctx.probe() {
+        probe(&mut stream, peer, &target_addr, &ctx).await;
+        conn.outcome = Outcome::Probed;
+        return;
+    }
+
+    let target_host = match ascii_host(&target_addr) {
+        Ok(host) => host,
+        Err(e) => {
+            log::warn!("Invalid target {}: {}, peer {}", target_addr, e, peer);
+            conn.outcome = Outcome::Rejected;
             return;
         }
     };
 
-    // 3. Resolves target socket address
-    let target_socket_addr = match lookup_host(&target_addr.to_string()).await {
-        Ok(addr) => addr,
+    if command == Command::Bind {
+        serve_bind(&mut stream, &mut conn, &target_addr, target_host, &ctx).await;
+        return;
+    }
+
+    // 3. Resolves target socket addresses
+    let target_socket_addrs = match resolve_target(&target_addr, &ctx).await {
+        Ok(addrs) => addrs,
         Err(e) => {
             log::warn!("Resolve {} failed: {}, peer {}", target_addr, e, peer);
+            conn.fail(&e);
             return;
         }
     };
+    let target_socket_addr = target_socket_addrs[0];
     let target_ip = target_socket_addr.ip();
 
-    // 4. Checks whether or not to block outbound
-    if ctx.is_block_outbound(target_ip, Some(&target_addr.to_string())) {
+    // 4. Checks whether the target is ss-remote itself
+    if ctx.is_self_connect(target_socket_addr) {
+        log::warn!(
+            "Reject the target, it is the server itself: {} -> {} ({})",
+            peer,
+            target_addr,
+            target_ip
+        );
+        Stats::incr(&ctx.stats().self_connects);
+        conn.outcome = Outcome::Rejected;
+        return;
+    }
+
+    // 5. Checks whether or not to block outbound
+    let target_host = match target_host {
+        Some(host) => Some(host),
+        None => ctx.reverse_lookup(target_ip).await,
+    };
+    if ctx.is_block_outbound(target_ip, target_host.as_deref()) {
         log::warn!(
             "Block outbound address: {} -> {} ({})",
             peer,
             target_addr,
             target_ip
         );
+        conn.outcome = Outcome::Rejected;
         return;
     }
 
@@ -179,9 +558,16 @@ where
         target_ip
     );
 
-    // 5. Connects to target address
-    let mut target_stream = match TokioTcpStream::connect(target_socket_addr).await {
-        Ok(stream) => make_timed_stream(stream),
+    // 6. Connects to target address, racing the other addresses that pass the same checks
+    let target_socket_addrs: Vec<SocketAddr> = target_socket_addrs
+        .into_iter()
+        .filter(|addr| {
+            !ctx.is_self_connect(*addr) && !ctx.is_block_outbound(addr.ip(), target_host.as_deref())
+        })
+        .collect();
+    let delay = constants::CONNECTION_ATTEMPT_DELAY;
+    let mut target_stream = match connect_any(&target_socket_addrs, delay, &ctx).await {
+        Ok(stream) => make_timed_stream(stream, &ctx),
         Err(e) => {
             log::debug!(
                 "Unable to connect to {} ({}): {}, peer {}",
@@ -190,13 +576,185 @@ where
                 e,
                 peer
             );
+            conn.fail(&e);
             return;
         }
     };
 
-    // 6. Establishes connection between ss-local and target
+    // 7. Establishes connection between ss-local and target
     let trans = format!("{} <=> {} ({})", peer, target_addr, target_ip);
-    transfer(&mut stream, &mut target_stream, &trans).await;
+    transfer(&mut stream, &mut target_stream, &trans, &mut conn, &ctx).await;
+}
+
+/// Reads the request of ss-local: the target address, preceded by a command byte for BIND.
+async fn read_request<R>(reader: &mut R) -> io::Result<(Command, Socks5Addr)>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut buf = [0u8];
+    reader.read_exact(&mut buf).await?;
+
+    match buf[0] {
+        constants::COMMAND_BIND => Ok((Command::Bind, Socks5Addr::construct(reader).await?)),
+        _ => {
+            let mut reader = (&buf[..]).chain(reader);
+            Ok((Command::Connect, Socks5Addr::construct(&mut reader).await?))
+        }
+    }
+}
+
+/// Serves a BIND request of ss-local.
+///
+/// Listens on an ephemeral port for a connection from the target, replies the listen address,
+/// then the address the connection came from, and relays it. Connections from ips the target
+/// doesn't resolve to are refused, and so are requests for an unspecified target.
+async fn serve_bind<S>(
+    stream: &mut S,
+    conn: &mut Connection,
+    target_addr: &Socks5Addr,
+    target_host: Option<String>,
+    ctx: &Ctx,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let peer = conn.peer;
+
+    if !ctx.socks5_bind() {
+        log::warn!("Reject the BIND request, it is disabled: peer {}", peer);
+        conn.outcome = Outcome::Rejected;
+        return;
+    }
+
+    // 3. Resolves the target ips, the only ones allowed to connect
+    let target_ips: Vec<IpAddr> = match resolve_target(target_addr, ctx).await {
+        Ok(addrs) => addrs.iter().map(SocketAddr::ip).collect(),
+        Err(e) => {
+            log::warn!("Resolve {} failed: {}, peer {}", target_addr, e, peer);
+            conn.fail(&e);
+            return;
+        }
+    };
+    let target_ip = target_ips[0];
+
+    // Anyone could connect to a listener for an unspecified target
+    if target_ips.iter().any(IpAddr::is_unspecified) {
+        log::warn!(
+            "Reject the BIND request, the target is unspecified: {} -> {}",
+            peer,
+            target_addr
+        );
+        conn.outcome = Outcome::Rejected;
+        return;
+    }
+
+    // 4. Checks whether or not to block outbound
+    if target_ips
+        .iter()
+        .any(|&ip| ctx.is_block_outbound(ip, target_host.as_deref()))
+    {
+        log::warn!(
+            "Block outbound address: {} -> {} ({})",
+            peer,
+            target_addr,
+            target_ip
+        );
+        conn.outcome = Outcome::Rejected;
+        return;
+    }
+
+    // 5. Listens on the local ip routed to the target, and replies the address
+    let listen = async {
+        let listener = TokioTcpListener::bind((route_ip(target_ip)?, 0)).await?;
+        let bound_addr = Socks5Addr::from(listener.local_addr()?);
+        stream.write_all(&bound_addr.get_raw_parts()).await?;
+        stream.flush().await?;
+        io::Result::Ok((listener, bound_addr))
+    };
+    let (listener, bound_addr) = match listen.await {
+        Ok(x) => x,
+        Err(e) => {
+            log::warn!("Listen for {} failed: {}, peer {}", target_addr, e, peer);
+            conn.fail(&e);
+            return;
+        }
+    };
+
+    log::debug!(
+        "BIND: {} -> {} listening on {}",
+        peer,
+        target_addr,
+        bound_addr
+    );
+
+    // 6. Accepts the connection within the connect timeout, and replies where it came from
+    let accept = async {
+        loop {
+            let (target_stream, addr) = listener.accept().await?;
+            set_nodelay(&target_stream, ctx.no_delay());
+            if target_ips.contains(&addr.ip().to_canonical()) {
+                break Ok((target_stream, addr));
+            }
+
+            log::warn!("Refuse BIND connection from {}, peer {}", addr, peer);
+        }
+    };
+    let res = match tokio::time::timeout(ctx.timeouts().connect, accept).await {
+        Ok(Ok((target_stream, addr))) => {
+            let from_addr = Socks5Addr::from(addr).get_raw_parts();
+            stream
+                .write_all(&from_addr)
+                .await
+                .map(|_| (target_stream, addr))
+        }
+        Ok(Err(e)) => Err(e),
+        Err(_) => {
+            Stats::incr(&ctx.stats().connect_timeouts);
+            Err(ErrorKind::TimedOut.into())
+        }
+    };
+    let (target_stream, addr) = match res {
+        Ok(x) => x,
+        Err(e) => {
+            log::debug!("Accept from {} failed: {}, peer {}", target_addr, e, peer);
+            conn.fail(&e);
+            return;
+        }
+    };
+    drop(listener);
+
+    // 7. Establishes connection between ss-local and the target
+    let mut target_stream = make_timed_stream(target_stream, ctx);
+    let trans = format!("{} <=> {} (BIND {})", peer, addr, bound_addr);
+    transfer(stream, &mut target_stream, &trans, conn, ctx).await;
+}
+
+/// Returns the local ip that connections to the given ip are routed from.
+fn route_ip(ip: IpAddr) -> io::Result<IpAddr> {
+    let unspecified: IpAddr = match ip {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+
+    // Connecting a udp socket sends nothing, it only picks the route. Any port will do.
+    let socket = std::net::UdpSocket::bind((unspecified, 0))?;
+    socket.connect((ip, 1))?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// Logs the target address and the first bytes a client sent, without relaying.
+async fn probe<S>(stream: &mut S, peer: SocketAddr, target_addr: &Socks5Addr, ctx: &Ctx)
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    Stats::incr(&ctx.stats().probes);
+
+    let mut buf = [0u8; constants::PROBE_SIZE];
+    let n = handshake_timeout(stream.read(&mut buf), ctx)
+        .await
+        .unwrap_or(0);
+    let data: String = buf[..n].iter().map(|x| format!("{:02x}", x)).collect();
+
+    log::info!("Probe: {} -> {}, {} bytes: {}", peer, target_addr, n, data);
 }
 
 /// Handles incoming connection from ss-local.
@@ -208,52 +766,273 @@ pub async fn handle_ss_local(
     key: Vec<u8>,
     ctx: Arc<Ctx>,
 ) {
-    let mut stream = make_timed_stream(stream);
+    // Buffered so the handshake takes a read per client message rather than per field.
+    // The buffer is kept for relaying, so client data read ahead of the handshake isn't lost.
+    let mut stream = BufReader::with_capacity(constants::HANDSHAKE_BUFFER_SIZE, stream);
+    let mut conn = Connection::accept(peer, ctx.clone());
+
+    // The udp relay listens on the same address as ss-local.
+    let udp_addr = match ctx.udp() {
+        true => stream.get_ref().local_addr().ok(),
+        false => None,
+    };
 
-    // 1. Constructs a socks5 address with timeout
-    let target_addr = match socks5::handshake(&mut stream).await {
-        Ok(addr) => addr,
+    // 1. Reads the socks5 or http request with timeout
+    let handshake = local_handshake(&mut stream, udp_addr, &ctx);
+    let (request, reply) = match handshake_timeout(handshake, &ctx).await {
+        Ok(res) => res,
         Err(e) => {
             match e.kind() {
                 ErrorKind::Other => log::warn!("Read target address failed: {}, peer {}", e, peer),
                 _ => log::debug!("Read target address failed: {}, peer {}", e, peer),
             }
+            conn.fail(&e);
             return;
         }
     };
 
-    // 2. Resolves target socket address
-    let target_socket_addr = match lookup_host(&target_addr.to_string()).await {
-        Ok(addr) => Some(addr),
-        Err(e) => {
-            log::debug!("Resolve {} failed: {}, peer {}", target_addr, e, peer);
-            None
+    let target_addr = match request {
+        Request::Connect(addr) => {
+            conn.awaits_reply = Some(reply);
+            addr
         }
-    };
+        Request::Bind(addr) => {
+            // Always through ss-remote, the target is to connect to it rather than to us.
+            conn.target(&addr);
+            log::debug!("BIND: {} -> {}", peer, addr);
 
-    // 3. Relays target address, bypass or proxy
-    let trans: String;
-    let host = target_addr
-        .to_string()
-        .split(':')
-        .next()
-        .map(str::to_owned)
-        .unwrap_or_default();
-    match target_socket_addr {
-        Some(addr) if ctx.is_bypass(addr.ip(), Some(&host)) => {
-            trans = format!("{} <=> {} ({})", peer, target_addr, addr.ip());
+            let mut stream = make_timed_stream(stream, &ctx);
+            let trans = format!("{} <=> {} (BIND)", peer, addr);
+            proxy_remote(
+                &mut stream,
+                &mut conn,
+                Command::Bind,
+                &addr,
+                remote_addr,
+                method,
+                &key,
+                &trans,
+                &ctx,
+            )
+            .await;
+            return;
+        }
+        Request::UdpAssociate(addr) => {
+            // Packets of the client are relayed as long as this connection, which is otherwise
+            // unused, is open, so it isn't subject to the idle timeout.
+            log::debug!("UDP associate: peer {}", peer);
+            let _client = ctx.udp_clients().register(peer.ip(), addr.port());
+            conn.outcome = tokio::select! {
+                res = read_to_end(&mut stream) => match res {
+                    Ok(_) => Outcome::Closed,
+                    Err(_) => Outcome::Error,
+                },
+                _ = ctx.shutting_down() => Outcome::Closed,
+            };
+            return;
+        }
+    };
+    conn.target(&target_addr);
 
-            log::debug!(
-                "Bypass target address: {} -> {} ({})",
-                peer,
-                target_addr,
-                addr.ip()
-            );
+    let mut stream = make_timed_stream(stream, &ctx);
+    relay_local(
+        &mut stream,
+        &mut conn,
+        target_addr,
+        remote_addr,
+        method,
+        key,
+        ctx,
+    )
+    .await;
+}
 
-            // 3.1 Connects to target host
-            let mut target_stream = match TokioTcpStream::connect(addr).await {
-                Ok(stream) => make_timed_stream(stream),
-                Err(e) => {
+/// Reads a SOCKS5 request, or an HTTP CONNECT one told apart by its first byte.
+async fn local_handshake<S>(
+    stream: &mut S,
+    udp_addr: Option<SocketAddr>,
+    ctx: &Ctx,
+) -> io::Result<(Request, Reply)>
+where
+    S: AsyncBufRead + AsyncWrite + Unpin + ?Sized,
+{
+    let first_byte = match stream.fill_buf().await?.first() {
+        Some(&x) => x,
+        None => return Err(ErrorKind::UnexpectedEof.into()),
+    };
+
+    if http_connect::is_http(first_byte) {
+        let addr = http_connect::handshake(stream, ctx.credentials()).await?;
+        return Ok((Request::Connect(addr), Reply::Http));
+    }
+
+    let request = socks5::handshake(stream, udp_addr, ctx.socks5_bind(), ctx.credentials()).await?;
+    Ok((request, Reply::Socks5))
+}
+
+/// Handles incoming connection redirected by iptables to ss-local.
+pub async fn handle_ss_redir(
+    stream: TokioTcpStream,
+    peer: SocketAddr,
+    remote_addr: SocketAddr,
+    method: Method,
+    key: Vec<u8>,
+    ctx: Arc<Ctx>,
+) {
+    let mut conn = Connection::accept(peer, ctx.clone());
+
+    // 1. Reads the original destination address
+    let target_addr = match sys::original_dst(&stream) {
+        Ok(addr) if Some(addr) == stream.local_addr().ok() => {
+            log::warn!("Not a redirected connection, peer {}", peer);
+            conn.outcome = Outcome::Rejected;
+            return;
+        }
+        Ok(SocketAddr::V4(addr)) => Socks5Addr::Ipv4(addr),
+        Ok(SocketAddr::V6(addr)) => Socks5Addr::Ipv6(addr),
+        Err(e) => {
+            log::warn!("Read original destination failed: {}, peer {}", e, peer);
+            return;
+        }
+    };
+
+    conn.target(&target_addr);
+
+    let mut stream = make_timed_stream(stream, &ctx);
+    relay_local(
+        &mut stream,
+        &mut conn,
+        target_addr,
+        remote_addr,
+        method,
+        key,
+        ctx,
+    )
+    .await;
+}
+
+/// Handles incoming connection to ss-tunnel, for the fixed target address.
+pub async fn handle_ss_tunnel(
+    stream: TokioTcpStream,
+    peer: SocketAddr,
+    target_addr: Socks5Addr,
+    remote_addr: SocketAddr,
+    method: Method,
+    key: Vec<u8>,
+    ctx: Arc<Ctx>,
+) {
+    let mut conn = Connection::accept(peer, ctx.clone());
+    conn.target(&target_addr);
+
+    let mut stream = make_timed_stream(stream, &ctx);
+    relay_local(
+        &mut stream,
+        &mut conn,
+        target_addr,
+        remote_addr,
+        method,
+        key,
+        ctx,
+    )
+    .await;
+}
+
+/// Relays the target address for ss-local, bypass or proxy.
+async fn relay_local<S>(
+    stream: &mut S,
+    conn: &mut Connection,
+    target_addr: Socks5Addr,
+    remote_addr: SocketAddr,
+    method: Method,
+    key: Vec<u8>,
+    ctx: Arc<Ctx>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let peer = conn.peer;
+
+    // 2. Decides whether to bypass, resolving the target address only if needed
+    let target_host = match target_addr {
+        Socks5Addr::Ipv4(addr) => ctx.reverse_lookup((*addr.ip()).into()).await,
+        Socks5Addr::Ipv6(addr) => ctx.reverse_lookup((*addr.ip()).into()).await,
+        Socks5Addr::DomainName(_) => match ascii_host(&target_addr) {
+            Ok(host) => host,
+            Err(e) => {
+                log::warn!("Invalid target {}: {}, peer {}", target_addr, e, peer);
+                conn.connect_failed(stream, &e).await;
+                conn.outcome = Outcome::Rejected;
+                return;
+            }
+        },
+    };
+    let host = target_host.as_deref().unwrap_or_default();
+    let (bypass, target_socket_addrs) = match target_addr {
+        Socks5Addr::Ipv4(addr) => (
+            ctx.is_bypass((*addr.ip()).into(), target_host.as_deref()),
+            vec![addr.into()],
+        ),
+        Socks5Addr::Ipv6(addr) => (
+            ctx.is_bypass((*addr.ip()).into(), target_host.as_deref()),
+            vec![addr.into()],
+        ),
+        Socks5Addr::DomainName(_) if ctx.should_resolve_remotely(host) => (false, Vec::new()),
+        Socks5Addr::DomainName(_) => match ctx.is_bypass_host(host) {
+            Some(false) => (false, Vec::new()),
+            Some(true) => match resolve_target(&target_addr, &ctx).await {
+                Ok(addrs) => (true, addrs),
+                Err(e) => {
+                    log::debug!("Resolve {} failed: {}, peer {}", target_addr, e, peer);
+                    (false, Vec::new())
+                }
+            },
+            // Decided by the first address, only the others with the same decision are raced.
+            None => match resolve_target(&target_addr, &ctx).await {
+                Ok(addrs) => {
+                    let bypass = ctx.is_bypass(addrs[0].ip(), Some(host));
+                    let addrs = addrs
+                        .into_iter()
+                        .filter(|addr| ctx.is_bypass(addr.ip(), Some(host)) == bypass)
+                        .collect();
+                    (bypass, addrs)
+                }
+                Err(e) => {
+                    log::debug!("Resolve {} failed: {}, peer {}", target_addr, e, peer);
+                    (false, Vec::new())
+                }
+            },
+        },
+    };
+    let target_socket_addr = target_socket_addrs.first().copied();
+
+    // 3. Checks whether or not to block outbound, the other addresses are only raced if allowed
+    let target_ip = target_socket_addr.map(|x| x.ip());
+    if ctx.is_block_local(target_ip, target_host.as_deref()) {
+        log::warn!("Block outbound address: {} -> {}", peer, target_addr);
+
+        let e = io::Error::new(ErrorKind::PermissionDenied, "blocked by the ACL");
+        conn.connect_failed(stream, &e).await;
+        conn.outcome = Outcome::Rejected;
+        return;
+    }
+    let target_socket_addrs: Vec<SocketAddr> = target_socket_addrs
+        .into_iter()
+        .filter(|addr| !ctx.is_block_local(Some(addr.ip()), target_host.as_deref()))
+        .collect();
+
+    // 4. Relays target address, bypass or proxy
+    let trans: String;
+    match target_socket_addr {
+        Some(addr) if bypass => {
+            let target = describe_target(&target_addr, Some(addr.ip()), true);
+            trans = format!("{} <=> {}", peer, target);
+
+            log::debug!("Bypass target address: {} -> {}", peer, target);
+
+            // 4.1 Connects to target host
+            let addrs = || future::ready(target_socket_addrs.clone());
+            let target_stream = match connect_retry(addrs, false, &ctx).await {
+                Ok(stream) => stream,
+                Err(e) => {
                     log::error!(
                         "Unable to connect to {} ({}): {}, peer {}",
                         target_addr,
@@ -261,86 +1040,2404 @@ pub async fn handle_ss_local(
                         e,
                         peer
                     );
+                    conn.connect_failed(stream, &e).await;
                     return;
                 }
             };
 
-            // 3.2 Establishes connection between ss-local and target
-            transfer(&mut stream, &mut target_stream, &trans).await;
+            // 4.2 Replies the SOCKS5 client, if any, with the address connected from
+            let bound_addr = target_stream
+                .local_addr()
+                .unwrap_or(([0, 0, 0, 0], 0).into());
+            if let Err(e) = conn.connected(stream, bound_addr).await {
+                log::debug!("Reply to {} failed: {}", peer, e);
+                conn.fail(&e);
+                return;
+            }
+
+            // 4.3 Establishes connection between ss-local and target
+            let mut target_stream = make_timed_stream(target_stream, &ctx);
+            transfer(stream, &mut target_stream, &trans, conn, &ctx).await;
         }
         _ => {
-            trans = format!("{} <=> {}", peer, target_addr);
+            let target = describe_target(&target_addr, target_socket_addr.map(|x| x.ip()), false);
+            trans = format!("{} <=> {}", peer, target);
 
-            if log::log_enabled!(log::Level::Debug) {
-                let mut str = format!("Proxy target address: {} -> {}", peer, target_addr);
+            log::debug!("Proxy target address: {} -> {}", peer, target);
 
-                if let Some(addr) = target_socket_addr {
-                    str.push_str(&format!(" ({})", addr.ip()));
-                }
+            proxy_remote(
+                stream,
+                conn,
+                Command::Connect,
+                &target_addr,
+                remote_addr,
+                method,
+                &key,
+                &trans,
+                &ctx,
+            )
+            .await;
+        }
+    }
+}
+
+/// Connects to ss-remote, over TLS if enabled, and relays the request through it.
+#[allow(clippy::too_many_arguments)]
+async fn proxy_remote<S>(
+    stream: &mut S,
+    conn: &mut Connection,
+    command: Command,
+    target_addr: &Socks5Addr,
+    remote_addr: SocketAddr,
+    method: Method,
+    key: &[u8],
+    trans: &str,
+    ctx: &Arc<Ctx>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let peer = conn.peer;
+
+    // 4.1 Connects to ss-remote, the best healthy one if there are several
+    let res = match ctx.remote_pool() {
+        Some(pool) => connect_pool(pool, ctx)
+            .await
+            .map(|(stream, x)| (stream, Some(x))),
+        None => connect_retry(
+            || resolve_remote(remote_addr, ctx.remote_host(), ctx),
+            true,
+            ctx,
+        )
+        .await
+        .map(|stream| (stream, None)),
+    };
+    let (remote_stream, server) = match res {
+        Ok(res) => res,
+        Err(e) => {
+            log::error!("Unable to connect to {}: {}, peer {}", remote_addr, e, peer);
+            conn.connect_failed(stream, &e).await;
+            return;
+        }
+    };
+    let remote_addr = remote_stream.peer_addr().unwrap_or(remote_addr);
+    let (method, key) = server.map_or((method, key), |x| (x.method(), x.key()));
+    let _active = server.map(Server::track);
 
-                log::debug!("{}", str);
+    #[cfg(feature = "tls")]
+    if let Some(Tls::Client(connector, server_name)) = ctx.tls() {
+        let connect = connector.connect(server_name.clone(), remote_stream);
+        let remote_stream = match handshake_timeout(connect, ctx).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::error!(
+                    "TLS handshake with {} failed: {}, peer {}",
+                    remote_addr,
+                    e,
+                    peer
+                );
+                conn.connect_failed(stream, &e).await;
+                return;
             }
+        };
 
-            // 3.1 Connects to ss-remote
-            let mut target_stream = match TokioTcpStream::connect(remote_addr).await {
-                Ok(stream) => make_timed_stream(SsTcpStream::new(stream, method, &key, ctx)),
-                Err(e) => {
-                    log::error!("Unable to connect to {}: {}, peer {}", remote_addr, e, peer);
-                    return;
-                }
-            };
+        let remote_stream = match SsTcpStream::new(remote_stream, method, key, ctx.clone()) {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::error!("Proxy {} failed: {}, peer {}", remote_addr, e, peer);
+                conn.connect_failed(stream, &e).await;
+                return;
+            }
+        };
+        proxy(
+            stream,
+            remote_stream,
+            conn,
+            command,
+            target_addr,
+            remote_addr,
+            trans,
+            ctx,
+        )
+        .await;
+        return;
+    }
 
-            // 3.2 Writes target address
-            let target_addr_bytes = target_addr.get_raw_parts();
-            match target_stream.write_all(&target_addr_bytes).await {
-                Ok(_) => {}
-                Err(e) => {
-                    log::error!(
-                        "Write target address to {} failed: {}, peer {}",
-                        remote_addr,
-                        e,
-                        peer
-                    );
-                    return;
+    let remote_stream = match SsTcpStream::new(remote_stream, method, key, ctx.clone()) {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::error!("Proxy {} failed: {}, peer {}", remote_addr, e, peer);
+            conn.connect_failed(stream, &e).await;
+            return;
+        }
+    };
+    proxy(
+        stream,
+        remote_stream,
+        conn,
+        command,
+        target_addr,
+        remote_addr,
+        trans,
+        ctx,
+    )
+    .await;
+}
+
+/// Connects to the servers of the pool in turn, from the picked one, marking those that can't be
+/// connected to unhealthy. Returns the connection with its server.
+async fn connect_pool<'a>(
+    pool: &'a RemotePool,
+    ctx: &Ctx,
+) -> io::Result<(TokioTcpStream, &'a Server)> {
+    let mut last_err = None;
+
+    for server in pool.candidates() {
+        let addrs = || resolve_remote(server.addr(), server.host(), ctx);
+        match connect_retry(addrs, true, ctx).await {
+            Ok(stream) => {
+                server.set_healthy(true);
+                return Ok((stream, server));
+            }
+            Err(e) => {
+                log::warn!("Unable to connect to {}: {}, failing over", server, e);
+                server.set_healthy(false);
+                last_err = Some(e);
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| ErrorKind::NotFound.into()))
+}
+
+/// Returns the domain name of the target address in its ascii form, see [`idna::to_ascii`],
+/// which is the one the access control list is matched against.
+fn ascii_host(target_addr: &Socks5Addr) -> io::Result<Option<String>> {
+    match target_addr {
+        Socks5Addr::DomainName((host, _)) => idna::to_ascii(host).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Resolves the target address, converting an internationalized domain name to punycode first,
+/// see [`idna::to_ascii`]. The target address itself is left as is, to be relayed unchanged.
+async fn resolve_target(target_addr: &Socks5Addr, ctx: &Ctx) -> io::Result<Vec<SocketAddr>> {
+    match target_addr {
+        Socks5Addr::DomainName((host, port)) => {
+            let host = idna::to_ascii(host)?;
+            ctx.resolve_all(&format!("{}:{}", host, port)).await
+        }
+        _ => ctx.resolve_all(&target_addr.to_string()).await,
+    }
+}
+
+/// Resolves the domain name of ss-remote again, if it has one, so that a changed address is
+/// followed without a restart. Lookups are cached like the ones of targets.
+///
+/// Falls back to the address resolved at startup if the lookup fails.
+async fn resolve_remote(remote_addr: SocketAddr, host: Option<&str>, ctx: &Ctx) -> Vec<SocketAddr> {
+    let host = match host {
+        Some(host) => host,
+        None => return vec![remote_addr],
+    };
+
+    match ctx.resolve_all(host).await {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            log::warn!("Resolve {} failed: {}, using {}", host, e, remote_addr);
+            vec![remote_addr]
+        }
+    }
+}
+
+/// Describes the target address for logging, with where it was resolved.
+///
+/// Bypassed domain names are resolved locally, proxied ones by ss-remote. A proxied domain name
+/// may also have been resolved locally to match the access control list, then its ip is shown too.
+fn describe_target(target_addr: &Socks5Addr, ip: Option<IpAddr>, bypass: bool) -> String {
+    match (target_addr, ip) {
+        (Socks5Addr::DomainName(_), Some(ip)) if bypass => {
+            format!("{} ({}, resolved locally)", target_addr, ip)
+        }
+        (Socks5Addr::DomainName(_), Some(ip)) => {
+            format!("{} (resolved remotely, {} locally)", target_addr, ip)
+        }
+        (Socks5Addr::DomainName(_), None) => format!("{} (resolved remotely)", target_addr),
+        _ => target_addr.to_string(),
+    }
+}
+
+/// Relays the target address through ss-remote, over the given encrypted connection to it.
+#[allow(clippy::too_many_arguments)]
+async fn proxy<S, R>(
+    stream: &mut S,
+    remote_stream: SsTcpStream<R>,
+    conn: &mut Connection,
+    command: Command,
+    target_addr: &Socks5Addr,
+    remote_addr: SocketAddr,
+    trans: &str,
+    ctx: &Ctx,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    R: AsyncRead + AsyncWrite + Reset + Unpin,
+{
+    let peer = conn.peer;
+    let mut target_stream = make_timed_stream(remote_stream, ctx);
+
+    // 4.2 Replies the SOCKS5 client, if any. Whether ss-remote can connect to the target is
+    // unknown, a failure there only shows as the connection being closed.
+    let replied = conn.awaits_reply.is_some();
+    if let Err(e) = conn.connected(stream, ([0, 0, 0, 0], 0).into()).await {
+        log::debug!("Reply to {} failed: {}", peer, e);
+        conn.fail(&e);
+        return;
+    }
+
+    // 4.3 Writes target address, with the first client data. A client only sends it once
+    // replied, so it is briefly waited for then.
+    let wait = match replied {
+        true => constants::FIRST_DATA_WAIT,
+        false => Duration::ZERO,
+    };
+    let res = match command {
+        Command::Connect => {
+            write_target_addr(stream, &mut target_stream, target_addr, wait, ctx).await
+        }
+        Command::Bind => request_bind(stream, &mut target_stream, target_addr).await,
+    };
+    if let Err(e) = res {
+        log::error!(
+            "Write target address to {} failed: {}, peer {}",
+            remote_addr,
+            e,
+            peer
+        );
+        // A failed BIND is replied to even after its first reply.
+        if command == Command::Bind {
+            socks5::reply_error(stream, &e).await.unwrap_or_default();
+        }
+        conn.fail(&e);
+        return;
+    }
+
+    // 4.4 Establishes connection between ss-local and ss-remote
+    transfer(stream, &mut target_stream, trans, conn, ctx).await;
+
+    // A plugin in between can't tell a stalled ss-remote or target apart from an idle one,
+    // so the connection is reset to make it close its own connection to ss-remote too.
+    if conn.outcome == Outcome::TimedOut && ctx.plugin() {
+        target_stream.get_ref().get_ref().reset_on_drop();
+    }
+}
+
+/// Connections to ss-remote that can be reset rather than closed gracefully.
+trait Reset {
+    /// Makes dropping the connection send a RST, discarding unsent data.
+    fn reset_on_drop(&self);
+}
+
+impl Reset for TokioTcpStream {
+    fn reset_on_drop(&self) {
+        if let Err(e) = self.set_linger(Some(Duration::ZERO)) {
+            log::debug!("Set linger failed: {}", e);
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Reset for tokio_rustls::client::TlsStream<TokioTcpStream> {
+    fn reset_on_drop(&self) {
+        self.get_ref().0.reset_on_drop();
+    }
+}
+
+/// Writes the target address to ss-remote.
+///
+/// Client data that is available within `wait` is written in the same chunk,
+/// so the first round trip carries payload.
+async fn write_target_addr<S, R>(
+    stream: &mut S,
+    target_stream: &mut R,
+    target_addr: &Socks5Addr,
+    wait: Duration,
+    ctx: &Ctx,
+) -> io::Result<()>
+where
+    S: AsyncRead + Unpin + ?Sized,
+    R: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = target_addr.get_raw_parts();
+    let addr_len = buf.len();
+    buf.resize(usize::max(ctx.max_payload_size(), addr_len), 0);
+
+    let n = match tokio::time::timeout(wait, stream.read(&mut buf[addr_len..])).await {
+        Ok(res) => res?,
+        Err(_) => 0,
+    };
+    buf.truncate(addr_len + n);
+
+    target_stream.write_all(&buf).await
+}
+
+/// Requests ss-remote to accept a connection from the target address,
+/// and replies the SOCKS5 client the listen address, then the address the connection came from.
+async fn request_bind<S, R>(
+    stream: &mut S,
+    remote_stream: &mut R,
+    target_addr: &Socks5Addr,
+) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin + ?Sized,
+    R: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = vec![constants::COMMAND_BIND];
+    buf.append(&mut target_addr.get_raw_parts());
+    remote_stream.write_all(&buf).await?;
+    remote_stream.flush().await?;
+
+    for _ in 0..2 {
+        let addr = Socks5Addr::construct(remote_stream).await?;
+        socks5::reply(stream, &addr).await?;
+    }
+
+    Ok(())
+}
+
+async fn transfer<A, B>(a: &mut A, b: &mut B, trans: &str, conn: &mut Connection, ctx: &Ctx)
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let counts = [AtomicU64::new(0), AtomicU64::new(0)];
+    let port_bytes = conn.port_bytes.clone();
+    let port_bytes = port_bytes.as_deref();
+
+    // Throttling the client side limits both directions.
+    let copy = async {
+        match ctx.rate_limit() {
+            Some((rate, burst)) => {
+                let mut a = ThrottledStream::new(a, rate, burst);
+                copy_within_max_duration(&mut a, b, &counts, port_bytes, ctx).await
+            }
+            None => copy_within_max_duration(a, b, &counts, port_bytes, ctx).await,
+        }
+    };
+    let res = tokio::select! {
+        res = copy => res,
+        _ = idle_at_shutdown(&counts, ctx) => {
+            log::debug!("{} closed, idle at shutdown", trans);
+            Ok(Ok(()))
+        }
+    };
+
+    conn.bytes_up = counts[0].load(Ordering::Relaxed);
+    conn.bytes_down = counts[1].load(Ordering::Relaxed);
+
+    match res {
+        Ok(Ok(())) => {
+            log::debug!(
+                "{} done: ltor {} bytes, rtol {} bytes",
+                trans,
+                conn.bytes_up,
+                conn.bytes_down
+            );
+            conn.outcome = Outcome::Closed;
+        }
+        Ok(Err(e)) => {
+            match e.kind() {
+                ErrorKind::Other => log::warn!("{} error: {}", trans, e),
+                ErrorKind::TimedOut => {
+                    Stats::incr(&ctx.stats().idle_timeouts);
+                    log::debug!("{} error: {}", trans, e);
                 }
+                _ => log::debug!("{} error: {}", trans, e),
             }
+            conn.fail(&e);
+        }
+        Err(_) => {
+            Stats::incr(&ctx.stats().max_duration_timeouts);
+            log::debug!("{} error: maximum duration exceeded", trans);
+            conn.outcome = Outcome::TimedOut;
+        }
+    }
+}
 
-            // 3.3 Establishes connection between ss-local and ss-remote
-            transfer(&mut stream, &mut target_stream, &trans).await;
+/// Returns once shutting down has started and the counted bytes haven't changed for a while.
+async fn idle_at_shutdown(counts: &[AtomicU64; 2], ctx: &Ctx) {
+    ctx.shutting_down().await;
+
+    let mut last = None;
+    loop {
+        let total = counts[0].load(Ordering::Relaxed) + counts[1].load(Ordering::Relaxed);
+        if last == Some(total) {
+            return;
         }
+
+        last = Some(total);
+        tokio::time::sleep(constants::SHUTDOWN_IDLE).await;
     }
 }
 
-async fn transfer<A, B>(a: &mut A, b: &mut B, trans: &str)
+/// Copies data in both directions between `a` and `b` for at most the maximum duration,
+/// see [`copy_bidirectional`].
+async fn copy_within_max_duration<A, B>(
+    a: &mut A,
+    b: &mut B,
+    counts: &[AtomicU64; 2],
+    total: Option<&AtomicU64>,
+    ctx: &Ctx,
+) -> Result<io::Result<()>, Elapsed>
 where
     A: AsyncRead + AsyncWrite + Unpin + ?Sized,
     B: AsyncRead + AsyncWrite + Unpin + ?Sized,
 {
-    match tokio::io::copy_bidirectional(a, b).await {
-        Ok((atob, btoa)) => log::trace!("{} done: ltor {} bytes, rtol {} bytes", trans, atob, btoa),
-        Err(e) => match e.kind() {
-            ErrorKind::Other => log::warn!("{} error: {}", trans, e),
-            _ => log::debug!("{} error: {}", trans, e),
-        },
+    let copy = copy_bidirectional(a, b, ctx.timeouts().half_close(), counts, total);
+
+    match ctx.timeouts().max_duration {
+        Some(duration) => tokio::time::timeout(duration, copy).await,
+        None => Ok(copy.await),
     }
 }
 
-async fn read_to_end<R>(reader: &mut R) -> io::Result<()>
+/// Copies data in both directions between `a` and `b`,
+/// adding the bytes written to `b` and `a` to `counts[0]` and `counts[1]`,
+/// and the bytes of both directions to `total` if given.
+///
+/// Like [`tokio::io::copy_bidirectional`], the writer of a direction is shut down
+/// once its reader reaches EOF, but the other direction is closed afterwards once it
+/// relays nothing for `half_close`.
+async fn copy_bidirectional<A, B>(
+    a: &mut A,
+    b: &mut B,
+    half_close: Duration,
+    counts: &[AtomicU64; 2],
+    total: Option<&AtomicU64>,
+) -> io::Result<()>
 where
-    R: AsyncRead + Unpin + ?Sized,
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
 {
-    let mut buf = [0; 2048];
+    let (mut ar, aw) = tokio::io::split(a);
+    let (mut br, bw) = tokio::io::split(b);
+    let mut aw = CountingWriter::new(aw, &counts[1], total);
+    let mut bw = CountingWriter::new(bw, &counts[0], total);
+
+    let mut atob = Box::pin(async {
+        tokio::io::copy(&mut ar, &mut bw).await?;
+        bw.shutdown().await
+    });
+    let mut btoa = Box::pin(async {
+        tokio::io::copy(&mut br, &mut aw).await?;
+        aw.shutdown().await
+    });
+
+    tokio::select! {
+        res = &mut atob => {
+            res?;
+            finish_half_closed(btoa, &counts[1], half_close).await
+        }
+        res = &mut btoa => {
+            res?;
+            finish_half_closed(atob, &counts[0], half_close).await
+        }
+    }
+}
 
+/// Relays the remaining direction of a half-closed connection,
+/// failing once it relays nothing, as counted by `count`, for `idle`.
+async fn finish_half_closed<F>(mut copy: F, count: &AtomicU64, idle: Duration) -> io::Result<()>
+where
+    F: Future<Output = io::Result<()>> + Unpin,
+{
     loop {
-        let n = reader.read(&mut buf).await?;
-        if n == 0 {
-            break;
+        let last = count.load(Ordering::Relaxed);
+        match tokio::time::timeout(idle, &mut copy).await {
+            Ok(res) => return res,
+            Err(_) if count.load(Ordering::Relaxed) != last => continue,
+            Err(_) => return Err(io::Error::new(ErrorKind::TimedOut, "half-close timeout")),
         }
     }
+}
 
-    Ok(())
+/// A writer that counts the bytes written to it, also to a total if given.
+struct CountingWriter<'a, W> {
+    inner: W,
+    count: &'a AtomicU64,
+    total: Option<&'a AtomicU64>,
+}
+
+impl<'a, W> CountingWriter<'a, W> {
+    fn new(inner: W, count: &'a AtomicU64, total: Option<&'a AtomicU64>) -> Self {
+        CountingWriter {
+            inner,
+            count,
+            total,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        if let Some(total) = self.total {
+            total.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        Ok(n).into()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// A connection being handled, reported to the connection observer.
+///
+/// The close is reported when this is dropped, so it is reported however the handling ends.
+struct Connection {
+    peer: SocketAddr,
+    port_bytes: Option<Arc<AtomicU64>>,
+    bytes_up: u64,
+    bytes_down: u64,
+    outcome: Outcome,
+    awaits_reply: Option<Reply>,
+    ctx: Arc<Ctx>,
+}
+
+impl Connection {
+    /// Reports an accepted connection.
+    fn accept(peer: SocketAddr, ctx: Arc<Ctx>) -> Self {
+        ctx.observer().on_accept(peer);
+        Stats::incr(&ctx.stats().connections);
+        Stats::incr(&ctx.stats().active_connections);
+
+        Connection {
+            peer,
+            port_bytes: None,
+            bytes_up: 0,
+            bytes_down: 0,
+            outcome: Outcome::Error,
+            awaits_reply: None,
+            ctx,
+        }
+    }
+
+    /// Replies success to the client, if it is waiting for its CONNECT request.
+    async fn connected<S>(&mut self, stream: &mut S, bound_addr: SocketAddr) -> io::Result<()>
+    where
+        S: AsyncWrite + Unpin + ?Sized,
+    {
+        match self.awaits_reply.take() {
+            Some(Reply::Socks5) => socks5::reply(stream, &bound_addr.into()).await,
+            Some(Reply::Http) => http_connect::reply(stream).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Replies failure to the client, if it is waiting for its CONNECT request,
+    /// and sets the outcome from the error.
+    async fn connect_failed<S>(&mut self, stream: &mut S, e: &io::Error)
+    where
+        S: AsyncWrite + Unpin + ?Sized,
+    {
+        // The connection is closed right after, so it doesn't matter if it fails.
+        match self.awaits_reply.take() {
+            Some(Reply::Socks5) => socks5::reply_error(stream, e).await.unwrap_or_default(),
+            Some(Reply::Http) => http_connect::reply_error(stream, e)
+                .await
+                .unwrap_or_default(),
+            None => {}
+        }
+
+        self.fail(e);
+    }
+
+    /// Reports the target address of the connection.
+    fn target(&self, target_addr: &Socks5Addr) {
+        self.ctx.observer().on_target(self.peer, target_addr);
+    }
+
+    /// Sets the outcome from the error that ended the connection.
+    fn fail(&mut self, e: &io::Error) {
+        self.outcome = match e.kind() {
+            ErrorKind::TimedOut => Outcome::TimedOut,
+            _ => Outcome::Error,
+        };
+    }
 }
 
-fn make_timed_stream<T>(stream: T) -> TimeoutStream<T> {
-    TimeoutStream::new(stream, constants::DEFAULT_TIMEOUT)
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let stats = self.ctx.stats();
+        Stats::decr(&stats.active_connections);
+        self.ctx.notify_closed();
+        Stats::add(&stats.bytes_up, self.bytes_up);
+        Stats::add(&stats.bytes_down, self.bytes_down);
+        if self.outcome == Outcome::Rejected {
+            Stats::incr(&stats.rejected);
+        }
+
+        let observer = self.ctx.observer();
+        observer.on_close(self.peer, self.bytes_up, self.bytes_down, self.outcome);
+    }
+}
+
+/// Runs a handshake future within the handshake timeout.
+async fn handshake_timeout<F, T>(handshake: F, ctx: &Ctx) -> io::Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    handshake_until(handshake, Instant::now() + ctx.timeouts().handshake, ctx).await
+}
+
+/// Runs a handshake future until the deadline, for handshakes in several steps.
+async fn handshake_until<F, T>(handshake: F, deadline: Instant, ctx: &Ctx) -> io::Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    let res = match tokio::time::timeout_at(deadline, handshake).await {
+        Ok(res) => res,
+        Err(_) => Err(ErrorKind::TimedOut.into()),
+    };
+
+    if let Err(ref e) = res {
+        if e.kind() == ErrorKind::TimedOut {
+            Stats::incr(&ctx.stats().handshake_timeouts);
+        }
+    }
+
+    res
+}
+
+/// Binds a listener to the given address, with TCP Fast Open and `SO_REUSEPORT` if enabled.
+async fn bind<A: ToSocketAddrs>(addr: A, ctx: &Ctx) -> io::Result<TokioTcpListener> {
+    if !ctx.fast_open() && !ctx.reuse_port() {
+        return TokioTcpListener::bind(addr).await;
+    }
+
+    let mut last_err = None;
+    for addr in tokio::net::lookup_host(addr).await? {
+        match bind_socket(addr, ctx) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| ErrorKind::NotFound.into()))
+}
+
+fn bind_socket(addr: SocketAddr, ctx: &Ctx) -> io::Result<TokioTcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+
+    // Same as `TcpListener::bind`.
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+
+    // Checked to be supported at startup.
+    if ctx.reuse_port() {
+        sys::set_reuseport(&socket)?;
+    }
+
+    if ctx.fast_open() {
+        if let Err(e) = sys::set_tcp_fastopen(&socket, constants::FAST_OPEN_QUEUE_LEN) {
+            log::debug!("Enable TCP Fast Open on {} failed: {}", addr, e);
+        }
+    }
+
+    socket.bind(addr)?;
+    socket.listen(constants::LISTEN_BACKLOG)
+}
+
+/// Connects to the given address within the connect timeout, with TCP Fast Open if enabled.
+async fn connect(addr: SocketAddr, ctx: &Ctx) -> io::Result<TokioTcpStream> {
+    connect_any(&[addr], constants::CONNECTION_ATTEMPT_DELAY, ctx).await
+}
+
+/// Connects to the addresses returned by `addrs`, retrying failures that may be transient
+/// with exponential backoff, see [`Ctx::set_connect_retries`].
+///
+/// The addresses are returned again before each retry, so ss-remote is re-resolved.
+/// Refused connections are only retried if `retry_refused`, a target refusing isn't
+/// restarting like ss-remote may be. All the attempts end within the connect timeout.
+async fn connect_retry<F, Fut>(
+    mut addrs: F,
+    retry_refused: bool,
+    ctx: &Ctx,
+) -> io::Result<TokioTcpStream>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Vec<SocketAddr>>,
+{
+    let (retries, mut delay) = ctx.connect_retries();
+    let deadline = Instant::now() + ctx.timeouts().connect;
+    let mut attempt = 0;
+
+    loop {
+        let addrs = addrs().await;
+        let connect = connect_any(&addrs, constants::CONNECTION_ATTEMPT_DELAY, ctx);
+        let res = match tokio::time::timeout_at(deadline, connect).await {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::new(ErrorKind::TimedOut, "connect timed out")),
+        };
+
+        match res {
+            Err(e)
+                if attempt < retries
+                    && is_transient(&e)
+                    && (retry_refused || e.kind() != ErrorKind::ConnectionRefused)
+                    && Instant::now() + delay < deadline =>
+            {
+                attempt += 1;
+                log::debug!(
+                    "Connect to {:?} failed: {}, retry {}/{} in {:?}",
+                    addrs,
+                    e,
+                    attempt,
+                    retries,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                delay = delay.saturating_mul(2).min(constants::MAX_RETRY_DELAY);
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Returns false for connect errors that retrying can't fix, such as a source address of
+/// the wrong family or no address to connect to.
+fn is_transient(e: &io::Error) -> bool {
+    !matches!(
+        e.kind(),
+        ErrorKind::InvalidInput
+            | ErrorKind::PermissionDenied
+            | ErrorKind::AddrNotAvailable
+            | ErrorKind::NotFound
+    )
+}
+
+/// Connects to the first of the given addresses that accepts within the connect timeout.
+///
+/// Attempts are started in order, the next one after the delay or as soon as the previous
+/// one fails, and race each other (Happy Eyeballs, RFC 8305). The others are aborted once
+/// one connects.
+async fn connect_any(
+    addrs: &[SocketAddr],
+    delay: Duration,
+    ctx: &Ctx,
+) -> io::Result<TokioTcpStream> {
+    let options = SocketOptions::new(ctx);
+
+    let race = async {
+        if let [addr] = addrs {
+            return open(*addr, options).await;
+        }
+
+        let mut attempts = JoinSet::new();
+        let mut addrs = addrs.iter().copied().peekable();
+        let mut last_err = None;
+
+        loop {
+            if let Some(addr) = addrs.next() {
+                attempts.spawn(async move { open(addr, options).await.map_err(|e| (addr, e)) });
+            }
+
+            tokio::select! {
+                Some(res) = attempts.join_next() => match res {
+                    Ok(Ok(stream)) => return Ok(stream),
+                    Ok(Err((addr, e))) => {
+                        log::debug!("Connect to {} failed: {}", addr, e);
+                        last_err = Some(e);
+                    }
+                    Err(e) => last_err = Some(io::Error::other(e)),
+                },
+                _ = tokio::time::sleep(delay), if addrs.peek().is_some() => {}
+                else => break,
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| ErrorKind::NotFound.into()))
+    };
+
+    match tokio::time::timeout(ctx.timeouts().connect, race).await {
+        Ok(res) => res,
+        Err(_) => {
+            Stats::incr(&ctx.stats().connect_timeouts);
+            Err(ErrorKind::TimedOut.into())
+        }
+    }
+}
+
+/// Options of outbound sockets, copied from the context for the connection attempts.
+#[derive(Clone, Copy)]
+struct SocketOptions {
+    fast_open: bool,
+    bind_ip: Option<IpAddr>,
+    fwmark: Option<u32>,
+    no_delay: bool,
+}
+
+impl SocketOptions {
+    fn new(ctx: &Ctx) -> Self {
+        SocketOptions {
+            fast_open: ctx.fast_open(),
+            bind_ip: ctx.outbound_bind(),
+            fwmark: ctx.fwmark(),
+            no_delay: ctx.no_delay(),
+        }
+    }
+}
+
+/// Opens a connection to the given address, from the source address, with the firewall mark
+/// and with TCP Fast Open if enabled.
+async fn open(addr: SocketAddr, options: SocketOptions) -> io::Result<TokioTcpStream> {
+    if !options.fast_open && options.bind_ip.is_none() && options.fwmark.is_none() {
+        let stream = TokioTcpStream::connect(addr).await?;
+        set_nodelay(&stream, options.no_delay);
+        return Ok(stream);
+    }
+
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+
+    // Checked to be supported at startup.
+    if let Some(mark) = options.fwmark {
+        sys::set_mark(&socket, mark)?;
+    }
+
+    if let Some(ip) = options.bind_ip {
+        if ip.is_ipv4() != addr.is_ipv4() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("outbound bind address {} can't connect to {}", ip, addr),
+            ));
+        }
+
+        socket.bind(SocketAddr::new(ip, 0))?;
+    }
+
+    if options.fast_open {
+        if let Err(e) = sys::set_tcp_fastopen_connect(&socket) {
+            log::debug!("Enable TCP Fast Open to {} failed: {}", addr, e);
+        }
+    }
+
+    let stream = socket.connect(addr).await?;
+    set_nodelay(&stream, options.no_delay);
+    Ok(stream)
+}
+
+/// Sets `TCP_NODELAY`, so that small writes of interactive traffic aren't delayed by Nagle's
+/// algorithm. A failure only costs latency.
+fn set_nodelay(stream: &TokioTcpStream, nodelay: bool) {
+    if let Err(e) = stream.set_nodelay(nodelay) {
+        log::debug!("Set TCP_NODELAY failed: {}", e);
+    }
+}
+
+async fn read_to_end<R>(reader: &mut R) -> io::Result<()>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut buf = [0; 2048];
+
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Drains a suspected probe for a random duration, then closes it,
+/// so that how long it is kept open doesn't tell anything about the server.
+async fn drain_probe<R>(reader: &mut R)
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let range = constants::MIN_PROBE_DRAIN..=constants::MAX_PROBE_DRAIN;
+    let duration = rand::thread_rng().gen_range(range);
+    tokio::time::timeout(duration, read_to_end(reader))
+        .await
+        .ok();
+}
+
+fn make_timed_stream<T>(stream: T, ctx: &Ctx) -> TimeoutStream<T> {
+    TimeoutStream::new(stream, ctx.timeouts().idle)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::{acl::Acl, context::Timeouts, observer::ConnectionObserver};
+
+    #[tokio::test]
+    async fn test_probe() {
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+
+        let mut ctx = Ctx::new();
+        ctx.set_probe(true);
+        let ctx = Arc::new(ctx);
+
+        let (a, b) = tokio::io::duplex(0x10000);
+        let mut client = SsTcpStream::new(a, METHOD, &KEY, ctx.clone()).unwrap();
+        let server = SsTcpStream::new(b, METHOD, &KEY, ctx.clone()).unwrap();
+
+        let target_addr = Socks5Addr::Ipv4("192.0.2.1:80".parse().unwrap());
+        client
+            .write_all(&target_addr.get_raw_parts())
+            .await
+            .unwrap();
+        client.write_all(b"GET / HTTP/1.1").await.unwrap();
+
+        let peer = "127.0.0.1:1234".parse().unwrap();
+        handle_ss_remote(server, peer, ctx.clone()).await;
+
+        assert_eq!(ctx.stats_snapshot().probes, 1);
+        assert_eq!(ctx.stats_snapshot().connect_timeouts, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mitigate_probing() {
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+
+        let mut ctx = Ctx::new();
+        ctx.set_mitigate_probing(true);
+        let ctx = Arc::new(ctx);
+
+        let (mut client, server) = tokio::io::duplex(0x10000);
+        let server = SsTcpStream::new(server, METHOD, &KEY, ctx.clone()).unwrap();
+        let peer = "127.0.0.1:1234".parse().unwrap();
+        let handle = tokio::spawn(handle_ss_remote(server, peer, ctx));
+
+        // A salt and a first chunk that fails to decrypt.
+        client.write_all(&[0u8; 64]).await.unwrap();
+
+        tokio::time::sleep(constants::MIN_PROBE_DRAIN / 2).await;
+        client.write_all(b"more garbage").await.unwrap();
+        assert!(!handle.is_finished());
+
+        tokio::time::sleep(constants::MAX_PROBE_DRAIN).await;
+        assert!(handle.is_finished());
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Event {
+        Accept,
+        Target(String),
+        Close(u64, u64, Outcome),
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<Event>>,
+    }
+
+    impl ConnectionObserver for Recorder {
+        fn on_accept(&self, _peer: SocketAddr) {
+            self.events.lock().unwrap().push(Event::Accept);
+        }
+
+        fn on_target(&self, _peer: SocketAddr, target: &Socks5Addr) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(Event::Target(target.to_string()));
+        }
+
+        fn on_close(&self, _peer: SocketAddr, bytes_up: u64, bytes_down: u64, outcome: Outcome) {
+            let event = Event::Close(bytes_up, bytes_down, outcome);
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_observer() {
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+
+        // An echo server as the target
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut r, mut w) = stream.split();
+            tokio::io::copy(&mut r, &mut w).await.unwrap();
+        });
+
+        let recorder = Arc::new(Recorder::default());
+        let mut ctx = Ctx::new();
+        ctx.set_observer(recorder.clone());
+        let ctx = Arc::new(ctx);
+
+        let (a, b) = tokio::io::duplex(0x10000);
+        let mut client = SsTcpStream::new(a, METHOD, &KEY, ctx.clone()).unwrap();
+        let server = SsTcpStream::new(b, METHOD, &KEY, ctx.clone()).unwrap();
+
+        let peer = "127.0.0.1:1234".parse().unwrap();
+        let handle = tokio::spawn(handle_ss_remote(server, peer, ctx.clone()));
+
+        let target_addr = match target {
+            SocketAddr::V4(addr) => Socks5Addr::Ipv4(addr),
+            SocketAddr::V6(addr) => Socks5Addr::Ipv6(addr),
+        };
+        client
+            .write_all(&target_addr.get_raw_parts())
+            .await
+            .unwrap();
+        client.write_all(b"hello").await.unwrap();
+
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        client.shutdown().await.unwrap();
+        handle.await.unwrap();
+
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            [
+                Event::Accept,
+                Event::Target(target.to_string()),
+                Event::Close(5, 5, Outcome::Closed)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tunnel() {
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+
+        // An echo server as the target
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut r, mut w) = stream.split();
+            tokio::io::copy(&mut r, &mut w).await.unwrap();
+        });
+
+        let ctx = Arc::new(Ctx::new());
+
+        let remote = SsTcpListener::bind("127.0.0.1:0", METHOD, &KEY, ctx.clone())
+            .await
+            .unwrap();
+        let remote_addr = remote.local_addr().unwrap();
+        let remote_ctx = ctx.clone();
+        tokio::spawn(async move {
+            let (stream, peer) = remote.accept().await.unwrap();
+            handle_ss_remote(stream, peer, remote_ctx).await;
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            let target_addr = Socks5Addr::from(target);
+            handle_ss_tunnel(
+                stream,
+                peer,
+                target_addr,
+                remote_addr,
+                METHOD,
+                KEY.to_vec(),
+                ctx,
+            )
+            .await;
+        });
+
+        // No handshake, the data is relayed to the target right away
+        let mut client = TcpStream::connect(local_addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        client.shutdown().await.unwrap();
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+
+        // An echo server as the target
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut r, mut w) = stream.split();
+            tokio::io::copy(&mut r, &mut w).await.unwrap();
+        });
+
+        let remote_ctx = Arc::new(Ctx::new());
+        let remote = SsTcpListener::bind("127.0.0.1:0", METHOD, &KEY, remote_ctx.clone())
+            .await
+            .unwrap();
+        let remote_addr = remote.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, peer) = remote.accept().await.unwrap();
+            handle_ss_remote(stream, peer, remote_ctx).await;
+        });
+
+        // The first server is down, the second one has its own method and key
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut ctx = Ctx::new();
+        ctx.set_remote_pool(RemotePool::new(vec![
+            Server::new(closed, None, Method::Aes128Gcm, vec![0u8; 16]),
+            Server::new(remote_addr, None, METHOD, KEY.to_vec()),
+        ]));
+        let ctx = Arc::new(ctx);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let local_ctx = ctx.clone();
+        let handle = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            let target_addr = Socks5Addr::from(target);
+            let key = vec![0u8; 16];
+            handle_ss_tunnel(
+                stream,
+                peer,
+                target_addr,
+                closed,
+                Method::Aes128Gcm,
+                key,
+                local_ctx,
+            )
+            .await;
+        });
+
+        let mut client = TcpStream::connect(local_addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        client.shutdown().await.unwrap();
+        handle.await.unwrap();
+
+        let servers = ctx.remote_pool().unwrap().servers();
+        assert!(!servers[0].is_healthy());
+        assert!(servers[1].is_healthy());
+        assert_eq!(servers[1].active(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_bind() {
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+
+        let mut ctx = Ctx::new();
+        ctx.set_socks5_bind(true);
+        let ctx = Arc::new(ctx);
+
+        let remote = SsTcpListener::bind("127.0.0.1:0", METHOD, &KEY, ctx.clone())
+            .await
+            .unwrap();
+        let remote_addr = remote.local_addr().unwrap();
+        let remote_ctx = ctx.clone();
+        tokio::spawn(async move {
+            let (stream, peer) = remote.accept().await.unwrap();
+            handle_ss_remote(stream, peer, remote_ctx).await;
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            handle_ss_local(stream, peer, remote_addr, METHOD, KEY.to_vec(), ctx).await;
+        });
+
+        // Greeting and BIND request, expecting a connection from 127.0.0.1
+        let mut client = TcpStream::connect(local_addr).await.unwrap();
+        let request = [0x05, 0x01, 0x00, 0x05, 0x02, 0x00, 0x01, 127, 0, 0, 1, 0, 0];
+        client.write_all(&request).await.unwrap();
+
+        let mut rsp = [0u8; 2 + 10];
+        client.read_exact(&mut rsp).await.unwrap();
+        assert_eq!(rsp[..5], [0x05, 0x00, 0x05, 0x00, 0x00]);
+        let bound_addr = match Socks5Addr::construct(&mut &rsp[5..]).await.unwrap() {
+            Socks5Addr::Ipv4(addr) => addr,
+            _ => unreachable!(),
+        };
+        assert_eq!(*bound_addr.ip(), Ipv4Addr::LOCALHOST);
+
+        // The target connects to the listen address
+        let mut target = TcpStream::connect(bound_addr).await.unwrap();
+        let mut rsp = [0u8; 10];
+        client.read_exact(&mut rsp).await.unwrap();
+        let from_addr = Socks5Addr::construct(&mut &rsp[3..]).await.unwrap();
+        assert_eq!(rsp[..3], [0x05, 0x00, 0x00]);
+        assert_eq!(
+            from_addr.to_string(),
+            target.local_addr().unwrap().to_string()
+        );
+
+        // Then it is relayed both ways
+        target.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        client.write_all(b"world").await.unwrap();
+        target.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+
+        client.shutdown().await.unwrap();
+        target.shutdown().await.unwrap();
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_reply() {
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+
+        // Nothing listens on ss-remote's address
+        let remote_addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let ctx = Arc::new(Ctx::new());
+        let handle = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            handle_ss_local(stream, peer, remote_addr, METHOD, KEY.to_vec(), ctx).await;
+        });
+
+        let mut client = TcpStream::connect(local_addr).await.unwrap();
+        let request = [
+            0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0, 80,
+        ];
+        client.write_all(&request).await.unwrap();
+        handle.await.unwrap();
+
+        // Replied only once the connection failed, as refused
+        let mut rsp = Vec::new();
+        client.read_to_end(&mut rsp).await.unwrap();
+        assert_eq!(rsp, [0x05, 0x00, 0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_block_local() {
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+        const ACL: &str = r"
+        [reject_all]
+
+        [proxy_list]
+        ||example.org
+
+        [outbound_block_list]
+        10.0.0.0/8
+        ||ads.example.org
+        ||xn--bcher-kva.example
+        ";
+
+        // Blocked before connecting to ss-remote, where nothing listens
+        let remote_addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let mut ctx = Ctx::new();
+        ctx.set_router(Arc::new(Acl::from_str(ACL)));
+        let ctx = Arc::new(ctx);
+        let handle = tokio::spawn(async move {
+            for _ in 0..4 {
+                let (stream, peer) = listener.accept().await.unwrap();
+                let ctx = ctx.clone();
+                handle_ss_local(stream, peer, remote_addr, METHOD, KEY.to_vec(), ctx).await;
+            }
+        });
+
+        let connect = |target: Vec<u8>| async move {
+            let mut client = TcpStream::connect(local_addr).await.unwrap();
+            client
+                .write_all(&[0x05, 0x01, 0x00, 0x05, 0x01, 0x00])
+                .await
+                .unwrap();
+            client.write_all(&target).await.unwrap();
+
+            let mut rsp = Vec::new();
+            client.read_to_end(&mut rsp).await.unwrap();
+            rsp[3]
+        };
+
+        let mut domain = vec![0x03, 15];
+        domain.extend_from_slice(b"ads.example.org");
+        domain.extend_from_slice(&[0, 80]);
+        // Matched in its ascii form
+        let mut unicode = vec![0x03, "Bücher.example".len() as u8];
+        unicode.extend_from_slice("Bücher.example".as_bytes());
+        unicode.extend_from_slice(&[0, 80]);
+        let mut allowed = vec![0x03, 11];
+        allowed.extend_from_slice(b"example.org");
+        allowed.extend_from_slice(&[0, 80]);
+
+        // Not allowed by the ACL, unlike a target failing to connect, which the mode doesn't block
+        assert_eq!(connect(vec![0x01, 10, 0, 0, 1, 0, 80]).await, 0x02);
+        assert_eq!(connect(domain).await, 0x02);
+        assert_eq!(connect(unicode).await, 0x02);
+        assert_eq!(connect(allowed).await, 0x05);
+
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_block_remote() {
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+        const ACL: &str = r"
+        [proxy_all]
+
+        [outbound_block_list]
+        ||localhost
+        ";
+
+        let mut ctx = Ctx::new();
+        ctx.set_router(Arc::new(Acl::from_str(ACL)));
+        let ctx = Arc::new(ctx);
+
+        let (a, b) = tokio::io::duplex(0x10000);
+        let mut client = SsTcpStream::new(a, METHOD, &KEY, ctx.clone()).unwrap();
+        let server = SsTcpStream::new(b, METHOD, &KEY, ctx.clone()).unwrap();
+
+        let peer = "127.0.0.1:1234".parse().unwrap();
+        let handle = tokio::spawn(handle_ss_remote(server, peer, ctx.clone()));
+
+        // Matched by the host, not by the host and port
+        let target_addr = Socks5Addr::DomainName(("localhost".to_owned(), 80));
+        client
+            .write_all(&target_addr.get_raw_parts())
+            .await
+            .unwrap();
+        handle.await.unwrap();
+
+        assert_eq!(ctx.stats_snapshot().rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn test_bind_disabled() {
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+
+        let ctx = Arc::new(Ctx::new());
+        let (a, b) = tokio::io::duplex(0x10000);
+        let mut client = SsTcpStream::new(a, METHOD, &KEY, ctx.clone()).unwrap();
+        let server = SsTcpStream::new(b, METHOD, &KEY, ctx.clone()).unwrap();
+
+        let peer = "127.0.0.1:1234".parse().unwrap();
+        let handle = tokio::spawn(handle_ss_remote(server, peer, ctx.clone()));
+
+        let mut request = vec![constants::COMMAND_BIND];
+        request.extend(Socks5Addr::Ipv4("127.0.0.1:0".parse().unwrap()).get_raw_parts());
+        client.write_all(&request).await.unwrap();
+        handle.await.unwrap();
+
+        // Closed without a reply
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+        assert_eq!(ctx.stats_snapshot().rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn test_bind_unspecified() {
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+
+        let mut ctx = Ctx::new();
+        ctx.set_socks5_bind(true);
+        ctx.set_ip_family(crate::net::resolver::IpFamily::System);
+        let ctx = Arc::new(ctx);
+
+        for target in ["0.0.0.0:0", "[::]:0"] {
+            let (a, b) = tokio::io::duplex(0x10000);
+            let mut client = SsTcpStream::new(a, METHOD, &KEY, ctx.clone()).unwrap();
+            let server = SsTcpStream::new(b, METHOD, &KEY, ctx.clone()).unwrap();
+
+            let peer = "127.0.0.1:1234".parse().unwrap();
+            let handle = tokio::spawn(handle_ss_remote(server, peer, ctx.clone()));
+
+            let mut request = vec![constants::COMMAND_BIND];
+            request.extend(target.parse::<Socks5Addr>().unwrap().get_raw_parts());
+            client.write_all(&request).await.unwrap();
+            handle.await.unwrap();
+
+            // Closed without listening
+            let mut buf = Vec::new();
+            client.read_to_end(&mut buf).await.unwrap();
+            assert!(buf.is_empty());
+        }
+        assert_eq!(ctx.stats_snapshot().rejected, 2);
+    }
+
+    #[tokio::test]
+    async fn test_drained() {
+        let ctx = Arc::new(Ctx::new());
+        ctx.drained().await;
+
+        let conn = Connection::accept("127.0.0.1:1234".parse().unwrap(), ctx.clone());
+        let drained = tokio::time::timeout(Duration::from_millis(50), ctx.drained());
+        assert!(drained.await.is_err());
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(conn);
+        });
+        let drained = tokio::time::timeout(Duration::from_secs(1), ctx.drained());
+        assert!(drained.await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown() {
+        let ctx = Arc::new(Ctx::new());
+        let peer = "127.0.0.1:1234".parse().unwrap();
+
+        let (mut a, mut a_peer) = tokio::io::duplex(64);
+        let (mut b, _b_peer) = tokio::io::duplex(64);
+        let mut conn = Connection::accept(peer, ctx.clone());
+        let relay_ctx = ctx.clone();
+        let relay = tokio::spawn(async move {
+            transfer(&mut a, &mut b, "test", &mut conn, &relay_ctx).await;
+            conn.outcome
+        });
+
+        // Busy connections are kept
+        ctx.shutdown();
+        let start = Instant::now();
+        for _ in 0..3 {
+            a_peer.write_all(b"hello").await.unwrap();
+            tokio::time::sleep(constants::SHUTDOWN_IDLE / 2).await;
+        }
+        assert!(!relay.is_finished());
+
+        // Idle ones are closed well before the idle timeout
+        assert_eq!(relay.await.unwrap(), Outcome::Closed);
+        assert!(start.elapsed() < ctx.timeouts().idle / 2);
+        ctx.drained().await;
+    }
+
+    #[tokio::test]
+    async fn test_max_conn_rate() {
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+
+        let mut ctx = Ctx::new();
+        ctx.set_max_conn_rate(1);
+        let ctx = Arc::new(ctx);
+
+        let accept = |peer: &str| {
+            let (a, b) = tokio::io::duplex(0x10000);
+            let server = SsTcpStream::new(b, METHOD, &KEY, ctx.clone()).unwrap();
+            (
+                a,
+                tokio::spawn(handle_ss_remote(server, peer.parse().unwrap(), ctx.clone())),
+            )
+        };
+
+        // Waits for the target address.
+        let (_a, first) = accept("192.0.2.1:1234");
+        tokio::task::yield_now().await;
+
+        // Dropped right away, without waiting for anything.
+        let (_b, second) = accept("192.0.2.1:1235");
+        second.await.unwrap();
+        assert_eq!(ctx.stats_snapshot().rejected, 1);
+
+        let (_c, third) = accept("192.0.2.2:1234");
+        tokio::task::yield_now().await;
+        assert!(!first.is_finished());
+        assert!(!third.is_finished());
+        assert_eq!(ctx.stats_snapshot().rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+
+        let mut ctx = Ctx::new();
+        ctx.set_max_connections(1);
+        let ctx = Arc::new(ctx);
+
+        let listener = SsTcpListener::bind("127.0.0.1:0", METHOD, &KEY, ctx.clone())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_remote(listener, ctx.clone()));
+
+        let remote_connections = || ctx.stats_snapshot().remote_connections;
+        let settle = || tokio::time::sleep(Duration::from_millis(100));
+
+        let first = TcpStream::connect(addr).await.unwrap();
+        settle().await;
+        assert_eq!(remote_connections(), 1);
+
+        // Left in the backlog.
+        let second = TcpStream::connect(addr).await.unwrap();
+        settle().await;
+        assert_eq!(remote_connections(), 1);
+        assert_eq!(ctx.stats_snapshot().connections, 1);
+
+        drop(first);
+        settle().await;
+        assert_eq!(remote_connections(), 1);
+        assert_eq!(ctx.stats_snapshot().connections, 2);
+
+        drop(second);
+        settle().await;
+        assert_eq!(remote_connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_probe_slot() {
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+
+        let mut ctx = Ctx::new();
+        ctx.set_max_connections(1);
+        ctx.set_mitigate_probing(true);
+        let ctx = Arc::new(ctx);
+
+        let listener = SsTcpListener::bind("127.0.0.1:0", METHOD, &KEY, ctx.clone())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_remote(listener, ctx.clone()));
+
+        let settle = || tokio::time::sleep(Duration::from_millis(100));
+
+        // A salt and a first chunk that fails to decrypt.
+        let mut probe = TcpStream::connect(addr).await.unwrap();
+        probe.write_all(&[0u8; 64]).await.unwrap();
+        settle().await;
+        assert_eq!(ctx.stats_snapshot().remote_connections, 0);
+
+        // Accepted while the probe is drained
+        let _second = TcpStream::connect(addr).await.unwrap();
+        settle().await;
+        assert_eq!(ctx.stats_snapshot().remote_connections, 1);
+        assert_eq!(ctx.stats_snapshot().connections, 2);
+        assert_eq!(ctx.stats_snapshot().active_connections, 2);
+    }
+
+    #[tokio::test]
+    async fn test_self_connect() {
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+
+        let recorder = Arc::new(Recorder::default());
+        let mut ctx = Ctx::new();
+        ctx.set_observer(recorder.clone());
+        ctx.add_listen_addr("0.0.0.0:5421".parse().unwrap());
+        let ctx = Arc::new(ctx);
+
+        let (a, b) = tokio::io::duplex(0x10000);
+        let mut client = SsTcpStream::new(a, METHOD, &KEY, ctx.clone()).unwrap();
+        let server = SsTcpStream::new(b, METHOD, &KEY, ctx.clone()).unwrap();
+
+        let peer = "127.0.0.1:1234".parse().unwrap();
+        let handle = tokio::spawn(handle_ss_remote(server, peer, ctx.clone()));
+
+        let target_addr = Socks5Addr::Ipv4("127.0.0.1:5421".parse().unwrap());
+        client
+            .write_all(&target_addr.get_raw_parts())
+            .await
+            .unwrap();
+        handle.await.unwrap();
+
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            [
+                Event::Accept,
+                Event::Target(target_addr.to_string()),
+                Event::Close(0, 0, Outcome::Rejected)
+            ]
+        );
+        assert_eq!(ctx.stats_snapshot().self_connects, 1);
+
+        assert!(ctx.is_self_connect("0.0.0.0:5421".parse().unwrap()));
+        assert!(!ctx.is_self_connect("127.0.0.1:5422".parse().unwrap()));
+        assert!(!ctx.is_self_connect("8.8.8.8:5421".parse().unwrap()));
+
+        // The public or LAN ip of the host also loops back through the wildcard listener
+        assert!(!ctx.is_self_connect("192.0.2.1:5421".parse().unwrap()));
+        ctx.add_local_ip("192.0.2.1".parse().unwrap());
+        assert!(ctx.is_self_connect("192.0.2.1:5421".parse().unwrap()));
+        assert!(ctx.is_self_connect("[::ffff:192.0.2.1]:5421".parse().unwrap()));
+        assert!(!ctx.is_self_connect("192.0.2.1:5422".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_failed() {
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+
+        // An ipv4 target resolves to nothing with only ipv6 allowed
+        let recorder = Arc::new(Recorder::default());
+        let mut ctx = Ctx::new();
+        ctx.set_observer(recorder.clone());
+        ctx.set_ip_family(crate::net::resolver::IpFamily::V6Only);
+        let ctx = Arc::new(ctx);
+
+        let (a, b) = tokio::io::duplex(0x10000);
+        let mut client = SsTcpStream::new(a, METHOD, &KEY, ctx.clone()).unwrap();
+        let server = SsTcpStream::new(b, METHOD, &KEY, ctx.clone()).unwrap();
+
+        let peer = "127.0.0.1:1234".parse().unwrap();
+        let handle = tokio::spawn(handle_ss_remote(server, peer, ctx.clone()));
+
+        let target_addr = Socks5Addr::Ipv4("127.0.0.1:80".parse().unwrap());
+        client
+            .write_all(&target_addr.get_raw_parts())
+            .await
+            .unwrap();
+        handle.await.unwrap();
+
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            [
+                Event::Accept,
+                Event::Target(target_addr.to_string()),
+                Event::Close(0, 0, Outcome::Error)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_buffered_handshake() {
+        let (mut client, server) = tokio::io::duplex(0x10000);
+        let mut server = BufReader::with_capacity(constants::HANDSHAKE_BUFFER_SIZE, server);
+
+        // Greeting, request and payload sent at once
+        let target_addr = Socks5Addr::DomainName(("example.com".to_owned(), 443));
+        let mut data = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00];
+        data.extend(target_addr.get_raw_parts());
+        data.extend(b"hello");
+        client.write_all(&data).await.unwrap();
+
+        let addr = match socks5::handshake(&mut server, None, false, None)
+            .await
+            .unwrap()
+        {
+            Request::Connect(addr) => addr,
+            _ => unreachable!(),
+        };
+        assert_eq!(addr.to_string(), target_addr.to_string());
+
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_udp_associate_handshake() {
+        let udp_addr: SocketAddr = "127.0.0.1:1080".parse().unwrap();
+        let request = [0x05, 0x01, 0x00, 0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+
+        // Replies with the address of the udp relay
+        let (mut client, mut server) = tokio::io::duplex(0x10000);
+        client.write_all(&request).await.unwrap();
+        match socks5::handshake(&mut server, Some(udp_addr), false, None)
+            .await
+            .unwrap()
+        {
+            Request::UdpAssociate(_) => {}
+            _ => unreachable!(),
+        }
+
+        let mut reply = vec![0x05, 0x00, 0x05, 0x00, 0x00];
+        reply.extend(Socks5Addr::from(udp_addr).get_raw_parts());
+        let mut buf = vec![0u8; reply.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, reply);
+
+        // Rejected without a udp relay
+        let (mut client, mut server) = tokio::io::duplex(0x10000);
+        client.write_all(&request).await.unwrap();
+        assert!(socks5::handshake(&mut server, None, false, None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_udp_associate_auth() {
+        let mut credentials = socks5::Credentials::new();
+        credentials.add("user", "pass");
+        let mut ctx = Ctx::new();
+        ctx.set_udp(true);
+        ctx.set_credentials(credentials);
+        let ctx = Arc::new(ctx);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let remote_addr = "127.0.0.1:1".parse().unwrap();
+        let server_ctx = ctx.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = listener.accept().await.unwrap();
+                let (method, key) = (Method::ChaCha20Poly1305, vec![7u8; 32]);
+                let ctx = server_ctx.clone();
+                tokio::spawn(handle_ss_local(stream, peer, remote_addr, method, key, ctx));
+            }
+        });
+        let associate = [0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+
+        // Clients that don't authenticate get no udp relay
+        let mut client = TcpStream::connect(local_addr).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut buf = [0u8; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0x05, 0xff]);
+        let _ = client.write_all(&associate).await;
+        assert!(ctx.udp_clients().is_empty());
+
+        let mut client = TcpStream::connect(local_addr).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x02]).await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0x05, 0x02]);
+        client.write_all(b"\x01\x04user\x04pass").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0x01, 0x00]);
+        client.write_all(&associate).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0x00);
+        assert_eq!(ctx.udp_clients().len(), 1);
+
+        // Unregistered once the connection is closed
+        drop(client);
+        for _ in 0..50 {
+            if ctx.udp_clients().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(ctx.udp_clients().is_empty());
+    }
+
+    #[test]
+    fn test_describe_target() {
+        let domain = Socks5Addr::DomainName(("example.com".to_owned(), 443));
+        let ip = "1.2.3.4".parse().unwrap();
+
+        assert_eq!(
+            describe_target(&domain, Some(ip), true),
+            "example.com:443 (1.2.3.4, resolved locally)"
+        );
+        assert_eq!(
+            describe_target(&domain, Some(ip), false),
+            "example.com:443 (resolved remotely, 1.2.3.4 locally)"
+        );
+        assert_eq!(
+            describe_target(&domain, None, false),
+            "example.com:443 (resolved remotely)"
+        );
+
+        let target_addr = Socks5Addr::Ipv4("1.2.3.4:443".parse().unwrap());
+        assert_eq!(describe_target(&target_addr, Some(ip), true), "1.2.3.4:443");
+    }
+
+    #[tokio::test]
+    async fn test_write_target_addr() {
+        struct WriteCalls<W> {
+            inner: W,
+            writes: usize,
+        }
+
+        impl<W: AsyncWrite + Unpin> AsyncWrite for WriteCalls<W> {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                self.writes += 1;
+                Pin::new(&mut self.inner).poll_write(cx, buf)
+            }
+
+            fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.inner).poll_flush(cx)
+            }
+
+            fn poll_shutdown(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+            ) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.inner).poll_shutdown(cx)
+            }
+        }
+
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+
+        let ctx = Arc::new(Ctx::new());
+        let target_addr = Socks5Addr::DomainName(("example.com".to_owned(), 80));
+
+        // Sent before, after a short delay as by a client waiting for its reply, or never
+        let cases = [
+            (&b"GET / HTTP/1.1"[..], None),
+            (&b"GET / HTTP/1.1"[..], Some(Duration::from_millis(5))),
+            (&b""[..], None),
+        ];
+        for (data, delay) in cases {
+            let (mut client, mut stream) = tokio::io::duplex(0x10000);
+            let send = async {
+                if let Some(delay) = delay {
+                    tokio::time::sleep(delay).await;
+                }
+                client.write_all(data).await.unwrap();
+            };
+            let wait = match delay {
+                Some(_) => constants::FIRST_DATA_WAIT,
+                None => Duration::ZERO,
+            };
+
+            let (a, b) = tokio::io::duplex(0x10000);
+            let mut target_stream = WriteCalls {
+                inner: SsTcpStream::new(a, METHOD, &KEY, ctx.clone()).unwrap(),
+                writes: 0,
+            };
+            let write =
+                write_target_addr(&mut stream, &mut target_stream, &target_addr, wait, &ctx);
+            tokio::join!(send, write).1.unwrap();
+            assert_eq!(target_stream.writes, 1);
+            drop(target_stream);
+
+            let mut reader = SsTcpStream::new(b, METHOD, &KEY, ctx.clone()).unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, [target_addr.get_raw_parts(), data.to_vec()].concat());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plugin_idle_timeout() {
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+
+        // A stalled backend as the target, accepting but never answering
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (_stream, _) = backend.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        // ss-remote, with the default idle timeout
+        let remote_ctx = Arc::new(Ctx::new());
+        let servers = vec![("127.0.0.1:0".parse().unwrap(), METHOD, KEY.to_vec())];
+        tokio::spawn(ss_remote(servers, remote_ctx.clone()));
+        let remote_addr = loop {
+            match remote_ctx.listen_addrs().first() {
+                Some(addr) => break *addr,
+                None => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+
+        // A stub plugin relaying ss-local to ss-remote, reporting when it cleans up
+        let plugin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let plugin_addr = plugin.local_addr().unwrap();
+        let (closed_tx, closed_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut local, _) = plugin.accept().await.unwrap();
+            let mut remote = TokioTcpStream::connect(remote_addr).await.unwrap();
+            let res = tokio::io::copy_bidirectional(&mut local, &mut remote).await;
+            closed_tx.send(res).unwrap();
+        });
+
+        // ss-local, connecting to the plugin, with a short idle timeout
+        let mut local_ctx = Ctx::new();
+        local_ctx.set_plugin(true);
+        local_ctx.set_timeouts(Timeouts {
+            idle: Duration::from_millis(200),
+            ..Timeouts::default()
+        });
+        let local_ctx = Arc::new(local_ctx);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TokioTcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+        tokio::spawn(handle_ss_local(
+            stream,
+            peer,
+            plugin_addr,
+            METHOD,
+            KEY.to_vec(),
+            local_ctx,
+        ));
+
+        let target_addr = match target {
+            SocketAddr::V4(addr) => Socks5Addr::Ipv4(addr),
+            SocketAddr::V6(addr) => Socks5Addr::Ipv6(addr),
+        };
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.read_exact(&mut [0u8; 2]).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client
+            .write_all(&target_addr.get_raw_parts())
+            .await
+            .unwrap();
+        client.read_exact(&mut [0u8; 10]).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+
+        // The idle timeout on the loopback leg closes the client and the plugin connections
+        let mut buf = [0u8; 1];
+        let res = tokio::time::timeout(Duration::from_secs(5), client.read(&mut buf)).await;
+        assert_eq!(
+            res.expect("stalled connection was kept open").unwrap_or(0),
+            0
+        );
+
+        // Reset by ss-local
+        let res = tokio::time::timeout(Duration::from_secs(5), closed_rx)
+            .await
+            .expect("plugin connection was kept open")
+            .unwrap();
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_close() {
+        const METHOD: Method = Method::ChaCha20Poly1305;
+        const KEY: [u8; 32] = [7u8; 32];
+
+        // A stalled ss-remote, reading until the connection is closed
+        let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote_addr = remote.local_addr().unwrap();
+        let (closed_tx, closed_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = remote.accept().await.unwrap();
+            let res = stream.read_to_end(&mut Vec::new()).await;
+            closed_tx.send(res).unwrap();
+        });
+
+        // ss-local without a plugin, with a short idle timeout
+        let mut ctx = Ctx::new();
+        ctx.set_timeouts(Timeouts {
+            idle: Duration::from_millis(200),
+            ..Timeouts::default()
+        });
+        let ctx = Arc::new(ctx);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TokioTcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+        tokio::spawn(handle_ss_local(
+            stream,
+            peer,
+            remote_addr,
+            METHOD,
+            KEY.to_vec(),
+            ctx,
+        ));
+
+        let target_addr = Socks5Addr::Ipv4("127.0.0.1:80".parse().unwrap());
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.read_exact(&mut [0u8; 2]).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client
+            .write_all(&target_addr.get_raw_parts())
+            .await
+            .unwrap();
+        client.read_exact(&mut [0u8; 10]).await.unwrap();
+
+        // Closed gracefully rather than reset
+        let res = tokio::time::timeout(Duration::from_secs(5), closed_rx)
+            .await
+            .expect("timed out connection was kept open")
+            .unwrap();
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_partial_bind_failure() {
+        const METHOD: Method = Method::ChaCha20Poly1305;
+
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let taken_addr = taken.local_addr().unwrap();
+
+        let ctx = Arc::new(Ctx::new());
+        let servers = vec![
+            (taken_addr, METHOD, vec![0u8; 32]),
+            ("127.0.0.1:0".parse().unwrap(), METHOD, vec![1u8; 32]),
+        ];
+        tokio::spawn(ss_remote(servers, ctx.clone()));
+
+        let addr = loop {
+            match ctx.listen_addrs().first() {
+                Some(addr) => break *addr,
+                None => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        assert_ne!(addr, taken_addr);
+        TokioTcpStream::connect(addr).await.unwrap();
+
+        let servers = vec![(taken_addr, METHOD, vec![0u8; 32])];
+        assert!(ss_remote(servers, Arc::new(Ctx::new())).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fast_open() {
+        let mut ctx = Ctx::new();
+        ctx.set_fast_open(true);
+
+        let listener = bind("127.0.0.1:0", &ctx).await.unwrap();
+        let mut client = connect(listener.local_addr().unwrap(), &ctx).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reuse_port() {
+        let mut ctx = Ctx::new();
+        ctx.set_reuse_port(true);
+
+        let first = bind("127.0.0.1:0", &ctx).await.unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind(addr, &ctx).await.unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+
+        // Without it, the port is taken.
+        assert!(bind(addr, &Ctx::new()).await.is_err());
+    }
+
+    // Only linux assigns the whole 127.0.0.0/8 to the loopback interface.
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_outbound_bind() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut ctx = Ctx::new();
+        ctx.set_outbound_bind("127.0.0.2".parse().unwrap());
+        let stream = connect(addr, &ctx).await.unwrap();
+        assert_eq!(
+            stream.local_addr().unwrap().ip(),
+            "127.0.0.2".parse::<IpAddr>().unwrap()
+        );
+
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer.ip(), "127.0.0.2".parse::<IpAddr>().unwrap());
+
+        // The source address must be of the family of the target.
+        ctx.set_outbound_bind("::1".parse().unwrap());
+        let e = connect(addr, &ctx).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_partial_salt() {
+        let mut ctx = Ctx::new();
+        ctx.set_timeouts(Timeouts {
+            handshake: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let ctx = Arc::new(ctx);
+
+        let key = [7u8; 32];
+        let listener = SsTcpListener::bind("127.0.0.1:0", Method::ChaCha20Poly1305, &key, ctx)
+            .await
+            .unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+        let remote_ctx = listener.ctx.clone();
+        let handle = tokio::spawn(handle_ss_remote(stream, peer, remote_ctx));
+
+        // A few bytes of the salt, then nothing
+        client.write_all(&[1u8; 5]).await.unwrap();
+
+        let mut buf = [0u8; 16];
+        let n = tokio::time::timeout(Duration::from_secs(2), client.read(&mut buf))
+            .await
+            .expect("the salt read isn't under the handshake timeout")
+            .unwrap_or(0);
+        assert_eq!(n, 0);
+        handle.await.unwrap();
+        assert_eq!(listener.ctx.stats_snapshot().handshake_timeouts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_no_delay() {
+        let mut ctx = Ctx::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let stream = connect(addr, &ctx).await.unwrap();
+        assert!(stream.nodelay().unwrap());
+
+        ctx.set_no_delay(false);
+        let stream = connect(addr, &ctx).await.unwrap();
+        assert!(!stream.nodelay().unwrap());
+
+        // Both ends of the relay
+        let key = [7u8; 32];
+        let ctx = Arc::new(Ctx::new());
+        let listener = SsTcpListener::bind("127.0.0.1:0", Method::ChaCha20Poly1305, &key, ctx)
+            .await
+            .unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        assert!(stream.get_ref().nodelay().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_connect_any() {
+        let ctx = Ctx::new();
+        let delay = Duration::from_millis(50);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        // Unroutable, either stalls or fails
+        let stalled = "192.0.2.1:80".parse().unwrap();
+
+        let stream = tokio::time::timeout(
+            Duration::from_secs(2),
+            connect_any(&[stalled, closed, addr], delay, &ctx),
+        )
+        .await
+        .expect("stalled address wasn't raced")
+        .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+
+        assert!(connect_any(&[closed, closed], delay, &ctx).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_retry() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        // The addresses are asked for again before each retry
+        let mut ctx = Ctx::new();
+        let calls = AtomicU64::new(0);
+        let addrs = || {
+            let call = calls.fetch_add(1, Ordering::Relaxed);
+            future::ready(vec![if call < 3 { closed } else { addr }])
+        };
+        assert!(connect_retry(addrs, true, &ctx).await.is_err());
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // Refused connections are only retried if asked to
+        ctx.set_connect_retries(2, Duration::from_millis(10));
+        let e = connect_retry(addrs, false, &ctx).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        let stream = connect_retry(addrs, true, &ctx).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+        assert_eq!(calls.load(Ordering::Relaxed), 4);
+
+        // Errors retrying can't fix are returned at once
+        let e = connect_retry(|| future::ready(vec![]), true, &ctx)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connect_retry_deadline() {
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let mut ctx = Ctx::new();
+        ctx.set_connect_retries(100, Duration::from_millis(100));
+        ctx.set_timeouts(Timeouts {
+            connect: Duration::from_secs(5),
+            ..Timeouts::default()
+        });
+
+        // 100ms, 200ms, 400ms, 800ms, then 2s as the most, the next would pass the timeout
+        let calls = AtomicU64::new(0);
+        let addrs = || {
+            calls.fetch_add(1, Ordering::Relaxed);
+            future::ready(vec![closed])
+        };
+        let start = Instant::now();
+        assert!(connect_retry(addrs, true, &ctx).await.is_err());
+        assert_eq!(calls.load(Ordering::Relaxed), 6);
+        assert!(start.elapsed() < ctx.timeouts().connect);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_remote() {
+        let addr: SocketAddr = "127.0.0.1:8388".parse().unwrap();
+        let ctx = Ctx::new();
+        assert_eq!(resolve_remote(addr, None, &ctx).await, vec![addr]);
+
+        let addrs = resolve_remote(addr, Some("localhost:5421"), &ctx).await;
+        assert!(addrs
+            .iter()
+            .all(|x| x.ip().is_loopback() && x.port() == 5421));
+
+        // Falls back to the address resolved at startup
+        assert_eq!(
+            resolve_remote(addr, Some("localhost"), &ctx).await,
+            vec![addr]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_target() {
+        let ctx = Ctx::new();
+        let addr: Socks5Addr = "127.0.0.1:80".parse().unwrap();
+        assert_eq!(
+            resolve_target(&addr, &ctx).await.unwrap(),
+            ["127.0.0.1:80".parse().unwrap()]
+        );
+
+        let addrs = resolve_target(&"localhost:80".parse().unwrap(), &ctx)
+            .await
+            .unwrap();
+        assert!(addrs.iter().all(|x| x.ip().is_loopback() && x.port() == 80));
+
+        // A mixed-script name is converted before the lookup, and rejected if too long
+        let addr = Socks5Addr::DomainName((format!("{}例え", "a".repeat(60)), 80));
+        let e = resolve_target(&addr, &ctx).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_half_close_timeout() {
+        let (mut a, mut a_peer) = tokio::io::duplex(64);
+        let (mut b, mut b_peer) = tokio::io::duplex(64);
+
+        // a's peer closes its write half, b's peer keeps its side open
+        a_peer.shutdown().await.unwrap();
+
+        let half_close = Duration::from_millis(50);
+        let counts = [AtomicU64::new(0), AtomicU64::new(0)];
+        let res = tokio::time::timeout(
+            Duration::from_secs(5),
+            copy_bidirectional(&mut a, &mut b, half_close, &counts, None),
+        )
+        .await
+        .expect("half-closed connection was kept open");
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::TimedOut);
+
+        let mut buf = [0u8; 1];
+        assert_eq!(b_peer.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_half_close_streaming() {
+        let (mut a, mut a_peer) = tokio::io::duplex(64);
+        let (mut b, mut b_peer) = tokio::io::duplex(64);
+
+        // a's peer half-closes after its request, b's peer streams the response for longer
+        // than the half-close timeout
+        a_peer.shutdown().await.unwrap();
+
+        let half_close = Duration::from_millis(50);
+        let counts = [AtomicU64::new(0), AtomicU64::new(0)];
+        let copy = copy_bidirectional(&mut a, &mut b, half_close, &counts, None);
+
+        let stream = async {
+            for _ in 0..10 {
+                b_peer.write_all(b"chunk").await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            b_peer.shutdown().await.unwrap();
+        };
+        let read = async {
+            let mut rsp = Vec::new();
+            a_peer.read_to_end(&mut rsp).await.unwrap();
+            rsp
+        };
+
+        let (res, _, rsp) = tokio::join!(copy, stream, read);
+        res.unwrap();
+        assert_eq!(rsp.len(), 50);
+    }
+
+    #[tokio::test]
+    async fn test_count_total() {
+        let (mut a, mut a_peer) = tokio::io::duplex(64);
+        let (mut b, mut b_peer) = tokio::io::duplex(64);
+
+        let half_close = Duration::from_secs(1);
+        let counts = [AtomicU64::new(0), AtomicU64::new(0)];
+        let total = AtomicU64::new(0);
+        let copy = copy_bidirectional(&mut a, &mut b, half_close, &counts, Some(&total));
+
+        // Counted while the connection is still open
+        let relay = async {
+            let mut buf = [0u8; 5];
+            a_peer.write_all(b"hello").await.unwrap();
+            b_peer.read_exact(&mut buf).await.unwrap();
+            b_peer.write_all(b"hi").await.unwrap();
+            a_peer.read_exact(&mut buf[..2]).await.unwrap();
+        };
+        tokio::select! {
+            _ = copy => panic!("relay closed"),
+            _ = relay => {}
+        }
+
+        assert_eq!(counts[0].load(Ordering::Relaxed), 5);
+        assert_eq!(counts[1].load(Ordering::Relaxed), 2);
+        assert_eq!(total.load(Ordering::Relaxed), 7);
+    }
 }
@@ -0,0 +1,165 @@
+//! Built-in TLS transport, wrapping shadowsocks connections between ss-local and ss-remote.
+//!
+//! Only available with the `tls` feature.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, ErrorKind},
+    path::Path,
+    sync::Arc,
+};
+
+use tokio_rustls::{
+    rustls::{
+        Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig,
+        ServerName,
+    },
+    TlsAcceptor, TlsConnector,
+};
+
+/// TLS configuration of ss-remote or ss-local.
+#[derive(Clone)]
+pub enum Tls {
+    /// ss-remote accepts TLS connections.
+    Server(TlsAcceptor),
+
+    /// ss-local connects to ss-remote over TLS, verifying the server name.
+    Client(TlsConnector, ServerName),
+}
+
+impl Tls {
+    /// Creates a server configuration from PEM-encoded certificate chain and private key files.
+    pub fn server(cert_path: &Path, key_path: &Path) -> io::Result<Self> {
+        let certs = load_certs(cert_path)?;
+        let key = load_key(key_path)?;
+
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+
+        Ok(Tls::Server(TlsAcceptor::from(Arc::new(config))))
+    }
+
+    /// Creates a client configuration verifying the given server name.
+    ///
+    /// Trusts the certificates in the given PEM file if any, the Mozilla root certificates otherwise.
+    pub fn client(server_name: &str, ca_path: Option<&Path>) -> io::Result<Self> {
+        let server_name = ServerName::try_from(server_name)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+
+        let mut roots = RootCertStore::empty();
+        match ca_path {
+            Some(path) => {
+                for cert in load_certs(path)? {
+                    roots
+                        .add(&cert)
+                        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+                }
+            }
+            None => roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            })),
+        }
+
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Ok(Tls::Client(
+            TlsConnector::from(Arc::new(config)),
+            server_name,
+        ))
+    }
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("no certificate found in {}", path.display()),
+        ));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+
+    Err(io::Error::new(
+        ErrorKind::InvalidData,
+        format!("no private key found in {}", path.display()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    const CA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls/ca.pem");
+    const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls/cert.pem");
+    const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls/key.pem");
+
+    fn configs(server_name: &str) -> (TlsAcceptor, TlsConnector, ServerName) {
+        let server = Tls::server(Path::new(CERT), Path::new(KEY)).unwrap();
+        let client = Tls::client(server_name, Some(Path::new(CA))).unwrap();
+
+        match (server, client) {
+            (Tls::Server(acceptor), Tls::Client(connector, server_name)) => {
+                (acceptor, connector, server_name)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (acceptor, connector, server_name) = configs("ss-rs.test");
+
+        let (a, b) = tokio::io::duplex(0x10000);
+        let (client, server) = tokio::join!(connector.connect(server_name, a), acceptor.accept(b));
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_wrong_name() {
+        let (acceptor, connector, server_name) = configs("example.com");
+
+        let (a, b) = tokio::io::duplex(0x10000);
+        let (client, _) = tokio::join!(connector.connect(server_name, a), acceptor.accept(b));
+        assert!(client.is_err());
+    }
+
+    #[test]
+    fn test_error() {
+        assert!(Tls::server(Path::new(KEY), Path::new(KEY)).is_err());
+        assert!(Tls::server(Path::new(CERT), Path::new(CERT)).is_err());
+        assert!(Tls::client("", None).is_err());
+    }
+}
@@ -0,0 +1,670 @@
+//! Shadowsocks udp services.
+//!
+//! ss-local receives SOCKS5 udp packets from clients, and relays them to ss-remote as
+//! shadowsocks packets, i.e. `[target address][data]` encrypted, see [`crate::net::packet`].
+//! ss-remote sends the data to the target, and replies the same way with the source address.
+//!
+//! Each client address is associated with its own outbound socket in a NAT table,
+//! until no packet is relayed either way for the idle timeout.
+//!
+//! ss-local only relays packets of clients with an open UDP ASSOCIATE connection, see
+//! [`UdpClients`], and their associations end with it.
+//!
+//! Unlike tcp, the access control list is only used by ss-remote, to reject clients and
+//! block targets. ss-local proxies all packets, and doesn't support fragmentation.
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::{
+    net::UdpSocket,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        watch,
+    },
+    task::JoinSet,
+};
+
+use crate::{
+    context::Ctx,
+    crypto::cipher::Method,
+    net::{idna, packet, sys},
+    socks5::Socks5Addr,
+};
+
+mod constants {
+    pub const MAX_PACKET_SIZE: usize = 0x10000;
+    pub const ASSOCIATION_QUEUE_SIZE: usize = 64;
+
+    // RSV, RSV and FRAG fields of SOCKS5 udp packets.
+    pub const SOCKS5_UDP_HEADER: [u8; 3] = [0x00, 0x00, 0x00];
+}
+
+/// The clients ss-local relays udp packets of, registered by their UDP ASSOCIATE requests.
+pub struct UdpClients {
+    clients: Mutex<Vec<UdpClient>>,
+    next_id: AtomicU64,
+}
+
+struct UdpClient {
+    id: u64,
+    ip: IpAddr,
+    port: Option<u16>,
+    closed: watch::Receiver<()>,
+}
+
+impl UdpClients {
+    /// Creates an empty set of clients.
+    pub fn new() -> Self {
+        UdpClients {
+            clients: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Allows packets from the ip, and only from the port unless it is zero, until the returned
+    /// guard is dropped. The associations of the client then end.
+    pub fn register(&self, ip: IpAddr, port: u16) -> Registration<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = watch::channel(());
+
+        self.clients.lock().unwrap().push(UdpClient {
+            id,
+            ip: ip.to_canonical(),
+            port: (port != 0).then_some(port),
+            closed: rx,
+        });
+
+        Registration {
+            clients: self,
+            id,
+            _closed: tx,
+        }
+    }
+
+    /// Returns a receiver closed when the client is unregistered,
+    /// or `None` if packets from the address aren't allowed.
+    fn admit(&self, peer: SocketAddr) -> Option<watch::Receiver<()>> {
+        let ip = peer.ip().to_canonical();
+
+        self.clients
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|x| x.ip == ip && x.port.is_none_or(|port| port == peer.port()))
+            .map(|x| x.closed.clone())
+    }
+
+    /// Returns the number of registered clients.
+    pub fn len(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// Returns true if no client is registered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for UdpClients {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A registered client, see [`UdpClients::register`].
+pub struct Registration<'a> {
+    clients: &'a UdpClients,
+    id: u64,
+    _closed: watch::Sender<()>,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let mut clients = self.clients.clients.lock().unwrap();
+        clients.retain(|x| x.id != self.id);
+    }
+}
+
+/// Starts a shadowsocks remote udp relay.
+///
+/// Listens on every given address with its own cipher method and key,
+/// skipping the addresses that can't be bound unless none can.
+pub async fn ss_remote_udp(
+    servers: Vec<(SocketAddr, Method, Vec<u8>)>,
+    ctx: Arc<Ctx>,
+) -> io::Result<()> {
+    let mut sockets = Vec::with_capacity(servers.len());
+    let mut bind_error = None;
+
+    for (addr, method, key) in servers {
+        if let Err(e) = method.validate_key(&key) {
+            let e = format!("invalid key of {}: {}", addr, e);
+            return Err(io::Error::new(ErrorKind::InvalidInput, e));
+        }
+
+        let socket = match UdpSocket::bind(addr).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                log::error!("Unable to listen on udp {}: {}", addr, e);
+                bind_error = Some(e);
+                continue;
+            }
+        };
+        log::info!("ss-remote udp relay listening on {} ({})", addr, method);
+
+        sockets.push((socket, method, key));
+    }
+
+    if let (true, Some(e)) = (sockets.is_empty(), bind_error) {
+        return Err(e);
+    }
+
+    let mut tasks = JoinSet::new();
+    for (socket, method, key) in sockets {
+        tasks.spawn(serve_remote_udp(Arc::new(socket), method, key, ctx.clone()));
+    }
+
+    while let Some(res) = tasks.join_next().await {
+        res.map_err(|e| io::Error::new(ErrorKind::Other, e))??;
+    }
+
+    Ok(())
+}
+
+/// Starts a shadowsocks local udp relay, for SOCKS5 UDP ASSOCIATE requests.
+pub async fn ss_local_udp(
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+    method: Method,
+    key: Vec<u8>,
+    ctx: Arc<Ctx>,
+) -> io::Result<()> {
+    let socket = UdpSocket::bind(local_addr).await?;
+    log::info!("ss-local udp relay listening on {}", local_addr);
+
+    serve_local_udp(Arc::new(socket), remote_addr, method, key, ctx).await
+}
+
+async fn serve_local_udp(
+    socket: Arc<UdpSocket>,
+    remote_addr: SocketAddr,
+    method: Method,
+    key: Vec<u8>,
+    ctx: Arc<Ctx>,
+) -> io::Result<()> {
+    let nat = Arc::new(NatTable::new());
+    let mut buf = vec![0u8; constants::MAX_PACKET_SIZE];
+
+    loop {
+        let (n, peer) = socket.recv_from(&mut buf).await?;
+
+        // Fragments have a non-zero FRAG field.
+        if n < constants::SOCKS5_UDP_HEADER.len() || buf[..3] != constants::SOCKS5_UDP_HEADER {
+            log::debug!("Drop a malformed or fragmented udp packet, peer {}", peer);
+            continue;
+        }
+
+        let closed = match ctx.udp_clients().admit(peer) {
+            Some(closed) => closed,
+            None => {
+                log::debug!("Drop a udp packet of an unassociated client, peer {}", peer);
+                continue;
+            }
+        };
+
+        let payload = buf[3..n].to_vec();
+        nat.dispatch(peer, payload, |rx| {
+            let association = LocalAssociation {
+                socket: socket.clone(),
+                peer,
+                remote_addr,
+                method,
+                key: key.clone(),
+                fwmark: ctx.fwmark(),
+                closed,
+            };
+            tokio::spawn(associate(association, rx, nat.clone(), ctx.clone()));
+        });
+    }
+}
+
+async fn serve_remote_udp(
+    socket: Arc<UdpSocket>,
+    method: Method,
+    key: Vec<u8>,
+    ctx: Arc<Ctx>,
+) -> io::Result<()> {
+    let nat = Arc::new(NatTable::new());
+    let mut buf = vec![0u8; constants::MAX_PACKET_SIZE];
+
+    loop {
+        let (n, peer) = socket.recv_from(&mut buf).await?;
+
+        let payload = match packet::decrypt(method, &key, &buf[..n]) {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::debug!("Drop a udp packet: {}, peer {}", e, peer);
+                continue;
+            }
+        };
+
+        if !ctx.is_client_allowed(peer.ip()) {
+            log::debug!("Reject the udp client: peer {}", peer);
+            continue;
+        }
+
+        nat.dispatch(peer, payload, |rx| {
+            let association = RemoteAssociation {
+                socket: socket.clone(),
+                peer,
+                method,
+                key: key.clone(),
+                ctx: ctx.clone(),
+                resolved: None,
+            };
+            tokio::spawn(associate(association, rx, nat.clone(), ctx.clone()));
+        });
+    }
+}
+
+/// Maps client addresses to the queues of their associations.
+struct NatTable {
+    associations: Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>,
+}
+
+impl NatTable {
+    fn new() -> Self {
+        NatTable {
+            associations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Queues the payload to the association of the client,
+    /// starting one with `associate` if there is none.
+    ///
+    /// Payloads are dropped if the association is lagging behind.
+    fn dispatch<F>(&self, peer: SocketAddr, payload: Vec<u8>, associate: F)
+    where
+        F: FnOnce(mpsc::Receiver<Vec<u8>>),
+    {
+        let mut associations = self.associations.lock().unwrap();
+
+        let payload = match associations.get(&peer) {
+            Some(tx) => match tx.try_send(payload) {
+                Ok(()) => return,
+                Err(TrySendError::Full(_)) => {
+                    log::trace!("Drop a udp packet, association is full, peer {}", peer);
+                    return;
+                }
+                Err(TrySendError::Closed(payload)) => payload,
+            },
+            None => payload,
+        };
+
+        let (tx, rx) = mpsc::channel(constants::ASSOCIATION_QUEUE_SIZE);
+        tx.try_send(payload).expect("new association is full");
+        associations.insert(peer, tx);
+
+        associate(rx);
+    }
+
+    /// Removes the association of the client, if it has ended.
+    fn remove(&self, peer: SocketAddr) {
+        let mut associations = self.associations.lock().unwrap();
+
+        if associations.get(&peer).is_some_and(|tx| tx.is_closed()) {
+            associations.remove(&peer);
+        }
+    }
+}
+
+/// The relay of one client.
+trait Association {
+    /// The outbound socket of the association.
+    fn bind(&self) -> io::Result<std::net::UdpSocket>;
+
+    /// Relays a payload from the client through the outbound socket.
+    async fn outbound(&mut self, outbound: &UdpSocket, payload: Vec<u8>) -> io::Result<()>;
+
+    /// Relays a packet received on the outbound socket back to the client.
+    async fn inbound(&mut self, data: &[u8], from: SocketAddr) -> io::Result<()>;
+
+    /// Returns the client address.
+    fn peer(&self) -> SocketAddr;
+
+    /// Returns a receiver closed when the association must end before its idle timeout.
+    fn closed(&self) -> Option<watch::Receiver<()>>;
+}
+
+/// Relays packets of an association until it is idle for the idle timeout, or closed.
+async fn associate<A: Association>(
+    mut association: A,
+    mut rx: mpsc::Receiver<Vec<u8>>,
+    nat: Arc<NatTable>,
+    ctx: Arc<Ctx>,
+) {
+    let peer = association.peer();
+
+    let outbound = match association.bind().and_then(UdpSocket::from_std) {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::error!("Unable to bind udp socket: {}, peer {}", e, peer);
+            rx.close();
+            nat.remove(peer);
+            return;
+        }
+    };
+
+    log::debug!("UDP association started: peer {}", peer);
+
+    let mut closed = association.closed();
+
+    let mut buf = vec![0u8; constants::MAX_PACKET_SIZE];
+    loop {
+        let res = tokio::select! {
+            payload = rx.recv() => match payload {
+                Some(payload) => association.outbound(&outbound, payload).await,
+                None => break,
+            },
+            res = outbound.recv_from(&mut buf) => match res {
+                Ok((n, from)) => association.inbound(&buf[..n], from).await,
+                Err(e) => Err(e),
+            },
+            _ = tokio::time::sleep(ctx.timeouts().idle) => break,
+            _ = wait_closed(&mut closed) => break,
+        };
+
+        if let Err(e) = res {
+            log::debug!("Relay a udp packet failed: {}, peer {}", e, peer);
+        }
+    }
+
+    log::debug!("UDP association ended: peer {}", peer);
+
+    rx.close();
+    nat.remove(peer);
+}
+
+/// Waits until the receiver is closed, forever if there is none.
+async fn wait_closed(closed: &mut Option<watch::Receiver<()>>) {
+    match closed {
+        Some(closed) => while closed.changed().await.is_ok() {},
+        None => std::future::pending().await,
+    }
+}
+
+struct LocalAssociation {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    remote_addr: SocketAddr,
+    method: Method,
+    key: Vec<u8>,
+    fwmark: Option<u32>,
+    closed: watch::Receiver<()>,
+}
+
+impl Association for LocalAssociation {
+    fn bind(&self) -> io::Result<std::net::UdpSocket> {
+        let socket = std::net::UdpSocket::bind(unspecified(self.remote_addr.ip()))?;
+        if let Some(mark) = self.fwmark {
+            sys::set_mark(&socket, mark)?;
+        }
+        socket.connect(self.remote_addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }
+
+    async fn outbound(&mut self, outbound: &UdpSocket, payload: Vec<u8>) -> io::Result<()> {
+        let packet = packet::encrypt(self.method, &self.key, &payload)?;
+        outbound.send(&packet).await?;
+        Ok(())
+    }
+
+    async fn inbound(&mut self, data: &[u8], from: SocketAddr) -> io::Result<()> {
+        if from != self.remote_addr {
+            return Ok(());
+        }
+
+        let mut reply = constants::SOCKS5_UDP_HEADER.to_vec();
+        reply.append(&mut packet::decrypt(self.method, &self.key, data)?);
+
+        self.socket.send_to(&reply, self.peer).await?;
+        Ok(())
+    }
+
+    fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    fn closed(&self) -> Option<watch::Receiver<()>> {
+        Some(self.closed.clone())
+    }
+}
+
+struct RemoteAssociation {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    method: Method,
+    key: Vec<u8>,
+    ctx: Arc<Ctx>,
+
+    // The last resolved domain name, as most associations have a single target.
+    resolved: Option<(String, SocketAddr)>,
+}
+
+impl RemoteAssociation {
+    async fn resolve(&mut self, target_addr: &Socks5Addr) -> io::Result<SocketAddr> {
+        let host = match target_addr {
+            Socks5Addr::Ipv4(addr) => return Ok((*addr).into()),
+            Socks5Addr::Ipv6(addr) => return Ok((*addr).into()),
+            Socks5Addr::DomainName((host, port)) => format!("{}:{}", idna::to_ascii(host)?, port),
+        };
+
+        if let Some((ref resolved_host, addr)) = self.resolved {
+            if *resolved_host == host {
+                return Ok(addr);
+            }
+        }
+
+        let addr = self.ctx.resolve(&host).await?;
+        self.resolved = Some((host, addr));
+
+        Ok(addr)
+    }
+}
+
+impl Association for RemoteAssociation {
+    fn bind(&self) -> io::Result<std::net::UdpSocket> {
+        // Dual-stack if possible, ipv4 targets are then sent to as ipv4-mapped addresses.
+        let socket = std::net::UdpSocket::bind(unspecified(Ipv6Addr::UNSPECIFIED.into()))
+            .or_else(|_| std::net::UdpSocket::bind(unspecified(Ipv4Addr::UNSPECIFIED.into())))?;
+        if let Some(mark) = self.ctx.fwmark() {
+            sys::set_mark(&socket, mark)?;
+        }
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }
+
+    async fn outbound(&mut self, outbound: &UdpSocket, payload: Vec<u8>) -> io::Result<()> {
+        let mut reader = &payload[..];
+        let target_addr = Socks5Addr::construct(&mut reader).await?;
+        let target = self.resolve(&target_addr).await?;
+
+        if self.ctx.is_self_connect(target) {
+            log::warn!(
+                "Reject the udp target, it is the server itself: {} -> {}",
+                self.peer,
+                target_addr
+            );
+            return Ok(());
+        }
+
+        let host = match &target_addr {
+            Socks5Addr::DomainName((host, _)) => Some(idna::to_ascii(host)?),
+            _ => None,
+        };
+        if self.ctx.is_block_outbound(target.ip(), host.as_deref()) {
+            log::debug!(
+                "Block udp outbound address: {} -> {}",
+                self.peer,
+                target_addr
+            );
+            return Ok(());
+        }
+
+        let target = match (outbound.local_addr()?, target.ip()) {
+            (SocketAddr::V6(_), IpAddr::V4(ip)) => {
+                SocketAddr::new(ip.to_ipv6_mapped().into(), target.port())
+            }
+            (SocketAddr::V4(_), IpAddr::V6(_)) => {
+                return Err(io::Error::new(
+                    ErrorKind::Unsupported,
+                    "ipv6 is unavailable",
+                ))
+            }
+            _ => target,
+        };
+
+        outbound.send_to(reader, target).await?;
+        Ok(())
+    }
+
+    async fn inbound(&mut self, data: &[u8], from: SocketAddr) -> io::Result<()> {
+        let from = match from {
+            SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+                Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+                None => from,
+            },
+            _ => from,
+        };
+
+        let mut payload = Socks5Addr::from(from).get_raw_parts();
+        payload.extend_from_slice(data);
+
+        let packet = packet::encrypt(self.method, &self.key, &payload)?;
+        self.socket.send_to(&packet, self.peer).await?;
+        Ok(())
+    }
+
+    fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    // Clients of ss-remote are only authenticated by the key.
+    fn closed(&self) -> Option<watch::Receiver<()>> {
+        None
+    }
+}
+
+fn unspecified(ip: IpAddr) -> SocketAddr {
+    match ip {
+        IpAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        IpAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::context::Timeouts;
+
+    use super::*;
+
+    async fn bind() -> Arc<UdpSocket> {
+        Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap())
+    }
+
+    async fn received(socket: &UdpSocket, buf: &mut [u8]) -> bool {
+        let res = tokio::time::timeout(Duration::from_millis(200), socket.recv(buf)).await;
+        res.is_ok()
+    }
+
+    #[tokio::test]
+    async fn test_udp_relay() {
+        let mut ctx = Ctx::new();
+        ctx.set_timeouts(Timeouts {
+            idle: Duration::from_millis(200),
+            ..Default::default()
+        });
+        let ctx = Arc::new(ctx);
+
+        let method = Method::ChaCha20Poly1305;
+        let key = vec![7u8; method.key_size()];
+
+        let echo = bind().await;
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            loop {
+                let (n, peer) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..n], peer).await.unwrap();
+            }
+        });
+
+        let remote = bind().await;
+        let remote_addr = remote.local_addr().unwrap();
+        tokio::spawn(serve_remote_udp(remote, method, key.clone(), ctx.clone()));
+
+        let local = bind().await;
+        let local_addr = local.local_addr().unwrap();
+        tokio::spawn(serve_local_udp(
+            local,
+            remote_addr,
+            method,
+            key,
+            ctx.clone(),
+        ));
+
+        let mut request = constants::SOCKS5_UDP_HEADER.to_vec();
+        request.append(&mut Socks5Addr::from(echo_addr).get_raw_parts());
+        request.extend_from_slice(b"hello");
+
+        let client = bind().await;
+        let mut buf = [0u8; 1024];
+
+        // Packets of clients without an association are dropped
+        client.send_to(&request, local_addr).await.unwrap();
+        assert!(!received(&client, &mut buf).await);
+
+        let other_port = ctx.udp_clients().register(Ipv4Addr::LOCALHOST.into(), 1);
+        client.send_to(&request, local_addr).await.unwrap();
+        assert!(!received(&client, &mut buf).await);
+        drop(other_port);
+
+        let registration = ctx.udp_clients().register(Ipv4Addr::LOCALHOST.into(), 0);
+        for _ in 0..2 {
+            client.send_to(&request, local_addr).await.unwrap();
+            let n = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..n], request);
+
+            // A new association after the idle timeout
+            tokio::time::sleep(Duration::from_millis(400)).await;
+        }
+
+        // Fragments are dropped
+        request[2] = 1;
+        client.send_to(&request, local_addr).await.unwrap();
+        assert!(!received(&client, &mut buf).await);
+
+        // The association ends with its control connection
+        request[2] = 0;
+        client.send_to(&request, local_addr).await.unwrap();
+        assert!(received(&client, &mut buf).await);
+        drop(registration);
+        assert!(ctx.udp_clients().is_empty());
+        client.send_to(&request, local_addr).await.unwrap();
+        assert!(!received(&client, &mut buf).await);
+    }
+}
@@ -5,7 +5,7 @@ use std::{
     str::FromStr,
 };
 
-use base64::{Engine as _, engine::general_purpose};
+use base64::{engine::general_purpose, Engine as _};
 
 use crate::crypto::cipher::Method;
 
@@ -16,11 +16,43 @@ pub struct SsUrl {
     pub password: String,
     pub hostname: String,
     pub port: u16,
+    /// The query key, normally `plugin`.
     pub plugin: Option<String>,
+    /// The url-decoded query value, i.e. `plugin-name;plugin-options`.
     pub plugin_opts: Option<String>,
+    /// The url-encoded fragment.
     pub tag: Option<String>,
 }
 
+impl SsUrl {
+    /// Returns the SIP003 plugin name and its options.
+    pub fn sip003_plugin(&self) -> Option<(String, Option<String>)> {
+        match (self.plugin.as_deref(), self.plugin_opts.as_deref()) {
+            (Some("plugin"), Some(value)) if !value.is_empty() => match value.split_once(';') {
+                Some((name, opts)) => Some((name.to_owned(), Some(opts.to_owned()))),
+                None => Some((value.to_owned(), None)),
+            },
+            _ => None,
+        }
+    }
+
+    /// Sets the SIP003 plugin name and its options.
+    pub fn set_sip003_plugin(&mut self, name: &str, opts: Option<&str>) {
+        let value = match opts {
+            Some(opts) => format!("{};{}", name, opts),
+            None => name.to_owned(),
+        };
+
+        self.plugin = Some("plugin".to_owned());
+        self.plugin_opts = Some(value);
+    }
+
+    /// Sets the tag, which will be url-encoded.
+    pub fn set_tag(&mut self, tag: &str) {
+        self.tag = Some(urlencoding::encode(tag).into_owned());
+    }
+}
+
 impl FromStr for SsUrl {
     type Err = ErrorKind;
 
@@ -30,6 +62,12 @@ impl FromStr for SsUrl {
         }
         s = &s[5..];
 
+        // SIP001 links encode everything but the tag, so there is no `@`.
+        let end = s.find('#').unwrap_or(s.len());
+        if !s[..end].contains('@') {
+            return parse_legacy(&s[..end], s.get(end + 1..));
+        }
+
         let (method, password) = parse_userinfo(&mut s)?;
         let hostname = parse_hostname(&mut s)?;
 
@@ -98,8 +136,15 @@ impl FromStr for SsUrl {
 
 impl Display for SsUrl {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        let s = format!("{}:{}", self.method.to_string(), self.password);
-        let s = general_purpose::URL_SAFE.encode(&s);
+        // SIP022: the userinfo of the 2022 methods, whose password is a base64 encoded key,
+        // is percent-encoded plaintext rather than base64.
+        let s = match self.method.is_2022() {
+            true => format!("{}:{}", self.method, urlencoding::encode(&self.password)),
+            false => {
+                let s = format!("{}:{}", self.method.to_string(), self.password);
+                general_purpose::URL_SAFE.encode(&s)
+            }
+        };
         let mut s = format!("ss://{}@{}:{}", s, self.hostname, self.port);
 
         if let Some(ref plugin) = self.plugin {
@@ -124,12 +169,9 @@ fn parse_userinfo(s: &mut &str) -> Result<(Method, String), ErrorKind> {
         None => return Err(ErrorKind::Invalid),
     };
 
-    let userinfo = &s[..pos];
-    let userinfo = match general_purpose::URL_SAFE.decode(userinfo) {
-        Ok(x) => String::from_utf8(x).unwrap(),
-        Err(_) => return Err(ErrorKind::Decode),
-    };
+    let userinfo = decode_userinfo(&s[..pos])?;
 
+    // Method names have no colons, the password may have.
     let (method, password) = match userinfo.split_once(':') {
         Some(x) => x,
         None => return Err(ErrorKind::UserInfo),
@@ -145,6 +187,66 @@ fn parse_userinfo(s: &mut &str) -> Result<(Method, String), ErrorKind> {
     Ok((method, password))
 }
 
+/// Parses a legacy SIP001 link, `BASE64(method:password@hostname:port)` and an optional tag.
+fn parse_legacy(encoded: &str, tag: Option<&str>) -> Result<SsUrl, ErrorKind> {
+    let decoded = match decode_base64(encoded) {
+        Some(x) => x,
+        None => return Err(ErrorKind::Decode),
+    };
+
+    // The password may have colons and `@`, the hostname neither.
+    let (userinfo, hostport) = match decoded.rsplit_once('@') {
+        Some(x) => x,
+        None => return Err(ErrorKind::Invalid),
+    };
+    let (method, password) = match userinfo.split_once(':') {
+        Some(x) => x,
+        None => return Err(ErrorKind::UserInfo),
+    };
+    let (hostname, port) = match hostport.rsplit_once(':') {
+        Some(x) => x,
+        None => return Err(ErrorKind::Invalid),
+    };
+
+    let method: Method = match method.parse() {
+        Ok(x) => x,
+        Err(_) => return Err(ErrorKind::Method),
+    };
+
+    Ok(SsUrl {
+        method,
+        password: password.to_owned(),
+        hostname: hostname.to_owned(),
+        port: parse_port(port)?,
+        plugin: None,
+        plugin_opts: None,
+        tag: tag.map(|x| x.to_owned()),
+    })
+}
+
+/// Decodes base64 with or without padding, in either the URL-safe or the standard alphabet.
+fn decode_base64(s: &str) -> Option<String> {
+    let unpadded = s.trim_end_matches('=');
+    [
+        &general_purpose::URL_SAFE_NO_PAD,
+        &general_purpose::STANDARD_NO_PAD,
+    ]
+    .iter()
+    .find_map(|engine| String::from_utf8(engine.decode(unpadded).ok()?).ok())
+}
+
+/// Decodes the userinfo, base64-encoded with or without padding, or percent-encoded plaintext.
+fn decode_userinfo(userinfo: &str) -> Result<String, ErrorKind> {
+    if let Some(x) = decode_base64(userinfo).filter(|x| x.contains(':')) {
+        return Ok(x);
+    }
+
+    match urlencoding::decode(userinfo) {
+        Ok(x) => Ok(x.into_owned()),
+        Err(_) => Err(ErrorKind::Decode),
+    }
+}
+
 fn parse_hostname(s: &mut &str) -> Result<String, ErrorKind> {
     let pos = match s.find(':') {
         Some(x) => x,
@@ -166,10 +268,13 @@ fn parse_port(s: &str) -> Result<u16, ErrorKind> {
 
 fn parse_plugin(s: &str) -> Result<(Option<String>, Option<String>), ErrorKind> {
     match s.split_once('=') {
-        Some((a, b)) => Ok((Some(a.to_owned()), Some(match urlencoding::decode(b) {
-            Ok(x) => x.into_owned(),
-            Err(_) => return Err(ErrorKind::Plugin),
-        }))),
+        Some((a, b)) => Ok((
+            Some(a.to_owned()),
+            Some(match urlencoding::decode(b) {
+                Ok(x) => x.into_owned(),
+                Err(_) => return Err(ErrorKind::Plugin),
+            }),
+        )),
         None => Ok((Some(s.to_owned()), None)),
     }
 }
@@ -252,4 +357,121 @@ mod tests {
             println!("{:#?}", ss_url);
         }
     }
+
+    #[test]
+    fn test_userinfo() {
+        // shadowsocks-android, URL-safe base64 without padding
+        let url = "ss://YWVzLTI1Ni1nY206dGVzdA@192.168.100.1:8888#Example";
+        let ss_url = url.parse::<SsUrl>().unwrap();
+        assert_eq!(ss_url.method, Method::Aes256Gcm);
+        assert_eq!(ss_url.password, "test");
+
+        // Shadowrocket, plaintext
+        let url = "ss://aes-128-gcm:test@192.168.100.1:8888";
+        let ss_url = url.parse::<SsUrl>().unwrap();
+        assert_eq!(ss_url.method, Method::Aes128Gcm);
+        assert_eq!(ss_url.password, "test");
+
+        // Percent-encoded plaintext, the password has a colon
+        let url = "ss://chacha20-ietf-poly1305:pa%3Ass%40word@192.168.100.1:8888";
+        let ss_url = url.parse::<SsUrl>().unwrap();
+        assert_eq!(ss_url.method, Method::ChaCha20Poly1305);
+        assert_eq!(ss_url.password, "pa:ss@word");
+
+        // Standard base64 alphabet, the password has a colon
+        let userinfo = general_purpose::STANDARD.encode("aes-256-gcm:a:b>>?");
+        let url = format!("ss://{}@192.168.100.1:8888", userinfo);
+        let ss_url = url.parse::<SsUrl>().unwrap();
+        assert_eq!(ss_url.password, "a:b>>?");
+
+        // Round trip
+        let ss_url = ss_url.to_string().parse::<SsUrl>().unwrap();
+        assert_eq!(ss_url.method, Method::Aes256Gcm);
+        assert_eq!(ss_url.password, "a:b>>?");
+
+        let res = "ss://dGVzdA@192.168.100.1:8888".parse::<SsUrl>();
+        assert!(matches!(res, Err(ErrorKind::UserInfo)));
+        let res = "ss://foo:bar@192.168.100.1:8888".parse::<SsUrl>();
+        assert!(matches!(res, Err(ErrorKind::Method)));
+    }
+
+    #[test]
+    fn test_2022() {
+        let url = "ss://2022-blake3-aes-256-gcm:YctPZ6U7xPPcU%2Bgp3u%2BOrVYKNjONh5ru7%2FZGdtRHLeo%3D@192.168.100.1:8888";
+        let ss_url = url.parse::<SsUrl>().unwrap();
+        assert_eq!(ss_url.method, Method::Blake3Aes256Gcm);
+        assert_eq!(
+            ss_url.password,
+            "YctPZ6U7xPPcU+gp3u+OrVYKNjONh5ru7/ZGdtRHLeo="
+        );
+        assert_eq!(ss_url.to_string(), url);
+    }
+
+    #[test]
+    fn test_legacy() {
+        // ss://BASE64(aes-256-gcm:test@192.168.100.1:8888)#Example
+        let url = "ss://YWVzLTI1Ni1nY206dGVzdEAxOTIuMTY4LjEwMC4xOjg4ODg#Example";
+        let ss_url = url.parse::<SsUrl>().unwrap();
+        assert_eq!(ss_url.method, Method::Aes256Gcm);
+        assert_eq!(ss_url.password, "test");
+        assert_eq!(ss_url.hostname, "192.168.100.1");
+        assert_eq!(ss_url.port, 8888);
+        assert_eq!(ss_url.tag.as_deref(), Some("Example"));
+
+        // Written back in SIP002
+        let sip002 = "ss://YWVzLTI1Ni1nY206dGVzdA==@192.168.100.1:8888#Example";
+        assert_eq!(ss_url.to_string(), sip002);
+        assert_eq!(sip002.parse::<SsUrl>().unwrap().to_string(), sip002);
+
+        // The password has `:` and `@`, padded standard base64
+        let encoded = general_purpose::STANDARD.encode("aes-128-gcm:p:a@ss@example.com:443");
+        let ss_url = format!("ss://{}", encoded).parse::<SsUrl>().unwrap();
+        assert_eq!(ss_url.password, "p:a@ss");
+        assert_eq!(ss_url.hostname, "example.com");
+        assert_eq!(ss_url.port, 443);
+        assert_eq!(ss_url.tag, None);
+
+        let encoded = general_purpose::STANDARD.encode("aes-128-gcm:test");
+        let res = format!("ss://{}", encoded).parse::<SsUrl>();
+        assert!(matches!(res, Err(ErrorKind::Invalid)));
+        assert!(matches!(
+            "ss://!!!".parse::<SsUrl>(),
+            Err(ErrorKind::Decode)
+        ));
+    }
+
+    #[test]
+    fn test_sip003_plugin() {
+        let url = "ss://Y2hhY2hhMjAtaWV0Zi1wb2x5MTMwNTpwYXNzd2Q=@192.168.100.1:8888/?plugin=obfs-local%3Bobfs%3Dhttp#Example2";
+        let ss_url = url.parse::<SsUrl>().unwrap();
+        assert_eq!(
+            ss_url.sip003_plugin(),
+            Some(("obfs-local".to_owned(), Some("obfs=http".to_owned())))
+        );
+
+        let mut ss_url = SsUrl {
+            method: Method::ChaCha20Poly1305,
+            password: "passwd".to_owned(),
+            hostname: "192.168.100.1".to_owned(),
+            port: 8888,
+            plugin: None,
+            plugin_opts: None,
+            tag: None,
+        };
+        assert_eq!(ss_url.sip003_plugin(), None);
+
+        ss_url.set_sip003_plugin("obfs-local", Some("obfs=http"));
+        ss_url.set_tag("Example2");
+        assert_eq!(ss_url.to_string(), url);
+
+        ss_url.set_sip003_plugin("v2ray-plugin", None);
+        ss_url.set_tag("My Server");
+        assert_eq!(
+            ss_url.sip003_plugin(),
+            Some(("v2ray-plugin".to_owned(), None))
+        );
+        assert!(ss_url
+            .to_string()
+            .ends_with("/?plugin=v2ray-plugin#My%20Server"));
+    }
 }
@@ -0,0 +1,69 @@
+//! Helpers shared by the integration tests.
+
+use std::net::SocketAddr;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// A local target server, so relays can be tested without network access.
+pub struct EchoServer {
+    addr: SocketAddr,
+}
+
+impl EchoServer {
+    /// Starts a server on a free loopback port, echoing back everything it receives
+    /// until the client shuts down its writing half.
+    pub async fn start() -> Self {
+        Self::spawn(|mut stream| async move {
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+            writer.shutdown().await.unwrap();
+        })
+        .await
+    }
+
+    /// Starts a server on a free loopback port, answering a HTTP request with the body,
+    /// then closing the connection.
+    pub async fn start_http(body: &'static str) -> Self {
+        Self::spawn(move |mut stream| async move {
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+
+            let rsp = format!(
+                "HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(rsp.as_bytes()).await.unwrap();
+            stream.shutdown().await.unwrap();
+        })
+        .await
+    }
+
+    /// Returns the address of the server.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    async fn spawn<F, Fut>(handle: F) -> Self
+    where
+        F: Fn(TcpStream) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(handle(stream));
+            }
+        });
+
+        EchoServer { addr }
+    }
+}
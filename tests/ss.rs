@@ -1,79 +1,199 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener as TokioTcpListener, TcpStream as TokioTcpStream},
-    sync::oneshot::{self, Receiver, Sender},
 };
 
 use ss_rs::{
     context::Ctx,
-    crypto::cipher::Method,
+    crypto::{cipher::Method, derive_key, derive_key_2022},
+    net::stream::TcpStream as SsTcpStream,
+    socks5::Socks5Addr,
     tcp::{handle_ss_local, handle_ss_remote, SsTcpListener},
 };
 
-const REMOTE_ADDR: &str = "127.0.0.1:10800";
-const LOCAL_ADDR: &str = "127.0.0.1:10801";
+use common::EchoServer;
+
+mod common;
 
 const METHOD: Method = Method::ChaCha20Poly1305;
-const KEY: &str = "123456";
+const PASSWORD: &str = "123456";
+
+// Pre-shared keys of the 2022 methods.
+const PSK_128: &str = "AAECAwQFBgcICQoLDA0ODw==";
+const PSK_256: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+// Greeting, then a CONNECT request without the target address.
+const SOCKS5_CONNECT: &[u8] = b"\x05\x02\x00\x01\x05\x01\x00";
 
-const REQ: &[u8] = b"\x05\x02\x00\x01\x05\x01\x00\x03\x0bexample.com\x00\x50GET / HTTP/1.0\r\n\r\n";
+// Version, method, then a reply with an ipv4 address.
+const SOCKS5_REPLY_SIZE: usize = 12;
 
 #[tokio::test]
 async fn test() {
-    let (tx1, rx1) = oneshot::channel::<()>();
-    let (tx2, rx2) = oneshot::channel::<()>();
+    let target = EchoServer::start_http("hello").await;
+    let local_addr = start_relay(METHOD, &key(METHOD)).await;
 
-    let mut handles = Vec::new();
+    let mut client = connect(local_addr, target.addr()).await;
+    client.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+
+    let mut rsp = String::new();
+    client.read_to_string(&mut rsp).await.unwrap();
+    assert_eq!(rsp, "HTTP/1.0 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+}
 
-    handles.push(tokio::spawn(remote(tx1)));
-    handles.push(tokio::spawn(local(tx2)));
-    handles.push(tokio::spawn(client(rx1, rx2)));
+#[tokio::test]
+async fn test_http_connect() {
+    let target = EchoServer::start().await;
+    let local_addr = start_relay(METHOD, &key(METHOD)).await;
+
+    let mut client = TokioTcpStream::connect(local_addr).await.unwrap();
+    let req = format!(
+        "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\nhello",
+        target.addr()
+    );
+    client.write_all(req.as_bytes()).await.unwrap();
+
+    let established = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+    let mut rsp = vec![0u8; established.len()];
+    client.read_exact(&mut rsp).await.unwrap();
+    assert_eq!(rsp, established);
+
+    let mut buf = [0u8; 5];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[tokio::test]
+async fn test_large_payload() {
+    let target = EchoServer::start().await;
+    let local_addr = start_relay(METHOD, &key(METHOD)).await;
 
-    for handle in handles {
-        handle.await.unwrap();
+    let client = connect(local_addr, target.addr()).await;
+    let (mut reader, mut writer) = client.into_split();
+
+    // Much larger than a chunk (0x3FFF bytes), sent while the echo is read.
+    let data: Vec<u8> = (0..0x40000).map(|x| (x % 251) as u8).collect();
+    let expected = data.clone();
+
+    let write = tokio::spawn(async move {
+        writer.write_all(&data).await.unwrap();
+        writer.shutdown().await.unwrap();
+    });
+
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf).await.unwrap();
+    write.await.unwrap();
+
+    assert_eq!(buf.len(), expected.len());
+    assert!(buf == expected);
+}
+
+#[tokio::test]
+async fn test_2022() {
+    let target = EchoServer::start().await;
+
+    for method in [
+        Method::Blake3Aes128Gcm,
+        Method::Blake3Aes256Gcm,
+        Method::Blake3ChaCha20Poly1305,
+    ] {
+        let psk = match method.key_size() {
+            16 => PSK_128,
+            _ => PSK_256,
+        };
+
+        let mut key = vec![0u8; method.key_size()];
+        derive_key_2022(psk.as_bytes(), &mut key).unwrap();
+        let local_addr = start_relay(method, &key).await;
+
+        // Target address without payload, then data both ways
+        let mut client = connect(local_addr, target.addr()).await;
+        client.write_all(b"hello").await.unwrap();
+
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
     }
 }
 
-async fn local(tx: Sender<()>) {
-    let listener = TokioTcpListener::bind(LOCAL_ADDR).await.unwrap();
-    tx.send(()).unwrap();
-
-    let (stream, peer) = listener.accept().await.unwrap();
-    handle_ss_local(
-        stream,
-        peer,
-        REMOTE_ADDR.parse().unwrap(),
-        METHOD,
-        KEY.into(),
-        Arc::new(Ctx::new()),
-    )
-    .await;
+#[tokio::test]
+async fn test_client() {
+    let target = EchoServer::start().await;
+
+    let key = key(METHOD);
+    let ctx = Arc::new(Ctx::new());
+    let remote = SsTcpListener::bind("127.0.0.1:0", METHOD, &key, ctx.clone())
+        .await
+        .unwrap();
+    let remote_addr = remote.local_addr().unwrap();
+
+    let remote_ctx = ctx.clone();
+    tokio::spawn(async move {
+        let (stream, peer) = remote.accept().await.unwrap();
+        handle_ss_remote(stream, peer, remote_ctx).await;
+    });
+
+    // Straight to ss-remote, without ss-local
+    let target_addr = Socks5Addr::from(target.addr());
+    let mut client = SsTcpStream::connect(remote_addr, target_addr, METHOD, &key, ctx.clone())
+        .await
+        .unwrap();
+    client.write_all(b"hello").await.unwrap();
+
+    let mut buf = [0u8; 5];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+
+    // A key of the wrong size fails before connecting
+    let target_addr = Socks5Addr::from(target.addr());
+    let res = SsTcpStream::connect(remote_addr, target_addr, METHOD, &key[..16], ctx).await;
+    assert_eq!(res.err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+}
+
+fn key(method: Method) -> Vec<u8> {
+    let mut key = vec![0u8; method.key_size()];
+    derive_key(PASSWORD.as_bytes(), &mut key).unwrap();
+    key
 }
 
-async fn remote(tx: Sender<()>) {
+/// Starts ss-remote and ss-local for a single connection, returns the address of ss-local.
+async fn start_relay(method: Method, key: &[u8]) -> SocketAddr {
+    let key = key.to_vec();
     let ctx = Arc::new(Ctx::new());
-    let listener = SsTcpListener::bind(REMOTE_ADDR, METHOD, KEY.as_bytes(), ctx.clone())
+    let remote = SsTcpListener::bind("127.0.0.1:0", method, &key, ctx.clone())
         .await
         .unwrap();
-    tx.send(()).unwrap();
+    let remote_addr = remote.local_addr().unwrap();
 
-    let (stream, peer) = listener.accept().await.unwrap();
-    handle_ss_remote(stream, peer, ctx.clone()).await;
+    tokio::spawn(async move {
+        let (stream, peer) = remote.accept().await.unwrap();
+        handle_ss_remote(stream, peer, ctx).await;
+    });
+
+    let local = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = local.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (stream, peer) = local.accept().await.unwrap();
+        handle_ss_local(stream, peer, remote_addr, method, key, Arc::new(Ctx::new())).await;
+    });
+
+    local_addr
 }
 
-async fn client(rx1: Receiver<()>, rx2: Receiver<()>) {
-    rx1.await.unwrap();
-    rx2.await.unwrap();
+/// Connects to the target through ss-local, returns after the SOCKS5 handshake.
+async fn connect(local_addr: SocketAddr, target: SocketAddr) -> TokioTcpStream {
+    let mut client = TokioTcpStream::connect(local_addr).await.unwrap();
 
-    let mut client = TokioTcpStream::connect(LOCAL_ADDR).await.unwrap();
-    client.write_all(REQ).await.unwrap();
+    let mut req = SOCKS5_CONNECT.to_vec();
+    req.extend(Socks5Addr::from(target).get_raw_parts());
+    client.write_all(&req).await.unwrap();
 
-    let mut buf = Vec::new();
-    client.read_to_end(&mut buf).await.unwrap();
+    let mut rsp = [0u8; SOCKS5_REPLY_SIZE];
+    client.read_exact(&mut rsp).await.unwrap();
+    assert_eq!(rsp[..4], [0x05, 0x00, 0x05, 0x00]);
 
-    let rsp = String::from_utf8(buf[12..].to_vec()).unwrap();
-    assert!(!rsp.is_empty());
-    println!("{}", rsp);
+    client
 }